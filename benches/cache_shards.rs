@@ -0,0 +1,66 @@
+//! Measures the lock contention of the cache with different numbers of shards (cache_shards).
+//! Several threads look up cached names at once, locking the shard of every name the way the cache does.
+//! Run with `cargo bench --bench cache_shards`
+
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant };
+use rusqlite::{ Connection, NO_PARAMS, params };
+
+use dns::database::shard_index;
+
+const THREADS: usize = 8;
+const NAMES: usize = 10_000;
+const LOOKUPS: usize = 20_000; //Per thread
+
+fn main() {
+    let baseline = run(1);
+    println!("{:>2} shard:  {:>9.0} lookups/s", 1, lookups_per_second(baseline));
+
+    for shards in [2, 4, 8, 16].iter() {
+        let elapsed = run(*shards);
+        println!(
+            "{:>2} shards: {:>9.0} lookups/s ({:.2}x)",
+            shards, lookups_per_second(elapsed), baseline.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
+
+fn lookups_per_second(elapsed: Duration) -> f64 {
+    (THREADS * LOOKUPS) as f64 / elapsed.as_secs_f64()
+}
+
+//Returns the time all threads took for their lookups
+fn run(shards: usize) -> Duration {
+    let stores: Vec<Mutex<Connection>> = (0..shards).map(|_| {
+        let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
+        conn.execute("CREATE TABLE bench(name TEXT PRIMARY KEY, ipv4 TEXT, ttl INT)", NO_PARAMS).unwrap();
+        Mutex::new(conn)
+    })
+    .collect();
+    for i in 0..NAMES {
+        let name = format!("host{}.bench", i);
+        let db = stores[shard_index(&name, shards)].lock().unwrap();
+        db.execute("INSERT INTO bench (name, ipv4, ttl) VALUES (?, ?, 600)", params![name, "192.0.2.1"]).unwrap();
+    }
+    let stores = Arc::new(stores);
+
+    let start = Instant::now();
+    let threads: Vec<thread::JoinHandle<()>> = (0..THREADS).map(|thread_index| {
+        let stores = stores.clone();
+        thread::spawn(move || {
+            for i in 0..LOOKUPS {
+                let name = format!("host{}.bench", (i * 7919 + thread_index * 104_729) % NAMES);
+                let db = stores[shard_index(&name, shards)].lock().unwrap();
+                let value: String = db.query_row("SELECT ipv4 FROM bench WHERE name = ?", params![name], |row| row.get(0)).unwrap();
+                assert_eq!(value, "192.0.2.1");
+            }
+        })
+    })
+    .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    start.elapsed()
+}
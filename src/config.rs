@@ -0,0 +1,938 @@
+use std::fs;
+use std::collections::HashMap;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::Arc;
+use serde::Deserialize;
+use lazy_static;
+
+use crate::dns_request::{ ExtendedError, DnsRecordType };
+use crate::zone;
+
+const CONFIG_PATH: &str = "./data/config.json";
+//Environment variables defining local records, like DNS_STATIC_A_myhost=10.0.0.5
+const ENV_RECORD_PREFIX: &str = "DNS_STATIC_";
+const ENV_RECORD_TYPES: [&str; 4] = ["A", "AAAA", "CNAME", "TXT"];
+
+lazy_static! {
+    static ref CONFIG: Arc<Config> = Arc::new(Config::load(CONFIG_PATH));
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_CONFIG: std::cell::RefCell<Option<Arc<Config>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// # Struct representing the configuration of the server
+///The configuration is read once at startup from ./data/config.json. Every field is optional
+///in the file and takes its default value when missing
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    ///Whether the CHAOS class identity queries (id.server and hostname.bind) are answered
+    pub chaos_identity: bool,
+    ///The identifier returned for id.server and hostname.bind (defaults to the machine hostname)
+    pub server_id: Option<String>,
+    ///The names of this server (such as its hostname) mapped to the addresses it listens on,
+    ///A and AAAA queries for them are answered with these addresses
+    pub server_addresses: HashMap<String, Vec<String>>,
+    ///Domains (and their subdomains) for which queries are refused
+    pub blocklist: Vec<String>,
+    ///How queries for blocked names are answered
+    pub block_mode: BlockMode,
+    ///The time to live (and minimum) of the SOA attached to negative responses for blocked names,
+    ///which clients use as the time to cache the negative response
+    pub negative_ttl: u32,
+    ///The Extended DNS Error attached to responses for blocked names and names not on the allowlist (null to omit it)
+    pub blocklist_error: Option<ExtendedError>,
+    ///Domains (and their subdomains) which are the only ones answered, unless allowlist_mode is off
+    pub allowlist: Vec<String>,
+    ///How queries for names not on the allowlist are answered
+    pub allowlist_mode: AllowlistMode,
+    ///Whether queries for names outside the local zones are forwarded to the upstream (the RA bit of responses).
+    ///Without recursion the server is authoritative only and refuses them
+    pub recursion_available: bool,
+    ///The dns-over-https json endpoint queries are forwarded to
+    pub upstream: String,
+    ///Addresses upstream endpoints given by hostname (such as https://dns.google/resolve) are connected to,
+    ///tried in order, so the hostname is never resolved (which would be a query to this server itself).
+    ///The certificate of the upstream has to be valid for these addresses
+    pub bootstrap: Vec<String>,
+    ///The User-Agent sent with upstream requests
+    pub user_agent: String,
+    ///Additional headers sent with upstream requests (header name -> value)
+    pub upstream_headers: HashMap<String, String>,
+    ///The dns server (ip:port) queried over udp for record types the json upstream is not used for,
+    ///its records are passed through unchanged (null to leave such queries unanswered)
+    pub wire_upstream: Option<String>,
+    ///Whether the case of the letters of names sent to the wire upstream is randomized (DNS 0x20 encoding),
+    ///responses not echoing the exact case are ignored as likely spoofed
+    pub wire_upstream_randomize_case: bool,
+    ///Whether the alias a (misconfigured) upstream answers an SOA query with is followed once: the alias is answered
+    ///along with the SOA record of its target, which is only cached for the target. Otherwise the query is answered
+    ///with SERVFAIL (as it is if the target is an alias as well)
+    pub follow_soa_alias: bool,
+    ///Endpoints used instead of the default upstream for names within a zone (zone suffix -> endpoint)
+    pub forwarders: HashMap<String, String>,
+    ///Whether ANY queries over udp are answered with an empty truncated response, so clients retry over tcp
+    pub truncate_udp_any: bool,
+    ///Record types (type mnemonics such as "ANY" or "AXFR") whose queries over udp are answered with an empty
+    ///truncated response, so clients retry over tcp
+    pub tcp_only_types: Vec<String>,
+    ///Limits of the answers of udp responses per type asked for (type mnemonic -> limit), answers beyond a limit
+    ///are dropped and the TrunCation bit is set so clients can ask again over tcp
+    pub answer_limits: HashMap<String, AnswerLimit>,
+    ///Whether every query and its response code are logged
+    pub log_queries: bool,
+    ///Whether lines of little interest to operators (such as queries for obsolete record types) are logged
+    pub log_debug: bool,
+    ///Whether every step of answering a query (the stage answering it, cache hits, alias follows, truncation, the response code) is logged
+    pub log_trace: bool,
+    ///The minimum time to live of cached records, shorter ttls of the upstream are raised to it
+    pub min_ttl: u32,
+    ///Minimum time to live per record type (type mnemonic -> ttl) used instead of min_ttl for that type
+    pub min_ttl_by_type: HashMap<String, u32>,
+    ///Record types (type mnemonics) answered from the upstream but never written to the cache
+    pub no_cache_types: Vec<String>,
+    ///The largest rdata (in bytes) of a record written to the cache, larger records are served but not cached (0 disables the limit)
+    pub max_cached_rdata: usize,
+    ///The number of seconds past their expiry cached records are still served while the upstream fails. Expired
+    ///records are always fetched again, and if the upstream fails once they have been expired for longer than this,
+    ///the query fails (SERVFAIL) instead of being answered with the old record. Null serves them however old they are
+    pub serve_stale_max_age: Option<u32>,
+    ///The number of independent stores (each with its own lock) the cache is split into by a hash of the name.
+    ///With more than one, shard n > 0 is stored in ./data/domains-n.db. When it is changed, the cached names are
+    ///moved to their new shard at startup and the files of shards no longer used are removed
+    pub cache_shards: usize,
+    ///Whether only cached records are served: the upstream is never contacted and the cache is never written
+    pub read_only_cache: bool,
+    ///Rules choosing how queries use the cache, the first matching rule applies. Queries matched by no rule
+    ///follow read_only_cache and no_cache_types (see [cache_policy](Config::cache_policy))
+    pub cache_policies: Vec<CachePolicyRule>,
+    ///Whether the subnet of the client is forwarded to the upstream (EDNS Client Subnet)
+    pub ecs_forwarding: bool,
+    ///The prefix length of forwarded ipv4 client subnets
+    pub ecs_prefix_v4: u8,
+    ///The prefix length of forwarded ipv6 client subnets
+    pub ecs_prefix_v6: u8,
+    ///How queries with more than one question are answered
+    pub multi_question_mode: MultiQuestionMode,
+    ///Whether every question of a query is echoed in the response, including duplicates
+    ///(which are only answered once), or only the unique questions
+    pub echo_duplicate_questions: bool,
+    ///The udp payload size advertised in the OPT record of responses, udp responses larger than
+    ///this (or than the size the client advertises, 512 bytes without EDNS) are truncated
+    pub edns_udp_size: u16,
+    ///Records answered directly by this server, without the cache or the upstream
+    pub local_records: Vec<LocalRecord>,
+    ///The valid local_records by their name, built once when the configuration is loaded
+    #[serde(skip)]
+    pub local_index: zone::LocalIndex,
+    ///Zone files (json or yaml lists in the format of local_records) whose records are added to the local records
+    pub zone_files: Vec<String>,
+    ///Files (json lists in the format of local_records) of static zones read once at startup into a read-only
+    ///in-memory map, which is answered from before the cache (see [snapshot](crate::snapshot))
+    pub snapshot_zones: Vec<String>,
+    ///The time to live records of the snapshot_zones are served with, replacing the ttls of their files
+    ///(null serves the ttls of the files)
+    pub local_ttl: Option<u32>,
+    ///Zones this server is authoritative for, names within them are only answered from the local records
+    pub zones: Vec<AuthoritativeZone>,
+    ///The address (ipv4 or ipv6) names within the zones without any local record are answered with instead of NXDOMAIN,
+    ///queries for other types than that of the address get an empty answer (null to answer NXDOMAIN)
+    pub local_default_address: Option<String>,
+    ///Whether a name the upstream answers with NXDOMAIN is looked up in the local records before NXDOMAIN is sent,
+    ///so internal names asked for under the home suffix (such as nas.home for the local record nas) still resolve
+    pub local_fallback: bool,
+    ///Whether NS queries for the root (the priming queries of resolvers) are answered from the root hints,
+    ///with the addresses of the root servers as additional records, instead of being forwarded
+    pub root_priming: bool,
+    ///The file (in the format of named.root) the root hints are read from (null for the built-in hints)
+    pub root_hints: Option<String>,
+    ///Zones this server is a secondary for, transferred (AXFR) from their primary and refreshed per their SOA record
+    pub secondary_zones: Vec<SecondaryZone>,
+    ///How the order of several records of the same name and type is rotated between responses
+    pub round_robin: RoundRobin,
+    ///The largest random change (in percent) applied to the ttls of served answers so that clients
+    ///caching the same record don't all expire it at once (0 disables the jitter)
+    pub ttl_jitter_percent: u8,
+    ///Rules rewriting address answers before they are sent, the first matching rule is applied
+    pub rewrites: Vec<RewriteRule>,
+    ///Whether private, loopback and link-local addresses are dropped from the answers of the cache and the upstream
+    ///(DNS rebinding protection), a name left without any address is answered with an empty answer. The local zones,
+    ///the preloaded zones and the names of the server are exempt
+    pub rebinding_protection: bool,
+    ///Whether records of every implemented type are built and parsed back at startup
+    pub self_test: bool,
+    ///Whether the server refuses to start if the self-test fails (otherwise a warning is logged)
+    pub self_test_fatal: bool,
+    ///The address the dns-over-https endpoint listens on (null to disable it)
+    pub doh_listen: Option<String>,
+    ///The address the health endpoint (a liveness probe answering 200 or 503) listens on (null to disable it)
+    pub health_listen: Option<String>,
+    ///The maximum number of dns-over-https queries resolved at once
+    pub doh_max_in_flight: usize,
+    ///The number of seconds rate limited dns-over-https clients are told to wait (Retry-After)
+    pub doh_retry_after: u32,
+    ///The number of seconds a dns-over-https connection may be idle before it is closed (0 keeps idle connections open)
+    pub doh_idle_timeout: u64,
+    ///The maximum number of open tcp connections (over all listeners, including dns-over-https), further connections are closed right away
+    pub max_tcp_connections: usize,
+    ///The number of seconds a tcp connection may be idle before it is closed if the client asked to keep it open
+    ///with the edns-tcp-keepalive option (RFC 7828), which is sent to the client. Other connections are closed after
+    ///the first response (0 closes every connection after the first response)
+    pub tcp_keepalive_timeout: u64,
+    ///The maximum number of queries of a single client resolved at once, further queries are refused (0 disables the limit)
+    pub max_outstanding_per_client: usize
+}
+
+/// # An enum which represents the possible ways of answering a query for a blocked name
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockMode {
+    ///Refuse the query (REFUSED)
+    Refused,
+    ///Claim the name does not exist (NXDOMAIN)
+    NxDomain,
+    ///Claim the name exists without any records of the requested type (NOERROR with no answers)
+    NoData,
+    ///Don't answer the query at all. Only the first question is checked before the query is parsed,
+    ///blocked names of further questions are refused
+    Drop
+}
+
+/// # An enum which represents the possible ways of answering a query for a name not on the allowlist
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AllowlistMode {
+    ///Every name is answered, the allowlist is ignored
+    Off,
+    ///Refuse the query (REFUSED)
+    Refused,
+    ///Claim the name does not exist (NXDOMAIN)
+    NxDomain
+}
+
+/// # An enum which represents the possible ways of answering a query with more than one question
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultiQuestionMode {
+    ///Answer every question
+    ResolveAll,
+    ///Answer the first question, the others are only echoed
+    FirstOnly,
+    ///Refuse the query (REFUSED)
+    Refuse
+}
+
+/// # An enum which represents the ways a query can use the cache
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    ///Answer from the cache if the record is cached, otherwise ask the upstream and cache its answer
+    CacheFirst,
+    ///Always ask the upstream, the cache is neither read nor written
+    ForwardAlways,
+    ///Only answer from the cache, the upstream is never contacted
+    CacheOnly
+}
+
+/// # An enum which represents the possible ways of rotating the order of records
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoundRobin {
+    ///Records are always in the same order
+    Off,
+    ///The order is rotated by one for every response
+    Global,
+    ///The order is rotated by an offset derived from the client address, so every client
+    ///consistently gets the same order while different clients are spread over the records
+    Client
+}
+
+/// # Struct representing a record of the local zone
+///TLSA records may name a certificate file (PEM or DER) instead of a value, their association data
+///is then the SHA-256 digest of the certificate (selector 0) or of its public key (selector 1).
+///Several PTR records may share a reverse name (an address with several hostnames), they are answered in the configured order.
+///A CNAME record is answered for queries of every type, followed by the records of its target within the local zone
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalRecord {
+    ///The full domain name of the record
+    pub name: String,
+    ///The record type ("A", "AAAA", "CNAME", "TXT", "MX", "TLSA" or "PTR")
+    #[serde(rename = "type")]
+    pub r#type: String,
+    ///The value of the record in presentation format
+    #[serde(default)]
+    pub value: String,
+    ///The time to live of the record
+    #[serde(default = "default_local_ttl")]
+    pub ttl: u32,
+    ///The path of the certificate a TLSA record is generated from
+    #[serde(default)]
+    pub certificate: Option<String>,
+    ///The certificate usage of a generated TLSA record (3: domain-issued certificate)
+    #[serde(default = "default_tlsa_usage")]
+    pub tlsa_usage: u8,
+    ///The selector of a generated TLSA record (0: full certificate, 1: SubjectPublicKeyInfo)
+    #[serde(default = "default_tlsa_selector")]
+    pub tlsa_selector: u8,
+    ///The relative weight of an A or AAAA record among the records of its name and type. Records with a weight
+    ///are ordered by weighted random selection, so the first answer is this one in weight/total of the responses
+    #[serde(default)]
+    pub weight: Option<u32>
+}
+
+fn default_local_ttl() -> u32 {
+    300
+}
+
+fn default_tlsa_usage() -> u8 {
+    3
+}
+
+fn default_tlsa_selector() -> u8 {
+    1
+}
+
+/// # Struct representing a zone this server is authoritative for
+///Its SOA record answers SOA queries for the zone and is added to the authority section of negative answers
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthoritativeZone {
+    ///The name of the zone (for example example.home)
+    pub name: String,
+    ///The primary name server of the zone
+    pub mname: String,
+    ///The mailbox of those responsible for the zone, in domain name form (hostmaster.example.home)
+    pub rname: String,
+    ///The version number of the zone
+    #[serde(default = "default_zone_serial")]
+    pub serial: u32,
+    ///The time in seconds before secondary servers refresh the zone
+    #[serde(default = "default_zone_refresh")]
+    pub refresh: u32,
+    ///The time in seconds before a failed refresh is retried
+    #[serde(default = "default_zone_retry")]
+    pub retry: u32,
+    ///The time in seconds until secondary servers stop answering for the zone
+    #[serde(default = "default_zone_expire")]
+    pub expire: u32,
+    ///The time to live of negative answers for names within the zone
+    #[serde(default = "default_zone_minimum")]
+    pub minimum: u32,
+    ///The time to live of the SOA record
+    #[serde(default = "default_zone_ttl")]
+    pub ttl: u32,
+    ///The secondary servers (ip:port) sent a NOTIFY when the zone is loaded
+    #[serde(default)]
+    pub notify: Vec<String>
+}
+
+/// # Struct representing a zone this server is a secondary for
+///The zone is transferred from its primary and answered authoritatively like the configured zones
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecondaryZone {
+    ///The name of the zone (for example example.home)
+    pub name: String,
+    ///The address (ip:port) of the primary name server the zone is transferred from
+    pub primary: String,
+    ///Addresses besides the one of the primary which are allowed to send NOTIFY messages for the zone
+    #[serde(default)]
+    pub allow_notify: Vec<String>
+}
+
+fn default_zone_serial() -> u32 {
+    1
+}
+
+fn default_zone_refresh() -> u32 {
+    7200
+}
+
+fn default_zone_retry() -> u32 {
+    3600
+}
+
+fn default_zone_expire() -> u32 {
+    1209600
+}
+
+fn default_zone_minimum() -> u32 {
+    3600
+}
+
+fn default_zone_ttl() -> u32 {
+    3600
+}
+
+/// # Struct representing a rule which rewrites address answers
+///If both from and to are networks of the same prefix length (e.g. 203.0.113.0/24 -> 10.1.2.0/24),
+///the host part of the address is kept, otherwise the answer is replaced with the address in to
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewriteRule {
+    ///The name the rule applies to, names starting with "*." also match every subdomain
+    pub name: String,
+    ///The record type the rule applies to ("A" or "AAAA")
+    #[serde(rename = "type")]
+    pub r#type: String,
+    ///The network or address answers have to be in to be rewritten (null to rewrite every answer)
+    pub from: Option<String>,
+    ///The address or network matching answers are rewritten to
+    pub to: String
+}
+
+/// # Struct representing the limits of the answers of a udp response
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct AnswerLimit {
+    ///The largest number of answers (null for no limit)
+    pub max_answers: Option<usize>,
+    ///The largest sum of the rdata lengths (in bytes) of the answers (null for no limit)
+    pub max_bytes: Option<usize>
+}
+
+/// # Struct representing a rule which chooses the cache policy of queries
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachePolicyRule {
+    ///The name the rule applies to, names starting with "*." also match every subdomain (null for every name)
+    #[serde(default)]
+    pub name: Option<String>,
+    ///The record types (type mnemonics) the rule applies to (empty for every type)
+    #[serde(default)]
+    pub types: Vec<String>,
+    ///How matching queries use the cache
+    pub policy: CachePolicy
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            chaos_identity: true,
+            server_id: None,
+            server_addresses: HashMap::new(),
+            blocklist: Vec::new(),
+            block_mode: BlockMode::Refused,
+            negative_ttl: 3600,
+            blocklist_error: Some(ExtendedError::Blocked),
+            allowlist: Vec::new(),
+            allowlist_mode: AllowlistMode::Off,
+            recursion_available: true,
+            upstream: String::from("https://8.8.8.8/resolve"),
+            bootstrap: Vec::new(),
+            user_agent: format!("dns/{}", env!("CARGO_PKG_VERSION")),
+            upstream_headers: HashMap::new(),
+            wire_upstream: None,
+            wire_upstream_randomize_case: false,
+            follow_soa_alias: true,
+            forwarders: HashMap::new(),
+            truncate_udp_any: false,
+            tcp_only_types: Vec::new(),
+            answer_limits: HashMap::new(),
+            log_queries: false,
+            log_debug: false,
+            log_trace: false,
+            min_ttl: 0,
+            min_ttl_by_type: HashMap::new(),
+            no_cache_types: Vec::new(),
+            max_cached_rdata: 4096,
+            serve_stale_max_age: None,
+            cache_shards: 1,
+            read_only_cache: false,
+            cache_policies: Vec::new(),
+            ecs_forwarding: false,
+            ecs_prefix_v4: 24,
+            ecs_prefix_v6: 56,
+            multi_question_mode: MultiQuestionMode::FirstOnly,
+            echo_duplicate_questions: true,
+            edns_udp_size: 1232,
+            local_records: Vec::new(),
+            local_index: HashMap::new(),
+            zone_files: Vec::new(),
+            snapshot_zones: Vec::new(),
+            local_ttl: None,
+            zones: Vec::new(),
+            local_default_address: None,
+            local_fallback: false,
+            root_priming: true,
+            root_hints: None,
+            secondary_zones: Vec::new(),
+            round_robin: RoundRobin::Off,
+            ttl_jitter_percent: 0,
+            rewrites: Vec::new(),
+            rebinding_protection: false,
+            self_test: false,
+            self_test_fatal: false,
+            doh_listen: None,
+            health_listen: None,
+            doh_max_in_flight: 64,
+            doh_retry_after: 1,
+            doh_idle_timeout: 30,
+            max_tcp_connections: 1024,
+            tcp_keepalive_timeout: 10,
+            max_outstanding_per_client: 0
+        }
+    }
+}
+
+impl Config {
+    fn load(path: &str) -> Self {
+        let mut config: Config = match fs::read_to_string(path) {
+            Ok(val) => serde_json::from_str(&val).expect("Failed to parse configuration file"),
+            Err(_) => Config::default()
+        };
+        config.local_records.append(&mut zone::zone_file_records(&config.zone_files));
+        config.local_records.append(&mut env_records(std::env::vars()));
+        config.local_index = zone::index(&config.local_records);
+
+        config
+    }
+
+    ///Returns the identifier of this server instance, falling back to the machine hostname
+    pub fn server_id(&self) -> String {
+        match &self.server_id {
+            Some(val) => val.clone(),
+            None => machine_hostname()
+        }
+    }
+
+    ///Returns the upstream endpoint for a name: the forwarder of the longest matching zone,
+    ///or the default upstream if no zone matches
+    pub fn upstream_for(&self, name: &[String]) -> &str {
+        let name = name.join(".").to_lowercase();
+
+        let mut best: Option<(usize, &str)> = None;
+        for (zone, endpoint) in &self.forwarders {
+            let zone = zone.trim_end_matches(".").to_lowercase();
+            let matches = name == zone || name.ends_with(&format!(".{}", zone));
+            if matches && best.is_none_or(|(len, _)| zone.len() > len) {
+                best = Some((zone.len(), endpoint.as_str()));
+            }
+        }
+
+        match best {
+            Some((_, endpoint)) => endpoint,
+            None => &self.upstream
+        }
+    }
+
+    ///Returns the minimum time to live of cached records of a type
+    pub fn min_ttl_for(&self, record_type: &DnsRecordType) -> u32 {
+        for (name, ttl) in &self.min_ttl_by_type {
+            if is_type(name, record_type) {
+                return *ttl;
+            }
+        }
+
+        self.min_ttl
+    }
+
+    ///Returns the limit of the answers of udp responses to queries for a record type
+    pub fn answer_limit(&self, record_type: &DnsRecordType) -> Option<AnswerLimit> {
+        self.answer_limits.iter()
+        .find(|(name, _)| is_type(name, record_type))
+        .map(|(_, limit)| *limit)
+    }
+
+    ///Returns whether queries for a record type have to be sent over tcp (truncate_udp_any and tcp_only_types)
+    pub fn tcp_only(&self, record_type: &DnsRecordType) -> bool {
+        (self.truncate_udp_any && record_type.to_byte().0 == 255)
+        || self.tcp_only_types.iter().any(|name| is_type(name, record_type))
+    }
+
+    ///Returns whether records of a type are written to the cache
+    pub fn caches_type(&self, record_type: &DnsRecordType) -> bool {
+        !self.no_cache_types.iter().any(|name| is_type(name, record_type))
+    }
+
+    ///Returns how a query uses the cache: the policy of the first matching rule of cache_policies, otherwise
+    ///cache only if read_only_cache is set, forward always for no_cache_types and cache first for everything else
+    pub fn cache_policy(&self, name: &[String], record_type: &DnsRecordType) -> CachePolicy {
+        if let Some(rule) = self.cache_policies.iter().find(|rule| rule.applies_to(name, record_type)) {
+            return rule.policy;
+        }
+
+        if self.read_only_cache {
+            CachePolicy::CacheOnly
+        }
+        else if !self.caches_type(record_type) {
+            CachePolicy::ForwardAlways
+        }
+        else {
+            CachePolicy::CacheFirst
+        }
+    }
+
+    ///Returns the subnet of a client forwarded to the upstream (for example 192.0.2.0/24),
+    ///or None if EDNS Client Subnet forwarding is disabled
+    pub fn client_subnet(&self, client: &IpAddr) -> Option<String> {
+        if !self.ecs_forwarding {
+            return None;
+        }
+
+        match client {
+            IpAddr::V4(ip) => {
+                let prefix = std::cmp::min(self.ecs_prefix_v4, 32) as u32;
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                let network = Ipv4Addr::from(u32::from(*ip) & mask);
+                Some(format!("{}/{}", network, prefix))
+            },
+            IpAddr::V6(ip) => {
+                let prefix = std::cmp::min(self.ecs_prefix_v6, 128) as u32;
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                let network = Ipv6Addr::from(u128::from(*ip) & mask);
+                Some(format!("{}/{}", network, prefix))
+            }
+        }
+    }
+
+    ///Returns the configured addresses of the ipv4 or ipv6 family if the name is a name of this server
+    ///(the list is empty if the server has no address of that family)
+    pub fn server_addresses_for(&self, name: &[String], ipv6: bool) -> Option<Vec<IpAddr>> {
+        let name = name.join(".").to_lowercase();
+        let (_, addresses) = self.server_addresses.iter()
+        .find(|(server_name, _)| server_name.trim_end_matches(".").to_lowercase() == name)?;
+
+        Some(
+            addresses.iter()
+            .filter_map(|address| address.parse::<IpAddr>().ok())
+            .filter(|address| address.is_ipv6() == ipv6)
+            .collect()
+        )
+    }
+
+    ///Returns the address unknown names within the zones are answered with (local_default_address)
+    pub fn local_default_address(&self) -> Option<IpAddr> {
+        self.local_default_address.as_ref().and_then(|address| address.parse().ok())
+    }
+
+    ///Returns whether a name may be answered: the allowlist is off or the name is on it, either directly
+    ///or as a subdomain of an entry
+    pub fn allowed(&self, name: &[String]) -> bool {
+        if self.allowlist_mode == AllowlistMode::Off {
+            return true;
+        }
+
+        let name = name.join(".").to_lowercase();
+        self.allowlist.iter().any(|entry| {
+            let entry = entry.trim_end_matches(".").to_lowercase();
+            name == entry || name.ends_with(&format!(".{}", entry))
+        })
+    }
+
+    ///Returns the blocklist entry a name falls under, either directly or as a subdomain of the entry
+    pub fn blocked_zone(&self, name: &[String]) -> Option<String> {
+        let name = name.join(".").to_lowercase();
+
+        for entry in &self.blocklist {
+            let entry = entry.trim_end_matches(".").to_lowercase();
+            if name == entry || name.ends_with(&format!(".{}", entry)) {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+}
+
+impl RewriteRule {
+    ///Returns whether the rule applies to the name and record type (type code) of an answer
+    pub fn applies_to(&self, name: &[String], record_type: u16) -> bool {
+        let rule_type = match self.r#type.to_uppercase().as_str() {
+            "A" => 1,
+            "AAAA" => 28,
+            _ => return false
+        };
+        if rule_type != record_type {
+            return false;
+        }
+
+        let name = name.join(".").to_lowercase();
+        let pattern = self.name.trim_end_matches(".").to_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(zone) => name == zone || name.ends_with(&format!(".{}", zone)),
+            None => name == pattern
+        }
+    }
+
+    ///Returns the address an answer is rewritten to, or None if the address is not matched by the rule
+    pub fn rewrite(&self, address: IpAddr) -> Option<IpAddr> {
+        let (to, to_prefix) = parse_network(&self.to)?;
+        let from = match &self.from {
+            Some(val) => Some(parse_network(val)?),
+            None => None
+        };
+
+        if let Some((from, from_prefix)) = from {
+            if !in_network(address, from, from_prefix) {
+                return None;
+            }
+
+            if from_prefix == to_prefix && address.is_ipv4() == to.is_ipv4() {
+                return Some(join_network(to, address, to_prefix));
+            }
+        }
+
+        if address.is_ipv4() != to.is_ipv4() {
+            return None;
+        }
+
+        Some(to)
+    }
+}
+
+impl CachePolicyRule {
+    ///Returns whether the rule applies to a query for the name and record type
+    pub fn applies_to(&self, name: &[String], record_type: &DnsRecordType) -> bool {
+        if !self.types.is_empty() && !self.types.iter().any(|r#type| is_type(r#type, record_type)) {
+            return false;
+        }
+
+        let pattern = match &self.name {
+            Some(val) => val.trim_end_matches(".").to_lowercase(),
+            None => return true
+        };
+        let name = name.join(".").to_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(zone) => name == zone || name.ends_with(&format!(".{}", zone)),
+            None => name == pattern
+        }
+    }
+}
+
+//Whether a type mnemonic names the type of a record
+fn is_type(name: &str, record_type: &DnsRecordType) -> bool {
+    DnsRecordType::from_name(name).map(|r#type| r#type.to_byte().0) == Some(record_type.to_byte().0)
+}
+
+fn parse_network(network: &str) -> Option<(IpAddr, u32)> {
+    let mut parts = network.splitn(2, "/");
+    let address: IpAddr = match parts.next()?.parse() {
+        Ok(val) => val,
+        Err(_) => return None
+    };
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next() {
+        Some(val) => match val.parse::<u32>() {
+            Ok(val) if val <= bits => val,
+            _ => return None
+        },
+        None => bits
+    };
+
+    Some((address, prefix))
+}
+
+fn address_bits(address: IpAddr) -> (u128, u32) {
+    match address {
+        IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128)
+    }
+}
+
+fn network_mask(prefix: u32, bits: u32) -> u128 {
+    let all = if bits == 32 { u32::MAX as u128 } else { u128::MAX };
+    all & all.checked_shl(bits - prefix).unwrap_or(0)
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u32) -> bool {
+    if address.is_ipv4() != network.is_ipv4() {
+        return false;
+    }
+
+    let (address, bits) = address_bits(address);
+    let (network, _) = address_bits(network);
+    let mask = network_mask(prefix, bits);
+    address & mask == network & mask
+}
+
+fn join_network(network: IpAddr, host: IpAddr, prefix: u32) -> IpAddr {
+    let (network, bits) = address_bits(network);
+    let (host, _) = address_bits(host);
+    let mask = network_mask(prefix, bits);
+    let joined = (network & mask) | (host & !mask);
+
+    if bits == 32 {
+        IpAddr::V4(Ipv4Addr::from(joined as u32))
+    }
+    else {
+        IpAddr::V6(Ipv6Addr::from(joined))
+    }
+}
+
+///Returns the local records defined by environment variables, which is convenient in containers without a configuration file.
+///A variable DNS_STATIC_<TYPE>_<NAME> (TYPE being A, AAAA, CNAME or TXT) defines a record of the name with the value
+///of the variable. As variable names can't contain dots, double underscores of the name stand for dots (www__example__home)
+pub fn env_records<I: Iterator<Item = (String, String)>>(vars: I) -> Vec<LocalRecord> {
+    let mut records: Vec<LocalRecord> = Vec::new();
+    for (key, value) in vars {
+        let rest = match key.strip_prefix(ENV_RECORD_PREFIX) {
+            Some(val) => val,
+            None => continue
+        };
+        let (record_type, name) = match rest.split_once("_") {
+            Some((record_type, name)) if ENV_RECORD_TYPES.contains(&record_type) && !name.is_empty() => (record_type, name),
+            _ => {
+                log!("Warning: ignoring environment variable {}, expected {}<TYPE>_<NAME>", key, ENV_RECORD_PREFIX);
+                continue;
+            }
+        };
+
+        records.push(LocalRecord {
+            name: name.replace("__", "."),
+            r#type: String::from(record_type),
+            value,
+            ttl: default_local_ttl(),
+            certificate: None,
+            tlsa_usage: default_tlsa_usage(),
+            tlsa_selector: default_tlsa_selector(),
+            weight: None
+        });
+    }
+
+    records
+}
+
+pub fn init_config() {
+    lazy_static::initialize(&CONFIG);
+}
+
+///Returns the current configuration
+pub fn get() -> Arc<Config> {
+    #[cfg(test)]
+    {
+        if let Some(config) = TEST_CONFIG.with(|config| config.borrow().clone()) {
+            return config;
+        }
+    }
+
+    CONFIG.clone()
+}
+
+///Overrides the configuration for the current thread (only used by tests)
+#[cfg(test)]
+pub fn set_for_test(mut config: Config) {
+    config.local_index = zone::index(&config.local_records);
+    TEST_CONFIG.with(|test_config| *test_config.borrow_mut() = Some(Arc::new(config)));
+}
+
+///Returns a local record with the default ttl and TLSA parameters (only used by tests)
+#[cfg(test)]
+pub fn local_record(name: &str, r#type: &str, value: &str) -> LocalRecord {
+    LocalRecord {
+        name: String::from(name),
+        r#type: String::from(r#type),
+        value: String::from(value),
+        ttl: default_local_ttl(),
+        certificate: None,
+        tlsa_usage: default_tlsa_usage(),
+        tlsa_selector: default_tlsa_selector(),
+        weight: None
+    }
+}
+
+fn machine_hostname() -> String {
+    for path in &["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if let Ok(name) = fs::read_to_string(path) {
+            let name = name.trim();
+            if !name.is_empty() {
+                return String::from(name);
+            }
+        }
+    }
+
+    String::from("localhost")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone;
+
+    #[test]
+    fn upstream_for_test() {
+        let mut config = Config::default();
+        config.forwarders.insert(String::from("internal"), String::from("https://10.0.0.1/resolve"));
+        config.forwarders.insert(String::from("corp.internal."), String::from("https://10.0.0.53/resolve"));
+
+        let name = |name: &str| name.split(".").map(String::from).collect::<Vec<String>>();
+
+        assert_eq!(config.upstream_for(&name("host.corp.internal")), "https://10.0.0.53/resolve");
+        assert_eq!(config.upstream_for(&name("corp.internal")), "https://10.0.0.53/resolve");
+        assert_eq!(config.upstream_for(&name("other.internal")), "https://10.0.0.1/resolve");
+        assert_eq!(config.upstream_for(&name("notcorp.internal.com")), "https://8.8.8.8/resolve");
+    }
+
+    #[test]
+    fn client_subnet_test() {
+        let mut config = Config::default();
+        let v4: IpAddr = "198.51.100.77".parse().unwrap();
+        let v6: IpAddr = "2001:db8:aaaa:bbbb::1".parse().unwrap();
+        assert_eq!(config.client_subnet(&v4), None);
+
+        config.ecs_forwarding = true;
+        assert_eq!(config.client_subnet(&v4), Some(String::from("198.51.100.0/24")));
+        assert_eq!(config.client_subnet(&v6), Some(String::from("2001:db8:aaaa:bb00::/56")));
+
+        config.ecs_prefix_v4 = 0;
+        assert_eq!(config.client_subnet(&v4), Some(String::from("0.0.0.0/0")));
+    }
+
+    #[test]
+    fn cache_policy_test() {
+        let mut config = Config {
+            no_cache_types: vec!(String::from("TXT")),
+            ..Config::default()
+        };
+        config.cache_policies.push(CachePolicyRule {
+            name: Some(String::from("*.live.example")),
+            types: Vec::new(),
+            policy: CachePolicy::ForwardAlways
+        });
+        config.cache_policies.push(CachePolicyRule {
+            name: None,
+            types: vec!(String::from("AAAA")),
+            policy: CachePolicy::CacheOnly
+        });
+
+        let name = |name: &str| name.split(".").map(String::from).collect::<Vec<String>>();
+        assert_eq!(config.cache_policy(&name("www.live.example"), &DnsRecordType::AAAA(None)), CachePolicy::ForwardAlways);
+        assert_eq!(config.cache_policy(&name("www.example"), &DnsRecordType::AAAA(None)), CachePolicy::CacheOnly);
+        assert_eq!(config.cache_policy(&name("www.example"), &DnsRecordType::TXT(None)), CachePolicy::ForwardAlways);
+        assert_eq!(config.cache_policy(&name("www.example"), &DnsRecordType::A(None)), CachePolicy::CacheFirst);
+
+        config.read_only_cache = true;
+        assert_eq!(config.cache_policy(&name("www.example"), &DnsRecordType::A(None)), CachePolicy::CacheOnly);
+    }
+
+    #[test]
+    fn env_records_test() {
+        let vars = vec!(
+            (String::from("DNS_STATIC_A_myhost__envtest"), String::from("10.0.0.5")),
+            (String::from("DNS_STATIC_AAAA_myhost__envtest"), String::from("fd00::5")),
+            (String::from("DNS_STATIC_CNAME_alias__envtest"), String::from("myhost.envtest")),
+            (String::from("DNS_STATIC_TXT__dmarc__envtest"), String::from("v=DMARC1; p=none")),
+            (String::from("PATH"), String::from("/usr/bin")) //Other variables are skipped
+        );
+        let config = Config {
+            local_records: env_records(vars.into_iter()),
+            ..Config::default()
+        };
+        assert_eq!(config.local_records.len(), 4);
+        set_for_test(config);
+
+        let name = |name: &str| name.split(".").map(String::from).collect::<Vec<String>>();
+        let answers = zone::lookup(&name("myhost.envtest"), &DnsRecordType::A(None)).unwrap();
+        assert_eq!(answers[0].rdata, vec!(10, 0, 0, 5));
+        let answers = zone::lookup(&name("myhost.envtest"), &DnsRecordType::AAAA(None)).unwrap();
+        assert_eq!(answers[0].rdata, "fd00::5".parse::<Ipv6Addr>().unwrap().octets().to_vec());
+        let answers = zone::lookup(&name("_dmarc.envtest"), &DnsRecordType::TXT(None)).unwrap();
+        assert_eq!(answers[0].r#type, DnsRecordType::new_txt("v=DMARC1; p=none").unwrap());
+
+        //The alias is followed to the address of its target
+        let answers = zone::lookup(&name("alias.envtest"), &DnsRecordType::A(None)).unwrap();
+        assert_eq!(answers[0].r#type, DnsRecordType::new_cname("myhost.envtest").unwrap());
+        assert_eq!(answers[1].name, name("myhost.envtest"));
+        assert_eq!(answers[1].rdata, vec!(10, 0, 0, 5));
+
+        assert!(env_records(vec!((String::from("DNS_STATIC_MX_mail"), String::new())).into_iter()).is_empty());
+    }
+}
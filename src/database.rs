@@ -1,164 +1,868 @@
 use std::sync::{ Mutex, MutexGuard };
-use std::time::Duration;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
 use std::thread;
-use rusqlite::{ Connection, NO_PARAMS };
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::convert::TryInto;
+use rusqlite::{ Connection, NO_PARAMS, params };
+use rusqlite::types::Value;
 use lazy_static;
 
-use crate::dns_request::{ DnsRecordType, DnsAnswer, DnsAuthRecord };
+use crate::dns_request::{ self, DnsRecordType, DnsAnswer, DnsAuthRecord };
 use crate::google_dns;
+use crate::config::{ self, CachePolicy };
+use crate::stats;
+use crate::snapshot;
 
 //TODO: get/set ptr record, add functionality for commented out record types
 //TODO: add update and check for val exists
 
+//Columns holding record values. A NULL value means the record is not cached,
+//while an empty string is a cached record with an empty value
+const COLUMNS: [&str; 10] = ["ipv4", "ipv6", "cname", "mx", "loc", "rp", "certificate", "authority", "txt", "openpgpkey"];
+//Version of the table layout stored in the user_version pragma
+const SCHEMA_VERSION: u32 = 4;
+//The maximum number of aliases followed when answering from the cache
+const MAX_CNAME_CHAIN: usize = 8;
+//The time to live of expired records served while the upstream fails (RFC 8767)
+const STALE_TTL: u32 = 30;
+//Marks a TXT value stored as the base64 of its text, as its bytes are not necessarily valid UTF-8.
+//Values cached by older versions, whose character-strings were joined before they were encoded
+const TXT_BASE64_PREFIX: &str = "base64:";
+//Marks a TXT value stored as the base64 of its rdata, so every character-string is kept as it was received
+const TXT_RDATA_PREFIX: &str = "rdata:";
+
+/// # Struct representing a record stored in the cache
+#[derive(PartialEq, Debug, Clone)]
+pub struct CacheEntry {
+    ///The full domain name of the record
+    pub name: String,
+    ///The client subnet the record was fetched for (EDNS Client Subnet)
+    pub subnet: Option<String>,
+    ///The type of the record (without associated data)
+    pub record_type: DnsRecordType,
+    ///The value of the record as it is stored in the database
+    pub value: String,
+    ///The time to live the record was cached with
+    pub ttl: u32,
+    ///The number of seconds until the record expires (None if the insertion time is unknown)
+    pub remaining_ttl: Option<u32>,
+    ///The unix timestamp (in seconds) at which the record was cached (None for records cached by older versions)
+    pub inserted_at: Option<u64>
+}
+
+/// # An enum which represents the reasons a record cannot be returned
+#[derive(PartialEq, Debug, Clone)]
+pub enum RecordError {
+    ///The record is neither cached nor could it be fetched from the upstream
+    NotFound,
+    ///The upstream answered that the name does not exist, with the SOA record of its zone
+    NxDomain(Box<DnsAnswer>),
+    ///The upstream answered that the name exists but has no record of the type, with the SOA record of its zone if it sent one
+    NoData(Option<Box<DnsAnswer>>),
+    ///The database could not be read, or the upstream can't be requested at all
+    Unavailable,
+    ///The upstream answered an SOA query with an alias, which can't be the apex of a zone. Holds the alias (CNAME)
+    ///record without its name, see [get_records](get_records)
+    Alias(Box<DnsAnswer>)
+}
+
+//Set while the database is failing, so the failure and the recovery are only logged once
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+//Set once the startup is complete, queries arriving before are answered with SERVFAIL.
+//Unit tests have no startup, the transition is tested by tests/startup.rs
+static READY: AtomicBool = AtomicBool::new(cfg!(test));
+
+#[cfg(test)]
+thread_local! {
+    static TEST_NOT_READY: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+impl CacheEntry {
+    ///Returns the cached record as an answer, with the remaining time to live as its ttl
+    pub fn answer(&self) -> DnsAnswer {
+        let ans = DnsAnswer::default()
+        .name(self.name.split(".").map(String::from).collect())
+        .ttl(self.remaining_ttl.unwrap_or(self.ttl));
+
+        get_ans_from_val(&self.value, self.record_type.clone(), ans)
+    }
+}
+
 lazy_static! {
-    static ref CONNECTION: Mutex<Connection> = {
-        let conn = Connection::open("./data/domains.db").expect("Failed to create connection to database");
-        Mutex::new(conn)
+    //Every name is stored in exactly one shard, so concurrent queries for names of different shards don't wait for each other
+    static ref SHARDS: Vec<Mutex<Connection>> = {
+        let config = config::get();
+        if config.read_only_cache {
+            log!("Read-only cache, tables of older versions are not upgraded and rows are not moved between shards");
+        }
+
+        let shards: Vec<Connection> = (0..std::cmp::max(config.cache_shards, 1)).map(|shard| {
+            let conn = open_connection(shard);
+            if !config.read_only_cache {
+                upgrade_tables(&conn);
+            }
+            conn
+        })
+        .collect();
+
+        //Rows cached with another number of shards are moved to the shard of their name
+        let unused = open_unused_shards(shards.len());
+        if !config.read_only_cache {
+            for (_, conn) in unused.iter() {
+                upgrade_tables(conn);
+            }
+            let unused_conns: Vec<&Connection> = unused.iter().map(|(_, conn)| conn).collect();
+            if rehash_shards(&shards, &unused_conns) {
+                remove_unused_shards(unused);
+            }
+        }
+
+        shards.into_iter().map(Mutex::new).collect()
     };
 }
 
 pub fn init_db() {
-    lazy_static::initialize(&CONNECTION);
+    lazy_static::initialize(&SHARDS);
+}
+
+#[cfg(not(test))]
+fn open_connection(shard: usize) -> Connection {
+    match shard {
+        0 => open_or_fallback("./data/domains.db"),
+        _ => open_or_fallback(&format!("./data/domains-{}.db", shard))
+    }
+}
+
+#[cfg(test)]
+fn open_connection(_shard: usize) -> Connection {
+    Connection::open_in_memory().expect("Failed to create in-memory database")
+}
+
+//Opens the files of shards beyond the configured number, left behind when cache_shards was lowered
+#[cfg(not(test))]
+fn open_unused_shards(shards: usize) -> Vec<(String, Connection)> {
+    let mut unused: Vec<(String, Connection)> = Vec::new();
+    for shard in std::cmp::max(shards, 1).. {
+        let path = format!("./data/domains-{}.db", shard);
+        if !std::path::Path::new(&path).exists() {
+            break;
+        }
+        match Connection::open(&path) {
+            Ok(conn) => unused.push((path, conn)),
+            Err(err) => log!("Failed to open database {}: {}", path, err)
+        }
+    }
+
+    unused
+}
+
+#[cfg(test)]
+fn open_unused_shards(_shards: usize) -> Vec<(String, Connection)> {
+    Vec::new()
+}
+
+//The files of unused shards are only removed once all of their rows were moved
+fn remove_unused_shards(unused: Vec<(String, Connection)>) {
+    for (path, conn) in unused {
+        drop(conn);
+        match std::fs::remove_file(&path) {
+            Ok(_) => log!("Moved the rows of {} to the configured shards", path),
+            Err(err) => log!("Failed to remove {}: {}", path, err)
+        }
+    }
+}
+
+//Moves every row which is not in the shard of its name (see shard_index), so a change of cache_shards
+//doesn't strand the rows cached before it. Every row of the unused shards is moved. Returns whether all rows were moved
+fn rehash_shards(shards: &[Connection], unused: &[&Connection]) -> bool {
+    let stores = shards.iter().enumerate().map(|(index, conn)| (Some(index), conn))
+    .chain(unused.iter().map(|conn| (None, *conn)));
+
+    let mut moved: usize = 0;
+    let mut complete = true;
+    for (index, db) in stores {
+        for table in get_tables(db) {
+            for name in get_names(db, &table) {
+                let target = shard_index(&name, shards.len());
+                if index == Some(target) {
+                    continue;
+                }
+                match move_row(db, &shards[target], &table, &name) {
+                    Ok(_) => moved += 1,
+                    Err(err) => {
+                        log!("Failed to move {} to shard {}: {}", name, target, err);
+                        complete = false;
+                    }
+                }
+            }
+        }
+    }
+    if moved > 0 {
+        log!("Moved {} cached names to the shard of their name", moved);
+    }
+
+    complete
+}
+
+fn get_names(db: &Connection, table: &str) -> Vec<String> {
+    let mut statement = match db.prepare(&format!("SELECT name FROM {}", table)) {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+    let names = match statement.query_map(NO_PARAMS, |row| row.get::<_, String>(0)) {
+        Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+        Err(_) => Vec::new()
+    };
+
+    names
+}
+
+//A row already cached in the target shard is kept, as it was cached with the current number of shards
+fn move_row(from: &Connection, to: &Connection, table: &str, name: &str) -> rusqlite::Result<()> {
+    let mut statement = from.prepare(&format!("SELECT * FROM {} WHERE name = ?", table))?;
+    let columns: Vec<String> = statement.column_names().iter().map(|column| column.to_string()).collect();
+    let values: Vec<Value> = statement.query_row(params![name], |row| {
+        (0..columns.len()).map(|i| row.get::<_, Value>(i)).collect()
+    })?;
+
+    create_table(to, table);
+    let placeholders: Vec<&str> = columns.iter().map(|_| "?").collect();
+    let request = format!("INSERT OR IGNORE INTO {} ({}) VALUES ({})", table, columns.join(", "), placeholders.join(", "));
+    to.execute(&request, &values)?;
+    from.execute(&format!("DELETE FROM {} WHERE name = ?", table), params![name])?;
+
+    Ok(())
+}
+
+//Opens the database file, falling back to an in-memory database (nothing persists
+//across restarts) if the file can't be opened, for example on a read-only filesystem
+fn open_or_fallback(path: &str) -> Connection {
+    let result = Connection::open(path).and_then(|conn| {
+        //Opening is lazy, a read of the schema fails if the file can't be created or read
+        conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get::<_, u32>(0))?;
+        Ok(conn)
+    });
+
+    match result {
+        Ok(val) => val,
+        Err(err) => {
+            log!("Failed to open database {} ({}), falling back to an in-memory database", path, err);
+            Connection::open_in_memory().expect("Failed to create in-memory database")
+        }
+    }
+}
+
+//Adds columns missing from tables created by older versions. Tables from before
+//version 1 also have their empty-string placeholders (which meant "not cached") converted to NULL
+fn upgrade_tables(db: &Connection) {
+    let version: u32 = db.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)).unwrap_or(0);
+    if version >= SCHEMA_VERSION {
+        return;
+    }
+
+    for table in get_tables(db) {
+        let mut existing: Vec<String> = Vec::new();
+        if let Ok(mut statement) = db.prepare(&format!("PRAGMA table_info({})", table)) {
+            if let Ok(rows) = statement.query_map(NO_PARAMS, |row| row.get::<_, String>(1)) {
+                existing = rows.filter_map(|row| row.ok()).collect();
+            }
+        }
+
+        if !existing.iter().any(|name| name == "inserted_at") {
+            let request = format!("ALTER TABLE {} ADD COLUMN inserted_at INT", table);
+            if let Err(err) = db.execute(&request, NO_PARAMS) {
+                log!("{}", err);
+            }
+        }
+
+        for column in COLUMNS.iter() {
+            if !existing.iter().any(|name| name == column) {
+                let request = format!("ALTER TABLE {} ADD COLUMN {} TEXT", table, column);
+                if let Err(err) = db.execute(&request, NO_PARAMS) {
+                    log!("{}", err);
+                }
+            }
+            //Values cached before version 4 keep the ttl and insertion time of their row (see timing_columns)
+            for timing in [format!("{}_ttl", column), format!("{}_inserted_at", column)].iter() {
+                if !existing.iter().any(|name| name == timing) {
+                    let request = format!("ALTER TABLE {} ADD COLUMN {} INT", table, timing);
+                    if let Err(err) = db.execute(&request, NO_PARAMS) {
+                        log!("{}", err);
+                    }
+                }
+            }
+
+            if version < 1 {
+                let request = format!("UPDATE {} SET {} = NULL WHERE {} = ''", table, column, column);
+                if let Err(err) = db.execute(&request, NO_PARAMS) {
+                    log!("{}", err);
+                }
+            }
+        }
+    }
+
+    if let Err(err) = db.execute(&format!("PRAGMA user_version = {}", SCHEMA_VERSION), NO_PARAMS) {
+        log!("{}", err);
+    }
+}
+
+fn get_tables(db: &Connection) -> Vec<String> {
+    let mut statement = match db.prepare("SELECT name FROM sqlite_master WHERE type = 'table'") {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+    let tables = match statement.query_map(NO_PARAMS, |row| row.get::<_, String>(0)) {
+        Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+        Err(_) => Vec::new()
+    };
+
+    tables
+}
+
+fn create_table(db: &Connection, domain: &str) {
+    let columns: Vec<String> = COLUMNS.iter()
+    .map(|column| format!("{0} TEXT,\n            {0}_ttl INT,\n            {0}_inserted_at INT", column))
+    .collect();
+    let request = format!(
+        "CREATE TABLE IF NOT EXISTS {}(
+            name TEXT PRIMARY KEY,
+            {},
+            ttl INT,
+            inserted_at INT
+        );",
+        domain,
+        columns.join(",\n            ")
+    );
+    if let Err(err) = db.execute(&request, NO_PARAMS) {
+        log!("{}", err);
+    }
+}
+
+//Every value has its own ttl and insertion time. The ttl and insertion time of the row are those of
+//values cached before version 4 and are used if the value has none
+fn timing_columns(column: &str) -> String {
+    format!("COALESCE({0}_ttl, ttl), COALESCE({0}_inserted_at, inserted_at)", column)
+}
+
+fn get_type(column: &str) -> Option<DnsRecordType> {
+    match column {
+        "ipv4" => Some(DnsRecordType::A(None)),
+        "ipv6" => Some(DnsRecordType::AAAA(None)),
+        "cname" => Some(DnsRecordType::CNAME(None)),
+        "mx" => Some(DnsRecordType::MX(None)),
+        "loc" => Some(DnsRecordType::LOC(None)),
+        "rp" => Some(DnsRecordType::RP(None)),
+        "certificate" => Some(DnsRecordType::TLSA(None)),
+        "authority" => Some(DnsRecordType::SOA(None)),
+        "txt" => Some(DnsRecordType::TXT(None)),
+        "openpgpkey" => Some(DnsRecordType::OPENPGPKEY(None)),
+        _ => None
+    }
+}
+
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(val) => val.as_secs(),
+        Err(_) => 0
+    }
+}
+
+fn cache_entry(key: String, column: &str, value: String, ttl: u32, inserted_at: Option<u64>) -> Option<CacheEntry> {
+    let remaining_ttl = inserted_at.map(|inserted_at| {
+        let age = unix_time().saturating_sub(inserted_at);
+        (ttl as u64).saturating_sub(age) as u32
+    });
+
+    let (name, subnet) = match key.find("#") {
+        Some(i) => (String::from(&key[..i]), Some(String::from(&key[i+1..]))),
+        None => (key, None)
+    };
+
+    Some(CacheEntry {
+        name,
+        subnet,
+        record_type: get_type(column)?,
+        value,
+        ttl,
+        remaining_ttl,
+        inserted_at
+    })
+}
+
+///Returns every record in the cache. This never contacts the upstream server
+pub fn dump_cache() -> Vec<CacheEntry> {
+    let mut entries: Vec<CacheEntry> = Vec::new();
+    for shard in SHARDS.iter() {
+        entries.append(&mut dump_shard(&lock(shard)));
+    }
+
+    entries
+}
+
+fn dump_shard(db: &Connection) -> Vec<CacheEntry> {
+    let mut entries: Vec<CacheEntry> = Vec::new();
+
+    for table in get_tables(db) {
+        let columns: Vec<String> = COLUMNS.iter().map(|column| format!("{}, {}", column, timing_columns(column))).collect();
+        let request = format!("SELECT name, {} FROM {}", columns.join(", "), table);
+        let mut statement = match db.prepare(&request) {
+            Ok(val) => val,
+            Err(_) => continue //Not a table of cached records
+        };
+
+        let rows = statement.query_map(NO_PARAMS, |row| {
+            let name: String = row.get(0)?;
+
+            let mut row_entries: Vec<CacheEntry> = Vec::new();
+            for (i, column) in COLUMNS.iter().enumerate() {
+                if let Some(value) = row.get::<_, Option<String>>(3*i + 1)? {
+                    let ttl: u32 = row.get(3*i + 2)?;
+                    let inserted_at: Option<i64> = row.get(3*i + 3)?;
+                    if let Some(entry) = cache_entry(name.clone(), column, value, ttl, inserted_at.map(|val| val as u64)) {
+                        row_entries.push(entry);
+                    }
+                }
+            }
+
+            Ok(row_entries)
+        });
+        if let Ok(rows) = rows {
+            for mut row_entries in rows.flatten() {
+                entries.append(&mut row_entries);
+            }
+        }
+    }
+
+    entries
+}
+
+///Returns the cached record of a type for a name (and client subnet), if there is one.
+///This never contacts the upstream server
+pub fn lookup_cache(name: &[String], record_type: DnsRecordType, subnet: Option<&str>) -> Option<CacheEntry> {
+    let column = get_column(&record_type)?;
+    if name.is_empty() {
+        return None;
+    }
+
+    let domain = name[name.len()-1].clone();
+    let name_short = cache_key(name, subnet);
+    let request = format!("SELECT {}, {} FROM {} WHERE name = ?", column, timing_columns(column), domain);
+
+    let db = get_shard_access(&name_short);
+    let result = db.query_row(&request, params![name_short], |row| {
+        Ok((row.get::<_, Option<String>>(0)?, row.get::<_, u32>(1)?, row.get::<_, Option<i64>>(2)?))
+    });
+    drop(db);
+
+    match result {
+        Ok((Some(value), ttl, inserted_at)) => cache_entry(name_short, column, value, ttl, inserted_at.map(|val| val as u64)),
+        _ => None
+    }
+}
+
+///Returns every cached record of a name (and client subnet), read with a single query in the order of the columns.
+///This never contacts the upstream server
+pub fn get_all_records(name: &[String], subnet: Option<&str>) -> Vec<DnsAnswer> {
+    if name.is_empty() {
+        return Vec::new();
+    }
+
+    let domain = name[name.len()-1].clone();
+    let name_short = cache_key(name, subnet);
+    let columns: Vec<String> = COLUMNS.iter().map(|column| format!("{}, {}", column, timing_columns(column))).collect();
+    let request = format!("SELECT {} FROM {} WHERE name = ?", columns.join(", "), domain);
+
+    let db = get_shard_access(&name_short);
+    let result = db.query_row(&request, params![name_short], |row| {
+        let mut values: Vec<(Option<String>, Option<u32>, Option<i64>)> = Vec::new();
+        for i in 0..COLUMNS.len() {
+            values.push((row.get(3*i)?, row.get(3*i + 1)?, row.get(3*i + 2)?));
+        }
+        Ok(values)
+    });
+    drop(db);
+
+    let values = match result {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+
+    let mut answers: Vec<DnsAnswer> = Vec::new();
+    for (column, (value, ttl, inserted_at)) in COLUMNS.iter().zip(values) {
+        let entry = match (value, ttl) {
+            (Some(value), Some(ttl)) => cache_entry(name_short.clone(), column, value, ttl, inserted_at.map(|val| val as u64)),
+            _ => None
+        };
+        if let Some(entry) = entry {
+            answers.push(entry.answer());
+        }
+    }
+
+    answers
+}
+
+///Answers a name which is cached only as an alias (CNAME) by following the aliases within the cache.
+///Returns the aliases followed by the address record, or None if the record of the name itself is cached
+///or the chain does not end in a cached record. This never contacts the upstream server
+pub fn follow_cname(name: &[String], record_type: DnsRecordType, subnet: Option<&str>) -> Option<Vec<DnsAnswer>> {
+    if config::get().cache_policy(name, &record_type) == CachePolicy::ForwardAlways {
+        return None;
+    }
+    let mut answers: Vec<DnsAnswer> = Vec::new();
+    let mut current = name.to_vec();
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        if let Some(entry) = lookup_cache(&current, record_type.clone(), subnet) {
+            if answers.is_empty() {
+                return None; //Not an alias
+            }
+
+            answers.push(entry.answer());
+            return Some(answers);
+        }
+
+        let entry = lookup_cache(&current, DnsRecordType::CNAME(None), subnet)?;
+        answers.push(entry.answer());
+        current = entry.value.split(".")
+        .filter(|label| !label.is_empty())
+        .map(String::from)
+        .collect();
+    }
+
+    None
+}
+
+//Rows of subnet specific answers are keyed by the name followed by the subnet (name#subnet)
+fn cache_key(name: &[String], subnet: Option<&str>) -> String {
+    match subnet {
+        Some(subnet) => format!("{}#{}", name.join("."), subnet),
+        None => name.join(".")
+    }
+}
+
+fn get_column(record_type: &DnsRecordType) -> Option<&'static str> {
+    match record_type {
+        DnsRecordType::A(_) => Some("ipv4"),
+        DnsRecordType::AAAA(_) => Some("ipv6"),
+        DnsRecordType::CNAME(_) => Some("cname"),
+        DnsRecordType::MX(_) => Some("mx"),
+        DnsRecordType::LOC(_) => Some("loc"),
+        DnsRecordType::RP(_) => Some("rp"),
+        DnsRecordType::TLSA(_) => Some("certificate"),
+        DnsRecordType::SOA(_) => Some("authority"),
+        DnsRecordType::TXT(_) => Some("txt"),
+        DnsRecordType::OPENPGPKEY(_) => Some("openpgpkey"),
+        //DnsRecordType::PTR(_) => return get_ptr_record(name),
+        _ => None
+    }
 }
 
+///Returns the shard (out of shards) a cache key is stored in. Names without a client subnet are their own cache key
+pub fn shard_index(key: &str, shards: usize) -> usize {
+    //FNV-1a, which (unlike the hasher of the standard library) is the same for every run
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.to_lowercase().bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    (hash % shards as u64) as usize
+}
+
+//Locks the shard storing a cache key
+fn get_shard_access(key: &str) -> MutexGuard<'static, Connection> {
+    lock(&SHARDS[shard_index(key, SHARDS.len())])
+}
+
+//Locks the first shard, tests always run with a single shard
+#[cfg(test)]
 fn get_db_access() -> MutexGuard<'static, Connection> {
-    match CONNECTION.lock() {
+    lock(&SHARDS[0])
+}
+
+fn lock(shard: &'static Mutex<Connection>) -> MutexGuard<'static, Connection> {
+    match shard.lock() {
         Ok(val) => val,
         Err(_) => {
             thread::sleep(Duration::from_millis(1000));
-            get_db_access()
+            lock(shard)
         }
     }
 }
 
-pub fn get_record(name: &Vec<String>, record_type: DnsRecordType) -> Option<DnsAnswer> {
-    let column = match record_type {
-        DnsRecordType::A(_) => "ipv4",
-        DnsRecordType::AAAA(_) => "ipv6",
-        DnsRecordType::CNAME(_) => "cname",
-        DnsRecordType::MX(_) => "mx",
-        DnsRecordType::LOC(_) => "loc",
-        DnsRecordType::RP(_) => "rp",
-        DnsRecordType::TLSA(_) => "certificate",
-        DnsRecordType::SOA(_) => "authority",
-        //DnsRecordType::PTR(_) => return get_ptr_record(name),
-        _ => return None
+///Returns the number of names in the cache
+pub fn cache_size() -> usize {
+    let mut size: i64 = 0;
+    for shard in SHARDS.iter() {
+        let db = lock(shard);
+        size += get_tables(&db).iter()
+        .filter_map(|table| db.query_row(&format!("SELECT COUNT(*) FROM {}", table), NO_PARAMS, |row| row.get::<_, i64>(0)).ok())
+        .sum::<i64>();
+    }
+
+    size as usize
+}
+
+///Returns whether the last access to the database failed
+pub fn is_degraded() -> bool {
+    DEGRADED.load(Ordering::SeqCst)
+}
+
+///Returns whether the database is usable: the last access did not fail and every shard can be queried
+pub fn healthy() -> bool {
+    !is_degraded() && SHARDS.iter().all(|shard| lock(shard).query_row("SELECT 1", NO_PARAMS, |row| row.get::<_, i64>(0)).is_ok())
+}
+
+///Marks the startup (the database, the preloaded zones and the self-test) as complete, queries are answered from then on
+pub fn set_ready() {
+    READY.store(true, Ordering::SeqCst);
+}
+
+///Returns whether the startup is complete
+pub fn is_ready() -> bool {
+    #[cfg(test)]
+    {
+        if TEST_NOT_READY.with(|not_ready| not_ready.get()) {
+            return false;
+        }
+    }
+
+    READY.load(Ordering::SeqCst)
+}
+
+///Returns the number of failed accesses to the database since startup
+pub fn failures() -> u64 {
+    FAILURES.load(Ordering::SeqCst)
+}
+
+fn record_failure(err: &rusqlite::Error) {
+    FAILURES.fetch_add(1, Ordering::SeqCst);
+    if !DEGRADED.swap(true, Ordering::SeqCst) {
+        log!("Database unavailable, health degraded: {}", err);
+    }
+}
+
+fn record_success() {
+    if DEGRADED.swap(false, Ordering::SeqCst) {
+        log!("Database available again");
+    }
+}
+
+//Tables are only created once a record of their top level domain is cached
+fn has_table(db: &Connection, domain: &str) -> bool {
+    db.query_row("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ? COLLATE NOCASE", params![domain], |_| Ok(())).is_ok()
+}
+
+///Returns the records of a type for a name: every preloaded record of the name (see [snapshot](crate::snapshot)),
+///otherwise the record of [get_record](get_record). An SOA query answered with an alias is answered with the alias
+///followed by the SOA record of its target if follow_soa_alias is set
+pub fn get_records(name: &[String], record_type: DnsRecordType, subnet: Option<&str>) -> Result<Vec<DnsAnswer>, RecordError> {
+    //Preloaded records are read without any lock
+    if let Some(answers) = snapshot::get().lookup(name, &record_type) {
+        trace!("Answered {} (type {}) from the preloaded zones", name.join("."), record_type.to_byte().0);
+        return Ok(answers.clone());
+    }
+
+    match get_record(name, record_type, subnet) {
+        Ok(answer) => Ok(vec!(answer)),
+        Err(RecordError::Alias(alias)) => follow_soa_alias(name, *alias, subnet),
+        Err(err) => Err(err)
+    }
+}
+
+//Only one alias is followed, an alias to an alias (or one which is not followed) is an error (SERVFAIL).
+//The SOA record keeps the name of the target, under which it is cached
+fn follow_soa_alias(name: &[String], alias: DnsAnswer, subnet: Option<&str>) -> Result<Vec<DnsAnswer>, RecordError> {
+    let target = match dns_request::wire_to_labels(&alias.rdata) {
+        Some(val) if config::get().follow_soa_alias => val,
+        _ => {
+            log!("The upstream answered the SOA query of {} with an alias", name.join("."));
+            return Err(RecordError::Unavailable);
+        }
+    };
+
+    trace!("The upstream answered the SOA query of {} with the alias {}, resolving it", name.join("."), target.join("."));
+    match get_record(&target, DnsRecordType::SOA(None), subnet) {
+        Ok(soa) => Ok(vec!(alias.name(name.to_vec()), soa.name(target))),
+        Err(RecordError::Alias(_)) => {
+            log!("The upstream answered the SOA query of {} with an alias to an alias", name.join("."));
+            Err(RecordError::Unavailable)
+        },
+        Err(err) => Err(err)
+    }
+}
+
+///Returns the record of a type for a name, fetching (and caching) it from the upstream if it is not cached.
+///Answers which depend on the client's subnet (EDNS Client Subnet) are cached separately for every subnet
+pub fn get_record(name: &[String], record_type: DnsRecordType, subnet: Option<&str>) -> Result<DnsAnswer, RecordError> {
+    let column = match get_column(&record_type) {
+        Some(val) => val,
+        None => return Err(RecordError::NotFound)
     };
     if name.len() == 0 {
-        return None;
+        return Err(RecordError::NotFound);
+    }
+
+    if config::get().cache_policy(name, &record_type) == CachePolicy::ForwardAlways {
+        trace!("Forwarding {} (type {}) without reading the cache", name.join("."), record_type.to_byte().0);
+        return save_record(name, record_type, subnet, None);
     }
 
     let domain = name[name.len()-1].clone();
-    let name_short = name[0..name.len()].join(".");
-    let request = format!(
-        "SELECT {}, ttl FROM {} WHERE name = '{}'",
-        column, domain, name_short
-    );
+    let name_short = cache_key(name, subnet);
+    let request = format!("SELECT {}, {} FROM {} WHERE name = ?", column, timing_columns(column), domain);
 
-    let db = get_db_access();
-    let (value, ttl): (String, String) = match db.query_row(&request, NO_PARAMS, |row| { Ok((row.get(0), row.get(1))) }) {
-        Ok((Ok(value), Ok(ttl))) => (value, ttl),
-        _ => {
-            drop(db);
-            return save_record(name, record_type);
-        }
+    let db = get_shard_access(&name_short);
+    let result = db.query_row(&request, params![name_short], |row| {
+        Ok((row.get::<_, Option<String>>(0)?, row.get::<_, u32>(1)?, row.get::<_, Option<i64>>(2)?))
+    });
+    let missing_table = match &result {
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => false,
+        Err(_) => !has_table(&db, &domain)
     };
     drop(db);
-    if value == "" {
-        return save_record(name, record_type);
+
+    //A missing row or a NULL value means the record is not cached yet
+    let (value, ttl, inserted_at) = match result {
+        Ok((Some(value), ttl, inserted_at)) if valid_value(&value, &record_type) => (value, ttl, inserted_at),
+        //Only the cache may be used, which is never written
+        Ok((Some(value), _, _)) if config::get().cache_policy(name, &record_type) != CachePolicy::CacheFirst => {
+            log!("Invalid cached value {:?} for {}", value, name.join("."));
+            return Err(RecordError::Unavailable);
+        },
+        Ok((Some(value), _, _)) => {
+            log!("Invalid cached value {:?} for {}, fetching it again", value, name.join("."));
+            clear_value(&name_short, &domain, column);
+            return save_record(name, record_type, subnet, None);
+        },
+        Ok((None, _, _)) | Err(rusqlite::Error::QueryReturnedNoRows) => return save_record(name, record_type, subnet, None),
+        Err(_) if missing_table => return save_record(name, record_type, subnet, None),
+        Err(err) => {
+            record_failure(&err);
+            return Err(RecordError::Unavailable);
+        }
+    };
+    record_success();
+
+    //Expired records are fetched again, unless only the cache may be used. Records cached without the time they
+    //were inserted at never expire
+    let age = inserted_at.map(|inserted_at| unix_time().saturating_sub(inserted_at as u64)).unwrap_or(0);
+    if age > ttl as u64 {
+        let expired_for = age - ttl as u64;
+        if config::get().cache_policy(name, &record_type) == CachePolicy::CacheFirst {
+            trace!("Cached record of {} (type {}) expired {}s ago, fetching it again", name_short, record_type.to_byte().0, expired_for);
+            let stale = get_ans_from_val(&value, record_type.clone(), DnsAnswer::default().ttl(STALE_TTL));
+            return save_record(name, record_type, subnet, Some((stale, expired_for)));
+        }
     }
+    let remaining_ttl = (ttl as u64).saturating_sub(age) as u32;
+    stats::record_hit();
+    trace!("Cache hit for {} (type {}), ttl {}", name_short, record_type.to_byte().0, ttl);
 
     let ans = DnsAnswer::default()
-    .ttl(ttl.parse::<u32>().unwrap());
+    .ttl(remaining_ttl);
 
-    Some(get_ans_from_val(&value, record_type, ans))
+    Ok(get_ans_from_val(&value, record_type, ans))
 }
 
-fn save_record(name: &Vec<String>, record_type: DnsRecordType) -> Option<DnsAnswer> {
-    let column = match record_type {
-        DnsRecordType::A(_) => "ipv4",
-        DnsRecordType::AAAA(_) => "ipv6",
-        DnsRecordType::CNAME(_) => "cname",
-        DnsRecordType::MX(_) => "mx",
-        DnsRecordType::LOC(_) => "loc",
-        DnsRecordType::RP(_) => "rp",
-        DnsRecordType::TLSA(_) => "certificate",
-        DnsRecordType::SOA(_) => "authority",
-        //DnsRecordType::PTR(_) => return save_ptr_record(name),
-        _ => return None
+//The fetched answer is returned even if it cannot be cached. The stale answer (with the seconds since it expired)
+//is returned instead if the upstream fails
+fn save_record(name: &[String], record_type: DnsRecordType, subnet: Option<&str>, stale: Option<(DnsAnswer, u64)>) -> Result<DnsAnswer, RecordError> {
+    let column = match get_column(&record_type) {
+        Some(val) => val,
+        None => return Err(RecordError::NotFound)
     };
+    let policy = config::get().cache_policy(name, &record_type);
+    if policy == CachePolicy::CacheOnly {
+        trace!("Cache miss for {} (type {}), the upstream is not asked", name.join("."), record_type.to_byte().0);
+        return Err(RecordError::NotFound); //Only cached records are served
+    }
 
-    let google_answer = match google_dns::request_query(name, record_type) {
+    stats::record_miss();
+    trace!("Cache miss for {} (type {}), asking the upstream", name.join("."), record_type.to_byte().0);
+    let cached = policy == CachePolicy::CacheFirst;
+    let mut google_answer = match google_dns::request_query(name, record_type, subnet) {
         Ok(val) => val,
-        Err(_) => return None
+        Err(err) => {
+            trace!("No answer from the upstream: {}", err);
+            let failure = match err.downcast_ref::<google_dns::ErrorType>() {
+                Some(google_dns::ErrorType::NoData(soa)) => return Err(RecordError::NoData(soa.clone().map(Box::new))),
+                Some(google_dns::ErrorType::NxDomain(Some(soa))) => return Err(RecordError::NxDomain(Box::new(soa.clone()))),
+                Some(google_dns::ErrorType::NxDomain(None)) => return Err(RecordError::NotFound),
+                Some(google_dns::ErrorType::ClientUnavailable) => RecordError::Unavailable,
+                Some(google_dns::ErrorType::UnexpectedAlias(alias)) => return Err(RecordError::Alias(Box::new(alias.clone()))),
+                _ => RecordError::NotFound
+            };
+            stats::record_upstream_error();
+            return serve_stale(name, stale, failure);
+        }
     };
-    let value = get_val_from_ans(&google_answer);
+    let min_ttl = config::get().min_ttl_for(&google_answer.r#type);
+    if google_answer.ttl < min_ttl {
+        google_answer.ttl = min_ttl;
+    }
+    let value = match get_val_from_ans(&google_answer) {
+        Some(val) => val,
+        None => {
+            log!("Invalid rdata for {} (type {})", name.join("."), google_answer.r#type.to_byte().0);
+            return Err(RecordError::NotFound);
+        }
+    };
+    if !cached {
+        return Ok(google_answer);
+    }
+    let max_rdata = config::get().max_cached_rdata;
+    if max_rdata != 0 && google_answer.rdata.len() > max_rdata {
+        log!("Not caching {} (type {}), its rdata of {} bytes exceeds {} bytes", name.join("."), google_answer.r#type.to_byte().0, google_answer.rdata.len(), max_rdata);
+        return Ok(google_answer);
+    }
 
     let domain = name[name.len()-1].clone();
-    let name_short = name[0..name.len()].join(".");
+    let name_short = cache_key(name, subnet);
 
-    let request = format!("SELECT name FROM sqlite_master WHERE tbl_name = '{}'", domain);
-    let db = get_db_access();
-    let results = db.query_row(&request, NO_PARAMS, |_| Ok(()));
-    if let Err(err) = results {
-        let request = format!(
-            "CREATE TABLE {}(
-                name TEXT PRIMARY KEY,
-                ipv4 TEXT,
-                ipv6 TEXT,
-                cname TEXT,
-                mx TEXT,
-                loc TEXT,
-                rp TEXT,
-                certificate TEXT,
-                authority TEXT,
-                ttl INT
-            );",
-            domain
-        );
-        match db.execute(&request, NO_PARAMS) {
-            Ok(val) => (),
-            Err(err) => println!("{}", err)
-        };
+    let db = get_shard_access(&name_short);
+    create_table(&db, &domain);
+
+    let request = format!("INSERT OR IGNORE INTO {} (name, ttl) VALUES (?, ?)", domain);
+    if let Err(err) = db.execute(&request, params![name_short, google_answer.ttl]) {
+        record_failure(&err);
+        return Ok(google_answer);
     }
 
-    let request = format!("SELECT name FROM {} WHERE name = '{}'", domain, name_short);
-    let results = db.query_row(&request, NO_PARAMS, |_| Ok(()));
-    if let Err(err) = results {
-        let request = format!(
-            "INSERT INTO {} VALUES (
-                '{}',
-                '',
-                '',
-                '',
-                '',
-                '',
-                '',
-                '',
-                '',
-                {}
-            );",
-            domain,
-            name_short,
-            google_answer.ttl
-        );
-        match db.execute(&request, NO_PARAMS) {
-            Ok(_) => (),
-            Err(err) => println!("{}", err)
-        };
+    let request = format!("UPDATE {0} SET {1} = ?, {1}_ttl = ?, {1}_inserted_at = ? WHERE name = ?", domain, column);
+    match db.execute(&request, params![value, google_answer.ttl, unix_time() as i64, name_short]) {
+        Ok(_) => record_success(),
+        Err(err) => record_failure(&err)
     }
 
-    let request = format!("UPDATE {} SET {} = '{}' WHERE name = '{}'", domain, column, value, name_short);
-    match db.execute(&request, NO_PARAMS) {
-        Ok(_) => (),
-        Err(err) => println!("{}", err)
+    Ok(google_answer)
+}
+
+//An expired record is served while the upstream fails, until it has been expired for longer than serve_stale_max_age
+//(if it is set)
+fn serve_stale(name: &[String], stale: Option<(DnsAnswer, u64)>, failure: RecordError) -> Result<DnsAnswer, RecordError> {
+    let (answer, expired_for) = match stale {
+        Some(val) => val,
+        None => return Err(failure)
+    };
+
+    if let Some(max_age) = config::get().serve_stale_max_age.filter(|max_age| expired_for > *max_age as u64) {
+        log!("Not serving the record of {}, it expired {}s ago (more than {}s)", name.join("."), expired_for, max_age);
+        return Err(RecordError::Unavailable);
     }
+    trace!("Serving the stale record of {}, expired {}s ago", name.join("."), expired_for);
+    Ok(answer)
+}
 
-    Some(google_answer)
+fn get_ans_from_val(value: &str, record_type: DnsRecordType, ans: DnsAnswer) -> DnsAnswer {
+    ans.record(get_record_from_val(value, &record_type))
 }
 
-fn get_ans_from_val(value: &str, record_type: DnsRecordType, mut ans: DnsAnswer) -> DnsAnswer {
-    let record = match record_type {
+//Returns None if the value can't be parsed, e.g. because an older version stored it in another format
+fn get_record_from_val(value: &str, record_type: &DnsRecordType) -> Option<DnsRecordType> {
+    match record_type {
         DnsRecordType::A(_) => DnsRecordType::new_a(value),
         DnsRecordType::AAAA(_) => DnsRecordType::new_aaaa(value),
         DnsRecordType::CNAME(_) => DnsRecordType::new_cname(value),
@@ -166,43 +870,101 @@ fn get_ans_from_val(value: &str, record_type: DnsRecordType, mut ans: DnsAnswer)
         DnsRecordType::LOC(_) => DnsRecordType::new_loc(value),
         DnsRecordType::RP(_) => DnsRecordType::new_rp(value),
         DnsRecordType::TLSA(_) => DnsRecordType::new_tlsa(value),
+        DnsRecordType::TXT(_) => {
+            if let Some(encoded) = value.strip_prefix(TXT_RDATA_PREFIX) {
+                return match base64::decode(encoded) {
+                    Ok(rdata) => txt_from_rdata(rdata),
+                    Err(_) => None
+                };
+            }
+            match value.strip_prefix(TXT_BASE64_PREFIX) {
+                Some(encoded) => match base64::decode(encoded) {
+                    Ok(bytes) => DnsRecordType::new_txt_bytes(&bytes),
+                    Err(_) => None
+                },
+                None => DnsRecordType::new_txt(value) //Cached as plain text before binary values were supported
+            }
+        },
+        DnsRecordType::OPENPGPKEY(_) => DnsRecordType::new_openpgpkey(value),
         DnsRecordType::SOA(_) => {
             DnsRecordType::new_soa(parse_auth_record(value))
         },
         DnsRecordType::PTR(_) => DnsRecordType::new_ptr(value),
-        _ => return ans
-    };
+        _ => None
+    }
+}
+
+//Returns a TXT record of rdata if its character-strings fill it exactly
+fn txt_from_rdata(rdata: Vec<u8>) -> Option<DnsRecordType> {
+    let mut i: usize = 0;
+    while i < rdata.len() {
+        i += 1 + rdata[i] as usize;
+    }
+    if rdata.is_empty() || i != rdata.len() {
+        return None;
+    }
+
+    Some(DnsRecordType::TXT(Some(rdata)))
+}
 
-    ans.record(record)
+//Whether a cached value can be turned back into a record of its type
+fn valid_value(value: &str, record_type: &DnsRecordType) -> bool {
+    match record_type {
+        DnsRecordType::MX(_) | DnsRecordType::LOC(_) | DnsRecordType::RP(_) => true, //Not parsed yet
+        _ => get_record_from_val(value, record_type).is_some()
+    }
+}
+
+//Removes a value that can't be parsed, so it isn't served again even if fetching it fails
+fn clear_value(name_short: &str, domain: &str, column: &str) {
+    let request = format!("UPDATE {} SET {} = NULL WHERE name = ?", domain, column);
+    let db = get_shard_access(name_short);
+    match db.execute(&request, params![name_short]) {
+        Ok(_) => record_success(),
+        Err(err) => record_failure(&err)
+    }
 }
 
-fn get_val_from_ans(ans: &DnsAnswer) -> String {
-    match ans.r#type.clone() {
+//Returns the value of an answer as it is stored in the database, or None if
+//its rdata is malformed (an address record of the wrong length)
+fn get_val_from_ans(ans: &DnsAnswer) -> Option<String> {
+    let value = match ans.r#type.clone() {
         DnsRecordType::A(_) => {
-            let mut octets: Vec<String> = Vec::new();
-            for byte in &ans.rdata {
-                octets.push(format!("{}", *byte));
-            }
-            octets.join(".")
+            let octets: [u8; 4] = ans.rdata.as_slice().try_into().ok()?;
+            Ipv4Addr::from(octets).to_string()
         },
         DnsRecordType::AAAA(_) => {
-            let mut octets: Vec<String> = Vec::new();
-            for byte in &ans.rdata {
-                octets.push(format!("{}", *byte));
+            let octets: [u8; 16] = ans.rdata.as_slice().try_into().ok()?;
+            Ipv6Addr::from(octets).to_string()
+        },
+        DnsRecordType::CNAME(_) => {
+            let mut labels: Vec<String> = Vec::new();
+            let mut i: usize = 0;
+            while i < ans.rdata.len() && ans.rdata[i] != 0 {
+                let len = ans.rdata[i] as usize;
+                let end = std::cmp::min(i + 1 + len, ans.rdata.len());
+                labels.push(String::from_utf8_lossy(&ans.rdata[i+1..end]).into_owned());
+                i = end;
             }
-            octets.join(":")
+            labels.join(".")
         },
-        //DnsRecordType::CNAME(_) => ,
         //DnsRecordType::MX(_) => DnsRecordType::new_mx(value),
         //DnsRecordType::LOC(_) => DnsRecordType::new_loc(value),
         //DnsRecordType::RP(_) => DnsRecordType::new_rp(value),
         //DnsRecordType::TLSA(_) => DnsRecordType::new_tlsa(value),
+        DnsRecordType::TXT(_) => {
+            txt_from_rdata(ans.rdata.clone())?;
+            format!("{}{}", TXT_RDATA_PREFIX, base64::encode(&ans.rdata))
+        },
+        DnsRecordType::OPENPGPKEY(_) => base64::encode(&ans.rdata),
         DnsRecordType::SOA(val) => {
             stringify_auth_record(&val.unwrap())
         },
         //DnsRecordType::PTR(_) => DnsRecordType::new_ptr(&value),
         _ => String::from("")
-    }
+    };
+
+    Some(value)
 }
 
 fn parse_auth_record(json: &str) -> DnsAuthRecord {
@@ -217,4 +979,686 @@ fn stringify_auth_record(auth_rec: &DnsAuthRecord) -> String {
         Ok(val) => val,
         Err(_) => String::new()
     }
-}
\ No newline at end of file
+}
+
+///Creates a table without the record columns, so every read of it fails like one of a broken database file
+#[cfg(test)]
+pub fn break_table_for_test(domain: &str) {
+    let db = get_db_access();
+    db.execute(&format!("CREATE TABLE {}(name TEXT PRIMARY KEY)", domain), NO_PARAMS).unwrap();
+}
+
+///Makes the current thread see the startup as incomplete (only used by tests)
+#[cfg(test)]
+pub fn set_not_ready_for_test() {
+    TEST_NOT_READY.with(|not_ready| not_ready.set(true));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::google_dns::mock;
+    use crate::config::{ LocalRecord, local_record };
+    use crate::dns_request::{ self, DnsResponse };
+
+    #[test]
+    fn empty_txt_from_cache_test() {
+        let db = get_db_access();
+        create_table(&db, "emptytxt");
+        db.execute(
+            "INSERT INTO emptytxt (name, txt, ttl) VALUES ('record.emptytxt', '', 300)",
+            NO_PARAMS
+        ).unwrap();
+        drop(db);
+
+        let name = vec!(String::from("record"), String::from("emptytxt"));
+        let answer = get_record(&name, DnsRecordType::TXT(None), None).unwrap();
+
+        assert_eq!(answer.rdata, vec!(0)); //A single empty character-string
+        assert_eq!(answer.ttl, 300);
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn null_value_is_fetched_test() {
+        let db = get_db_access();
+        create_table(&db, "nulltxt");
+        db.execute("INSERT INTO nulltxt (name, ttl) VALUES ('record.nulltxt', 300)", NO_PARAMS).unwrap();
+        drop(db);
+
+        let name = vec!(String::from("record"), String::from("nulltxt"));
+        let answer = get_record(&name, DnsRecordType::TXT(None), None);
+
+        assert_eq!(answer.unwrap_err(), RecordError::NotFound); //No upstream response is mocked
+        assert_eq!(mock::calls(), 1);
+    }
+
+    #[test]
+    fn upgrade_tables_test() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute(
+            "CREATE TABLE com(name TEXT PRIMARY KEY, ipv4 TEXT, ipv6 TEXT, cname TEXT, mx TEXT,
+            loc TEXT, rp TEXT, certificate TEXT, authority TEXT, ttl INT)",
+            NO_PARAMS
+        ).unwrap();
+        db.execute("INSERT INTO com VALUES ('example.com', '1.2.3.4', '', '', '', '', '', '', '', 60)", NO_PARAMS).unwrap();
+
+        upgrade_tables(&db);
+
+        let (ipv4, ipv6, txt): (Option<String>, Option<String>, Option<String>) = db.query_row(
+            "SELECT ipv4, ipv6, txt FROM com WHERE name = 'example.com'",
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).unwrap();
+
+        assert_eq!(ipv4, Some(String::from("1.2.3.4")));
+        assert_eq!(ipv6, None);
+        assert_eq!(txt, None);
+
+        //The value cached before the upgrade keeps the ttl of its row
+        let (ttl, inserted_at): (u32, Option<i64>) = db.query_row(
+            &format!("SELECT {} FROM com WHERE name = 'example.com'", timing_columns("ipv4")),
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        assert_eq!(ttl, 60);
+        assert_eq!(inserted_at, None);
+    }
+
+    #[test]
+    fn cache_inspection_test() {
+        mock::respond("name=www.inspect&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.inspect.", "type": 1 }],
+            "Answer": [{ "name": "www.inspect.", "type": 1, "TTL": 600, "data": "192.0.2.7" }]
+        }"#);
+        let name = vec!(String::from("www"), String::from("inspect"));
+        get_record(&name, DnsRecordType::A(None), None).unwrap();
+        let calls = mock::calls();
+
+        let entry = lookup_cache(&name, DnsRecordType::A(None), None).unwrap();
+        assert_eq!(entry.name, "www.inspect");
+        assert_eq!(entry.record_type, DnsRecordType::A(None));
+        assert_eq!(entry.value, "192.0.2.7");
+        assert_eq!(entry.ttl, 600);
+        assert!(entry.remaining_ttl.unwrap() <= 600 && entry.remaining_ttl.unwrap() >= 590);
+        assert!(entry.inserted_at.unwrap() <= unix_time());
+
+        assert_eq!(lookup_cache(&name, DnsRecordType::AAAA(None), None), None);
+
+        let entries: Vec<CacheEntry> = dump_cache().into_iter().filter(|entry| entry.name == "www.inspect").collect();
+        assert_eq!(entries, vec!(entry));
+
+        assert_eq!(mock::calls(), calls); //Inspecting the cache never contacts the upstream
+    }
+
+    #[test]
+    fn per_type_timing_test() {
+        mock::respond("name=www.timing&type=28", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.timing.", "type": 28 }],
+            "Answer": [{ "name": "www.timing.", "type": 28, "TTL": 60, "data": "2001:db8::7" }]
+        }"#);
+        mock::respond("name=www.timing&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.timing.", "type": 1 }],
+            "Answer": [{ "name": "www.timing.", "type": 1, "TTL": 600, "data": "192.0.2.7" }]
+        }"#);
+        let name = vec!(String::from("www"), String::from("timing"));
+
+        //The A record was cached 100 seconds before the AAAA record
+        get_record(&name, DnsRecordType::A(None), None).unwrap();
+        let a_inserted_at = unix_time() as i64 - 100;
+        get_db_access().execute("UPDATE timing SET ipv4_inserted_at = ? WHERE name = 'www.timing'", params![a_inserted_at]).unwrap();
+        get_record(&name, DnsRecordType::AAAA(None), None).unwrap();
+
+        let a = lookup_cache(&name, DnsRecordType::A(None), None).unwrap();
+        assert_eq!(a.ttl, 600);
+        assert_eq!(a.inserted_at, Some(a_inserted_at as u64));
+        assert!(a.remaining_ttl.unwrap() <= 500 && a.remaining_ttl.unwrap() >= 490);
+
+        let aaaa = lookup_cache(&name, DnsRecordType::AAAA(None), None).unwrap();
+        assert_eq!(aaaa.ttl, 60);
+        assert!(aaaa.remaining_ttl.unwrap() <= 60 && aaaa.remaining_ttl.unwrap() >= 50);
+
+        let entries: Vec<CacheEntry> = dump_cache().into_iter().filter(|entry| entry.name == "www.timing").collect();
+        assert_eq!(entries, vec!(a, aaaa));
+    }
+
+    #[test]
+    fn get_all_records_test() {
+        let json = |r#type: u16, data: &str| format!(r#"{{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{{ "name": "www.allrecords.", "type": {0} }}],
+            "Answer": [{{ "name": "www.allrecords.", "type": {0}, "TTL": 600, "data": "{1}" }}]
+        }}"#, r#type, data);
+        mock::respond("name=www.allrecords&type=16", &json(16, "hello")); //Before type=1, which it contains
+        mock::respond("name=www.allrecords&type=1", &json(1, "192.0.2.8"));
+        mock::respond("name=www.allrecords&type=28", &json(28, "2001:db8::8"));
+        let name = vec!(String::from("www"), String::from("allrecords"));
+        let a = get_record(&name, DnsRecordType::A(None), None).unwrap();
+        let aaaa = get_record(&name, DnsRecordType::AAAA(None), None).unwrap();
+        let txt = get_record(&name, DnsRecordType::TXT(None), None).unwrap();
+        let calls = mock::calls();
+
+        let answers = get_all_records(&name, None);
+        let types: Vec<u16> = answers.iter().map(|answer| answer.r#type.to_byte().0).collect();
+        assert_eq!(types, vec!(1, 28, 16));
+        assert_eq!(answers[0].rdata, a.rdata);
+        assert_eq!(answers[1].rdata, aaaa.rdata);
+        assert_eq!(answers[2].rdata, txt.rdata);
+        assert_eq!(answers[0].name, name);
+
+        assert_eq!(get_all_records(&[String::from("other"), String::from("allrecords")], None), Vec::new());
+        assert_eq!(get_all_records(&name, Some("198.51.100.0/24")), Vec::new());
+        assert_eq!(mock::calls(), calls);
+    }
+
+    #[test]
+    fn subnet_cache_test() {
+        let json = |address: &str| format!(r#"{{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{{ "name": "cdn.ecs.", "type": 1 }}],
+            "Answer": [{{ "name": "cdn.ecs.", "type": 1, "TTL": 600, "data": "{}" }}]
+        }}"#, address);
+        mock::respond("edns_client_subnet=198.51.100.0/24", &json("192.0.2.1"));
+        mock::respond("edns_client_subnet=203.0.113.0/24", &json("192.0.2.2"));
+
+        let name = vec!(String::from("cdn"), String::from("ecs"));
+        let first = get_record(&name, DnsRecordType::A(None), Some("198.51.100.0/24")).unwrap();
+        let second = get_record(&name, DnsRecordType::A(None), Some("203.0.113.0/24")).unwrap();
+        assert_eq!(first.rdata, vec!(192, 0, 2, 1));
+        assert_eq!(second.rdata, vec!(192, 0, 2, 2));
+        assert_eq!(mock::calls(), 2);
+
+        //Both answers are now served from the cache
+        let first = get_record(&name, DnsRecordType::A(None), Some("198.51.100.0/24")).unwrap();
+        let second = get_record(&name, DnsRecordType::A(None), Some("203.0.113.0/24")).unwrap();
+        assert_eq!(first.rdata, vec!(192, 0, 2, 1));
+        assert_eq!(second.rdata, vec!(192, 0, 2, 2));
+        assert_eq!(mock::calls(), 2);
+
+        let entry = lookup_cache(&name, DnsRecordType::A(None), Some("203.0.113.0/24")).unwrap();
+        assert_eq!(entry.name, "cdn.ecs");
+        assert_eq!(entry.subnet, Some(String::from("203.0.113.0/24")));
+        assert_eq!(lookup_cache(&name, DnsRecordType::A(None), None), None);
+    }
+
+    #[test]
+    fn follow_cname_test() {
+        let db = get_db_access();
+        create_table(&db, "alias");
+        db.execute("INSERT INTO alias (name, cname, ttl) VALUES ('www.alias', 'edge.cdn.alias.', 300)", NO_PARAMS).unwrap();
+        db.execute("INSERT INTO alias (name, cname, ttl) VALUES ('cdn.alias', 'edge.cdn.alias', 300)", NO_PARAMS).unwrap();
+        db.execute("INSERT INTO alias (name, ipv4, ttl) VALUES ('edge.cdn.alias', '192.0.2.80', 60)", NO_PARAMS).unwrap();
+        db.execute("INSERT INTO alias (name, cname, ttl) VALUES ('loop.alias', 'loop.alias', 300)", NO_PARAMS).unwrap();
+        drop(db);
+
+        let name = |name: &str| name.split(".").map(String::from).collect::<Vec<String>>();
+
+        let answers = follow_cname(&name("www.alias"), DnsRecordType::A(None), None).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].name, name("www.alias"));
+        assert_eq!(answers[0].r#type, DnsRecordType::new_cname("edge.cdn.alias").unwrap());
+        assert_eq!(answers[1].name, name("edge.cdn.alias"));
+        assert_eq!(answers[1].rdata, vec!(192, 0, 2, 80));
+        assert_eq!(answers[1].ttl, 60);
+
+        assert_eq!(get_val_from_ans(&answers[0]).unwrap(), "edge.cdn.alias");
+        assert!(follow_cname(&name("edge.cdn.alias"), DnsRecordType::A(None), None).is_none()); //Not an alias
+        assert!(follow_cname(&name("www.alias"), DnsRecordType::AAAA(None), None).is_none()); //No cached address
+        assert!(follow_cname(&name("loop.alias"), DnsRecordType::A(None), None).is_none());
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn unavailable_database_test() {
+        break_table_for_test("brokendb");
+        let failures = failures();
+
+        let name = vec!(String::from("www"), String::from("brokendb"));
+        let answer = get_record(&name, DnsRecordType::A(None), None);
+
+        assert_eq!(answer.unwrap_err(), RecordError::Unavailable);
+        assert!(super::failures() > failures);
+        assert_eq!(mock::calls(), 0);
+
+        //Only a table which does not exist is a cache miss
+        assert!(has_table(&get_db_access(), "BrokenDb"));
+        assert!(!has_table(&get_db_access(), "missingdb"));
+    }
+
+    #[test]
+    fn binary_txt_value_test() {
+        let text: Vec<u8> = vec!(0xff, 0xfe, b'a', 0x80, 0);
+        let answer = DnsAnswer::default()
+        .name(vec!(String::from("binary"), String::from("example")))
+        .ttl(60)
+        .record(DnsRecordType::new_txt_bytes(&text));
+
+        let value = get_val_from_ans(&answer).unwrap();
+        let parsed = get_ans_from_val(&value, DnsRecordType::TXT(None), answer.clone());
+        let mut rdata = vec!(5);
+        rdata.extend_from_slice(&text);
+        assert_eq!(parsed.rdata, rdata);
+
+        let response = DnsResponse::default().add_answer(parsed);
+        let wire = dns_request::parse_response(&response.build(false), false).unwrap();
+        assert_eq!(wire.answers[0].rdata, rdata);
+
+        //Values cached before binary values were supported are plain text
+        let legacy = get_ans_from_val("hello", DnsRecordType::TXT(None), DnsAnswer::default());
+        assert_eq!(legacy.rdata, vec!(5, 104, 101, 108, 108, 111));
+        //and values cached before the rdata was stored are the base64 of the text
+        let legacy = get_ans_from_val("base64:aGVsbG8=", DnsRecordType::TXT(None), DnsAnswer::default());
+        assert_eq!(legacy.rdata, vec!(5, 104, 101, 108, 108, 111));
+    }
+
+    #[test]
+    fn multi_string_txt_test() {
+        //Two character-strings, "v=spf1" and " -all"
+        let rdata: Vec<u8> = vec!(6, 118, 61, 115, 112, 102, 49, 5, 32, 45, 97, 108, 108);
+        let answer = DnsAnswer::default().record(Some(DnsRecordType::TXT(Some(rdata.clone()))));
+        let value = get_val_from_ans(&answer).unwrap();
+
+        let db = get_db_access();
+        create_table(&db, "txtstrings");
+        db.execute("INSERT INTO txtstrings (name, txt, ttl) VALUES ('multi.txtstrings', ?, 600)", params![value]).unwrap();
+        drop(db);
+
+        let name = vec!(String::from("multi"), String::from("txtstrings"));
+        assert_eq!(get_record(&name, DnsRecordType::TXT(None), None).unwrap().rdata, rdata);
+        assert_eq!(mock::calls(), 0);
+
+        //Character-strings which do not fill the rdata are not cached
+        let invalid = DnsAnswer::default().record(Some(DnsRecordType::TXT(Some(vec!(6, 118, 61)))));
+        assert_eq!(get_val_from_ans(&invalid), None);
+    }
+
+    #[test]
+    fn openpgpkey_value_test() {
+        let key: Vec<u8> = vec!(0x99, 0x01, 0x0d, 0x04, 0x5f, 0x00, 0xff);
+        let answer = DnsAnswer::default()
+        .ttl(60)
+        .record(DnsRecordType::new_openpgpkey(&base64::encode(&key)));
+
+        let value = get_val_from_ans(&answer).unwrap();
+        let parsed = get_ans_from_val(&value, DnsRecordType::OPENPGPKEY(None), DnsAnswer::default());
+
+        assert_eq!(parsed.r#type, DnsRecordType::OPENPGPKEY(Some(key.clone())));
+        assert_eq!(parsed.rdata, key);
+    }
+
+    #[test]
+    fn out_of_bailiwick_not_cached_test() {
+        mock::respond("name=www.poison&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.poison.", "type": 1 }],
+            "Answer": [
+                { "name": "bank.poison.", "type": 1, "TTL": 86400, "data": "203.0.113.66" },
+                { "name": "www.poison.", "type": 1, "TTL": 60, "data": "192.0.2.20" }
+            ]
+        }"#);
+        let name = vec!(String::from("www"), String::from("poison"));
+        get_record(&name, DnsRecordType::A(None), None).unwrap();
+
+        assert_eq!(lookup_cache(&name, DnsRecordType::A(None), None).unwrap().value, "192.0.2.20");
+        let bank = vec!(String::from("bank"), String::from("poison"));
+        assert_eq!(lookup_cache(&bank, DnsRecordType::A(None), None), None);
+    }
+
+    #[test]
+    fn min_ttl_by_type_test() {
+        let mut config = config::Config::default();
+        config.min_ttl_by_type.insert(String::from("a"), 300);
+        config::set_for_test(config);
+
+        mock::respond("name=www.floor&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.floor.", "type": 1 }],
+            "Answer": [{ "name": "www.floor.", "type": 1, "TTL": 30, "data": "192.0.2.30" }]
+        }"#);
+        mock::respond("name=www.floor&type=6", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.floor.", "type": 6 }],
+            "Authority": [{ "name": "floor.", "type": 6, "TTL": 30, "data": "ns.floor. hostmaster.floor. 1 7200 3600 1209600 30" }]
+        }"#);
+        let name = vec!(String::from("www"), String::from("floor"));
+
+        assert_eq!(get_record(&name, DnsRecordType::A(None), None).unwrap().ttl, 300);
+        assert_eq!(lookup_cache(&name, DnsRecordType::A(None), None).unwrap().ttl, 300);
+        assert_eq!(get_record(&name, DnsRecordType::SOA(None), None).unwrap().ttl, 30);
+    }
+
+    #[test]
+    fn no_cache_types_test() {
+        let mut config = config::Config::default();
+        config.no_cache_types.push(String::from("TXT"));
+        config::set_for_test(config);
+
+        mock::respond("name=www.nocache&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.nocache.", "type": 16 }],
+            "Answer": [{ "name": "www.nocache.", "type": 16, "TTL": 300, "data": "\"volatile\"" }]
+        }"#);
+        mock::respond("name=www.nocache&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.nocache.", "type": 1 }],
+            "Answer": [{ "name": "www.nocache.", "type": 1, "TTL": 300, "data": "192.0.2.31" }]
+        }"#);
+        let name = vec!(String::from("www"), String::from("nocache"));
+
+        assert!(get_record(&name, DnsRecordType::TXT(None), None).is_ok());
+        assert!(lookup_cache(&name, DnsRecordType::TXT(None), None).is_none());
+        assert!(get_record(&name, DnsRecordType::A(None), None).is_ok());
+        assert!(lookup_cache(&name, DnsRecordType::A(None), None).is_some());
+    }
+
+    #[test]
+    fn forward_always_policy_test() {
+        let db = get_db_access();
+        create_table(&db, "forwarded");
+        db.execute("INSERT INTO forwarded (name, ipv4, ttl) VALUES ('www.forwarded', '192.0.2.50', 600)", NO_PARAMS).unwrap();
+        drop(db);
+
+        let mut config = config::Config::default();
+        config.cache_policies.push(config::CachePolicyRule {
+            name: Some(String::from("*.forwarded")),
+            types: Vec::new(),
+            policy: CachePolicy::ForwardAlways
+        });
+        config::set_for_test(config);
+        mock::respond("name=www.forwarded&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.forwarded.", "type": 1 }],
+            "Answer": [{ "name": "www.forwarded.", "type": 1, "TTL": 60, "data": "192.0.2.51" }]
+        }"#);
+        let name = vec!(String::from("www"), String::from("forwarded"));
+
+        //The cached record is neither served nor replaced
+        assert_eq!(get_record(&name, DnsRecordType::A(None), None).unwrap().rdata, vec!(192, 0, 2, 51));
+        assert_eq!(mock::calls(), 1);
+        assert_eq!(lookup_cache(&name, DnsRecordType::A(None), None).unwrap().value, "192.0.2.50");
+    }
+
+    #[test]
+    fn cache_only_policy_test() {
+        let db = get_db_access();
+        create_table(&db, "cacheonly");
+        db.execute("INSERT INTO cacheonly (name, ipv4, ttl) VALUES ('www.cacheonly', '192.0.2.60', 600)", NO_PARAMS).unwrap();
+        db.execute("INSERT INTO cacheonly (name, ipv4, ttl) VALUES ('corrupt.cacheonly', '192.0.2', 600)", NO_PARAMS).unwrap();
+        drop(db);
+
+        let mut config = config::Config::default();
+        config.cache_policies.push(config::CachePolicyRule {
+            name: Some(String::from("*.cacheonly")),
+            types: vec!(String::from("A")),
+            policy: CachePolicy::CacheOnly
+        });
+        config::set_for_test(config);
+        let cached = vec!(String::from("www"), String::from("cacheonly"));
+        let missing = vec!(String::from("other"), String::from("cacheonly"));
+
+        assert_eq!(get_record(&cached, DnsRecordType::A(None), None).unwrap().rdata, vec!(192, 0, 2, 60));
+        assert_eq!(get_record(&missing, DnsRecordType::A(None), None).unwrap_err(), RecordError::NotFound);
+
+        //An invalid value is neither fetched again nor cleared
+        let corrupt = vec!(String::from("corrupt"), String::from("cacheonly"));
+        assert_eq!(get_record(&corrupt, DnsRecordType::A(None), None).unwrap_err(), RecordError::Unavailable);
+        assert_eq!(lookup_cache(&corrupt, DnsRecordType::A(None), None).unwrap().value, "192.0.2");
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn oversized_rdata_test() {
+        let config = config::Config {
+            max_cached_rdata: 64,
+            ..config::Config::default()
+        };
+        config::set_for_test(config);
+
+        let text = "x".repeat(100);
+        mock::respond("name=www.oversized&type=16", &format!(r#"{{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{{ "name": "www.oversized.", "type": 16 }}],
+            "Answer": [{{ "name": "www.oversized.", "type": 16, "TTL": 300, "data": "\"{}\"" }}]
+        }}"#, text));
+        let name = vec!(String::from("www"), String::from("oversized"));
+
+        let answer = get_record(&name, DnsRecordType::TXT(None), None).unwrap();
+        assert_eq!(answer.rdata.len(), 101);
+        assert!(lookup_cache(&name, DnsRecordType::TXT(None), None).is_none());
+    }
+
+    #[test]
+    fn open_fallback_test() {
+        let db = open_or_fallback("./data/missing-directory/domains.db");
+        create_table(&db, "fallback");
+        assert_eq!(get_tables(&db), vec!(String::from("fallback")));
+    }
+
+    #[test]
+    fn address_value_test() {
+        let answer = |record: DnsRecordType| DnsAnswer::default().ttl(60).record(Some(record));
+
+        assert_eq!(get_val_from_ans(&answer(DnsRecordType::A(Some(vec!(192, 0, 2, 1))))).unwrap(), "192.0.2.1");
+        assert_eq!(get_val_from_ans(&answer(DnsRecordType::A(Some(vec!(192, 0, 2))))), None);
+
+        let ipv6 = DnsRecordType::new_aaaa("2001:db8::1").unwrap();
+        let value = get_val_from_ans(&answer(ipv6.clone())).unwrap();
+        assert_eq!(value, "2001:db8::1");
+        assert_eq!(get_ans_from_val(&value, DnsRecordType::AAAA(None), DnsAnswer::default()).r#type, ipv6);
+        assert_eq!(get_val_from_ans(&answer(DnsRecordType::AAAA(Some(vec!(32, 1, 13, 184))))), None);
+    }
+
+    #[test]
+    fn corrupt_address_refetched_test() {
+        let db = get_db_access();
+        create_table(&db, "corrupt");
+        db.execute("INSERT INTO corrupt (name, ipv4, ttl) VALUES ('www.corrupt', '192.0.2', 60)", NO_PARAMS).unwrap();
+        drop(db);
+
+        mock::respond("name=www.corrupt&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.corrupt.", "type": 1 }],
+            "Answer": [{ "name": "www.corrupt.", "type": 1, "TTL": 60, "data": "192.0.2.41" }]
+        }"#);
+        let name = vec!(String::from("www"), String::from("corrupt"));
+
+        let answer = get_record(&name, DnsRecordType::A(None), None).unwrap();
+        assert_eq!(answer.rdata, vec!(192, 0, 2, 41));
+        assert_eq!(mock::calls(), 1);
+        assert_eq!(lookup_cache(&name, DnsRecordType::A(None), None).unwrap().value, "192.0.2.41");
+    }
+
+    #[test]
+    fn obsolete_value_cleared_test() {
+        let db = get_db_access();
+        create_table(&db, "obsolete");
+        db.execute(
+            "INSERT INTO obsolete (name, ipv6, ttl) VALUES ('www.obsolete', '32:1:13:184:0:0:0:0:0:0:0:0:0:0:0:1', 60)",
+            NO_PARAMS
+        ).unwrap();
+        drop(db);
+
+        let name = vec!(String::from("www"), String::from("obsolete"));
+        assert!(!valid_value("32:1:13:184:0:0:0:0:0:0:0:0:0:0:0:1", &DnsRecordType::AAAA(None)));
+
+        //No upstream response is mocked, so the value can't be repaired but is no longer served
+        assert_eq!(get_record(&name, DnsRecordType::AAAA(None), None).unwrap_err(), RecordError::NotFound);
+        assert_eq!(lookup_cache(&name, DnsRecordType::AAAA(None), None), None);
+    }
+
+    #[test]
+    fn serve_stale_max_age_test() {
+        let config = config::Config {
+            serve_stale_max_age: Some(3600),
+            ..config::Config::default()
+        };
+        config::set_for_test(config);
+
+        let now = unix_time() as i64;
+        let db = get_db_access();
+        create_table(&db, "stale");
+        db.execute(
+            "INSERT INTO stale (name, ipv4, ttl, inserted_at) VALUES ('fresh.stale', '192.0.2.90', 600, ?)",
+            params![now]
+        ).unwrap();
+        db.execute(
+            "INSERT INTO stale (name, ipv4, ttl, inserted_at) VALUES ('recent.stale', '192.0.2.91', 60, ?)",
+            params![now - 120]
+        ).unwrap();
+        db.execute(
+            "INSERT INTO stale (name, ipv4, ttl, inserted_at) VALUES ('old.stale', '192.0.2.92', 60, ?)",
+            params![now - 86400]
+        ).unwrap();
+        drop(db);
+        let name = |host: &str| vec!(String::from(host), String::from("stale"));
+
+        //No upstream response is mocked, so the upstream fails for every expired record
+        let fresh = get_record(&name("fresh"), DnsRecordType::A(None), None).unwrap();
+        assert_eq!(fresh.rdata, vec!(192, 0, 2, 90));
+        assert!(fresh.ttl <= 600 && fresh.ttl >= 590); //The remaining ttl is served
+        assert_eq!(mock::calls(), 0);
+
+        let recent = get_record(&name("recent"), DnsRecordType::A(None), None).unwrap();
+        assert_eq!(recent.rdata, vec!(192, 0, 2, 91));
+        assert_eq!(recent.ttl, STALE_TTL);
+        assert_eq!(mock::calls(), 1);
+
+        assert_eq!(get_record(&name("old"), DnsRecordType::A(None), None).unwrap_err(), RecordError::Unavailable);
+        assert_eq!(mock::calls(), 2);
+
+        //Once the upstream answers again, the expired record is replaced
+        mock::respond("name=old.stale&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "old.stale.", "type": 1 }],
+            "Answer": [{ "name": "old.stale.", "type": 1, "TTL": 300, "data": "192.0.2.93" }]
+        }"#);
+        assert_eq!(get_record(&name("old"), DnsRecordType::A(None), None).unwrap().rdata, vec!(192, 0, 2, 93));
+        assert_eq!(lookup_cache(&name("old"), DnsRecordType::A(None), None).unwrap().remaining_ttl, Some(300));
+
+        //Without a bound, expired records are still fetched again, and served however old they are if the upstream fails
+        config::set_for_test(config::Config::default());
+        let db = get_db_access();
+        db.execute(
+            "INSERT INTO stale (name, ipv4, ttl, inserted_at) VALUES ('unbounded.stale', '192.0.2.94', 60, ?)",
+            params![now - 86400]
+        ).unwrap();
+        drop(db);
+        let unbounded = get_record(&name("unbounded"), DnsRecordType::A(None), None).unwrap();
+        assert_eq!(unbounded.rdata, vec!(192, 0, 2, 94));
+        assert_eq!(unbounded.ttl, STALE_TTL);
+        assert_eq!(mock::calls(), 4);
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let mut records: Vec<LocalRecord> = (0..10000).map(|i| local_record(&format!("host{}.preloaded", i), "A", &format!("10.0.{}.{}", i / 256, i % 256))).collect();
+        records.push(local_record("host4242.preloaded", "A", "10.1.0.1"));
+        snapshot::set_for_test(snapshot::Snapshot::build(&records));
+        //Reading the table fails, so answers can only come from the snapshot
+        break_table_for_test("preloaded");
+
+        let name = vec!(String::from("host4242"), String::from("preloaded"));
+        let answers = get_records(&name, DnsRecordType::A(None), None).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].rdata, vec!(10, 0, 16, 146));
+        assert_eq!(answers[1].rdata, vec!(10, 1, 0, 1));
+        assert!(answers.iter().all(|answer| answer.name == name && answer.ttl == 300));
+        assert_eq!(mock::calls(), 0);
+
+        let name = vec!(String::from("other"), String::from("preloaded"));
+        assert_eq!(get_records(&name, DnsRecordType::A(None), None).unwrap_err(), RecordError::Unavailable);
+    }
+
+    #[test]
+    fn shard_index_test() {
+        let names: Vec<String> = (0..1000).map(|i| format!("host{}.example", i)).collect();
+        let mut counts = [0; 4];
+        for name in &names {
+            counts[shard_index(name, 4)] += 1;
+        }
+
+        assert!(counts.iter().all(|count| *count > 200), "{:?}", counts);
+        assert_eq!(shard_index("Host1.Example", 4), shard_index("host1.example", 4));
+        assert_eq!(shard_index("host1.example", 1), 0);
+    }
+
+    #[test]
+    fn soa_alias_test() {
+        mock::respond("name=alias.soaalias&type=6", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "alias.soaalias.", "type": 6 }],
+            "Answer": [{ "name": "alias.soaalias.", "type": 5, "TTL": 300, "data": "zone.soatarget." }]
+        }"#);
+        mock::respond("name=zone.soatarget&type=6", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "zone.soatarget.", "type": 6 }],
+            "Authority": [{ "name": "zone.soatarget.", "type": 6, "TTL": 1800, "data": "ns.soatarget. hostmaster.soatarget. 7 7200 3600 1209600 300" }]
+        }"#);
+        mock::respond("name=chain.soaalias&type=6", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "chain.soaalias.", "type": 6 }],
+            "Answer": [{ "name": "chain.soaalias.", "type": 5, "TTL": 300, "data": "alias.soaalias." }]
+        }"#);
+        let name = |domain: &str| domain.split(".").map(String::from).collect::<Vec<String>>();
+
+        //The alias is answered along with the SOA record of its target
+        let answers = get_records(&name("alias.soaalias"), DnsRecordType::SOA(None), None).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].name, name("alias.soaalias"));
+        assert_eq!(answers[0].r#type, DnsRecordType::new_cname("zone.soatarget").unwrap());
+        assert_eq!(answers[1].name, name("zone.soatarget"));
+        match &answers[1].r#type {
+            DnsRecordType::SOA(Some(auth_record)) => assert_eq!(auth_record.serial, 7),
+            other => panic!("Expected an SOA record, got {:?}", other)
+        }
+
+        //The SOA record is only cached for the target
+        assert!(lookup_cache(&name("alias.soaalias"), DnsRecordType::SOA(None), None).is_none());
+        assert!(lookup_cache(&name("zone.soatarget"), DnsRecordType::SOA(None), None).is_some());
+
+        //Only one alias is followed
+        assert_eq!(get_records(&name("chain.soaalias"), DnsRecordType::SOA(None), None).unwrap_err(), RecordError::Unavailable);
+
+        let config = config::Config {
+            follow_soa_alias: false,
+            ..config::Config::default()
+        };
+        config::set_for_test(config);
+        assert_eq!(get_records(&name("alias.soaalias"), DnsRecordType::SOA(None), None).unwrap_err(), RecordError::Unavailable);
+    }
+
+    #[test]
+    fn rehash_shards_test() {
+        let shards: Vec<Connection> = (0..2).map(|_| Connection::open_in_memory().unwrap()).collect();
+        let unused = Connection::open_in_memory().unwrap();
+        let names: Vec<String> = (0..20).map(|i| format!("host{}.rehash", i)).collect();
+
+        //Every name was cached in the first shard, or in a shard which is no longer configured
+        create_table(&shards[0], "rehash");
+        create_table(&unused, "rehash");
+        for (i, name) in names.iter().enumerate() {
+            let db = if i % 2 == 0 { &shards[0] } else { &unused };
+            db.execute(
+                "INSERT INTO rehash (name, ipv4, ipv4_ttl, ttl) VALUES (?, ?, 600, 300)",
+                params![name, format!("192.0.2.{}", i)]
+            ).unwrap();
+        }
+
+        assert!(rehash_shards(&shards, &[&unused]));
+        for (i, name) in names.iter().enumerate() {
+            let db = &shards[shard_index(name, 2)];
+            let (ipv4, ipv4_ttl, ttl) = db.query_row(
+                "SELECT ipv4, ipv4_ttl, ttl FROM rehash WHERE name = ?",
+                params![name],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?, row.get::<_, u32>(2)?))
+            ).unwrap();
+            assert_eq!((ipv4, ipv4_ttl, ttl), (format!("192.0.2.{}", i), 600, 300));
+        }
+        let count = |db: &Connection| db.query_row("SELECT COUNT(*) FROM rehash", NO_PARAMS, |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count(&shards[0]) + count(&shards[1]), 20);
+        assert_eq!(count(&unused), 0);
+    }
+}
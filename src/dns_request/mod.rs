@@ -4,51 +4,410 @@
 //! <https://tools.ietf.org/html/rfc1035> (The complete dns specifications)\
 //! <https://tools.ietf.org/html/rfc1464> (The specifications for the TXT record format)
 
-use std::convert::TryInto; 
+use std::convert::{ TryFrom, TryInto };
 
 mod structs;
 pub use structs::*;
 
+//Types with names in their rdata which are expanded when a response is parsed (RFC 3597 section 4),
+//along with the number of bytes before the first name and the number of names:
+//NS, MD, MF, MB, MG, MR, MINFO, AFSDB, RT, PX and SRV (CNAME, PTR, MX, RP and SOA have their own types)
+const COMPRESSED_NAME_TYPES: [(u16, usize, usize); 11] = [
+    (2, 0, 1), (3, 0, 1), (4, 0, 1), (7, 0, 1), (8, 0, 1), (9, 0, 1), (14, 0, 2), (18, 2, 1), (21, 2, 1), (26, 2, 2), (33, 6, 1)
+];
+
 /// Function to parse through a dns query
 /// This function takes as input a buffer consisting soley of the bytes required to read the query,
 /// and a boolean to signify whether the request was sent by tcp or udp. It returns a DnsQuery on sucess
 /// or None on failure
 pub fn parse_query(buffer: &Vec<u8>, tcp: bool) -> Option<DnsQuery> {
+    try_parse_query(buffer, tcp).ok()
+}
+
+/// Function to parse through a dns query, with the reason on failure
+/// This takes the same input as [parse_query](parse_query) and returns the [ParseError](ParseError) of the
+/// first malformed part of the query
+pub fn try_parse_query(buffer: &[u8], tcp: bool) -> Result<DnsQuery, ParseError> {
     let mut buffer = buffer;
     let buffer_temp;
     if tcp {
-        buffer_temp = buffer[2..].to_vec(); //Ignore Length bits
+        buffer_temp = match buffer.get(2..) {
+            Some(val) => val.to_vec(), //Ignore Length bits
+            None => return Err(ParseError::TooShort)
+        };
         buffer = &buffer_temp;
     }
 
-    let (header, mut buffer) = match parse_header(&buffer) {
+    let message = buffer;
+    let (header, mut buffer) = match parse_header(buffer) {
         Some(val) => val,
         _ => {
-            return None;
+            return Err(ParseError::TooShort);
         }
     };
 
+    //Exactly qd_count questions are parsed, any bytes after them belong to the other sections
     let mut questions: Vec<DnsQuestion> = Vec::new();
-    while let Some(ref new_buffer) = buffer {
-        let question;
-        let ques_buff = match parse_question(&new_buffer) {
-            Some(val) => val,
-            None => {
-                return None;
-            }
+    for _ in 0..header.qd_count {
+        let new_buffer = match buffer {
+            Some(ref val) => val,
+            None => return Err(ParseError::TooShort) //Fewer questions than the header claims
         };
+
+        let question;
+        let ques_buff = parse_question(new_buffer)?;
         question = ques_buff.0;
         buffer = ques_buff.1;
         questions.push(question);
     }
 
-    Some(DnsQuery {
+    //The records after the questions are only read for the OPT record of the client.
+    //A malformed record ends the search without rejecting the query
+    let mut edns: Option<Edns> = None;
+    let mut invalid_edns = false;
+    let mut offset = message.len() - buffer.as_ref().map_or(0, |remaining| remaining.len());
+    let record_count = header.an_count as usize + header.ns_count as usize + header.ar_count as usize;
+    for _ in 0..record_count {
+        match parse_record(message, offset) {
+            Some((ParsedRecord::Opt(opt, root), end)) => {
+                //At most one OPT record is allowed, and it must be owned by the root (RFC 6891)
+                if edns.is_some() || !root {
+                    invalid_edns = true;
+                }
+                if edns.is_none() {
+                    edns = Some(opt);
+                }
+                offset = end;
+            },
+            Some((ParsedRecord::Answer(_), end)) => offset = end,
+            None => break
+        }
+    }
+
+    Ok(DnsQuery {
         header: header,
-        questions: questions
+        questions,
+        edns,
+        invalid_edns
     })
 }
 
-fn parse_header(buffer: &Vec<u8>) -> Option<(DnsHeader, Option<Vec<u8>>)> {
+/// Function to read only the name and type of the first question of a query, without parsing the whole query.
+/// This takes the same input as [parse_query](parse_query) and returns None if the query has no question
+/// or the question is malformed
+pub fn peek_question(buffer: &[u8], tcp: bool) -> Option<(Vec<String>, u16)> {
+    let message = if tcp { buffer.get(2..)? } else { buffer };
+    if message.len() < 12 || u16::from_be_bytes([message[4], message[5]]) == 0 {
+        return None;
+    }
+
+    let (name, end) = read_name(message, 12)?;
+    let qtype = message.get(end..end+2)?;
+
+    Some((name, u16::from_be_bytes([qtype[0], qtype[1]])))
+}
+
+/// Function to parse through a dns response (for example one returned by an upstream server)
+/// This function takes the same input as [parse_query](parse_query). Compressed names, both in the
+/// owner names and in the rdata of the types which may be compressed (RFC 3597 section 4), are expanded so that the records
+/// no longer reference the original message and can be cached or built into a new message.
+/// It returns a DnsResponse on success or None on failure
+pub fn parse_response(buffer: &[u8], tcp: bool) -> Option<DnsResponse> {
+    let message: &[u8] = if tcp {
+        buffer.get(2..)? //Ignore Length bits
+    }
+    else {
+        buffer
+    };
+
+    let (header, _) = parse_header(message)?;
+    let mut offset: usize = 12;
+
+    let mut questions: Vec<DnsQuestion> = Vec::new();
+    for _ in 0..header.qd_count {
+        let (qname, next) = read_name(message, offset)?;
+        let fields = message.get(next..next+4)?;
+        questions.push(DnsQuestion {
+            qname,
+            qtype: DnsRecordType::from_byte(u16::from_be_bytes([fields[0], fields[1]])),
+            qclass: u16::from_be_bytes([fields[2], fields[3]])
+        });
+        offset = next + 4;
+    }
+
+    let mut answers: Vec<DnsAnswer> = Vec::new();
+    let mut authority_records: Vec<DnsAnswer> = Vec::new();
+    let mut additional_records: Vec<DnsAnswer> = Vec::new();
+    let mut edns: Option<Edns> = None;
+    let sections = [header.an_count, header.ns_count, header.ar_count];
+    for (section, count) in sections.iter().enumerate() {
+        for _ in 0..*count {
+            let (record, next) = parse_record(message, offset)?;
+            offset = next;
+
+            match record {
+                ParsedRecord::Answer(answer) => match section {
+                    0 => answers.push(answer),
+                    1 => authority_records.push(answer),
+                    _ => additional_records.push(answer)
+                },
+                ParsedRecord::Opt(opt, _) => edns = Some(opt)
+            }
+        }
+    }
+
+    Some(DnsResponse {
+        header,
+        questions,
+        answers,
+        authority_records,
+        additional_records,
+        edns
+    })
+}
+
+enum ParsedRecord {
+    Answer(DnsAnswer),
+    Opt(Edns, bool) //Whether the owner name is the root
+}
+
+//Reads a resource record starting at offset, returning it along with the offset after it
+fn parse_record(message: &[u8], offset: usize) -> Option<(ParsedRecord, usize)> {
+    let (name, next) = read_name(message, offset)?;
+    let fields = message.get(next..next+10)?;
+    let r#type = u16::from_be_bytes([fields[0], fields[1]]);
+    let class = u16::from_be_bytes([fields[2], fields[3]]);
+    let ttl = u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]);
+    let rd_length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+
+    let rdata_start = next + 10;
+    let rdata = message.get(rdata_start..rdata_start+rd_length)?.to_vec();
+    let end = rdata_start + rd_length;
+
+    if r#type == 41 { //OPT pseudo-record
+        let opt = Edns {
+            udp_size: class,
+            extended_error: None,
+            dnssec_ok: ttl & 0x8000 != 0,
+            options: parse_options(&rdata)?
+        };
+        return Some((ParsedRecord::Opt(opt, name.is_empty()), end));
+    }
+    let record = match DnsRecordType::from_byte(r#type) {
+        DnsRecordType::CNAME(_) => DnsRecordType::CNAME(Some(domain_list_to_bytes(&read_name(message, rdata_start)?.0))),
+        DnsRecordType::PTR(_) => DnsRecordType::PTR(Some(domain_list_to_bytes(&read_name(message, rdata_start)?.0))),
+        DnsRecordType::MX(_) => DnsRecordType::MX(Some(expand_names(message, rdata_start, end, 2, 1)?)), //After the preference
+        DnsRecordType::SOA(_) => {
+            let (mname, next) = read_name(message, rdata_start)?;
+            let (rname, next) = read_name(message, next)?;
+            let timers = message.get(next..next+20)?;
+            let timer = |i: usize| u32::from_be_bytes([timers[i], timers[i+1], timers[i+2], timers[i+3]]);
+
+            DnsRecordType::SOA(Some(
+                DnsAuthRecord::new()
+                .mname(mname)
+                .rname(rname)
+                .serial(timer(0))
+                .refresh(timer(4))
+                .retry(timer(8))
+                .expire(timer(12))
+                .minimum(timer(16))
+            ))
+        },
+        DnsRecordType::A(_) => DnsRecordType::A(Some(rdata.clone())),
+        DnsRecordType::AAAA(_) => DnsRecordType::AAAA(Some(rdata.clone())),
+        DnsRecordType::LOC(_) => DnsRecordType::LOC(Some(rdata.clone())),
+        DnsRecordType::RP(_) => DnsRecordType::RP(Some(expand_names(message, rdata_start, end, 0, 2)?)),
+        DnsRecordType::TLSA(_) => DnsRecordType::TLSA(Some(rdata.clone())),
+        DnsRecordType::TXT(_) => DnsRecordType::TXT(Some(rdata.clone())),
+        DnsRecordType::OPENPGPKEY(_) => DnsRecordType::OPENPGPKEY(Some(rdata.clone())),
+        DnsRecordType::NotImplemented(code, _) => match COMPRESSED_NAME_TYPES.iter().find(|(r#type, _, _)| *r#type == code) {
+            Some((_, prefix, names)) => DnsRecordType::NotImplemented(code, expand_names(message, rdata_start, end, *prefix, *names)?),
+            None => DnsRecordType::NotImplemented(code, rdata.clone())
+        }
+    };
+
+    let answer = DnsAnswer::default()
+    .name(name)
+    .class(class)
+    .ttl(ttl)
+    .record(Some(record));
+
+    Some((ParsedRecord::Answer(answer), end))
+}
+
+//Copies the rdata ending at end with its names expanded, the names following the first prefix bytes
+fn expand_names(message: &[u8], rdata_start: usize, end: usize, prefix: usize, names: usize) -> Option<Vec<u8>> {
+    let mut expanded = message.get(rdata_start..rdata_start+prefix)?.to_vec();
+    let mut position = rdata_start + prefix;
+    for _ in 0..names {
+        let (name, next) = read_name(message, position)?;
+        write_domain_list(&name, &mut expanded);
+        position = next;
+    }
+    expanded.extend_from_slice(message.get(position..end)?);
+
+    Some(expanded)
+}
+
+//Reads the options of an OPT record, returning None if an option runs past the end of the rdata
+fn parse_options(rdata: &[u8]) -> Option<Vec<(u16, Vec<u8>)>> {
+    let mut options: Vec<(u16, Vec<u8>)> = Vec::new();
+    let mut offset: usize = 0;
+    while offset < rdata.len() {
+        let fields = rdata.get(offset..offset+4)?;
+        let code = u16::from_be_bytes([fields[0], fields[1]]);
+        let length = u16::from_be_bytes([fields[2], fields[3]]) as usize;
+        let data = rdata.get(offset+4..offset+4+length)?;
+        options.push((code, data.to_vec()));
+        offset += 4 + length;
+    }
+
+    Some(options)
+}
+
+///Splits a domain name in presentation format ("www.example.com") into its labels.
+///A trailing dot and the root name (".") are accepted, escaped characters (\\. or \\DDD) are kept within their label
+pub fn domain_to_labels(name: &str) -> Vec<String> {
+    if name == "." {
+        return Vec::new();
+    }
+
+    let mut labels: Vec<String> = Vec::new();
+    let mut label = String::new();
+    let mut chars = name.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let digits: String = (0..3).filter_map(|_| chars.next_if(|c| c.is_ascii_digit())).collect();
+                if digits.len() == 3 {
+                    match digits.parse::<u8>() {
+                        Ok(byte) => label.push(byte as char),
+                        Err(_) => label.push_str(&digits)
+                    }
+                }
+                else if !digits.is_empty() {
+                    label.push_str(&digits);
+                }
+                else if let Some(escaped) = chars.next() {
+                    label.push(escaped);
+                }
+            },
+            '.' => labels.push(std::mem::take(&mut label)),
+            c => label.push(c)
+        }
+    }
+    labels.push(label);
+
+    //The empty label left by a trailing dot is not a label of the name
+    if labels.last().map(|label| label.is_empty()) == Some(true) {
+        labels.pop();
+    }
+
+    labels
+}
+
+///Joins labels into a domain name in presentation format, escaping dots and backslashes within labels
+///as well as non-printable characters (\\DDD). The root name is returned as "."
+pub fn labels_to_domain(labels: &[String]) -> String {
+    if labels.is_empty() {
+        return String::from(".");
+    }
+
+    let escaped: Vec<String> = labels.iter().map(|label| {
+        let mut result = String::new();
+        for c in label.chars() {
+            match c {
+                '.' | '\\' => {
+                    result.push('\\');
+                    result.push(c);
+                },
+                '!'..='~' => result.push(c),
+                c => result.push_str(&format!("\\{:03}", c as u32))
+            }
+        }
+        result
+    }).collect();
+
+    escaped.join(".")
+}
+
+///Returns whether a name is the zone or a name within it, ignoring case. The zone is given
+///as a domain ("example.home", a trailing dot is accepted)
+pub fn in_zone(name: &[String], zone: &str) -> bool {
+    let zone = domain_to_labels(zone);
+    name.len() >= zone.len() && name[name.len()-zone.len()..].iter().zip(&zone).all(|(label, zone)| label.eq_ignore_ascii_case(zone))
+}
+
+///Converts labels to an uncompressed name in wire format, ending with the root label. Every char of a label is
+///written as one byte, the way [wire_to_labels](wire_to_labels) reads it. Returns None if a label holds a char above
+///U+00FF, a label is longer than 63 bytes or the name longer than 255 bytes
+pub fn labels_to_wire(labels: &[String]) -> Option<Vec<u8>> {
+    if labels.iter().any(|label| label.chars().any(|c| u8::try_from(c).is_err()) || label.chars().count() > 63) {
+        return None;
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    write_domain_list(labels, &mut bytes);
+    if bytes.len() > 255 {
+        return None;
+    }
+
+    Some(bytes)
+}
+
+///Reads the labels of an uncompressed name in wire format, returning None if the bytes are not exactly one name
+pub fn wire_to_labels(bytes: &[u8]) -> Option<Vec<String>> {
+    let (labels, end) = read_name(bytes, 0)?;
+    if end != bytes.len() {
+        return None;
+    }
+
+    Some(labels)
+}
+
+//Reads a (possibly compressed) name starting at offset, returning its labels along with the offset
+//after the name as it is stored at offset. Compression pointers may only point backwards in the
+//message, which rules out pointer loops
+fn read_name(message: &[u8], offset: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut position = offset;
+    let mut end: Option<usize> = None;
+
+    loop {
+        let len = *message.get(position)? as usize;
+
+        if len & 0b1100_0000 == 0b1100_0000 { //Compression pointer
+            let target = ((len & 0b0011_1111) << 8) | *message.get(position + 1)? as usize;
+            if target >= position {
+                return None;
+            }
+            if end.is_none() {
+                end = Some(position + 2);
+            }
+            position = target;
+        }
+        else if len == 0 {
+            if end.is_none() {
+                end = Some(position + 1);
+            }
+            break;
+        }
+        else if len > 63 {
+            return None; //Reserved label types
+        }
+        else {
+            let label = message.get(position+1..position+1+len)?;
+            labels.push(label.iter().map(|byte| *byte as char).collect());
+            position += 1 + len;
+        }
+    }
+
+    Some((labels, end?))
+}
+
+fn parse_header(buffer: &[u8]) -> Option<(DnsHeader, Option<Vec<u8>>)> {
     if buffer.len() < 12 {
         return None;
     }
@@ -97,34 +456,33 @@ fn parse_header(buffer: &Vec<u8>) -> Option<(DnsHeader, Option<Vec<u8>>)> {
     Some((header, remaining))
 }
 
-fn parse_question(buffer: &Vec<u8>) -> Option<(DnsQuestion, Option<Vec<u8>>)> {
+fn parse_question(buffer: &[u8]) -> Result<(DnsQuestion, Option<Vec<u8>>), ParseError> {
     let mut domains: Vec<String> = Vec::new();
 
+    //i always points at the next length byte, after the name it points at the qtype
     let mut i: usize = 0;
-    while i < buffer.len() {
-        let mut name = String::new();
-        let name_len = buffer[i] as usize;
-
-        let mut j: usize;
-        if name_len != 0 {
-            i += 1;
-            j = 0;
-            while j < name_len {
-                if i + j >= buffer.len() {
-                    return None;
-                }
-                name.push(buffer[i + j] as char);
-                j += 1;
-            }
-        }
-        else {
-            i += 1;
+    loop {
+        let name_len = match buffer.get(i) {
+            Some(val) => *val as usize,
+            None => return Err(ParseError::TooShort) //The name is not terminated
+        };
+        i += 1;
+        if name_len == 0 {
             break;
         }
-        domains.push(name);
-        i = i + j - 1;
+        if name_len & 0b1100_0000 == 0b1100_0000 {
+            return Err(ParseError::BadPointer);
+        }
+        if name_len > 63 {
+            return Err(ParseError::OversizedLabel);
+        }
 
-        i += 1;
+        let label = match buffer.get(i..i + name_len) {
+            Some(val) => val,
+            None => return Err(ParseError::TooShort)
+        };
+        domains.push(label.iter().map(|byte| *byte as char).collect());
+        i += name_len;
     }
 
     let qtype;
@@ -134,12 +492,12 @@ fn parse_question(buffer: &Vec<u8>) -> Option<(DnsQuestion, Option<Vec<u8>>)> {
         qclass = u16::from_be_bytes(buffer[i+2..i+4].try_into().unwrap());
     }
     else {
-        return None;
+        return Err(ParseError::TooShort);
     }
 
     let question = DnsQuestion {
         qname: domains,
-        qtype: DnsRecordType::from_byte(qtype as u8),
+        qtype: DnsRecordType::from_byte(qtype),
         qclass: qclass
     };
     let remaining;
@@ -150,7 +508,7 @@ fn parse_question(buffer: &Vec<u8>) -> Option<(DnsQuestion, Option<Vec<u8>>)> {
         remaining = None;
     }
 
-    Some((question, remaining))
+    Ok((question, remaining))
 }
 
 #[cfg(test)]
@@ -219,7 +577,7 @@ mod tests {
             0b0001_0000, //Second byte of id (16)
             0b0_0001_1_0_1, //qr (0), opcode (1), aa (1), tc (0), rd (1)
             0b1_100_0100, //ra (1), z (4), rcode (4)
-            0, 1, //qd_count
+            0, 2, //qd_count
             0, 0, //an_count
             0, 0, //ns_count
             0, 0, //ar_count
@@ -245,7 +603,7 @@ mod tests {
         expected_header.ra = true;
         expected_header.z = 4;
         expected_header.rcode = DnsResponseCode::NotImplemented;
-        expected_header.qd_count = 1;
+        expected_header.qd_count = 2;
 
         let expected_q1 = DnsQuestion {
             qname: vec!(String::from("www"), 
@@ -266,13 +624,392 @@ mod tests {
 
         let expected = DnsQuery {
             header: expected_header,
-            questions: vec!(expected_q1, expected_q2)
+            questions: vec!(expected_q1, expected_q2),
+            edns: None,
+            invalid_edns: false
         };
         let result = parse_query(&query, true).unwrap();
 
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn parse_query_udp_questions_test() {
+        let query: Vec<u8> = vec!(
+            0, 16, //id (16)
+            0b0000_0001, 0, //rd (1)
+            0, 3, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 0, //ar_count
+            0b0000_0001, 97, //length (1), a
+            0b0000_0000, //length (0)
+            0, 1, //qtype (1)
+            0, 1, //qclass (1)
+            0b0000_0000, //length (0), the root
+            0, 2, //qtype (2)
+            0, 1, //qclass (1)
+            0b0000_0100, 109, 97, 105, 108, //length (4), mail
+            0b0000_0111, 101, 120, 97, 109, 112, 108, 101, //length (7), example
+            0b0000_0000, //length (0)
+            0, 15, //qtype (15)
+            0, 1 //qclass (1)
+        );
+
+        let result = parse_query(&query, false).unwrap();
+        assert_eq!(result.questions.len(), 3);
+        assert_eq!(result.questions[0].qname, vec!(String::from("a")));
+        assert_eq!(result.questions[0].qtype, DnsRecordType::A(None));
+        assert_eq!(result.questions[1].qname, Vec::<String>::new());
+        assert_eq!(result.questions[1].qtype.to_byte().0, 2);
+        assert_eq!(result.questions[2].qname, vec!(String::from("mail"), String::from("example")));
+        assert_eq!(result.questions[2].qtype, DnsRecordType::MX(None));
+        assert_eq!(result.questions[2].qclass, 1);
+
+        //A label running past the end of the message
+        let mut truncated = query.clone();
+        truncated.truncate(query.len() - 14);
+        assert_eq!(parse_query(&truncated, false), None);
+    }
+
+    #[test]
+    fn parse_compressed_response_test() {
+        let response: Vec<u8> = vec!(
+            0, 9, //id (9)
+            0b1000_0001, 0b1000_0000, //qr (1), rd (1), ra (1)
+            0, 1, //qd_count
+            0, 2, //an_count
+            0, 0, //ns_count
+            0, 0, //ar_count
+            //Question (offset 12):
+            3, 119, 119, 119, //www
+            7, 101, 120, 97, 109, 112, 108, 101, //example (offset 16)
+            3, 99, 111, 109, 0, //com
+            0, 1, 0, 1, //qtype (1), qclass (1)
+            //Answer 1 (offset 33): www.example.com CNAME cdn.example.com
+            0b1100_0000, 12, //pointer to www.example.com
+            0, 5, 0, 1, //type (5), class (1)
+            0, 0, 0, 60, //ttl (60)
+            0, 6, //rd_length (6)
+            3, 99, 100, 110, 0b1100_0000, 16, //cdn + pointer to example.com (offset 45)
+            //Answer 2: cdn.example.com A 192.0.2.1
+            0b1100_0000, 45, //pointer to cdn.example.com
+            0, 1, 0, 1, //type (1), class (1)
+            0, 0, 0, 30, //ttl (30)
+            0, 4, //rd_length (4)
+            192, 0, 2, 1
+        );
+
+        let www = vec!(String::from("www"), String::from("example"), String::from("com"));
+        let cdn = vec!(String::from("cdn"), String::from("example"), String::from("com"));
+
+        let parsed = parse_response(&response, false).unwrap();
+        assert_eq!(parsed.header.id, 9);
+        assert_eq!(parsed.questions[0].qname, www);
+        assert_eq!(parsed.answers[0].name, www);
+        assert_eq!(parsed.answers[0].rdata, structs::domain_list_to_bytes(&cdn));
+        assert_eq!(parsed.answers[1].name, cdn);
+        assert_eq!(parsed.answers[1].rdata, vec!(192, 0, 2, 1));
+
+        //The rebuilt message is standalone: it holds no pointers and parses to the same records
+        let rebuilt = parsed.build(false);
+        assert_eq!(rebuilt.len(), 12 + 21 + (17 + 10 + 17) + (17 + 10 + 4));
+        let reparsed = parse_response(&rebuilt, false).unwrap();
+        assert_eq!(reparsed.answers[0].name, www);
+        assert_eq!(reparsed.answers[0].rdata, parsed.answers[0].rdata);
+        assert_eq!(reparsed.answers[1].name, cdn);
+        assert_eq!(reparsed.answers[1].ttl, 30);
+    }
+
+    #[test]
+    fn parse_compressed_rdata_test() {
+        let response: Vec<u8> = vec!(
+            0, 9, //id (9)
+            0b1000_0001, 0b1000_0000, //qr (1), rd (1), ra (1)
+            0, 1, //qd_count
+            0, 2, //an_count
+            0, 0, //ns_count
+            0, 0, //ar_count
+            //Question (offset 12):
+            7, 101, 120, 97, 109, 112, 108, 101, //example
+            3, 99, 111, 109, 0, //com
+            0, 2, 0, 1, //qtype (2), qclass (1)
+            //Answer 1 (offset 29): example.com NS ns1.example.com
+            0b1100_0000, 12, //pointer to example.com
+            0, 2, 0, 1, //type (2), class (1)
+            0, 0, 0, 60, //ttl (60)
+            0, 6, //rd_length (6)
+            3, 110, 115, 49, 0b1100_0000, 12, //ns1 + pointer to example.com (offset 41)
+            //Answer 2: example.com MINFO ns1.example.com example.com
+            0b1100_0000, 12, //pointer to example.com
+            0, 14, 0, 1, //type (14), class (1)
+            0, 0, 0, 60, //ttl (60)
+            0, 4, //rd_length (4)
+            0b1100_0000, 41, 0b1100_0000, 12 //pointers to ns1.example.com and example.com
+        );
+
+        let example = domain_to_labels("example.com");
+        let ns1 = domain_to_labels("ns1.example.com");
+        let mut minfo = structs::domain_list_to_bytes(&ns1);
+        minfo.extend(structs::domain_list_to_bytes(&example));
+
+        let parsed = parse_response(&response, false).unwrap();
+        assert_eq!(parsed.answers[0].r#type, DnsRecordType::NotImplemented(2, structs::domain_list_to_bytes(&ns1)));
+        assert_eq!(parsed.answers[1].r#type, DnsRecordType::NotImplemented(14, minfo.clone()));
+
+        //Rebuilt without the original message the names are still intact
+        let reparsed = parse_response(&parsed.build(false), false).unwrap();
+        assert_eq!(reparsed.answers[0].rdata, structs::domain_list_to_bytes(&ns1));
+        assert_eq!(reparsed.answers[1].rdata, minfo);
+    }
+
+    #[test]
+    fn parse_pointer_loop_test() {
+        let response: Vec<u8> = vec!(
+            0, 9, 0b1000_0001, 0, //id, flags
+            0, 0, 0, 1, 0, 0, 0, 0, //1 answer
+            0b1100_0000, 12, //pointer to itself
+            0, 1, 0, 1, 0, 0, 0, 0, 0, 0
+        );
+
+        assert!(parse_response(&response, false).is_none());
+    }
+
+    #[test]
+    fn parse_query_qd_count_test() {
+        let mut query: Vec<u8> = vec!(
+            0, 16, //id (16)
+            0b0000_0001, 0, //rd (1)
+            0, 1, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 0, //ar_count
+            0b0000_0011, 119, 119, 119, //length (3), www
+            0b0000_0011, 99, 111, 109, //length (3), com
+            0b0000_0000, //length (0)
+            0, 1, //qtype (1)
+            0, 1, //qclass (1)
+            0b0000_0011, 119, 119, 119, 0, 0 //trailing bytes
+        );
+
+        let result = parse_query(&query, false).unwrap();
+        assert_eq!(result.questions.len(), 1);
+        assert_eq!(result.questions[0].qname, vec!(String::from("www"), String::from("com")));
+
+        query[5] = 2; //qd_count (2) with only one question
+        query.truncate(12 + 13);
+        assert_eq!(parse_query(&query, false), None);
+    }
+
+    #[test]
+    fn parse_query_edns_test() {
+        let query: Vec<u8> = vec!(
+            0, 16, //id (16)
+            0b0000_0001, 0, //rd (1)
+            0, 1, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 1, //ar_count
+            0b0000_0011, 99, 111, 109, //length (3), com
+            0b0000_0000, //length (0)
+            0, 1, //qtype (1)
+            0, 1, //qclass (1)
+            0, //root
+            0, 41, //type (41 = OPT)
+            0x10, 0x00, //udp payload size (4096)
+            0, 0, 0, 0, //extended rcode, version, flags
+            0, 0 //rd_length
+        );
+
+        let result = parse_query(&query, false).unwrap();
+        assert_eq!(result.questions.len(), 1);
+        assert!(!result.invalid_edns);
+        let edns = result.edns.unwrap();
+        assert_eq!(edns.udp_size, 4096);
+        assert!(!edns.dnssec_ok);
+
+        let mut with_do = query.clone();
+        with_do[28] = 0x80; //DO (1)
+        assert!(parse_query(&with_do, false).unwrap().edns.unwrap().dnssec_ok);
+    }
+
+    #[test]
+    fn parse_edns_options_test() {
+        let mut query: Vec<u8> = vec!(
+            0, 16, //id (16)
+            0b0000_0001, 0, //rd (1)
+            0, 1, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 1, //ar_count
+            0b0000_0011, 99, 111, 109, //length (3), com
+            0b0000_0000, //length (0)
+            0, 1, //qtype (1)
+            0, 1, //qclass (1)
+            0, //root
+            0, 41, //type (41 = OPT)
+            0x10, 0x00, //udp payload size (4096)
+            0, 0, 0, 0, //extended rcode, version, flags
+            0, 18, //rd_length
+            0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8, //COOKIE (10), a client cookie of 8 bytes
+            0xfd, 0xe9, 0, 2, 0xab, 0xcd //An unknown option (65001)
+        );
+
+        let edns = parse_query(&query, false).unwrap().edns.unwrap();
+        assert_eq!(edns.options, vec!((10, vec!(1, 2, 3, 4, 5, 6, 7, 8)), (65001, vec!(0xab, 0xcd))));
+
+        //Options of the client are not echoed, the response only carries the options of the server
+        let response = DnsResponse::default()
+        .udp_size(1232)
+        .extended_error(ExtendedError::Blocked)
+        .add_option(65002, vec!(7));
+        let parsed = parse_response(&response.build(false), false).unwrap().edns.unwrap();
+        assert_eq!(parsed.options, vec!((15, vec!(0, 15)), (65002, vec!(7))));
+
+        //An option longer than the rdata makes the OPT record malformed
+        let len = query.len();
+        query[len-3] = 3;
+        assert_eq!(parse_query(&query, false).unwrap().edns, None);
+    }
+
+    #[test]
+    fn parse_query_invalid_edns_test() {
+        let mut query: Vec<u8> = vec!(
+            0, 16, //id (16)
+            0b0000_0001, 0, //rd (1)
+            0, 1, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 2, //ar_count
+            0b0000_0011, 99, 111, 109, //length (3), com
+            0b0000_0000, //length (0)
+            0, 1, //qtype (1)
+            0, 1 //qclass (1)
+        );
+        let opt: Vec<u8> = vec!(
+            0, //root
+            0, 41, //type (41 = OPT)
+            0x10, 0x00, //udp payload size (4096)
+            0, 0, 0, 0, //extended rcode, version, flags
+            0, 0 //rd_length
+        );
+
+        //Two OPT records
+        let mut double = query.clone();
+        double.extend(&opt);
+        double.extend(&opt);
+        assert!(parse_query(&double, false).unwrap().invalid_edns);
+
+        //An OPT record owned by com instead of the root
+        query[11] = 1; //ar_count (1)
+        query.extend(&[3, 99, 111, 109]);
+        query.extend(&opt);
+        assert!(parse_query(&query, false).unwrap().invalid_edns);
+    }
+
+    #[test]
+    fn unknown_type_round_trip_test() {
+        let rdata: Vec<u8> = vec!(0xde, 0xad, 0xbe, 0xef, 0, 1);
+        let name = vec!(String::from("private"), String::from("example"));
+        let response = DnsResponse::default()
+        .id(3)
+        .add_question(DnsQuestion {
+            qname: name.clone(),
+            qtype: DnsRecordType::from_byte(65280),
+            qclass: 1
+        })
+        .add_answer(
+            DnsAnswer::default()
+            .name(name.clone())
+            .ttl(120)
+            .record(Some(DnsRecordType::NotImplemented(65280, rdata.clone())))
+        );
+
+        let parsed = parse_response(&response.build(false), false).unwrap();
+
+        assert_eq!(parsed.questions[0].qtype, DnsRecordType::NotImplemented(65280, Vec::new()));
+        assert_eq!(parsed.answers.len(), 1);
+        assert_eq!(parsed.answers[0].r#type, DnsRecordType::NotImplemented(65280, rdata.clone()));
+        assert_eq!(parsed.answers[0].rd_length, 6);
+        assert_eq!(parsed.answers[0].rdata, rdata);
+    }
+
+    #[test]
+    fn record_types_round_trip_test() {
+        let name = domain_to_labels("round.tr\\200ip.example"); //A byte above 127 is read back as the same char
+        let mut mx: Vec<u8> = vec!(0x01, 0x02); //Preference (258)
+        mx.extend(domain_list_to_bytes(&domain_to_labels("mail.example")));
+        let soa = DnsAuthRecord::new()
+        .mname(domain_to_labels("ns1.example"))
+        .rname(domain_to_labels("hostmaster.example"))
+        .serial(0x89ABCDEF)
+        .refresh(0x01020304)
+        .retry(0x00FF00FF)
+        .expire(0xFEDCBA98)
+        .minimum(0x00000100);
+        let records = vec!(
+            DnsRecordType::new_a("192.0.2.1").unwrap(),
+            DnsRecordType::new_aaaa("2001:db8::102:304").unwrap(),
+            DnsRecordType::new_cname("target.example").unwrap(),
+            DnsRecordType::MX(Some(mx)),
+            DnsRecordType::new_soa(soa).unwrap(),
+            DnsRecordType::new_txt("v=spf1 -all").unwrap(),
+            DnsRecordType::new_ptr("host.example").unwrap(),
+            DnsRecordType::new_tlsa("3 1 1 0a1b2c3d").unwrap(),
+            DnsRecordType::new_openpgpkey("AQIDBA==").unwrap(),
+            DnsRecordType::NotImplemented(2, domain_list_to_bytes(&domain_to_labels("ns1.example"))), //NS
+            DnsRecordType::NotImplemented(0xFF01, vec!(0x80, 0x00, 0x00, 0x01))
+        );
+
+        let mut response = DnsResponse::default()
+        .id(0xABCD)
+        .add_question(DnsQuestion {
+            qname: name.clone(),
+            qtype: DnsRecordType::from_byte(255),
+            qclass: 1
+        });
+        let mut answers: Vec<DnsAnswer> = Vec::new();
+        for (i, record) in records.into_iter().enumerate() {
+            let answer = DnsAnswer::default()
+            .name(name.clone())
+            .class(1)
+            .ttl(0x01020304 + i as u32 * 0x01010101) //Every byte of the ttl differs
+            .record(Some(record));
+            answers.push(answer.clone());
+            response = response.add_answer(answer);
+        }
+        let authority = answers[4].clone();
+        let additional = answers[0].clone();
+        response = response.add_auth_record(authority.clone()).add_additional_record(additional.clone());
+
+        let builds = vec!(
+            (response.build(false), false),
+            (response.build(true), true),
+            (response.build_compressed(false), false),
+            (response.build_compressed(true), true)
+        );
+        for (bytes, tcp) in builds {
+            let parsed = parse_response(&bytes, tcp).unwrap();
+
+            assert_eq!(parsed.header.id, 0xABCD);
+            assert_eq!(parsed.header.qd_count, 1);
+            assert_eq!(parsed.header.an_count, answers.len() as u16);
+            assert_eq!(parsed.header.ns_count, 1);
+            assert_eq!(parsed.header.ar_count, 1);
+            assert_eq!(parsed.questions, response.questions);
+            for (parsed, expected) in parsed.answers.iter().zip(answers.iter()) {
+                assert_eq!(parsed.r#type.to_byte().0, expected.r#type.to_byte().0);
+                assert_eq!(parsed.class, expected.class);
+                assert_eq!(parsed.ttl, expected.ttl);
+                assert_eq!(parsed.rd_length, expected.rd_length);
+                assert_eq!(parsed, expected);
+            }
+            assert_eq!(parsed.answers.len(), answers.len());
+            assert_eq!(parsed.authority_records, vec!(authority.clone()));
+            assert_eq!(parsed.additional_records, vec!(additional.clone()));
+        }
+    }
+
     #[test]
     fn parse_query_test_fail() {
         let query: Vec<u8> = vec!(
@@ -281,7 +1018,7 @@ mod tests {
             0b0001_0000, //Second byte of id (16)
             0b0_0001_1_0_1, //qr (0), opcode (1), aa (1), tc (0), rd (1)
             0b1_100_0100, //ra (1), z (4), rcode (4)
-            0, 1, //qd_count
+            0, 2, //qd_count
             0, 0, //an_count
             0, 0, //ns_count
             0, 0, //ar_count
@@ -301,4 +1038,71 @@ mod tests {
 
         assert_eq!(parse_query(&query, true), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn domain_labels_test() {
+        let labels = domain_to_labels("www.example.com.");
+        assert_eq!(labels, vec!(String::from("www"), String::from("example"), String::from("com")));
+        assert_eq!(labels_to_domain(&labels), "www.example.com");
+        assert_eq!(labels_to_wire(&labels), Some(vec!(3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0)));
+
+        assert_eq!(domain_to_labels("."), Vec::<String>::new());
+        assert_eq!(labels_to_domain(&[]), ".");
+        assert_eq!(labels_to_wire(&[]), Some(vec!(0)));
+        assert_eq!(wire_to_labels(&[0]), Some(Vec::new()));
+
+        //An escaped dot is part of its label
+        let name = "first\\.last.example.com";
+        let labels = domain_to_labels(name);
+        assert_eq!(labels[0], "first.last");
+        assert_eq!(labels_to_domain(&labels), name);
+
+        let wire = labels_to_wire(&domain_to_labels("a\\032b\\\\c.example")).unwrap();
+        assert_eq!(&wire[..7], &[5, 97, 32, 98, 92, 99, 7]);
+        let labels = wire_to_labels(&wire).unwrap();
+        assert_eq!(labels_to_domain(&labels), "a\\032b\\\\c.example");
+
+        assert_eq!(wire_to_labels(&[3, 119, 119]), None);
+        assert_eq!(wire_to_labels(&[1, 97, 0, 0]), None);
+
+        //Every char is one byte, so escaped bytes above 127 convert back unchanged
+        let labels = domain_to_labels("a\\200b.example");
+        let wire = labels_to_wire(&labels).unwrap();
+        assert_eq!(&wire[..4], &[3, 97, 200, 98]);
+        assert_eq!(wire_to_labels(&wire).unwrap(), labels);
+        assert_eq!(labels_to_domain(&labels), "a\\200b.example");
+
+        //Chars without a single byte, too long labels and too long names are rejected
+        assert_eq!(labels_to_wire(&[String::from("\u{20ac}")]), None);
+        assert!(labels_to_wire(&["a".repeat(63)]).is_some());
+        assert_eq!(labels_to_wire(&["a".repeat(64)]), None);
+        let long: Vec<String> = (0..4).map(|_| "a".repeat(63)).collect();
+        assert_eq!(labels_to_wire(&long), None);
+    }
+
+    #[test]
+    fn peek_question_test() {
+        let query: Vec<u8> = vec!(
+            0, 18, //length (18)
+            0, 16, //id (16)
+            0b0000_0001, 0, //rd (1)
+            0, 1, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 0, //ar_count
+            0b0000_0011, 99, 111, 109, //length (3), com
+            0b0000_0000, //length (0)
+            0, 28, //qtype (28)
+            0, 1 //qclass (1)
+        );
+
+        let parsed = parse_query(&query, true).unwrap();
+        let (name, qtype) = peek_question(&query, true).unwrap();
+        assert_eq!(name, parsed.questions[0].qname);
+        assert_eq!(qtype, parsed.questions[0].qtype.to_byte().0);
+        assert_eq!(peek_question(&query[2..], false), Some((name, qtype)));
+
+        assert_eq!(peek_question(&query[2..14], false), None); //No question
+        assert_eq!(peek_question(&query[..20], true), None); //Cut off type
+    }
+}
@@ -1,4 +1,6 @@
 use std::clone::Clone;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::{ Ipv4Addr, Ipv6Addr };
 use serde::{ Deserialize, Serialize };
 
@@ -8,7 +10,12 @@ pub struct DnsQuery {
     ///The header of the query
     pub header: DnsHeader,
     ///The questions the sender wants answered
-    pub questions: Vec<DnsQuestion>
+    pub questions: Vec<DnsQuestion>,
+    ///The EDNS OPT pseudo-record of the sender, if it supports EDNS
+    pub edns: Option<Edns>,
+    ///Whether the OPT record of the query is malformed (more than one OPT record, or an owner name other than the root),
+    ///which is answered with a FormatError
+    pub invalid_edns: bool
 }
 
 /// # Struct representing a dns response
@@ -23,7 +30,24 @@ pub struct DnsResponse {
     ///Authority records for non-recursive queries
     pub authority_records: Vec<DnsAnswer>,
     ///Additional records
-    pub additional_records: Vec<DnsAnswer>
+    pub additional_records: Vec<DnsAnswer>,
+    ///The EDNS OPT pseudo-record, emitted as the last additional record
+    pub edns: Option<Edns>
+}
+
+/// # Struct representing the EDNS(0) OPT pseudo-record of a message
+///For more information see <https://tools.ietf.org/html/rfc6891>
+#[derive(PartialEq, Debug, Clone)]
+pub struct Edns {
+    ///The largest udp payload (in bytes) the sender is able to receive
+    pub udp_size: u16,
+    ///An Extended DNS Error explaining the response code (RFC 8914)
+    pub extended_error: Option<ExtendedError>,
+    ///The DO (DNSSEC OK) bitflag, set if the sender wants DNSSEC records such as RRSIG (RFC 3225)
+    pub dnssec_ok: bool,
+    ///The options of the record as (option code, option data) pairs. Every option of a parsed record is kept here,
+    ///when building, the extended_error is emitted before them
+    pub options: Vec<(u16, Vec<u8>)>
 }
 
 /// # Struct representing the header of a dns message
@@ -43,7 +67,7 @@ pub struct DnsHeader {
     pub rd: bool,
     ///Recursion Avalible bitflag
     pub ra: bool,
-    ///3 Reserved bits which should always be 0
+    ///3 Reserved bits which should always be 0 (they are always sent as 0)
     pub z: u8,
     ///Response Code
     pub rcode: DnsResponseCode,
@@ -69,7 +93,7 @@ pub struct DnsQuestion {
 }
 
 /// # Struct representing an answer in a dns response
-#[derive(Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub struct DnsAnswer {
     ///The list of domains asked for by the question
     pub name: Vec<String>,
@@ -121,6 +145,65 @@ pub enum DnsResponseCode {
     Refused
 }
 
+/// # An enum which represents the reasons a query cannot be parsed
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ParseError {
+    ///The message ends before its header, a question or a name is complete (or has fewer questions than its header claims)
+    TooShort,
+    ///A name of a question is compressed, which queries are not parsed with
+    BadPointer,
+    ///A label of a question is longer than 63 bytes (its length uses the reserved bits)
+    OversizedLabel
+}
+
+/// # An enum which represents the Extended DNS Error codes (RFC 8914) the server can attach to a response
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ExtendedError {
+    ///An error which does not match any other code
+    Other,
+    ///The answer was served from an expired cache entry
+    StaleAnswer,
+    ///The response code was served from a cached error
+    CachedError,
+    ///The server is not yet able to answer the query
+    NotReady,
+    ///The name is on a blocklist of the operator
+    Blocked,
+    ///The name is blocked due to an external requirement
+    Censored,
+    ///The name is blocked at the request of the client
+    Filtered,
+    ///The client is not allowed to query this server
+    Prohibited,
+    ///The query was sent to a server which is not authoritative for the name
+    NotAuthoritative,
+    ///The requested operation or query is not supported
+    NotSupported,
+    ///No upstream server could be reached to answer the query
+    NoReachableAuthority,
+    ///An unrecoverable network error occurred while resolving
+    NetworkError,
+    ///The server holds data for the name which is not valid
+    InvalidData
+}
+
+/// # An enum which represents the inconsistencies of a response that would make its wire format malformed
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ResponseError {
+    ///The question count of the header does not match the number of questions
+    QuestionCount,
+    ///The answer count of the header does not match the number of answers
+    AnswerCount,
+    ///The name server count of the header does not match the number of authority records
+    AuthorityCount,
+    ///The additional count of the header does not match the number of additional records (and OPT record)
+    AdditionalCount,
+    ///A record has a class other than IN, CH or HS
+    InvalidClass,
+    ///The rd_length of a record does not match the length of its rdata
+    RdLength
+}
+
 /// # An enum which represents the most common possible record types that are queried and returned
 ///Record types that come without associated data (i.e. those from parsed questions) will by default have the 
 ///value of None 
@@ -147,8 +230,87 @@ pub enum DnsRecordType {
     ///A SOA record (authority record: provides information about the name server of a domain)
     ///with a [DnsAuthRecord](DnsAuthRecord) struct
     SOA(Option<DnsAuthRecord>), //6
-    ///A stand-in for unimplemented record types with its associated record code
-    NotImplemented(u8)
+    ///An OPENPGPKEY record (OpenPGP public key of an e-mail address) and its associated rdata field
+    OPENPGPKEY(Option<Vec<u8>>), //61
+    ///A stand-in for unimplemented record types with its associated record code and rdata field,
+    ///which is passed through unchanged
+    NotImplemented(u16, Vec<u8>)
+}
+
+impl Default for DnsQuery {
+    ///Returns the default configuration of a DnsQuery (a standard query desiring recursion) to be added upon.
+    ///The id and the questions will likely need to be set later
+    fn default() -> Self {
+        let mut header = DnsHeader::new();
+        header.rd = true;
+
+        DnsQuery {
+            header,
+            questions: Vec::new(),
+            edns: None,
+            invalid_edns: false
+        }
+    }
+}
+
+impl DnsQuery {
+    ///Returns whether every question of the query is for a name within the zones (see
+    ///[DnsQuestion::is_authoritative_candidate](DnsQuestion::is_authoritative_candidate))
+    pub fn is_authoritative_candidate(&self, zones: &[String]) -> bool {
+        !self.questions.is_empty() && self.questions.iter().all(|question| question.is_authoritative_candidate(zones))
+    }
+
+    ///Sets the id of the header field of the Query
+    pub fn id(mut self, id: u16) -> Self {
+        self.header.id = id;
+        self
+    }
+
+    ///Sets the opcode of the header field of the Query
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.header.opcode = opcode;
+        self
+    }
+
+    ///Adds a question to the query
+    pub fn add_question(mut self, question: DnsQuestion) -> Self {
+        self.questions.push(question);
+        self.header.qd_count += 1;
+        self
+    }
+
+    ///Attaches an EDNS OPT record to the query
+    pub fn edns(mut self, edns: Edns) -> Self {
+        if self.edns.is_none() {
+            self.header.ar_count += 1;
+        }
+        self.edns = Some(edns);
+        self
+    }
+
+    ///Converts the query to the binary format so it can be sent over a connection.
+    ///The tcp parameter indicates whether the query will be sent over tcp or udp
+    ///to account for the length bytes in a tcp query
+    pub fn build(&self, tcp: bool) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::new();
+
+        result.append(&mut self.header.build());
+        for question in &self.questions {
+            result.append(&mut question.build());
+        }
+        if let Some(edns) = &self.edns {
+            result.append(&mut edns.build());
+        }
+
+        if !tcp {
+            return result;
+        }
+
+        let mut len_result = (result.len() as u16).to_be_bytes().to_vec();
+        len_result.append(&mut result);
+
+        len_result
+    }
 }
 
 impl DnsResponse {
@@ -162,7 +324,7 @@ impl DnsResponse {
             aa: false, //All results are from cache
             tc: false, //Not truncated
             rd: true, //Most queries desire recursion 
-            ra: true, //This server should simulate recursion
+            ra: false, //Set by the handler when the server recurses for the query
             z: 0, //Must be 0
             rcode: DnsResponseCode::NoError,
             qd_count: 0, //Field for requester
@@ -176,7 +338,8 @@ impl DnsResponse {
             questions: Vec::new(),
             answers: Vec::new(),
             authority_records: Vec::new(),
-            additional_records: Vec::new()
+            additional_records: Vec::new(),
+            edns: None
         }
     }
 
@@ -192,6 +355,18 @@ impl DnsResponse {
         self
     }
 
+    ///Sets the ra (recursion available) bitflag of the header field of the Response
+    pub fn ra(mut self, ra: bool) -> Self {
+        self.header.ra = ra;
+        self
+    }
+
+    ///Sets the aa (authoritative answer) bitflag of the header field of the Response
+    pub fn aa(mut self, aa: bool) -> Self {
+        self.header.aa = aa;
+        self
+    }
+
     ///Sets the opcode of the header field of the Response
     pub fn opcode(mut self, opcode: u8) -> Self {
         self.header.opcode = opcode;
@@ -210,6 +385,104 @@ impl DnsResponse {
         self
     }
 
+    ///Attaches an EDNS OPT record to the response
+    pub fn edns(mut self, edns: Edns) -> Self {
+        if self.edns.is_none() {
+            self.header.ar_count += 1;
+        }
+        self.edns = Some(edns);
+        self
+    }
+
+    ///Attaches an Extended DNS Error to the response, adding an OPT record if there is none yet
+    pub fn extended_error(self, error: ExtendedError) -> Self {
+        let mut edns = self.edns.clone().unwrap_or_default();
+        edns.extended_error = Some(error);
+
+        self.edns(edns)
+    }
+
+    ///Adds an option to the OPT record of the response, adding an OPT record if there is none yet
+    pub fn add_option(self, code: u16, data: Vec<u8>) -> Self {
+        let mut edns = self.edns.clone().unwrap_or_default();
+        edns.options.push((code, data));
+
+        self.edns(edns)
+    }
+
+    ///Sets the udp payload size advertised in the OPT record of the response, adding an OPT record if there is none yet
+    pub fn udp_size(self, udp_size: u16) -> Self {
+        let mut edns = self.edns.clone().unwrap_or_default();
+        edns.udp_size = udp_size;
+
+        self.edns(edns)
+    }
+
+    ///Removes every record (except the OPT record) and sets the TrunCation bit,
+    ///telling the client to retry over tcp
+    pub fn truncate(mut self) -> Self {
+        self.answers.clear();
+        self.authority_records.clear();
+        self.additional_records.clear();
+        self.header.an_count = 0;
+        self.header.ns_count = 0;
+        self.header.ar_count = if self.edns.is_some() { 1 } else { 0 };
+        self.header.tc = true;
+        self
+    }
+
+    ///Removes records, least important first (additional, then authority, then answer records, each from the end),
+    ///until the response built for udp with [build_compressed](DnsResponse::build_compressed) is at most max_size bytes.
+    ///The OPT record is kept. The TrunCation bit is set if any answer was removed
+    pub fn truncate_to(&mut self, max_size: usize) {
+        while self.build_compressed(false).len() > max_size {
+            if self.additional_records.pop().is_some() {
+                self.header.ar_count -= 1;
+            }
+            else if self.authority_records.pop().is_some() {
+                self.header.ns_count -= 1;
+            }
+            else if self.answers.pop().is_some() {
+                self.header.an_count -= 1;
+                self.header.tc = true;
+            }
+            else {
+                break; //The header, questions and OPT record alone are too large
+            }
+        }
+    }
+
+    ///Checks the internal consistency of the response (header counts, record classes and rdata lengths)
+    ///which would otherwise only show up as malformed wire bytes
+    pub fn validate(&self) -> Result<(), ResponseError> {
+        let additional = self.additional_records.len() + if self.edns.is_some() { 1 } else { 0 };
+        if self.header.qd_count as usize != self.questions.len() {
+            return Err(ResponseError::QuestionCount);
+        }
+        if self.header.an_count as usize != self.answers.len() {
+            return Err(ResponseError::AnswerCount);
+        }
+        if self.header.ns_count as usize != self.authority_records.len() {
+            return Err(ResponseError::AuthorityCount);
+        }
+        if self.header.ar_count as usize != additional {
+            return Err(ResponseError::AdditionalCount);
+        }
+
+        let records = self.answers.iter().chain(&self.authority_records).chain(&self.additional_records);
+        for record in records {
+            //IN (1), CH (3) and HS (4)
+            if ![1, 3, 4].contains(&record.class) {
+                return Err(ResponseError::InvalidClass);
+            }
+            if record.rd_length as usize != record.rdata.len() {
+                return Err(ResponseError::RdLength);
+            }
+        }
+
+        Ok(())
+    }
+
     ///Adds an answer to the response
     pub fn add_answer(mut self, answer: DnsAnswer) -> Self {
         self.answers.push(answer);
@@ -231,6 +504,13 @@ impl DnsResponse {
         self
     }
 
+    ///Adds an additional record to the response
+    pub fn add_additional_record(mut self, additional_record: DnsAnswer) -> Self {
+        self.additional_records.push(additional_record);
+        self.header.ar_count += 1;
+        self
+    }
+
     ///Converts the response to the binary format so it can be sent over a connection.
     ///The tcp parameter indicates whether the request will be sent over tcp or udp
     ///to account for the length bytes in a tcp response
@@ -251,6 +531,44 @@ impl DnsResponse {
         for add_record in &self.additional_records {
             result.append(&mut add_record.build().clone());
         }
+        if let Some(edns) = &self.edns {
+            result.append(&mut edns.build());
+        }
+
+        if !tcp {
+            return result;
+        }
+
+        let mut len_result = (result.len() as u16).to_be_bytes().to_vec();
+        len_result.append(&mut result);
+
+        len_result
+    }
+
+    ///Converts the response to the binary format like [build](DnsResponse::build), with the names of the questions
+    ///and the owner names of the records compressed: a name (or its ending) which occurred before is replaced
+    ///with a pointer to it (RFC 1035 4.1.4). Names within the rdata are left uncompressed
+    pub fn build_compressed(&self, tcp: bool) -> Vec<u8> {
+        let mut result: Vec<u8> = self.header.build();
+        let mut offsets: HashMap<Vec<String>, u16> = HashMap::new();
+
+        for question in &self.questions {
+            compress_name(&question.qname, &mut result, &mut offsets);
+            result.extend_from_slice(&question.qtype.to_byte().0.to_be_bytes());
+            result.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        let records = self.answers.iter().chain(&self.authority_records).chain(&self.additional_records);
+        for record in records {
+            compress_name(&record.name, &mut result, &mut offsets);
+            result.extend_from_slice(&record.r#type.to_byte().0.to_be_bytes());
+            result.extend_from_slice(&record.class.to_be_bytes());
+            result.extend_from_slice(&record.ttl.to_be_bytes());
+            result.extend_from_slice(&record.rd_length.to_be_bytes());
+            result.extend_from_slice(&record.rdata);
+        }
+        if let Some(edns) = &self.edns {
+            result.append(&mut edns.build());
+        }
 
         if !tcp {
             return result;
@@ -263,6 +581,24 @@ impl DnsResponse {
     }
 }
 
+//Writes a name to the message, ending with a pointer to the longest ending of it written before.
+//Only offsets within the 14 bits of a pointer are remembered
+fn compress_name(name: &[String], result: &mut Vec<u8>, offsets: &mut HashMap<Vec<String>, u16>) {
+    for i in 0..name.len() {
+        if let Some(offset) = offsets.get(&name[i..]) {
+            result.extend_from_slice(&(0xC000 | offset).to_be_bytes());
+            return;
+        }
+
+        if result.len() < 0x4000 {
+            offsets.insert(name[i..].to_vec(), result.len() as u16);
+        }
+        write_label(&name[i], result);
+    }
+
+    result.push(0);
+}
+
 impl DnsHeader {
     fn build(&self) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
@@ -277,9 +613,8 @@ impl DnsHeader {
         flag_byte_1 |= (self.qr as u8) << 7;
         result.push(flag_byte_1);
 
-        //Second Flag Byte: ra z*3 rcode*4
+        //Second Flag Byte: ra z*3 rcode*4. The reserved z bits are always sent as 0, whatever the value of z
         let mut flag_byte_2: u8 = self.rcode.to_byte();
-        flag_byte_2 |= self.z << 4;
         flag_byte_2 |= (self.ra as u8) << 7;
         result.push(flag_byte_2);
 
@@ -359,11 +694,20 @@ impl DnsAnswer {
         self
     }
 
+    ///Returns whether both answers hold the same record, that is the same name (ignoring case), type, class and rdata.
+    ///Unlike ==, differing ttls (such as those of a cached and a fresh copy) are ignored
+    pub fn same_record(&self, other: &DnsAnswer) -> bool {
+        self.r#type.to_byte().0 == other.r#type.to_byte().0
+        && self.class == other.class
+        && self.rdata == other.rdata
+        && self.name.join(".").to_lowercase() == other.name.join(".").to_lowercase()
+    }
+
     fn build(&self) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
 
-        result.append(&mut domain_list_to_bytes(&self.name));
-        result.append(&mut (self.r#type.to_byte().0 as u16).to_be_bytes().to_vec());
+        write_domain_list(&self.name, &mut result);
+        result.append(&mut self.r#type.to_byte().0.to_be_bytes().to_vec());
         result.append(&mut self.class.to_be_bytes().to_vec());
         result.append(&mut self.ttl.to_be_bytes().to_vec());
         result.append(&mut self.rd_length.to_be_bytes().to_vec());
@@ -374,17 +718,83 @@ impl DnsAnswer {
 }
 
 impl DnsQuestion {
+    ///Returns whether the name asked for is one of the zones (such as "example.home") or within one,
+    ///which makes it a name answered by this server rather than forwarded
+    pub fn is_authoritative_candidate(&self, zones: &[String]) -> bool {
+        zones.iter().any(|zone| super::in_zone(&self.qname, zone))
+    }
+
     fn build(&self) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
 
-        result.append(&mut domain_list_to_bytes(&self.qname));
-        result.append(&mut (self.qtype.to_byte().0 as u16).to_be_bytes().to_vec());
+        write_domain_list(&self.qname, &mut result);
+        result.append(&mut self.qtype.to_byte().0.to_be_bytes().to_vec());
         result.append(&mut self.qclass.to_be_bytes().to_vec());
 
         result
     }
 }
 
+impl Default for Edns {
+    ///Returns the default OPT record advertised by this server
+    fn default() -> Self {
+        Edns {
+            udp_size: 1232, //Avoids ip fragmentation on common links
+            extended_error: None,
+            dnssec_ok: false,
+            options: Vec::new()
+        }
+    }
+}
+
+impl Edns {
+    fn build(&self) -> Vec<u8> {
+        let mut options: Vec<u8> = Vec::new();
+        if let Some(error) = self.extended_error {
+            options.append(&mut 15u16.to_be_bytes().to_vec()); //Option code (15 = Extended DNS Error)
+            options.append(&mut 2u16.to_be_bytes().to_vec()); //Option length
+            options.append(&mut error.to_code().to_be_bytes().to_vec());
+        }
+        for (code, data) in &self.options {
+            options.append(&mut code.to_be_bytes().to_vec());
+            options.append(&mut (data.len() as u16).to_be_bytes().to_vec());
+            options.extend_from_slice(data);
+        }
+
+        let mut result: Vec<u8> = Vec::new();
+        result.push(0); //Owner name is always the root
+        result.append(&mut 41u16.to_be_bytes().to_vec()); //Type (41 = OPT)
+        result.append(&mut self.udp_size.to_be_bytes().to_vec()); //Class holds the udp payload size
+        let flags: u32 = if self.dnssec_ok { 0x8000 } else { 0 }; //DO is the highest bit of the flags
+        result.append(&mut flags.to_be_bytes().to_vec()); //Extended rcode, version and flags
+        result.append(&mut (options.len() as u16).to_be_bytes().to_vec());
+        result.append(&mut options);
+
+        result
+    }
+}
+
+impl ExtendedError {
+    ///Returns the info-code of the error as defined in RFC 8914
+    pub fn to_code(&self) -> u16 {
+        match self {
+            Self::Other => 0,
+            Self::StaleAnswer => 3,
+            Self::CachedError => 13,
+            Self::NotReady => 14,
+            Self::Blocked => 15,
+            Self::Censored => 16,
+            Self::Filtered => 17,
+            Self::Prohibited => 18,
+            Self::NotAuthoritative => 20,
+            Self::NotSupported => 21,
+            Self::NoReachableAuthority => 22,
+            Self::NetworkError => 23,
+            Self::InvalidData => 24
+        }
+    }
+}
+
 impl DnsAuthRecord {
     ///Returns a new instance of a DnsAuthRecord to be added upon.
     ///The fields will need to be set later
@@ -445,8 +855,8 @@ impl DnsAuthRecord {
     fn build(&self) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
 
-        result.append(&mut domain_list_to_bytes(&self.mname));
-        result.append(&mut domain_list_to_bytes(&self.rname));
+        write_domain_list(&self.mname, &mut result);
+        write_domain_list(&self.rname, &mut result);
         result.append(&mut self.serial.to_be_bytes().to_vec());
         result.append(&mut self.refresh.to_be_bytes().to_vec());
         result.append(&mut self.retry.to_be_bytes().to_vec());
@@ -484,7 +894,7 @@ impl DnsResponseCode {
 ///All new functions will return None upon failure.
 ///Those that cannot fail have the Option type to maintain consistency
 impl DnsRecordType {
-    pub(super) fn from_byte(byte: u8) -> Self {
+    pub(super) fn from_byte(byte: u16) -> Self {
         match byte {
             1 => Self::A(None),
             28 => Self::AAAA(None),
@@ -496,11 +906,12 @@ impl DnsRecordType {
             12 => Self::PTR(None),
             16 => Self::TXT(None),
             6 => Self::SOA(None),
-            num => Self::NotImplemented(num)
+            61 => Self::OPENPGPKEY(None),
+            num => Self::NotImplemented(num, Vec::new())
         }
     }
 
-    pub(crate) fn to_byte(&self) -> (u8, Option<Vec<u8>>) {
+    pub(crate) fn to_byte(&self) -> (u16, Option<Vec<u8>>) {
         match self.clone() {
             Self::A(val) => (1, val),
             Self::AAAA(val) => (28, val),
@@ -511,6 +922,7 @@ impl DnsRecordType {
             Self::TLSA(val) => (52, val),
             Self::PTR(val) => (12, val),
             Self::TXT(val) => (16, val),
+            Self::OPENPGPKEY(val) => (61, val),
             Self::SOA(val) => {
                 let mut ret = None;
                 if let Some(auth) = val {
@@ -519,10 +931,40 @@ impl DnsRecordType {
 
                 (6, ret)
             }
-            Self::NotImplemented(val) => (val, Some(Vec::new()))
+            Self::NotImplemented(val, rdata) => (val, Some(rdata))
         }
     }
 
+    ///Returns the record type (without associated data) of a type mnemonic such as "AAAA",
+    ///or of the generic form "TYPE65280" for types without a mnemonic (RFC 3597)
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_uppercase();
+        let code = match name.as_str() {
+            "A" => 1,
+            "NS" => 2,
+            "CNAME" => 5,
+            "SOA" => 6,
+            "PTR" => 12,
+            "MX" => 15,
+            "TXT" => 16,
+            "RP" => 17,
+            "AAAA" => 28,
+            "LOC" => 29,
+            "SRV" => 33,
+            "TLSA" => 52,
+            "OPENPGPKEY" => 61,
+            "IXFR" => 251,
+            "AXFR" => 252,
+            "ANY" => 255,
+            _ => match name.strip_prefix("TYPE") {
+                Some(code) => code.parse::<u16>().ok()?,
+                None => return None
+            }
+        };
+
+        Some(Self::from_byte(code))
+    }
+
     ///Creates a new A record from a string containing an ipv4 address
     pub fn new_a(ipv4: &str) -> Option<Self> {
         let ip: Ipv4Addr = match ipv4.parse() {
@@ -560,25 +1002,78 @@ impl DnsRecordType {
         Some(Self::SOA(Some(auth_record)))
     }
 
-    ///Creates a new TXT record from a string
+    ///Creates a new TXT record from a string.
+    ///The text is split into character-strings of at most 255 bytes, each prefixed by its length
     pub fn new_txt(text: &str) -> Option<Self> {
+        Self::new_txt_bytes(text.as_bytes())
+    }
+
+    ///Creates a new TXT record from arbitrary bytes, which do not need to be valid UTF-8.
+    ///The bytes are split into character-strings of at most 255 bytes
+    pub fn new_txt_bytes(text: &[u8]) -> Option<Self> {
+        let mut rdata: Vec<u8> = Vec::new();
+        for chunk in text.chunks(255) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+        if rdata.is_empty() {
+            rdata.push(0); //A TXT record always holds at least one (empty) character-string
+        }
+
         Some(
             Self::TXT(
                 Some(
-                    text.as_bytes().to_vec()
+                    rdata
                 )
             )
         )
     }
 
-    ///Creates a new CNAME record (unimplemented)
+    ///Creates a new OPENPGPKEY record from the base64 encoded key material
+    pub fn new_openpgpkey(key: &str) -> Option<Self> {
+        let key: String = key.split_whitespace().collect();
+        match base64::decode(&key) {
+            Ok(val) => Some(Self::OPENPGPKEY(Some(val))),
+            Err(_) => None
+        }
+    }
+
+    ///Creates a new CNAME record from a string containing a domain name
     pub fn new_cname(cname: &str) -> Option<Self> {
-        None
+        let labels: Vec<String> = cname.split(".")
+        .filter(|label| !label.is_empty())
+        .map(String::from)
+        .collect();
+        if labels.is_empty() || labels.iter().any(|label| label.len() > 63) {
+            return None;
+        }
+
+        Some(Self::CNAME(Some(domain_list_to_bytes(&labels))))
     }
 
-    ///Creates a new MX record (unimplemented)
-    pub fn new_mx(_val: &str) -> Option<Self> {
-        None
+    ///Creates a new MX record from a string in presentation format (the preference and the mail exchange, e.g. "10 mail.example.home")
+    pub fn new_mx(val: &str) -> Option<Self> {
+        let fields: Vec<&str> = val.split_whitespace().collect();
+        if fields.len() != 2 {
+            return None;
+        }
+
+        let preference = match fields[0].parse::<u16>() {
+            Ok(val) => val,
+            Err(_) => return None
+        };
+        let labels: Vec<String> = fields[1].split(".")
+        .filter(|label| !label.is_empty())
+        .map(String::from)
+        .collect();
+        if labels.is_empty() || labels.iter().any(|label| label.len() > 63) {
+            return None;
+        }
+
+        let mut rdata: Vec<u8> = preference.to_be_bytes().to_vec();
+        write_domain_list(&labels, &mut rdata);
+
+        Some(Self::MX(Some(rdata)))
     }
 
     ///Creates a new LOC record (unimplemented)
@@ -591,28 +1086,84 @@ impl DnsRecordType {
         None
     }
 
-    ///Creates a new TLSA record (unimplemented)
-    pub fn new_tlsa(_val: &str) -> Option<Self> {
-        None
+    ///Creates a new TLSA record from a string in presentation format
+    ///(certificate usage, selector, matching type and the hex encoded association data, e.g. "3 1 1 0a1b...")
+    pub fn new_tlsa(val: &str) -> Option<Self> {
+        let fields: Vec<&str> = val.split_whitespace().collect();
+        if fields.len() < 4 {
+            return None;
+        }
+
+        let mut rdata: Vec<u8> = Vec::new();
+        for field in &fields[0..3] {
+            match field.parse::<u8>() {
+                Ok(val) => rdata.push(val),
+                Err(_) => return None
+            }
+        }
+
+        let hex: String = fields[3..].concat();
+        if !hex.len().is_multiple_of(2) {
+            return None;
+        }
+        for i in (0..hex.len()).step_by(2) {
+            match u8::from_str_radix(hex.get(i..i+2)?, 16) {
+                Ok(val) => rdata.push(val),
+                Err(_) => return None
+            }
+        }
+
+        Some(Self::TLSA(Some(rdata)))
     }
 
-    ///Creates a new PTR record (unimplemented)
-    pub fn new_ptr(_val: &str) -> Option<Self> {
-        None
+    ///Creates a new PTR record pointing to the hostname
+    pub fn new_ptr(hostname: &str) -> Option<Self> {
+        let labels: Vec<String> = hostname.split(".")
+        .filter(|label| !label.is_empty())
+        .map(String::from)
+        .collect();
+        if labels.is_empty() || labels.iter().any(|label| label.len() > 63) {
+            return None;
+        }
+
+        Some(Self::PTR(Some(domain_list_to_bytes(&labels))))
     }
 }
 
-fn domain_list_to_bytes(list: &Vec<String>) -> Vec<u8> {
-    let mut bytes: Vec<u8> = Vec::new();
+pub(super) fn domain_list_to_bytes(list: &[String]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(list.iter().map(|domain| domain.len() + 1).sum::<usize>() + 1);
+    write_domain_list(list, &mut bytes);
+
+    bytes
+}
 
+//Appends the name in wire format to the buffer, without allocating for each label
+pub(super) fn write_domain_list(list: &[String], bytes: &mut Vec<u8>) {
     for domain in list {
-        bytes.push(domain.len() as u8);
-        bytes.append(&mut domain.as_bytes().to_vec());
+        write_label(domain, bytes);
     }
 
     bytes.push(0);
+}
 
-    bytes
+//Writes a label with its length. Labels hold one char for every byte of the wire format, as they are read,
+//so chars up to U+00FF are written as a single byte. Chars above it have no such byte and are written as UTF-8
+pub(super) fn write_label(label: &str, bytes: &mut Vec<u8>) {
+    if label.is_ascii() {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+        return;
+    }
+
+    let start = bytes.len();
+    bytes.push(0);
+    for c in label.chars() {
+        match u8::try_from(c) {
+            Ok(byte) => bytes.push(byte),
+            Err(_) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+        }
+    }
+    bytes[start] = (bytes.len() - start - 1) as u8;
 }
 
 #[cfg(test)]
@@ -637,6 +1188,35 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn write_domain_list_test() {
+        //The implementation before labels were written into the buffer directly
+        let previous = |list: &Vec<String>| {
+            let mut bytes: Vec<u8> = Vec::new();
+            for domain in list {
+                bytes.push(domain.len() as u8);
+                bytes.append(&mut domain.as_bytes().to_vec());
+            }
+            bytes.push(0);
+            bytes
+        };
+        let names: Vec<Vec<String>> = vec!(
+            Vec::new(),
+            vec!(String::from("com")),
+            vec!(String::from("_443"), String::from("_tcp"), String::from("www"), String::from("example"), String::from("home")),
+            vec!("a".repeat(63), "b".repeat(63), String::from("example"))
+        );
+
+        for name in &names {
+            assert_eq!(domain_list_to_bytes(name), previous(name));
+
+            let mut buffer: Vec<u8> = vec!(1, 2);
+            write_domain_list(name, &mut buffer);
+            assert_eq!(buffer[..2], [1, 2]);
+            assert_eq!(buffer[2..], previous(name)[..]);
+        }
+    }
+
     #[test]
     fn header_test() {
         let mut header = DnsHeader::new();
@@ -652,7 +1232,7 @@ mod tests {
             0b0000_0000, //First byte of id
             0b0001_0000, //Second byte of id (16)
             0b0_0001_1_0_1, //qr (0), opcode (1), aa (1), tc (0), rd (1)
-            0b1_100_0100, //ra (1), z (4), rcode (4)
+            0b1000_0100, //ra (1), z (0 although 4 is set), rcode (4)
             0, 0, //qd_count
             0, 0, //an_count
             0, 0, //ns_count
@@ -671,6 +1251,7 @@ mod tests {
 
         let resp = DnsResponse::default()
         .id(32)
+        .ra(true)
         .opcode(3)
         .rcode(DnsResponseCode::Refused)
         .add_answer(ans1);
@@ -700,4 +1281,190 @@ mod tests {
 
         assert_eq!(resp.build(true), expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn openpgpkey_test() {
+        let key: Vec<u8> = vec!(0x99, 0x01, 0x0d, 0x04, 0x5f, 0x00, 0xff);
+        let record = DnsRecordType::new_openpgpkey("mQENBF8A/w==").unwrap();
+
+        assert_eq!(record, DnsRecordType::OPENPGPKEY(Some(key.clone())));
+        assert_eq!(DnsRecordType::from_byte(61), DnsRecordType::OPENPGPKEY(None));
+        assert_eq!(DnsRecordType::new_openpgpkey("not base64!"), None);
+
+        let answer = DnsAnswer::default()
+        .name(vec!(String::from("key")))
+        .record(Some(record));
+        let bytes = answer.build();
+
+        assert_eq!(&bytes[5..7], &[0, 61]); //type (61)
+        assert_eq!(&bytes[13..15], &[0, 7]); //rd_length (7)
+        assert_eq!(&bytes[15..], &key[..]);
+    }
+
+    #[test]
+    fn extended_error_test() {
+        let resp = DnsResponse::default()
+        .id(1)
+        .rcode(DnsResponseCode::Refused)
+        .extended_error(ExtendedError::Blocked);
+
+        let expected: Vec<u8> = vec!(
+            //Header:
+            0, 1, //id (1)
+            0b1000_0001, //qr (1), opcode (0), aa (0), tc (0), rd (1)
+            0b0000_0101, //ra (0), z (0), rcode (5)
+            0, 0, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 1, //ar_count (1)
+            //OPT record:
+            0, //root name
+            0, 41, //type (41)
+            0b0000_0100, 0b1101_0000, //udp payload size (1232)
+            0, 0, 0, 0, //extended rcode, version, flags
+            0, 6, //rd_length (6)
+            0, 15, //option code (15)
+            0, 2, //option length (2)
+            0, 15 //info-code (15 = Blocked)
+        );
+
+        assert_eq!(resp.build(false), expected);
+    }
+
+    #[test]
+    fn validate_test() {
+        let answer = DnsAnswer::default()
+        .name(vec!(String::from("example"), String::from("com")))
+        .ttl(60)
+        .record(DnsRecordType::new_a("192.0.2.1"));
+        let valid = || DnsResponse::default().add_answer(answer.clone()).udp_size(1232);
+        assert_eq!(valid().validate(), Ok(()));
+
+        let mut resp = valid();
+        resp.header.qd_count = 1;
+        assert_eq!(resp.validate(), Err(ResponseError::QuestionCount));
+
+        let mut resp = valid();
+        resp.answers.push(answer.clone());
+        assert_eq!(resp.validate(), Err(ResponseError::AnswerCount));
+
+        let mut resp = valid();
+        resp.header.ar_count = 0;
+        assert_eq!(resp.validate(), Err(ResponseError::AdditionalCount));
+
+        let mut resp = valid();
+        resp.answers[0].class = 0;
+        assert_eq!(resp.validate(), Err(ResponseError::InvalidClass));
+
+        let mut resp = valid();
+        resp.answers[0].rdata.pop();
+        assert_eq!(resp.validate(), Err(ResponseError::RdLength));
+    }
+
+    #[test]
+    fn authoritative_candidate_test() {
+        let question = |name: &str| DnsQuestion {
+            qname: super::super::domain_to_labels(name),
+            qtype: DnsRecordType::A(None),
+            qclass: 1
+        };
+        let zones = vec!(String::from("example.home."), String::from("lan"));
+
+        assert!(question("example.home").is_authoritative_candidate(&zones));
+        assert!(question("NAS.Example.Home").is_authoritative_candidate(&zones));
+        assert!(question("printer.lan").is_authoritative_candidate(&zones));
+        assert!(!question("home").is_authoritative_candidate(&zones));
+        assert!(!question("otherexample.home").is_authoritative_candidate(&zones));
+        assert!(!question("www.example.com").is_authoritative_candidate(&zones));
+        assert!(!question("example.home").is_authoritative_candidate(&[]));
+
+        let query = DnsQuery::default().add_question(question("nas.lan"));
+        assert!(query.is_authoritative_candidate(&zones));
+        assert!(!query.add_question(question("www.example.com")).is_authoritative_candidate(&zones));
+        assert!(!DnsQuery::default().is_authoritative_candidate(&zones));
+    }
+
+    #[test]
+    fn same_record_test() {
+        let answer = DnsAnswer::default()
+        .name(vec!(String::from("www"), String::from("example"), String::from("com")))
+        .ttl(300)
+        .record(DnsRecordType::new_a("192.0.2.1"));
+
+        assert!(answer.same_record(&answer.clone().ttl(60)));
+        assert_ne!(answer, answer.clone().ttl(60));
+        assert!(answer.same_record(&answer.clone().name(vec!(String::from("WWW"), String::from("example"), String::from("com")))));
+        assert!(!answer.same_record(&answer.clone().record(DnsRecordType::new_a("192.0.2.2"))));
+    }
+
+    #[test]
+    fn build_compressed_test() {
+        let name = vec!(String::from("www"), String::from("example"), String::from("com"));
+        let resp = DnsResponse::default()
+        .add_question(DnsQuestion { qname: name.clone(), qtype: DnsRecordType::A(None), qclass: 1 })
+        .add_answer(DnsAnswer::default().name(name.clone()).ttl(60).record(DnsRecordType::new_a("192.0.2.1")))
+        .add_answer(DnsAnswer::default().name(vec!(String::from("mail"), String::from("example"), String::from("com"))).ttl(60).record(DnsRecordType::new_a("192.0.2.2")));
+
+        let bytes = resp.build_compressed(false);
+        assert_eq!(&bytes[33..35], &[0xc0, 12]); //The owner name of the first answer points to the question
+        assert_eq!(&bytes[49..56], &[4, 109, 97, 105, 108, 0xc0, 16]); //mail, then a pointer to example.com
+        assert_eq!(bytes.len(), resp.build(false).len() - 15 - 11);
+
+        let parsed = crate::dns_request::parse_response(&bytes, false).unwrap();
+        assert_eq!(parsed.answers[1].name, vec!(String::from("mail"), String::from("example"), String::from("com")));
+        assert_eq!(parsed.answers[1].rdata, vec!(192, 0, 2, 2));
+    }
+
+    #[test]
+    fn truncate_to_compression_test() {
+        //28 answers need 34 bytes each uncompressed (and 16 compressed), after a 12 byte header and a 24 byte question
+        let name = vec!(String::from("compressed"), String::from("example"));
+        let mut resp = DnsResponse::default()
+        .add_question(DnsQuestion { qname: name.clone(), qtype: DnsRecordType::A(None), qclass: 1 });
+        for i in 0..28 {
+            resp = resp.add_answer(DnsAnswer::default().name(name.clone()).ttl(60).record(DnsRecordType::new_a(&format!("192.0.2.{}", i))));
+        }
+        assert!(resp.build(false).len() > 512);
+
+        resp.truncate_to(512);
+        assert!(!resp.header.tc);
+        assert_eq!(resp.answers.len(), 28);
+        assert!(resp.build_compressed(false).len() <= 512);
+    }
+
+    #[test]
+    fn truncate_to_test() {
+        let record = |address: &str| DnsAnswer::default()
+        .name(vec!(String::from("big"), String::from("example")))
+        .ttl(60)
+        .record(DnsRecordType::new_a(address));
+
+        let mut resp = DnsResponse::default().udp_size(1232);
+        for i in 0..40 {
+            resp = resp.add_answer(record(&format!("192.0.2.{}", i)));
+        }
+        resp = resp.add_auth_record(record("192.0.2.200"));
+        resp.additional_records.push(record("192.0.2.201"));
+        resp.header.ar_count += 1;
+        assert!(resp.build_compressed(false).len() > 512);
+
+        resp.truncate_to(512);
+        assert!(resp.build_compressed(false).len() <= 512);
+        assert!(resp.header.tc);
+        assert!(!resp.answers.is_empty() && resp.answers.len() < 40);
+        assert_eq!(resp.answers[0].rdata, vec!(192, 0, 2, 0));
+        assert_eq!(resp.authority_records.len(), 0);
+        assert_eq!(resp.additional_records.len(), 0);
+        assert!(resp.edns.is_some());
+        assert_eq!(resp.validate(), Ok(()));
+
+        //Only the additional record has to go, the answers are complete
+        let mut resp = DnsResponse::default().add_answer(record("192.0.2.1"));
+        resp.additional_records.push(record("192.0.2.2"));
+        resp.header.ar_count += 1;
+        let size = resp.build_compressed(false).len();
+        resp.truncate_to(size - 1);
+        assert!(!resp.header.tc);
+        assert_eq!(resp.answers.len(), 1);
+    }
+}
@@ -0,0 +1,459 @@
+//! # Module for the dns-over-https endpoint
+//! Queries are accepted as GET (base64url encoded `dns` parameter) and POST (application/dns-message body)
+//! requests to /dns-query over plain HTTP/1.1 (HTTP/2 is not supported). TLS is expected to be terminated by a reverse proxy.
+//! Connections count towards the same max_tcp_connections as those of the tcp listener
+//! ## For more information see:
+//! <https://tools.ietf.org/html/rfc8484> (The dns-over-https specifications)
+
+use std::io::{ BufRead, BufReader, ErrorKind, Read, Write };
+use std::net::{ IpAddr, TcpListener, TcpStream };
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::handle_data::{ self, Refusal, Transport };
+use crate::limits::Limit;
+use crate::server;
+
+const MAX_BODY_SIZE: usize = 65535;
+//The longest request line or header line, and the most headers a request may have
+const MAX_LINE_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 100;
+
+static IN_FLIGHT: Limit = Limit::new();
+
+/// # Struct representing an http request to the endpoint
+#[derive(PartialEq, Debug)]
+pub struct HttpRequest {
+    ///The request method (GET or POST)
+    pub method: String,
+    ///The request target (path and query string)
+    pub target: String,
+    ///The request headers with lowercase names
+    pub headers: Vec<(String, String)>,
+    ///The request body
+    pub body: Vec<u8>
+}
+
+/// # Struct representing an http response of the endpoint
+#[derive(PartialEq, Debug)]
+pub struct HttpResponse {
+    ///The status code of the response
+    pub status: u16,
+    ///The response headers (Content-Length is added when building)
+    pub headers: Vec<(String, String)>,
+    ///The response body
+    pub body: Vec<u8>
+}
+
+///Accepts clients on the listener, handling each in its own thread. Connections past the configured
+///max_tcp_connections (shared with the tcp listener) are closed right away, and connections idle for longer
+///than the configured doh_idle_timeout are closed
+pub fn serve(listener: TcpListener) {
+    let config = config::get();
+    let idle_timeout = match config.doh_idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs))
+    };
+    serve_with_timeout(listener, idle_timeout, &server::TCP_CONNECTIONS, config.max_tcp_connections);
+}
+
+fn serve_with_timeout(listener: TcpListener, idle_timeout: Option<Duration>, connections: &'static Limit, max_connections: usize) {
+    for client in listener.incoming() {
+        if let Ok(client) = client {
+            let guard = match connections.try_acquire(max_connections) {
+                Some(val) => val,
+                None => {
+                    drop(client);
+                    continue;
+                }
+            };
+
+            thread::spawn(move || {
+                handle_client(client, idle_timeout);
+                drop(guard);
+            });
+        }
+        else {
+            println!("Failed to accept client (DoH)");
+        }
+    }
+}
+
+fn handle_client(client: TcpStream, idle_timeout: Option<Duration>) {
+    let address = match client.peer_addr() {
+        Ok(val) => val.ip(),
+        Err(_) => return
+    };
+    //A read timing out ends the connection like the client closing it
+    if client.set_read_timeout(idle_timeout).is_err() {
+        return;
+    }
+    let mut writer = match client.try_clone() {
+        Ok(val) => val,
+        Err(_) => return
+    };
+    let mut reader = BufReader::new(client);
+
+    //Connections are kept alive until the client closes them, asks for them to be closed or is idle for too long
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(val) => val,
+            Err(status) => {
+                if let Some(status) = status {
+                    let _ = writer.write_all(&HttpResponse::new(status).header("Connection", "close").build());
+                }
+                return;
+            }
+        };
+
+        let response = handle_request(&request, address, &IN_FLIGHT, config::get().doh_max_in_flight);
+        if writer.write_all(&response.build()).is_err() {
+            return;
+        }
+
+        if let Some(connection) = request.header("connection") {
+            if connection.eq_ignore_ascii_case("close") {
+                return;
+            }
+        }
+    }
+}
+
+//Reads a request from the connection. A request which can't be read is answered with the returned status
+//before the connection is closed, no status closes it right away
+fn read_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest, Option<u16>> {
+    let mut line = String::new();
+    if read_line(reader, &mut line)? == 0 {
+        return Err(None); //Connection closed
+    }
+
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (String::from(method), String::from(target)),
+        _ => return Err(Some(400))
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    loop {
+        line.clear();
+        if read_line(reader, &mut line)? == 0 {
+            return Err(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Some(431));
+        }
+
+        let separator = header.find(":").ok_or(Some(400))?;
+        headers.push((
+            header[..separator].trim().to_lowercase(),
+            String::from(header[separator+1..].trim())
+        ));
+    }
+
+    let mut request = HttpRequest {
+        method,
+        target,
+        headers,
+        body: Vec::new()
+    };
+
+    let length = match request.header("content-length") {
+        Some(val) => val.parse::<usize>().map_err(|_| Some(400))?,
+        None => 0
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Some(413));
+    }
+    request.body = vec!(0; length);
+    reader.read_exact(&mut request.body).map_err(|_| None)?;
+
+    Ok(request)
+}
+
+//Reads a line of at most MAX_LINE_SIZE bytes, a longer line is answered with 431. Reading fails (without a status)
+//like the connection closing, such as once it was idle for too long
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<usize, Option<u16>> {
+    let num_bytes = match reader.take(MAX_LINE_SIZE as u64 + 1).read_line(line) {
+        Ok(val) => val,
+        Err(err) if err.kind() == ErrorKind::InvalidData => return Err(Some(400)), //Not UTF-8
+        Err(_) => return Err(None)
+    };
+    if num_bytes > MAX_LINE_SIZE {
+        return Err(Some(431));
+    }
+
+    Ok(num_bytes)
+}
+
+///Answers a request to the endpoint. At most max_in_flight queries are resolved at once,
+///further requests are rejected with status 429 until a slot is free. Refused queries are
+///answered with an http status (429 with Retry-After when rate limited or the client has too many
+///outstanding queries, 403 when blocked) rather than a REFUSED dns response
+pub fn handle_request(request: &HttpRequest, client: IpAddr, in_flight: &Limit, max_in_flight: usize) -> HttpResponse {
+    let (path, query) = match request.target.find("?") {
+        Some(i) => (&request.target[..i], &request.target[i+1..]),
+        None => (request.target.as_str(), "")
+    };
+    if path != "/dns-query" {
+        return HttpResponse::new(404);
+    }
+
+    let message = match request.method.as_str() {
+        "GET" => {
+            let param = query.split("&").find(|param| param.starts_with("dns="));
+            match param.map(|param| base64::decode_config(&param[4..], base64::URL_SAFE_NO_PAD)) {
+                Some(Ok(val)) => val,
+                _ => return HttpResponse::new(400)
+            }
+        },
+        "POST" => {
+            if request.header("content-type") != Some("application/dns-message") {
+                return HttpResponse::new(415);
+            }
+            request.body.clone()
+        },
+        _ => return HttpResponse::new(405)
+    };
+
+    let _guard = match in_flight.try_acquire(max_in_flight) {
+        Some(val) => val,
+        None => return refusal_response(Refusal::RateLimited)
+    };
+    let _outstanding = match handle_data::acquire_outstanding(client) {
+        Ok(val) => val,
+        Err(refusal) => return refusal_response(refusal)
+    };
+
+    let query = match handle_data::parse_message(&message, Transport::Https, client) {
+        Some(val) => val,
+        None => return HttpResponse::new(400)
+    };
+    if let Some(refusal) = handle_data::refusal(&query) {
+        return refusal_response(refusal);
+    }
+
+    match handle_data::handle_https_query(query, client) {
+        Some(bytes) => HttpResponse::new(200)
+        .header("Content-Type", "application/dns-message")
+        .body(bytes),
+        None => HttpResponse::new(400)
+    }
+}
+
+fn refusal_response(refusal: Refusal) -> HttpResponse {
+    match refusal {
+        Refusal::RateLimited | Refusal::TooManyOutstanding => HttpResponse::new(429)
+        .header("Retry-After", &config::get().doh_retry_after.to_string()),
+        Refusal::Blocked | Refusal::NotAllowed => HttpResponse::new(403)
+    }
+}
+
+impl HttpRequest {
+    ///Returns the value of a header (the name must be lowercase)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_str())
+    }
+}
+
+impl HttpResponse {
+    ///Returns an empty response with a status code
+    pub fn new(status: u16) -> Self {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new()
+        }
+    }
+
+    ///Adds a header to the response
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    ///Sets the body of the response
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    ///Converts the response to the bytes sent over the connection
+    pub fn build(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Unknown"
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+
+        let mut result = head.into_bytes();
+        result.extend_from_slice(&self.body);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Query for id.server (class CH, type TXT), which is answered without the cache or upstream
+    const QUERY: [u8; 27] = [
+        0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, //Header (id 1, rd, 1 question)
+        2, 105, 100, 6, 115, 101, 114, 118, 101, 114, 0, //id.server
+        0, 16, //qtype (16)
+        0, 3 //qclass (3)
+    ];
+
+    fn client() -> IpAddr {
+        "192.0.2.53".parse().unwrap()
+    }
+
+    fn get_request() -> HttpRequest {
+        HttpRequest {
+            method: String::from("GET"),
+            target: format!("/dns-query?dns={}", base64::encode_config(&QUERY[..], base64::URL_SAFE_NO_PAD)),
+            headers: Vec::new(),
+            body: Vec::new()
+        }
+    }
+
+    #[test]
+    fn read_request_test() {
+        let raw = b"POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/dns-message\r\nContent-Length: 3\r\n\r\nabc";
+        let request = read_request(&mut BufReader::new(&raw[..])).unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/dns-query");
+        assert_eq!(request.header("content-type"), Some("application/dns-message"));
+        assert_eq!(request.body, b"abc".to_vec());
+
+        //Endless lines and headers are cut off rather than read into memory
+        let long_line = format!("GET /dns-query?dns={} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_SIZE));
+        assert_eq!(read_request(&mut BufReader::new(long_line.as_bytes())), Err(Some(431)));
+        let many_headers = format!("GET /dns-query HTTP/1.1\r\n{}\r\n", "X-Header: 1\r\n".repeat(MAX_HEADERS + 1));
+        assert_eq!(read_request(&mut BufReader::new(many_headers.as_bytes())), Err(Some(431)));
+        assert_eq!(read_request(&mut BufReader::new(&b"GET\r\n\r\n"[..])), Err(Some(400)));
+        assert_eq!(read_request(&mut BufReader::new(&b""[..])), Err(None));
+    }
+
+    #[test]
+    fn idle_timeout_test() {
+        use std::io::Read;
+        use std::time::Instant;
+        static CONNECTIONS: Limit = Limit::new();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve_with_timeout(listener, Some(Duration::from_millis(200)), &CONNECTIONS, 4));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let start = Instant::now();
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0); //Closed by the server
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn connection_limit_test() {
+        use std::io::Read;
+        static CONNECTIONS: Limit = Limit::new();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve_with_timeout(listener, None, &CONNECTIONS, 1));
+
+        //The first client keeps its connection open without sending a request
+        let _first = TcpStream::connect(address).unwrap();
+        while CONNECTIONS.active() < 1 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut second = TcpStream::connect(address).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(second.read(&mut buffer).unwrap(), 0); //Closed by the server
+        assert_eq!(CONNECTIONS.active(), 1);
+    }
+
+    #[test]
+    fn get_request_test() {
+        let response = handle_request(&get_request(), client(), &Limit::new(), 4);
+
+        assert_eq!(response.status, 200);
+        assert_eq!(&response.body[0..2], &[0, 1]); //Same id as the query
+    }
+
+    #[test]
+    fn in_flight_limit_test() {
+        let in_flight = Limit::new();
+        let _busy = (in_flight.try_acquire(2), in_flight.try_acquire(2));
+
+        let response = handle_request(&get_request(), client(), &in_flight, 2);
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers, vec!((String::from("Retry-After"), String::from("1"))));
+
+        drop(_busy);
+        assert_eq!(handle_request(&get_request(), client(), &in_flight, 2).status, 200);
+    }
+
+    #[test]
+    fn outstanding_limit_test() {
+        let config = config::Config {
+            max_outstanding_per_client: 1,
+            ..config::Config::default()
+        };
+        config::set_for_test(config);
+        let busy: IpAddr = "198.51.100.71".parse().unwrap();
+        let _slow = handle_data::acquire_outstanding(busy).unwrap(); //A query still awaiting the upstream
+
+        let response = handle_request(&get_request(), busy, &Limit::new(), 4);
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers, vec!((String::from("Retry-After"), String::from("1"))));
+
+        //Other clients are still answered
+        assert_eq!(handle_request(&get_request(), client(), &Limit::new(), 4).status, 200);
+    }
+
+    #[test]
+    fn blocked_test() {
+        let config = config::Config {
+            blocklist: vec!(String::from("server")),
+            ..config::Config::default()
+        };
+        config::set_for_test(config);
+
+        assert_eq!(handle_request(&get_request(), client(), &Limit::new(), 4).status, 403);
+    }
+
+    #[test]
+    fn bad_request_test() {
+        let mut request = get_request();
+        request.target = String::from("/dns-query?dns=!!!");
+        assert_eq!(handle_request(&request, client(), &Limit::new(), 4).status, 400);
+
+        request.target = String::from("/other");
+        assert_eq!(handle_request(&request, client(), &Limit::new(), 4).status, 404);
+    }
+}
@@ -1,43 +1,234 @@
 use std::error::Error;
+use std::time::Instant;
 
 mod structs;
 
-use crate::dns_request::{ DnsRecordType, DnsAuthRecord, DnsAnswer };
+use crate::dns_request::{ self, DnsRecordType, DnsAuthRecord, DnsAnswer };
+use crate::config;
+use crate::stats;
 pub use structs::*;
 
-pub fn request_query(name: &Vec<String>, r#type: DnsRecordType) -> Result<DnsAnswer, Box<dyn Error>> {
-    let (u8_type, _) = DnsRecordType::to_byte(&r#type);
-    let name = domains_to_str(name);
-    
-    let response = reqwest::blocking::get(&format!("https://8.8.8.8/resolve?name={}&type={}", name, u8_type))?
-    .json::<GoogleDnsResponse>()?;
+lazy_static! {
+    //Shared by every upstream request, or the reason it could not be built (such as a failed TLS initialization)
+    static ref CLIENT: Result<reqwest::blocking::Client, String> = build_client(reqwest::blocking::Client::builder());
+}
 
-    if response.Status == 3 {
-        return Err(Box::new(ErrorType::NxDomain));
+///Builds the client used for upstream requests, returning the reason if this fails. Without a client
+///the local zone and the cache are still answered, while queries needing the upstream fail (SERVFAIL)
+pub fn init_client() -> Result<(), String> {
+    lazy_static::initialize(&CLIENT);
+    match CLIENT.as_ref() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.clone())
+    }
+}
+
+fn build_client(builder: reqwest::blocking::ClientBuilder) -> Result<reqwest::blocking::Client, String> {
+    match builder.build() {
+        Ok(val) => Ok(val),
+        Err(err) => Err(err.to_string())
     }
+}
+
+///Requests a record from the upstream. If a client subnet is given it is forwarded with
+///the request (EDNS Client Subnet) so the upstream can return an answer for that subnet
+pub fn request_query(name: &[String], r#type: DnsRecordType, subnet: Option<&str>) -> Result<DnsAnswer, Box<dyn Error>> {
+    let (type_code, _) = DnsRecordType::to_byte(&r#type);
+    let response = upstream_response(name, type_code, subnet)?;
 
     match r#type {
-        DnsRecordType::SOA(_) => Ok(to_soa(response)?),
-        DnsRecordType::A(_) => Ok(to_a(response)?),
-        DnsRecordType::AAAA(_) => Ok(to_aaaa(response)?),
+        DnsRecordType::SOA(_) => Ok(to_soa(reject_soa_alias(response)?)?),
+        DnsRecordType::A(_) => Ok(to_a(response, subnet)?),
+        DnsRecordType::AAAA(_) => Ok(to_aaaa(response, subnet)?),
+        DnsRecordType::TXT(_) => Ok(to_txt(response)?),
+        DnsRecordType::OPENPGPKEY(_) => Ok(to_openpgpkey(response)?),
         _ => Err(Box::new(ErrorType::new("Requested type not implemented")))
     }
 }
 
-fn to_a(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
-    if let None = response.Answer {
-        return Err(Box::new(ErrorType::new("No answers")));
+//Requests a name and type from the upstream, returning its response with the out-of-bailiwick answers dropped
+fn upstream_response(name: &[String], type_code: u16, subnet: Option<&str>) -> Result<GoogleDnsResponse, Box<dyn Error>> {
+    let config = config::get();
+    let endpoint = config.upstream_for(name);
+    let name = domains_to_str(name);
+
+    let mut url = format!("{}?name={}&type={}", endpoint, name, type_code);
+    if let Some(subnet) = subnet {
+        url = format!("{}&edns_client_subnet={}", url, subnet);
+    }
+    let start = Instant::now();
+    let result = fetch(&url);
+    stats::record_upstream_latency(start.elapsed());
+    let mut response = result?;
+    response.Answer = response.Answer.map(|answers| in_bailiwick(&name, answers));
+
+    if response.Status == 3 {
+        return Err(Box::new(ErrorType::NxDomain(negative_soa(&response))));
+    }
+
+    Ok(response)
+}
+
+#[cfg(not(test))]
+fn fetch(url: &str) -> Result<GoogleDnsResponse, Box<dyn Error>> {
+    let client = match CLIENT.as_ref() {
+        Ok(val) => val,
+        Err(_) => return Err(Box::new(ErrorType::ClientUnavailable))
+    };
+    let mut last_error: Option<Box<dyn Error>> = None;
+
+    let config = config::get();
+
+    for (url, host) in bootstrap_urls(url, &config.bootstrap) {
+        match upstream_request(client, &url, host, &config).send().and_then(|response| response.json::<GoogleDnsResponse>()) {
+            Ok(val) => return Ok(val),
+            Err(err) => last_error = Some(Box::new(err))
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| Box::new(ErrorType::new("No bootstrap address"))))
+}
+
+//Prepares a request to the upstream with the configured User-Agent and headers, along with the Host header
+//of a bootstrapped url
+fn upstream_request(client: &reqwest::blocking::Client, url: &str, host: Option<String>, config: &config::Config) -> reqwest::blocking::RequestBuilder {
+    let mut request = client.get(url)
+    .header(reqwest::header::USER_AGENT, config.user_agent.as_str());
+    for (name, value) in &config.upstream_headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    if let Some(host) = host {
+        request = request.header(reqwest::header::HOST, host);
+    }
+
+    request
+}
+
+//Returns the urls an endpoint is requested at: with the hostname replaced by each bootstrap address
+//(along with the hostname for the Host header), or the endpoint itself if it has no hostname or there is no bootstrap address
+fn bootstrap_urls(url: &str, bootstrap: &Vec<String>) -> Vec<(String, Option<String>)> {
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(val) => val,
+        Err(_) => return vec!((String::from(url), None))
+    };
+    let host = match parsed.domain() {
+        Some(host) if !bootstrap.is_empty() => String::from(host),
+        _ => return vec!((String::from(url), None)) //An ip address or no bootstrap address
+    };
+
+    let mut urls: Vec<(String, Option<String>)> = Vec::new();
+    for address in bootstrap {
+        let ip: std::net::IpAddr = match address.parse() {
+            Ok(val) => val,
+            Err(_) => {
+                log!("Invalid bootstrap address: {}", address);
+                continue;
+            }
+        };
+
+        let mut bootstrapped = parsed.clone();
+        if bootstrapped.set_ip_host(ip).is_ok() {
+            urls.push((bootstrapped.into_string(), Some(host.clone())));
+        }
+    }
+
+    urls
+}
+
+#[cfg(test)]
+fn fetch(url: &str) -> Result<GoogleDnsResponse, Box<dyn Error>> {
+    mock::fetch(url)
+}
+
+/// # Trait for the records of an upstream answer, which are checked with [in_bailiwick](in_bailiwick)
+pub trait AnswerRecord {
+    ///Returns the owner name of the record as a domain (a trailing dot is accepted)
+    fn owner(&self) -> String;
+    ///Returns the target of the record as a domain if it is a CNAME record
+    fn alias_target(&self) -> Option<String>;
+}
+
+impl AnswerRecord for GoogleDnsAnswer {
+    fn owner(&self) -> String {
+        self.name.clone()
+    }
+
+    fn alias_target(&self) -> Option<String> {
+        match self.r#type {
+            5 => Some(self.data.clone()), //5 = CNAME record
+            _ => None
+        }
+    }
+}
+
+impl AnswerRecord for DnsAnswer {
+    fn owner(&self) -> String {
+        self.name.join(".")
+    }
+
+    fn alias_target(&self) -> Option<String> {
+        match self.r#type {
+            DnsRecordType::CNAME(_) => dns_request::wire_to_labels(&self.rdata).map(|labels| labels.join(".")),
+            _ => None
+        }
+    }
+}
+
+///Drops the answers which are neither for the name (or a subdomain of it) nor for the target of a kept CNAME record,
+///so a misbehaving upstream cannot have records of unrelated names cached or passed on
+pub fn in_bailiwick<T: AnswerRecord>(name: &str, answers: Vec<T>) -> Vec<T> {
+    let normalize = |name: &str| name.trim_end_matches(".").to_lowercase();
+    let name = normalize(name);
+    let mut targets: Vec<String> = Vec::new();
+    let mut kept = vec!(false; answers.len());
+
+    //CNAME records may come in any order, so the answers are checked until no more are kept
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, answer) in answers.iter().enumerate() {
+            let owner = normalize(&answer.owner());
+            if kept[i] || !(owner == name || owner.ends_with(&format!(".{}", name)) || targets.contains(&owner)) {
+                continue;
+            }
+
+            kept[i] = true;
+            changed = true;
+            if let Some(target) = answer.alias_target() {
+                targets.push(normalize(&target));
+            }
+        }
     }
 
-    let answer_results = response.Answer.unwrap();
+    let mut result: Vec<T> = Vec::new();
+    for (i, answer) in answers.into_iter().enumerate() {
+        if kept[i] {
+            result.push(answer);
+        }
+        else {
+            log!("Dropped out-of-bailiwick record for {} from the answer for {}", answer.owner(), name);
+        }
+    }
+
+    result
+}
+
+fn to_a(response: GoogleDnsResponse, subnet: Option<&str>) -> Result<DnsAnswer, Box<dyn Error>> {
+    //A successful response without answers means the name exists without any A record
+    let soa = negative_soa(&response);
+    let answer_results = match response.Answer {
+        Some(val) => val,
+        None => return Err(Box::new(ErrorType::NoData(soa)))
+    };
     let answer = get_ans_from_rec_type(&answer_results, 1); //1 = A record
     if let None = answer {
         let cname_answer = get_ans_from_rec_type(&answer_results, 5); //5 = CNAME record
         if let Some(ans) = cname_answer {
-            return request_query(&str_to_domains(&ans.data), DnsRecordType::A(None));
+            trace!("The upstream answered with the alias {}, resolving it", ans.data);
+            return request_query(&str_to_domains(&ans.data), DnsRecordType::A(None), subnet);
         }
         else {
-            return Err(Box::new(ErrorType::new("No cname response")));
+            return Err(Box::new(ErrorType::NoData(soa)));
         }
     }
     let answer = answer.unwrap();
@@ -50,20 +241,22 @@ fn to_a(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
     Ok(answer_from_record(Some(record), answer))
 }
 
-fn to_aaaa(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
-    if let None = response.Answer {
-        return Err(Box::new(ErrorType::new("No answers")));
-    }
-
-    let answer_results = response.Answer.unwrap();
+fn to_aaaa(response: GoogleDnsResponse, subnet: Option<&str>) -> Result<DnsAnswer, Box<dyn Error>> {
+    //A successful response without answers means the name exists without any AAAA record
+    let soa = negative_soa(&response);
+    let answer_results = match response.Answer {
+        Some(val) => val,
+        None => return Err(Box::new(ErrorType::NoData(soa)))
+    };
     let answer = get_ans_from_rec_type(&answer_results, 28); //28 = AAAA record
     if let None = answer {
         let cname_answer = get_ans_from_rec_type(&answer_results, 5); //5 = CNAME record
         if let Some(ans) = cname_answer {
-            return request_query(&str_to_domains(&ans.data), DnsRecordType::AAAA(None));
+            trace!("The upstream answered with the alias {}, resolving it", ans.data);
+            return request_query(&str_to_domains(&ans.data), DnsRecordType::AAAA(None), subnet);
         }
         else {
-            return Err(Box::new(ErrorType::new("No cname response")));
+            return Err(Box::new(ErrorType::NoData(soa)));
         }
     }
     let answer = answer.unwrap();
@@ -76,6 +269,79 @@ fn to_aaaa(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
     Ok(answer_from_record(Some(record), answer))
 }
 
+fn to_txt(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
+    if response.Answer.is_none() {
+        return Err(Box::new(ErrorType::new("No answers")));
+    }
+
+    let answer = match get_ans_from_rec_type(&response.Answer.unwrap(), 16) { //16 = TXT record
+        Some(val) => val,
+        None => return Err(Box::new(ErrorType::new("No txt response")))
+    };
+
+    //The character-strings of the record are given in quotes
+    let text: String = answer.data.split("\"")
+    .enumerate()
+    .filter(|(i, _)| i % 2 == 1)
+    .map(|(_, part)| part)
+    .collect();
+    let text = if answer.data.contains("\"") { text } else { answer.data.clone() };
+
+    Ok(answer_from_record(DnsRecordType::new_txt(&text), answer))
+}
+
+fn to_openpgpkey(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
+    if response.Answer.is_none() {
+        return Err(Box::new(ErrorType::new("No answers")));
+    }
+
+    let answer = match get_ans_from_rec_type(&response.Answer.unwrap(), 61) { //61 = OPENPGPKEY record
+        Some(val) => val,
+        None => return Err(Box::new(ErrorType::new("No openpgpkey response")))
+    };
+
+    let record = match DnsRecordType::new_openpgpkey(&answer.data) {
+        Some(val) => val,
+        None => return Err(Box::new(ErrorType::new("Invalid openpgpkey")))
+    };
+
+    Ok(answer_from_record(Some(record), answer))
+}
+
+//Returns the SOA record of the authority section of a negative response. Its ttl is lowered to
+//the minimum field, which is the time the negative response may be cached for (RFC 2308)
+fn negative_soa(response: &GoogleDnsResponse) -> Option<DnsAnswer> {
+    let zone = str_to_domains(&response.Authority.as_ref()?.first()?.name);
+    let soa = to_soa(response.clone()).ok()?;
+    let minimum = match &soa.r#type {
+        DnsRecordType::SOA(Some(auth_record)) => auth_record.minimum,
+        _ => return None
+    };
+    let ttl = std::cmp::min(soa.ttl, minimum);
+
+    Some(soa.name(zone).ttl(ttl))
+}
+
+//A zone apex can't be an alias, but misconfigured zones answer SOA queries with one anyway. The alias is returned
+//as an error, so the SOA record of its target is never taken for the one of the name (see database::get_records)
+fn reject_soa_alias(response: GoogleDnsResponse) -> Result<GoogleDnsResponse, Box<dyn Error>> {
+    match soa_alias(&response) {
+        Some(alias) => Err(Box::new(ErrorType::UnexpectedAlias(alias))),
+        None => Ok(response)
+    }
+}
+
+//Returns the alias an SOA query was answered with, if it was answered with one instead of an SOA record
+fn soa_alias(response: &GoogleDnsResponse) -> Option<DnsAnswer> {
+    let answers = response.Answer.as_ref()?;
+    if get_ans_from_rec_type(answers, 6).is_some() { //6 = SOA record
+        return None;
+    }
+
+    get_ans_from_rec_type(answers, 5) //5 = CNAME record
+    .map(|answer| answer_from_record(DnsRecordType::new_cname(&answer.data), answer))
+}
+
 fn to_soa(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
     if let None = response.Authority {
         return Err(Box::new(ErrorType::new("No authority response")));
@@ -113,7 +379,7 @@ fn to_soa(response: GoogleDnsResponse) -> Result<DnsAnswer, Box<dyn Error>> {
     Ok(answer_from_record(record, auth_results[0].clone()))
 }
 
-fn get_ans_from_rec_type(answers: &Vec<GoogleDnsAnswer>, rec_type: u8) -> Option<GoogleDnsAnswer> {
+fn get_ans_from_rec_type(answers: &Vec<GoogleDnsAnswer>, rec_type: u16) -> Option<GoogleDnsAnswer> {
     for answer in answers {
         if answer.r#type == rec_type {
             return Some(answer.clone());
@@ -142,7 +408,7 @@ fn str_to_domains(url: &str) -> Vec<String> {
     filtered_domains
 }
 
-fn domains_to_str(domains: &Vec<String>) -> String {
+fn domains_to_str(domains: &[String]) -> String {
     let mut url = String::new();
 
     for domain in domains {
@@ -150,4 +416,201 @@ fn domains_to_str(domains: &Vec<String>) -> String {
     }
 
     String::from(&url[..url.len()-1])
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txt_response_test() {
+        mock::respond("name=txt.example.org&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "txt.example.org.", "type": 16 }],
+            "Answer": [{ "name": "txt.example.org.", "type": 16, "TTL": 120, "data": "\"hello\"" }]
+        }"#);
+
+        let answer = request_query(&str_to_domains("txt.example.org"), DnsRecordType::TXT(None), None).unwrap();
+
+        assert_eq!(answer.ttl, 120);
+        assert_eq!(answer.rdata, vec!(5, 104, 101, 108, 108, 111)); //length (5), hello
+    }
+
+    #[test]
+    fn client_failure_test() {
+        assert!(build_client(reqwest::blocking::Client::builder()).is_ok());
+        assert!(build_client(reqwest::blocking::Client::builder().user_agent("invalid\nagent")).is_err());
+    }
+
+    #[test]
+    fn nodata_response_test() {
+        mock::respond("name=nodata.example.org&type=28", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "nodata.example.org.", "type": 28 }],
+            "Authority": [{ "name": "example.org.", "type": 6, "TTL": 1800, "data": "ns.example.org. hostmaster.example.org. 1 7200 3600 1209600 3600" }]
+        }"#);
+
+        let err = request_query(&str_to_domains("nodata.example.org"), DnsRecordType::AAAA(None), None).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ErrorType>(), Some(ErrorType::NoData(Some(_)))));
+    }
+
+    #[test]
+    fn nxdomain_response_test() {
+        mock::respond("name=missing.example.org&type=1", r#"{
+            "Status": 3, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "missing.example.org.", "type": 1 }],
+            "Authority": [{ "name": "example.org.", "type": 6, "TTL": 1800, "data": "ns.example.org. hostmaster.example.org. 1 7200 3600 1209600 3600" }]
+        }"#);
+
+        let err = request_query(&str_to_domains("missing.example.org"), DnsRecordType::A(None), None).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ErrorType>(), Some(ErrorType::NxDomain(Some(_)))));
+    }
+
+    #[test]
+    fn bailiwick_test() {
+        mock::respond("name=www.victim.example&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.victim.example.", "type": 1 }],
+            "Answer": [
+                { "name": "bank.other.example.", "type": 1, "TTL": 86400, "data": "203.0.113.66" },
+                { "name": "cdn.provider.example.", "type": 1, "TTL": 60, "data": "192.0.2.10" },
+                { "name": "www.victim.example.", "type": 5, "TTL": 60, "data": "cdn.provider.example." }
+            ]
+        }"#);
+
+        let answer = request_query(&str_to_domains("www.victim.example"), DnsRecordType::A(None), None).unwrap();
+        assert_eq!(answer.rdata, vec!(192, 0, 2, 10)); //Reached through the CNAME record
+
+        let answers = vec!(
+            GoogleDnsAnswer { name: String::from("bank.other.example."), r#type: 1, TTL: 60, data: String::from("203.0.113.66") },
+            GoogleDnsAnswer { name: String::from("Sub.WWW.victim.example."), r#type: 1, TTL: 60, data: String::from("192.0.2.1") },
+            GoogleDnsAnswer { name: String::from("www.victim.example.evil."), r#type: 1, TTL: 60, data: String::from("203.0.113.67") }
+        );
+        let kept: Vec<String> = in_bailiwick("www.victim.example", answers).into_iter().map(|answer| answer.data).collect();
+        assert_eq!(kept, vec!(String::from("192.0.2.1")));
+    }
+
+    #[test]
+    fn soa_alias_test() {
+        mock::respond("name=alias.example.org&type=6", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "alias.example.org.", "type": 6 }],
+            "Answer": [{ "name": "alias.example.org.", "type": 5, "TTL": 300, "data": "zone.example.net." }]
+        }"#);
+
+        //The alias is returned, its target is not asked for
+        let err = request_query(&str_to_domains("alias.example.org"), DnsRecordType::SOA(None), None).unwrap_err();
+        match err.downcast_ref::<ErrorType>() {
+            Some(ErrorType::UnexpectedAlias(alias)) => {
+                assert_eq!(alias.r#type, DnsRecordType::new_cname("zone.example.net").unwrap());
+                assert_eq!(alias.ttl, 300);
+            },
+            other => panic!("Expected an alias, got {:?}", other)
+        }
+        assert_eq!(err.to_string(), "Unexpected alias to zone.example.net");
+        assert_eq!(mock::calls(), 1);
+    }
+
+    #[test]
+    fn conditional_forwarding_test() {
+        let mut config = config::Config::default();
+        config.forwarders.insert(String::from("corp.internal"), String::from("https://10.0.0.53/resolve"));
+        config::set_for_test(config);
+
+        mock::respond("https://10.0.0.53/resolve?name=intranet.corp.internal&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "intranet.corp.internal.", "type": 1 }],
+            "Answer": [{ "name": "intranet.corp.internal.", "type": 1, "TTL": 60, "data": "10.1.2.3" }]
+        }"#);
+
+        let answer = request_query(&str_to_domains("intranet.corp.internal"), DnsRecordType::A(None), None).unwrap();
+        assert_eq!(answer.rdata, vec!(10, 1, 2, 3));
+
+        //Names outside the zone still go to the default upstream
+        let result = request_query(&str_to_domains("www.example.com"), DnsRecordType::A(None), None);
+        assert!(result.is_err());
+        assert_eq!(mock::calls(), 2);
+    }
+
+    #[test]
+    fn upstream_request_test() {
+        let client = reqwest::blocking::Client::new();
+        let mut config = config::Config::default();
+        assert!(config.user_agent.starts_with("dns/"));
+
+        config.user_agent = String::from("home-resolver/2.0");
+        config.upstream_headers.insert(String::from("X-Client"), String::from("lan"));
+        let request = upstream_request(&client, "https://192.0.2.1/resolve", Some(String::from("dns.example")), &config)
+        .build()
+        .unwrap();
+
+        assert_eq!(request.headers()["user-agent"], "home-resolver/2.0");
+        assert_eq!(request.headers()["x-client"], "lan");
+        assert_eq!(request.headers()["host"], "dns.example");
+    }
+
+    #[test]
+    fn bootstrap_urls_test() {
+        let bootstrap = vec!(String::from("8.8.8.8"), String::from("2001:4860:4860::8888"));
+
+        assert_eq!(bootstrap_urls("https://dns.google/resolve?name=a&type=1", &bootstrap), vec!(
+            (String::from("https://8.8.8.8/resolve?name=a&type=1"), Some(String::from("dns.google"))),
+            (String::from("https://[2001:4860:4860::8888]/resolve?name=a&type=1"), Some(String::from("dns.google")))
+        ));
+        assert_eq!(
+            bootstrap_urls("https://8.8.4.4/resolve", &bootstrap),
+            vec!((String::from("https://8.8.4.4/resolve"), None))
+        );
+        assert_eq!(
+            bootstrap_urls("https://dns.google/resolve", &Vec::new()),
+            vec!((String::from("https://dns.google/resolve"), None))
+        );
+    }
+}
+
+/// # Stand-in for the upstream server used by tests
+///Responses are registered per thread, so tests running in parallel do not interfere with each other
+#[cfg(test)]
+pub mod mock {
+    use std::cell::{ Cell, RefCell };
+    use std::error::Error;
+    use super::{ GoogleDnsResponse, ErrorType };
+
+    thread_local! {
+        static RESPONSES: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+        static CALLS: Cell<usize> = const { Cell::new(0) };
+        static CLIENT_FAILED: Cell<bool> = const { Cell::new(false) };
+    }
+
+    ///Makes upstream requests of this thread fail like those without a client (see [init_client](super::init_client))
+    pub fn fail_client() {
+        CLIENT_FAILED.with(|failed| failed.set(true));
+    }
+
+    ///Registers the json returned for requests whose url contains the pattern
+    pub fn respond(pattern: &str, json: &str) {
+        RESPONSES.with(|responses| responses.borrow_mut().push((String::from(pattern), String::from(json))));
+    }
+
+    ///Returns the number of upstream requests made by this thread
+    pub fn calls() -> usize {
+        CALLS.with(|calls| calls.get())
+    }
+
+    pub(super) fn fetch(url: &str) -> Result<GoogleDnsResponse, Box<dyn Error>> {
+        CALLS.with(|calls| calls.set(calls.get() + 1));
+        if CLIENT_FAILED.with(|failed| failed.get()) {
+            return Err(Box::new(ErrorType::ClientUnavailable));
+        }
+
+        let json = RESPONSES.with(|responses| {
+            responses.borrow().iter()
+            .find(|(pattern, _)| url.contains(pattern.as_str()))
+            .map(|(_, json)| json.clone())
+        });
+        match json {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Err(Box::new(ErrorType::new("No upstream response mocked")))
+        }
+    }
+}
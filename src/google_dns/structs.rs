@@ -4,6 +4,8 @@ use std::error::Error;
 use std::fmt::{ Display, Result, Formatter };
 use serde::Deserialize;
 
+use crate::dns_request::DnsAnswer;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct GoogleDnsResponse {
     pub Status: u8,
@@ -21,13 +23,13 @@ pub struct GoogleDnsResponse {
 #[derive(Debug, Deserialize, Clone)]
 pub struct GoogleDnsQuestion {
     name: String,
-    r#type: u8
+    r#type: u16
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct GoogleDnsAnswer {
     pub name: String,
-    pub r#type: u8,
+    pub r#type: u16,
     pub TTL: u32,
     pub data: String
 }
@@ -35,7 +37,15 @@ pub struct GoogleDnsAnswer {
 #[derive(Debug)]
 pub enum ErrorType {
     ErrMsg(String),
-    NxDomain,
+    ///The name does not exist, with the SOA record of the zone if the upstream sent one
+    NxDomain(Option<DnsAnswer>),
+    ///The name exists but has no record of the requested type, with the SOA record of the zone if the upstream sent one
+    NoData(Option<DnsAnswer>),
+    ///The upstream can't be requested, as its client could not be built
+    ClientUnavailable,
+    ///The upstream answered an SOA query with an alias, which can't be the apex of a zone.
+    ///Holds the alias (CNAME) record, without its name
+    UnexpectedAlias(DnsAnswer),
 }
 
 impl ErrorType {
@@ -47,7 +57,13 @@ impl ErrorType {
 impl Display for ErrorType {
     fn fmt(&self, formatter: &mut Formatter<'_>) -> Result {
         match self {
-            Self::NxDomain => write!(formatter, "NXDOMAIN"),
+            Self::NxDomain(_) => write!(formatter, "NXDOMAIN"),
+            Self::NoData(_) => write!(formatter, "NODATA"),
+            Self::ClientUnavailable => write!(formatter, "Upstream client unavailable"),
+            Self::UnexpectedAlias(alias) => {
+                let target = crate::dns_request::wire_to_labels(&alias.rdata).unwrap_or_default();
+                write!(formatter, "Unexpected alias to {}", target.join("."))
+            },
             Self::ErrMsg(val) => write!(formatter, "Error: {}", val)
         }
     }
@@ -1,145 +1,2513 @@
-use crate::dns_request::{ DnsResponse, DnsAnswer, DnsRecordType, DnsResponseCode };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::collections::hash_map::RandomState;
+use std::hash::{ BuildHasher, Hasher };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+
+use crate::dns_request::{ DnsQuery, DnsQuestion, DnsResponse, DnsAnswer, DnsRecordType, DnsResponseCode, DnsAuthRecord, ExtendedError };
 use crate::dns_request;
-use crate::database;
+use crate::database::{ self, RecordError };
+use crate::zone;
+use crate::wire_upstream;
+use crate::google_dns;
+use crate::logging;
+use crate::stats;
+use crate::secondary;
+use crate::root_hints;
+use crate::snapshot;
+use crate::limits::{ ClientLimit, ClientLimitGuard };
+use crate::config::{ self, Config, AnswerLimit, BlockMode, AllowlistMode, MultiQuestionMode, RoundRobin, AuthoritativeZone };
 
-pub fn handle_message(buffer: Vec<u8>, tcp: bool) -> Option<Vec<u8>> {
-    let query = match dns_request::parse_query(&buffer, tcp) {
-        Some(val) => val,
+const CHAOS_CLASS: u16 = 3;
+const BLOCKED_TTL: u32 = 60;
+//The largest udp message a client without EDNS can receive
+const MIN_UDP_SIZE: u16 = 512;
+//The largest ttl a record may have (RFC 2181)
+const MAX_TTL: u32 = i32::MAX as u32;
+const NS_TYPE: u16 = 2;
+const HOME_SUFFIX: &str = "home";
+const ANY_TYPE: u16 = 255;
+const NOTIFY_OPCODE: u8 = 4;
+const KEEPALIVE_OPTION: u16 = 11;
+const SERVER_ADDRESS_TTL: u32 = 300;
+//Record types which are obsolete (MD, MF, MB, MG, MR, NXT, A6, MAILB and MAILA), queries for them are not implemented.
+//MINFO is still passed through to the wire upstream
+const OBSOLETE_TYPES: [u16; 9] = [3, 4, 7, 8, 9, 30, 38, 253, 254];
+//RRSIG, NSEC and NSEC3
+const DNSSEC_TYPES: [u16; 3] = [46, 47, 50];
+//Cached record types answered for an ANY query
+const ANY_TYPES: [DnsRecordType; 9] = [
+    DnsRecordType::A(None), DnsRecordType::AAAA(None), DnsRecordType::CNAME(None),
+    DnsRecordType::MX(None), DnsRecordType::LOC(None), DnsRecordType::RP(None),
+    DnsRecordType::TLSA(None), DnsRecordType::TXT(None), DnsRecordType::OPENPGPKEY(None)
+];
+
+static ROTATION: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    //The queries of each client which are currently being resolved
+    static ref OUTSTANDING: ClientLimit = ClientLimit::new();
+}
+
+/// # An enum which represents the transports a message can be received over
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Transport {
+    ///Plain udp, responses are truncated to the payload size of the client
+    Udp,
+    ///Plain tcp, messages are prefixed by their length
+    Tcp,
+    ///dns-over-https, messages are sent as they are
+    Https
+}
+
+///Answers a message of a client, returning the response to send (None if none is sent).
+///A query passes these stages in order, the first one answering it ends it:
+///1. the readiness of the server, queries arriving during the startup are answered with SERVFAIL
+///2. the client's limits (max_outstanding_per_client) and the transport (tcp_only_types)
+///3. the blocklist and then the allowlist, both matched against the name exactly as it was asked
+///4. the local zones, the root hints and the names of the server
+///5. the cache and the upstream, for A and AAAA queries after stripping the home suffix
+///
+///A blocked name within the home suffix is therefore blocked before the suffix is stripped
+pub fn handle_message(buffer: Vec<u8>, transport: Transport, client: IpAddr) -> Option<Vec<u8>> {
+    handle(buffer, transport, client).map(|(bytes, _)| bytes)
+}
+
+///Answers a message of a tcp client like [handle_message](handle_message), along with the time the connection
+///may be idle after the response if the client asked to keep it open (see [tcp_keepalive_timeout](crate::config::Config::tcp_keepalive_timeout)).
+///No time closes the connection
+pub fn handle_tcp_message(buffer: Vec<u8>, client: IpAddr) -> Option<(Vec<u8>, Option<Duration>)> {
+    handle(buffer, Transport::Tcp, client)
+}
+
+fn handle(buffer: Vec<u8>, transport: Transport, client: IpAddr) -> Option<(Vec<u8>, Option<Duration>)> {
+    let _request = logging::begin_request();
+    let _active = stats::begin_query();
+    let config = config::get();
+    let tcp = transport == Transport::Tcp;
+    if config.block_mode == BlockMode::Drop {
+        if let Some((name, _)) = dns_request::peek_question(&buffer, tcp) {
+            if config.blocked_zone(&name).is_some() {
+                if config.log_queries {
+                    log!("Dropped query from {} for {}", client, name.join("."));
+                }
+                return None;
+            }
+        }
+    }
+
+    //A tcp message shorter than its length prefix was only partially received
+    if tcp && buffer.len() >= 2 && (u16::from_be_bytes([buffer[0], buffer[1]]) as usize) > buffer.len() - 2 {
+        if config.log_queries {
+            log!("Partially received query from {}", client);
+        }
+        return Some((format_error(&buffer).build(tcp), None));
+    }
+
+    let query = parse_message(&buffer, transport, client)?;
+    answer(query, transport, client, &config)
+}
+
+///Answers a dns-over-https query which was already parsed with [parse_message](parse_message),
+///like [handle_message](handle_message) answers its message
+pub fn handle_https_query(query: DnsQuery, client: IpAddr) -> Option<Vec<u8>> {
+    let _request = logging::begin_request();
+    let _active = stats::begin_query();
+    let config = config::get();
+    if config.block_mode == BlockMode::Drop {
+        if let Some(question) = query.questions.first() {
+            if config.blocked_zone(&question.qname).is_some() {
+                if config.log_queries {
+                    log!("Dropped query from {} for {}", client, question.qname.join("."));
+                }
+                return None;
+            }
+        }
+    }
+
+    answer(query, Transport::Https, client, &config).map(|(bytes, _)| bytes)
+}
+
+///Parses a message of a client, returning None (and counting it as malformed) if it is not a query
+pub fn parse_message(buffer: &[u8], transport: Transport, client: IpAddr) -> Option<DnsQuery> {
+    match dns_request::try_parse_query(buffer, transport == Transport::Tcp) {
+        Ok(val) => Some(val),
+        Err(err) => {
+            stats::record_malformed(err);
+            if config::get().log_queries {
+                log!("Unparsable query from {} ({:?})", client, err);
+            }
+            None
+        }
+    }
+}
+
+//Answers a parsed query, from the readiness of the server on (see handle_message)
+fn answer(query: DnsQuery, transport: Transport, client: IpAddr, config: &Config) -> Option<(Vec<u8>, Option<Duration>)> {
+    let tcp = transport == Transport::Tcp;
+    if config.log_queries {
+        for question in &query.questions {
+            log!("Query from {} for {} (type {})", client, question.qname.join("."), question.qtype.to_byte().0);
+        }
+    }
+    for question in &query.questions {
+        trace!("Question {} (type {}, class {}) from {} over {:?}", question.qname.join("."), question.qtype.to_byte().0, question.qclass, client, transport);
+    }
+
+    //The database and the preloaded zones may still be initializing
+    if !database::is_ready() {
+        trace!("Answered with SERVFAIL, the server is still starting");
+        return Some((not_ready(query).build(tcp), None));
+    }
+
+    //dns-over-https requests take their slot before the message is handled, see acquire_outstanding
+    let _outstanding = match transport {
+        Transport::Https => None,
+        _ => match acquire_outstanding(client) {
+            Ok(val) => val,
+            Err(_) => return Some((refused(query).build(tcp), None))
+        }
+    };
+
+    let max_size = udp_payload_limit(&query, config);
+    //ANY queries are a common means of amplification, over udp they (like other types configured as tcp only)
+    //are only answered with the TrunCation bit
+    if transport == Transport::Udp && query.questions.iter().any(|question| config.tcp_only(&question.qtype)) {
+        trace!("Answered with an empty truncated response, the type is only answered over tcp");
+        return Some((truncated(query, config).build(tcp), None));
+    }
+
+    let keepalive = transport == Transport::Tcp && requests_keepalive(&query, config);
+    let idle_timeout = if keepalive { Some(Duration::from_secs(config.tcp_keepalive_timeout)) } else { None };
+    let mut response = handle_query(query, client);
+    if keepalive {
+        //The timeout is given in units of 100 milliseconds
+        let timeout = std::cmp::min(config.tcp_keepalive_timeout * 10, u16::MAX as u64) as u16;
+        response = response.add_option(KEEPALIVE_OPTION, timeout.to_be_bytes().to_vec());
+    }
+    if config.log_queries {
+        log!("Response {:?} with {} answers", response.header.rcode, response.answers.len());
+    }
+    //A failed validation is a bug of the server, not of the client
+    debug_assert_eq!(response.validate(), Ok(()), "Invalid response");
+    //Names are compressed before any answer is dropped to fit the udp payload size
+    if transport == Transport::Udp {
+        let answers = response.answers.len();
+        limit_answers(config, &mut response);
+        response.truncate_to(max_size);
+        if response.header.tc {
+            trace!("Truncated to {} of {} answers to fit {} bytes", response.answers.len(), answers, max_size);
+        }
+        trace!("Response {:?} with {} answers", response.header.rcode, response.answers.len());
+        stats::record_answer_count(response.answers.len());
+        return Some((response.build_compressed(tcp), None));
+    }
+
+    trace!("Response {:?} with {} answers", response.header.rcode, response.answers.len());
+    stats::record_answer_count(response.answers.len());
+    Some((response.build(tcp), idle_timeout))
+}
+
+///Takes a slot of the queries of a client which are being resolved, so that a client waiting on many slow queries
+///can't occupy every worker thread. Returns [TooManyOutstanding](Refusal::TooManyOutstanding) if the client already
+///has max_outstanding_per_client queries outstanding, and no slot if the limit is disabled
+pub fn acquire_outstanding(client: IpAddr) -> Result<Option<ClientLimitGuard<'static>>, Refusal> {
+    let config = config::get();
+    if config.max_outstanding_per_client == 0 {
+        return Ok(None);
+    }
+
+    match OUTSTANDING.try_acquire(client, config.max_outstanding_per_client) {
+        Some(val) => Ok(Some(val)),
         None => {
-            return None;
+            if config.log_queries {
+                log!("Refused query from {}, too many outstanding queries", client);
+            }
+            trace!("Refused, the client has {} outstanding queries", config.max_outstanding_per_client);
+            Err(Refusal::TooManyOutstanding)
+        }
+    }
+}
+
+//Whether the query carries the edns-tcp-keepalive option and keep-alive is enabled
+fn requests_keepalive(query: &DnsQuery, config: &Config) -> bool {
+    config.tcp_keepalive_timeout != 0
+    && query.edns.as_ref().is_some_and(|edns| edns.options.iter().any(|(code, _)| *code == KEEPALIVE_OPTION))
+}
+
+//Drops the answers beyond the limits of the types asked for (answer_limits), setting the TrunCation bit if any was dropped
+fn limit_answers(config: &Config, response: &mut DnsResponse) {
+    let limits: Vec<AnswerLimit> = response.questions.iter().filter_map(|question| config.answer_limit(&question.qtype)).collect();
+    for limit in limits {
+        let mut bytes: usize = 0;
+        let mut kept: usize = 0;
+        for answer in &response.answers {
+            bytes += answer.rdata.len();
+            if limit.max_answers.is_some_and(|max| kept >= max) || limit.max_bytes.is_some_and(|max| bytes > max) {
+                break;
+            }
+            kept += 1;
+        }
+
+        if kept < response.answers.len() {
+            trace!("Limited {} answers to {} (at most {:?} answers of {:?} bytes)", response.answers.len(), kept, limit.max_answers, limit.max_bytes);
+            response.answers.truncate(kept);
+            response.header.an_count = kept as u16;
+            response.header.tc = true;
+        }
+    }
+}
+
+//The largest udp response to a query: the payload size advertised by the client,
+//capped by the configured size
+fn udp_payload_limit(query: &DnsQuery, config: &Config) -> usize {
+    let client_size = match &query.edns {
+        Some(edns) => std::cmp::max(edns.udp_size, MIN_UDP_SIZE),
+        None => return MIN_UDP_SIZE as usize
+    };
+
+    std::cmp::min(client_size, std::cmp::max(config.edns_udp_size, MIN_UDP_SIZE)) as usize
+}
+
+/// # An enum which represents the reasons for refusing to answer a client
+///Transports able to express these reasons (such as dns-over-https) use them instead of a REFUSED response
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Refusal {
+    ///The client sent more queries than it is allowed to
+    RateLimited,
+    ///The client has more queries outstanding than it is allowed to (see [acquire_outstanding](acquire_outstanding))
+    TooManyOutstanding,
+    ///The query asks for a name on the blocklist
+    Blocked,
+    ///The query asks for a name which is not on the allowlist
+    NotAllowed
+}
+
+///Returns the reason the query would be refused, if it would be refused
+pub fn refusal(query: &DnsQuery) -> Option<Refusal> {
+    let config = config::get();
+    for question in &query.questions {
+        //Blocked names are otherwise answered with a dns response
+        if config.block_mode == BlockMode::Refused && config.blocked_zone(&question.qname).is_some() {
+            return Some(Refusal::Blocked);
         }
+        if config.allowlist_mode == AllowlistMode::Refused && !config.allowed(&question.qname) {
+            return Some(Refusal::NotAllowed);
+        }
+    }
+
+    None
+}
+
+//A FORMERR response to a tcp message which could not be parsed, with the id of the message if it was received
+fn format_error(buffer: &[u8]) -> DnsResponse {
+    let id = match buffer.get(2..4) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0
     };
 
+    DnsResponse::default()
+    .id(id)
+    .rcode(DnsResponseCode::FormatError)
+}
+
+//A REFUSED response to the query, with its questions
+fn refused(query: DnsQuery) -> DnsResponse {
+    let mut response = DnsResponse::default()
+    .id(query.header.id)
+    .rd(query.header.rd)
+    .ra(config::get().recursion_available);
+    for question in query.questions {
+        response = response.add_question(question);
+    }
+
+    response.rcode(DnsResponseCode::Refused)
+}
+
+//A SERVFAIL response to a query arriving during the startup, with the Extended DNS Error Not Ready if the client uses EDNS
+fn not_ready(query: DnsQuery) -> DnsResponse {
     let mut response = DnsResponse::default()
     .id(query.header.id)
-    .rd(query.header.rd);
-    if !query.header.rd {
-        response = response.rcode(DnsResponseCode::NxDomain);
+    .rd(query.header.rd)
+    .ra(config::get().recursion_available);
+    for question in query.questions {
+        response = response.add_question(question);
+    }
+    if query.edns.is_some() {
+        response = response.extended_error(ExtendedError::NotReady);
     }
 
+    response.rcode(DnsResponseCode::ServerFailure)
+}
+
+//An empty response to the query with the TrunCation bit set
+fn truncated(query: DnsQuery, config: &Config) -> DnsResponse {
+    let mut response = DnsResponse::default()
+    .id(query.header.id)
+    .rd(query.header.rd)
+    .ra(config.recursion_available);
     for question in query.questions {
+        response = response.add_question(question);
+    }
+    if query.edns.is_some() {
+        response = response.udp_size(config.edns_udp_size);
+    }
+
+    response.truncate()
+}
+
+fn handle_query(query: DnsQuery, client: IpAddr) -> DnsResponse {
+    let config = config::get();
+    let subnet = config.client_subnet(&client);
+    let client_edns = query.edns.is_some();
+    let dnssec_ok = query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+
+    let mut response = DnsResponse::default()
+    .id(query.header.id)
+    .rd(query.header.rd)
+    .ra(config.recursion_available);
+
+    //Identical questions are only answered once
+    let mut questions: Vec<DnsQuestion> = Vec::new();
+    for question in &query.questions {
+        if !questions.iter().any(|unique| same_question(unique, question)) {
+            questions.push(question.clone());
+        }
+    }
+
+    let echoed = if config.echo_duplicate_questions { &query.questions } else { &questions };
+    for question in echoed {
         response = response.add_question(question.clone());
+    }
+
+    if query.invalid_edns {
+        return response.rcode(DnsResponseCode::FormatError);
+    }
+
+    if query.header.opcode == NOTIFY_OPCODE {
+        return handle_notify(&query, client, &config, response);
+    }
+
+    //Type 0 and class 0 are reserved (RFC 6895), a query for them is malformed
+    if questions.iter().any(|question| question.qtype == DnsRecordType::NotImplemented(0, Vec::new()) || question.qclass == 0) {
+        return response.rcode(DnsResponseCode::FormatError);
+    }
+
+    if questions.len() > 1 && config.multi_question_mode == MultiQuestionMode::Refuse {
+        return response.rcode(DnsResponseCode::Refused);
+    }
+
+    for (i, question) in questions.into_iter().enumerate() {
+        if i > 0 && config.multi_question_mode == MultiQuestionMode::FirstOnly {
+            break; //Only echoed
+        }
+
+        if let Some(zone) = config.blocked_zone(&question.qname) {
+            trace!("Answered by the blocklist (zone {}, mode {:?})", zone, config.block_mode);
+            response = handle_blocked(&zone, &config, client_edns, response);
+            continue;
+        }
+        if !config.allowed(&question.qname) {
+            trace!("Answered by the allowlist (mode {:?})", config.allowlist_mode);
+            response = handle_not_allowed(&question.qname, &config, client_edns, response);
+            continue;
+        }
+
+        let (type_code, _) = question.qtype.to_byte();
+        if OBSOLETE_TYPES.contains(&type_code) {
+            debug!("Query for obsolete record type {}", type_code);
+            trace!("Obsolete record type {}, not implemented", type_code);
+            response = response.rcode(DnsResponseCode::NotImplemented);
+            continue;
+        }
 
+        //Names of the local zone are answered authoritatively, whether or not recursion is desired
+        if question.is_authoritative_candidate(&zone::suffixes()) || zone::contains(&question.qname) {
+            let authority = zone::authority(&question.qname);
+            trace!("Answered by the local zone{}", authority.as_ref().map_or(String::new(), |zone| format!(" {}", zone.name)));
+            response = handle_local(&question, authority, response.aa(true).rcode(DnsResponseCode::NoError));
+            continue;
+        }
+
+        if config.root_priming && type_code == NS_TYPE && question.qname.is_empty() && question.qclass == 1 {
+            trace!("Answered the priming query from the root hints");
+            response = handle_priming(response);
+            continue;
+        }
+
+        let own_name = config.server_addresses_for(&question.qname, false).is_some() || config.server_addresses_for(&question.qname, true).is_some();
+        if !config.recursion_available && !own_name && question.qclass != CHAOS_CLASS {
+            trace!("Refused, recursion is not available");
+            response = response.rcode(DnsResponseCode::Refused);
+            continue;
+        }
+        let forwarded = response.answers.len();
         response = match question.qtype {
-            DnsRecordType::A(_) => handle_a(question.qname.clone(), query.header.rd, response),
-            DnsRecordType::AAAA(_) => handle_aaaa(question.qname.clone(), query.header.rd, response),
-            DnsRecordType::TXT(_) => handle_txt(question.qname.clone(), response),
-            DnsRecordType::NotImplemented(num) => {
-                println!("Record Type not yet defined: {}", num);
+            DnsRecordType::A(_) => handle_a(question.qname.clone(), query.header.rd, subnet.as_deref(), response),
+            DnsRecordType::AAAA(_) => handle_aaaa(question.qname.clone(), query.header.rd, subnet.as_deref(), response),
+            DnsRecordType::TXT(_) if question.qclass == CHAOS_CLASS => handle_chaos(question.qname.clone(), response),
+            DnsRecordType::TXT(_) => handle_txt(&question.qname, subnet.as_deref(), response),
+            DnsRecordType::NotImplemented(ANY_TYPE, _) => handle_any(&question.qname, subnet.as_deref(), response),
+            _ if config.wire_upstream.is_some() => handle_passthrough(&question, &config, dnssec_ok, response),
+            DnsRecordType::NotImplemented(num, _) => {
+                log!("Record Type not yet defined: {}", num);
                 continue;
             },
             val => {
-                println!("Query not yet implemented: {:#?}", val);
+                log!("Query not yet implemented: {:#?}", val);
                 continue;
             }
+        };
+        if config.rebinding_protection && !own_name {
+            response = strip_private_answers(forwarded, response);
+        }
+    }
+
+    //Clients supporting EDNS get an OPT record, as do responses carrying an extended error.
+    //The options of the client are never echoed, unknown ones are ignored (RFC 6891)
+    if client_edns || response.edns.is_some() {
+        response = response.udp_size(config.edns_udp_size);
+    }
+    if dnssec_ok {
+        if let Some(edns) = response.edns.as_mut() {
+            edns.dnssec_ok = true; //Echoed as required by RFC 3225
+        }
+    }
+    else {
+        response = strip_dnssec(response);
+    }
+
+    response = order_aliases(response);
+    response = dedupe_answers(response);
+    response = rotate_answers(&config, client, response);
+    response = weight_answers(&config, response);
+    response = jitter_ttls(&config, response);
+    rewrite_answers(&config, response)
+}
+
+///Orders every run of local answers with the same name and type which have configured weights by weighted
+///random selection (records without a weight count as weight 1). This replaces their round-robin rotation
+fn weight_answers(config: &Config, mut response: DnsResponse) -> DnsResponse {
+    if !config.local_records.iter().any(|local| local.weight.is_some()) {
+        return response;
+    }
+
+    let mut start: usize = 0;
+    while start < response.answers.len() {
+        let mut end = start + 1;
+        while end < response.answers.len()
+        && response.answers[end].name == response.answers[start].name
+        && response.answers[end].r#type.to_byte().0 == response.answers[start].r#type.to_byte().0 {
+            end += 1;
+        }
+
+        let weights: Vec<Option<u32>> = response.answers[start..end].iter()
+        .map(|answer| zone::weight(&answer.name, answer.r#type.to_byte().0, &answer.rdata))
+        .collect();
+        if weights.iter().any(|weight| weight.is_some()) {
+            let mut remaining: Vec<(DnsAnswer, u64)> = response.answers[start..end].iter().cloned()
+            .zip(weights.iter().map(|weight| weight.unwrap_or(1) as u64))
+            .collect();
+
+            for i in start..end {
+                let total: u64 = remaining.iter().map(|(_, weight)| weight).sum();
+                let mut pick = if total == 0 { 0 } else { random() % total };
+                let index = remaining.iter()
+                .position(|(_, weight)| {
+                    if pick < *weight {
+                        return true;
+                    }
+                    pick -= weight;
+                    false
+                })
+                .unwrap_or(0);
+                response.answers[i] = remaining.remove(index).0;
+            }
         }
+
+        start = end;
     }
 
-    Some(response.build(tcp))
+    response
+}
+
+///Changes the ttl of every answer by a random amount of up to ttl_jitter_percent percent,
+///keeping it within the minimum ttl of its type and the largest valid ttl
+fn jitter_ttls(config: &Config, mut response: DnsResponse) -> DnsResponse {
+    if config.ttl_jitter_percent == 0 {
+        return response;
+    }
+
+    for answer in response.answers.iter_mut() {
+        let spread = answer.ttl as u64 * config.ttl_jitter_percent as u64 / 100;
+        let offset = (random() % (2 * spread + 1)) as i64 - spread as i64;
+        let min_ttl = std::cmp::min(config.min_ttl_for(&answer.r#type), MAX_TTL);
+        let ttl = (answer.ttl as i64 + offset).clamp(min_ttl as i64, MAX_TTL as i64);
+        answer.ttl = ttl as u32;
+    }
+
+    response
 }
 
-fn handle_a(name: Vec<String>, rd: bool, mut response: DnsResponse) -> DnsResponse {
-    let mut answer;
-    let name = {
-        let name_temp;
-        if name[&name.len()-1] == "home" {
-            name_temp = name[..name.len()-1].to_vec();
+///A random number, the standard library seeds every RandomState with random keys
+pub(crate) fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+///Removes the DNSSEC records (RRSIG, NSEC and NSEC3) from a response to a client which did not ask for them,
+///except for those of the type asked for by a question
+fn strip_dnssec(mut response: DnsResponse) -> DnsResponse {
+    let asked: Vec<u16> = response.questions.iter().map(|question| question.qtype.to_byte().0).collect();
+    let keep = |answer: &DnsAnswer| {
+        let (record_type, _) = answer.r#type.to_byte();
+        !DNSSEC_TYPES.contains(&record_type) || asked.contains(&record_type)
+    };
+
+    response.answers.retain(|answer| keep(answer));
+    response.authority_records.retain(|answer| keep(answer));
+    response.header.an_count = response.answers.len() as u16;
+    response.header.ns_count = response.authority_records.len() as u16;
+    response
+}
+
+//Answers holding the same record (for instance a record reached through two aliases) are only sent once,
+//with the lowest of their ttls
+fn dedupe_answers(mut response: DnsResponse) -> DnsResponse {
+    let mut answers: Vec<DnsAnswer> = Vec::new();
+    for answer in response.answers.drain(..) {
+        match answers.iter_mut().find(|kept| kept.same_record(&answer)) {
+            Some(kept) => kept.ttl = std::cmp::min(kept.ttl, answer.ttl),
+            None => answers.push(answer)
         }
-        else {
-            name_temp = name;
+    }
+    response.header.an_count = answers.len() as u16;
+    response.answers = answers;
+
+    response
+}
+
+//Moves every alias (CNAME) in front of the answers of its target, so the chain can be read in order (RFC 1034 3.6.2).
+//Answers of names which are no alias target keep their order, each followed by the chain it starts
+fn order_aliases(mut response: DnsResponse) -> DnsResponse {
+    let targets: Vec<String> = response.answers.iter().filter_map(alias_target).collect();
+    if targets.is_empty() {
+        return response;
+    }
+
+    let mut answers: Vec<Option<DnsAnswer>> = response.answers.drain(..).map(Some).collect();
+    let mut ordered: Vec<DnsAnswer> = Vec::new();
+    for i in 0..answers.len() {
+        let target = answers[i].as_ref().is_some_and(|answer| targets.contains(&answer.name.join(".").to_lowercase()));
+        if !target {
+            take_chain(&mut answers, i, &mut ordered);
         }
+    }
+    ordered.extend(answers.into_iter().flatten()); //Aliases pointing at each other
+    response.answers = ordered;
 
-        name_temp
+    response
+}
+
+//Takes an answer, followed by the answers of its target if it is an alias. Every answer is only taken once
+fn take_chain(answers: &mut Vec<Option<DnsAnswer>>, i: usize, ordered: &mut Vec<DnsAnswer>) {
+    let answer = match answers[i].take() {
+        Some(val) => val,
+        None => return
     };
+    let target = alias_target(&answer);
+    ordered.push(answer);
 
-    if !rd {
-        answer = match database::get_record(&name, DnsRecordType::SOA(None)) {
-            Some(val) => val,
-            None => {
-                response = response.rcode(DnsResponseCode::NxDomain);
-                return response;
+    if let Some(target) = target {
+        for j in 0..answers.len() {
+            if answers[j].as_ref().is_some_and(|answer| answer.name.join(".").to_lowercase() == target) {
+                take_chain(answers, j, ordered);
             }
-        };
-        answer = answer.name(name);
+        }
+    }
+}
 
-        response = response.add_answer(answer);
+//The (lowercase) name an alias points to
+fn alias_target(answer: &DnsAnswer) -> Option<String> {
+    if answer.r#type.to_byte().0 != 5 { //5 = CNAME record
+        return None;
     }
-    else {
-        answer = match database::get_record(&name, DnsRecordType::A(None)) {
+
+    Some(dns_request::wire_to_labels(&answer.rdata)?.join(".").to_lowercase())
+}
+
+///Rotates the order of every run of answers with the same name and type
+fn rotate_answers(config: &Config, client: IpAddr, mut response: DnsResponse) -> DnsResponse {
+    let offset = match config.round_robin {
+        RoundRobin::Off => return response,
+        RoundRobin::Global => ROTATION.fetch_add(1, Ordering::SeqCst),
+        RoundRobin::Client => client_hash(client) as usize
+    };
+
+    let mut start: usize = 0;
+    while start < response.answers.len() {
+        let mut end = start + 1;
+        while end < response.answers.len()
+        && response.answers[end].name == response.answers[start].name
+        && response.answers[end].r#type.to_byte().0 == response.answers[start].r#type.to_byte().0 {
+            end += 1;
+        }
+
+        let len = end - start;
+        response.answers[start..end].rotate_left(offset % len);
+        start = end;
+    }
+
+    response
+}
+
+//FNV-1a hash of the client address, which (unlike the hasher of the standard library) is the same for every build
+fn client_hash(client: IpAddr) -> u64 {
+    let octets = match client {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec()
+    };
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for octet in octets {
+        hash ^= octet as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+fn same_question(first: &DnsQuestion, second: &DnsQuestion) -> bool {
+    first.qtype == second.qtype
+    && first.qclass == second.qclass
+    && first.qname.join(".").to_lowercase() == second.qname.join(".").to_lowercase()
+}
+
+///Applies the configured rewrite rules to the address answers of a response
+fn rewrite_answers(config: &Config, mut response: DnsResponse) -> DnsResponse {
+    if config.rewrites.is_empty() {
+        return response;
+    }
+
+    for answer in response.answers.iter_mut() {
+        let (record_type, _) = answer.r#type.to_byte();
+        let address = match answer_address(answer) {
             Some(val) => val,
-            None => {
-                response = response.rcode(DnsResponseCode::NxDomain);
-                return response;
-            }
+            None => continue
         };
-        answer = answer.name(name);
 
-        response = response.add_answer(answer);
+        let rewritten = config.rewrites.iter()
+        .filter(|rule| rule.applies_to(&answer.name, record_type))
+        .find_map(|rule| rule.rewrite(address));
+
+        let record = match rewritten {
+            Some(IpAddr::V4(ip)) => DnsRecordType::new_a(&ip.to_string()),
+            Some(IpAddr::V6(ip)) => DnsRecordType::new_aaaa(&ip.to_string()),
+            None => continue
+        };
+        *answer = answer.clone().record(record);
     }
 
     response
 }
 
-fn handle_aaaa(name: Vec<String>, rd: bool, mut response: DnsResponse) -> DnsResponse {
-    let mut answer;
-    let name = {
-        let name_temp;
-        if name[&name.len()-1] == "home" {
-            name_temp = name[..name.len()-1].to_vec();
+//The address of an A or AAAA answer
+fn answer_address(answer: &DnsAnswer) -> Option<IpAddr> {
+    match (answer.r#type.to_byte().0, answer.rdata.len()) {
+        (1, 4) => Some(IpAddr::V4(Ipv4Addr::new(answer.rdata[0], answer.rdata[1], answer.rdata[2], answer.rdata[3]))),
+        (28, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&answer.rdata);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        _ => None
+    }
+}
+
+//Whether an address is only reachable within a network (RFC 1918, unique local, loopback, link-local or unspecified)
+fn is_private_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => is_private_address(IpAddr::V4(ipv4)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+            }
+        }
+    }
+}
+
+//Drops the private addresses among the answers from the index on (rebinding_protection), along with the aliases
+//leading to them if no address is left, so the name gets an empty answer. Local and preloaded records
+//(such as those of local_fallback) are kept
+fn strip_private_answers(from: usize, mut response: DnsResponse) -> DnsResponse {
+    let snapshot = snapshot::get();
+    let answers = response.answers.split_off(from);
+    let addresses = answers.iter().filter(|answer| answer_address(answer).is_some()).count();
+
+    let mut kept: Vec<DnsAnswer> = Vec::new();
+    for answer in answers {
+        let private = answer_address(&answer).is_some_and(is_private_address)
+        && snapshot.lookup(&answer.name, &answer.r#type).is_none()
+        && !zone::contains(&answer.name);
+        if private {
+            debug!("Dropped the private address answer for {}", answer.name.join("."));
         }
         else {
-            name_temp = name;
+            kept.push(answer);
         }
+    }
+    if addresses != 0 && !kept.iter().any(|answer| answer_address(answer).is_some()) {
+        kept.retain(|answer| answer.r#type.to_byte().0 != 5); //5 = CNAME record
+    }
 
-        name_temp
-    };
+    response.answers.append(&mut kept);
+    response.header.an_count = response.answers.len() as u16;
+    response
+}
 
-    if !rd {
-        answer = match database::get_record(&name, DnsRecordType::SOA(None)) {
-            Some(val) => val,
-            None => {
-                response = response.rcode(DnsResponseCode::NxDomain);
-                return response;
-            }
-        };
-        answer = answer.name(name);
+//Names under the home suffix are not zones of this server, they are forwarded without the suffix
+fn forwarded_name(name: Vec<String>) -> Vec<String> {
+    if dns_request::in_zone(&name, HOME_SUFFIX) {
+        return name[..name.len()-1].to_vec();
+    }
 
-        response = response.add_answer(answer);
+    name
+}
+
+fn handle_a(name: Vec<String>, rd: bool, subnet: Option<&str>, mut response: DnsResponse) -> DnsResponse {
+    if let Some(addresses) = config::get().server_addresses_for(&name, false) {
+        trace!("Answered with the addresses of the server");
+        return handle_server_name(name, addresses, response);
+    }
+
+    let name = forwarded_name(name);
+
+    if !rd {
+        response = handle_non_recursive(&name, subnet, response);
     }
     else {
-        answer = match database::get_record(&name, DnsRecordType::AAAA(None)) {
-            Some(val) => val,
-            None => {
-                response = response.rcode(DnsResponseCode::NxDomain);
-                return response;
+        //A name cached only as an alias is answered by following the alias within the cache
+        if let Some(answers) = database::follow_cname(&name, DnsRecordType::A(None), subnet) {
+            trace!("Answered by following {} cached aliases", answers.len() - 1);
+            for answer in answers {
+                response = response.add_answer(answer);
             }
+            return response;
+        }
+
+        let answers = match database::get_records(&name, DnsRecordType::A(None), subnet) {
+            Ok(val) => val,
+            Err(err) => return local_fallback(&name, &DnsRecordType::A(None), err, response)
         };
-        answer = answer.name(name);
+        for answer in answers {
+            response = response.add_answer(answer.name(name.clone()));
+        }
+    }
 
-        response = response.add_answer(answer);
+    response
+}
+
+//The names of this server are its own, so they are answered authoritatively with its addresses, without the cache or the upstream
+fn handle_server_name(name: Vec<String>, addresses: Vec<IpAddr>, mut response: DnsResponse) -> DnsResponse {
+    response = response.aa(true);
+    for address in addresses {
+        let record = match address {
+            IpAddr::V4(ip) => DnsRecordType::A(Some(ip.octets().to_vec())),
+            IpAddr::V6(ip) => DnsRecordType::AAAA(Some(ip.octets().to_vec()))
+        };
+        response = response.add_answer(
+            DnsAnswer::default()
+            .name(name.clone())
+            .ttl(SERVER_ADDRESS_TTL)
+            .record(Some(record))
+        );
     }
 
     response
 }
 
-fn handle_txt(fields: Vec<String>, mut response: DnsResponse) -> DnsResponse {
-    for field in fields {
-        let mut answer = DnsAnswer::default()
-        .name(vec!(field.clone()))
-        .ttl(30);
+//Answers a question for a name of the local zone. Names within a configured zone get the SOA record
+//of the zone for SOA queries of the apex and in the authority section of negative answers
+fn handle_local(question: &DnsQuestion, authority: Option<AuthoritativeZone>, mut response: DnsResponse) -> DnsResponse {
+    let mut answers = zone::lookup(&question.qname, &question.qtype).unwrap_or_default();
+    let apex = authority.as_ref().is_some_and(|zone| zone::is_apex(&question.qname, zone));
+    if let (Some(zone), DnsRecordType::SOA(_)) = (&authority, &question.qtype) {
+        if apex {
+            answers.push(zone::soa(zone));
+        }
+    }
 
-        let record = DnsRecordType::new_txt(
-            match field.as_str() {
-                "version" => "\"version=1.0\"",
-                "bind" => "\"bind=hello\"",
-                _ => "unknown=unknown"
+    if answers.is_empty() {
+        if let Some(zone) = authority {
+            if !apex && !zone::contains(&question.qname) {
+                //With a default address every name of the zone exists
+                match config::get().local_default_address() {
+                    Some(address) => if let Some(record) = address_record(&question.qtype, address) {
+                        trace!("Answered with the default address of the local zone");
+                        return response.add_answer(
+                            DnsAnswer::default()
+                            .name(question.qname.clone())
+                            .ttl(zone.ttl)
+                            .record(Some(record))
+                        );
+                    },
+                    None => response = response.rcode(DnsResponseCode::NxDomain)
+                }
             }
-        );
-
-        answer = answer.record(record);
+            let negative_ttl = std::cmp::min(zone.ttl, zone.minimum);
+            return response.add_auth_record(zone::soa(&zone).ttl(negative_ttl));
+        }
+    }
 
+    for answer in answers {
         response = response.add_answer(answer);
     }
 
     response
-}
\ No newline at end of file
+}
+
+//The record of an address if it is of the type asked for
+fn address_record(record_type: &DnsRecordType, address: IpAddr) -> Option<DnsRecordType> {
+    match (record_type, address) {
+        (DnsRecordType::A(_), IpAddr::V4(ip)) => Some(DnsRecordType::A(Some(ip.octets().to_vec()))),
+        (DnsRecordType::AAAA(_), IpAddr::V6(ip)) => Some(DnsRecordType::AAAA(Some(ip.octets().to_vec()))),
+        _ => None
+    }
+}
+
+//A NOTIFY of the primary of a secondary zone starts its refresh, the message is acknowledged before the refresh is done
+fn handle_notify(query: &DnsQuery, client: IpAddr, config: &Config, response: DnsResponse) -> DnsResponse {
+    let response = response.opcode(NOTIFY_OPCODE).aa(true);
+    let question = match query.questions.first() {
+        Some(val) => val,
+        None => return response.rcode(DnsResponseCode::FormatError)
+    };
+
+    let name = question.qname.join(".").to_lowercase();
+    let zone = config.secondary_zones.iter().find(|zone| zone.name.trim_end_matches(".").to_lowercase() == name);
+    match zone {
+        Some(zone) if secondary::accepts_notify(zone, &client) => {
+            log!("NOTIFY from {} for zone {}", client, zone.name);
+            if !secondary::notify(&zone.name) {
+                log!("No refresh task for zone {}", zone.name);
+            }
+            response
+        },
+        _ => {
+            log!("Refused NOTIFY from {} for {}", client, name);
+            response.rcode(DnsResponseCode::Refused)
+        }
+    }
+}
+
+//Answers the NS query for the root with the root servers, and their addresses as additional records
+fn handle_priming(mut response: DnsResponse) -> DnsResponse {
+    let hints = root_hints::get();
+    for server in &hints.servers {
+        response = response.add_answer(server.clone());
+    }
+    for glue in &hints.glue {
+        response = response.add_additional_record(glue.clone());
+    }
+
+    response
+}
+
+//Queries not desiring recursion are answered with the SOA record of the zone in the authority section.
+//If the name is an alias, the alias is answered and the SOA record is the one of its target
+fn handle_non_recursive(name: &[String], subnet: Option<&str>, response: DnsResponse) -> DnsResponse {
+    match database::get_records(name, DnsRecordType::SOA(None), subnet) {
+        Ok(val) => val.into_iter().fold(response, |response, answer| match answer.r#type {
+            DnsRecordType::CNAME(_) => response.add_answer(answer),
+            _ => response.add_auth_record(answer)
+        }),
+        Err(err) => record_error_response(err, response)
+    }
+}
+
+fn handle_aaaa(name: Vec<String>, rd: bool, subnet: Option<&str>, mut response: DnsResponse) -> DnsResponse {
+    if let Some(addresses) = config::get().server_addresses_for(&name, true) {
+        trace!("Answered with the addresses of the server");
+        return handle_server_name(name, addresses, response);
+    }
+
+    let name = forwarded_name(name);
+
+    if !rd {
+        response = handle_non_recursive(&name, subnet, response);
+    }
+    else {
+        //A name cached only as an alias is answered by following the alias within the cache
+        if let Some(answers) = database::follow_cname(&name, DnsRecordType::AAAA(None), subnet) {
+            trace!("Answered by following {} cached aliases", answers.len() - 1);
+            for answer in answers {
+                response = response.add_answer(answer);
+            }
+            return response;
+        }
+
+        let answers = match database::get_records(&name, DnsRecordType::AAAA(None), subnet) {
+            Ok(val) => val,
+            Err(err) => return local_fallback(&name, &DnsRecordType::AAAA(None), err, response)
+        };
+        for answer in answers {
+            response = response.add_answer(answer.name(name.clone()));
+        }
+    }
+
+    response
+}
+
+//Forwards the question to the wire upstream, passing the records of its answer within the bailiwick of the
+//question through unchanged
+fn handle_passthrough(question: &DnsQuestion, config: &Config, dnssec_ok: bool, mut response: DnsResponse) -> DnsResponse {
+    let server = match &config.wire_upstream {
+        Some(val) => val,
+        None => return response
+    };
+
+    let upstream = match wire_upstream::request_query(server, question, dnssec_ok) {
+        Ok(val) => val,
+        Err(err) => {
+            stats::record_upstream_error();
+            log!("Wire upstream failed: {}", err);
+            return response.rcode(DnsResponseCode::ServerFailure);
+        }
+    };
+
+    if upstream.header.rcode != DnsResponseCode::NoError {
+        return response.rcode(upstream.header.rcode);
+    }
+    for answer in google_dns::in_bailiwick(&question.qname.join("."), upstream.answers) {
+        response = response.add_answer(answer);
+    }
+
+    response
+}
+
+//With local_fallback a name which does not exist upstream is answered from the local records, if it has any
+fn local_fallback(name: &[String], record_type: &DnsRecordType, err: RecordError, mut response: DnsResponse) -> DnsResponse {
+    let nxdomain = matches!(err, RecordError::NotFound | RecordError::NxDomain(_));
+    if nxdomain && config::get().local_fallback {
+        if let Some(answers) = zone::lookup(name, record_type) {
+            trace!("Answered by the local zone, the upstream answered NXDOMAIN");
+            for answer in answers {
+                response = response.add_answer(answer);
+            }
+            return response;
+        }
+    }
+
+    record_error_response(err, response)
+}
+
+//Negative answers of the upstream carry the SOA record of the zone in the authority section,
+//so clients know for how long they may cache them
+fn record_error_response(err: RecordError, response: DnsResponse) -> DnsResponse {
+    match err {
+        RecordError::NotFound => response.rcode(DnsResponseCode::NxDomain),
+        RecordError::NxDomain(soa) => response.rcode(DnsResponseCode::NxDomain).add_auth_record(*soa),
+        RecordError::NoData(Some(soa)) => response.add_auth_record(*soa), //An empty answer
+        RecordError::NoData(None) => response,
+        RecordError::Unavailable => response.rcode(DnsResponseCode::ServerFailure), //The name may well exist
+        RecordError::Alias(_) => response.rcode(DnsResponseCode::ServerFailure)
+    }
+}
+
+//TXT records are resolved for the name as a whole like any other record, including service names such as
+//_dmarc.example.com or _sip._tcp.example.com
+fn handle_txt(name: &[String], subnet: Option<&str>, response: DnsResponse) -> DnsResponse {
+    match database::get_records(name, DnsRecordType::TXT(None), subnet) {
+        Ok(val) => val.into_iter().fold(response, |response, answer| response.add_answer(answer.name(name.to_vec()))),
+        Err(err) => record_error_response(err, response)
+    }
+}
+
+//Answers an ANY query with every cached record of the name. If none is cached, the records of each of the types are
+//fetched from the upstream, which is not asked ANY queries, so a name nobody asked for before is answered in full too
+fn handle_any(name: &[String], subnet: Option<&str>, mut response: DnsResponse) -> DnsResponse {
+    let mut answers: Vec<DnsAnswer> = database::get_all_records(name, subnet).into_iter()
+    .filter(|answer| ANY_TYPES.iter().any(|record_type| record_type.to_byte().0 == answer.r#type.to_byte().0))
+    .collect();
+    if answers.is_empty() {
+        for record_type in ANY_TYPES.iter() {
+            match database::get_record(name, record_type.clone(), subnet) {
+                Ok(val) => answers.push(val.name(name.to_vec())),
+                Err(err @ RecordError::NxDomain(_)) => return record_error_response(err, response),
+                Err(_) => continue //The name has no record of the type
+            }
+        }
+    }
+
+    for answer in answers {
+        response = response.add_answer(answer);
+    }
+
+    response
+}
+
+//The Extended DNS Error is only added for clients using EDNS, others must not get an OPT record (RFC 6891)
+fn handle_blocked(zone: &str, config: &Config, client_edns: bool, mut response: DnsResponse) -> DnsResponse {
+    response = match config.block_mode {
+        BlockMode::Refused | BlockMode::Drop => response.rcode(DnsResponseCode::Refused),
+        BlockMode::NxDomain => response.rcode(DnsResponseCode::NxDomain).add_auth_record(blocked_soa(zone, config)),
+        BlockMode::NoData => response.rcode(DnsResponseCode::NoError).add_auth_record(blocked_soa(zone, config))
+    };
+    if let (Some(error), true) = (config.blocklist_error, client_edns) {
+        response = response.extended_error(error);
+    }
+
+    response
+}
+
+fn handle_not_allowed(name: &[String], config: &Config, client_edns: bool, mut response: DnsResponse) -> DnsResponse {
+    response = match config.allowlist_mode {
+        AllowlistMode::NxDomain => response.rcode(DnsResponseCode::NxDomain).add_auth_record(blocked_soa(&name.join("."), config)),
+        _ => response.rcode(DnsResponseCode::Refused)
+    };
+    if let (Some(error), true) = (config.blocklist_error, client_edns) {
+        response = response.extended_error(error);
+    }
+
+    response
+}
+
+fn blocked_soa(zone: &str, config: &Config) -> DnsAnswer {
+    let zone: Vec<String> = zone.split(".").map(String::from).collect();
+    let mut rname = vec!(String::from("hostmaster"));
+    rname.extend(zone.clone());
+
+    let auth_record = DnsAuthRecord::new()
+    .mname(config.server_id().split(".").map(String::from).collect())
+    .rname(rname)
+    .serial(1)
+    .refresh(BLOCKED_TTL)
+    .retry(BLOCKED_TTL)
+    .expire(BLOCKED_TTL)
+    .minimum(config.negative_ttl);
+
+    DnsAnswer::default()
+    .name(zone)
+    .ttl(config.negative_ttl)
+    .record(DnsRecordType::new_soa(auth_record))
+}
+
+fn handle_chaos(name: Vec<String>, response: DnsResponse) -> DnsResponse {
+    let config = config::get();
+
+    let text = match name.join(".").to_lowercase().as_str() {
+        "version.bind" | "version.server" => String::from(env!("CARGO_PKG_VERSION")),
+        "id.server" | "hostname.bind" if config.chaos_identity => config.server_id(),
+        _ => return response.rcode(DnsResponseCode::Refused)
+    };
+
+    let answer = DnsAnswer::default()
+    .name(name)
+    .class(CHAOS_CLASS)
+    .ttl(0)
+    .record(DnsRecordType::new_txt(&text));
+
+    response.add_answer(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ Config, RewriteRule, LocalRecord, SecondaryZone, local_record };
+    use crate::dns_request::{ Edns, ParseError };
+    use crate::google_dns::mock;
+
+    fn labels(name: &str) -> Vec<String> {
+        name.split(".").map(String::from).collect()
+    }
+
+    fn client() -> IpAddr {
+        "192.0.2.53".parse().unwrap()
+    }
+
+    fn query(name: &str, qtype: u16) -> DnsQuery {
+        query_questions(&[(name, qtype, 1)])
+    }
+
+    fn query_questions(questions: &[(&str, u16, u16)]) -> DnsQuery {
+        dns_request::parse_query(&query_bytes(questions), false).unwrap()
+    }
+
+    fn query_bytes(questions: &[(&str, u16, u16)]) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec!(
+            0, 7, //id (7)
+            0b0000_0001, //qr (0), opcode (0), aa (0), tc (0), rd (1)
+            0, //ra (0), z (0), rcode (0)
+            0, questions.len() as u8, //qd_count
+            0, 0, //an_count
+            0, 0, //ns_count
+            0, 0 //ar_count
+        );
+        for (name, qtype, qclass) in questions {
+            for label in name.split(".") {
+                buffer.push(label.len() as u8);
+                buffer.extend_from_slice(label.as_bytes());
+            }
+            buffer.push(0);
+            buffer.extend_from_slice(&qtype.to_be_bytes());
+            buffer.extend_from_slice(&qclass.to_be_bytes());
+        }
+
+        buffer
+    }
+
+    #[test]
+    fn chaos_id_server_test() {
+        let config = Config {
+            server_id: Some(String::from("ns1")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_chaos(labels("id.server"), DnsResponse::default());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].class, CHAOS_CLASS);
+        assert_eq!(response.answers[0].rdata, vec!(3, 110, 115, 49)); //length (3), ns1
+    }
+
+    #[test]
+    fn chaos_hostname_bind_test() {
+        let config = Config {
+            server_id: Some(String::from("resolver-a")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_chaos(labels("HOSTNAME.BIND"), DnsResponse::default());
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, labels("HOSTNAME.BIND"));
+        assert_eq!(response.answers[0].rdata[0], 10);
+        assert_eq!(&response.answers[0].rdata[1..], "resolver-a".as_bytes());
+    }
+
+    #[test]
+    fn chaos_identity_disabled_test() {
+        let config = Config {
+            chaos_identity: false,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_chaos(labels("id.server"), DnsResponse::default());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn not_ready_test() {
+        config::set_for_test(Config::default());
+        database::set_not_ready_for_test();
+
+        let bytes = handle_message(query_bytes(&[("www.starting", 1, 1)]), Transport::Udp, client()).unwrap();
+        let response = dns_request::parse_response(&bytes, false).unwrap();
+
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, DnsResponseCode::ServerFailure);
+        assert_eq!(response.questions[0].qname, labels("www.starting"));
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn blocklist_extended_error_test() {
+        let config = Config {
+            blocklist: vec!(String::from("ads.example")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(with_edns(query("tracker.ads.example", 1), 1232), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.edns.unwrap().extended_error, Some(ExtendedError::Blocked));
+
+        //A client without EDNS gets no OPT record
+        let response = handle_query(query("tracker.ads.example", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(response.edns, None);
+        let bytes = handle_message(query_bytes(&[("tracker.ads.example", 1, 1)]), Transport::Udp, client()).unwrap();
+        assert_eq!(dns_request::parse_response(&bytes, false).unwrap().additional_records.len(), 0);
+        assert_eq!(&bytes[10..12], &[0, 0]); //ar_count
+    }
+
+    #[test]
+    fn allowlist_test() {
+        mock::respond("name=www.allowed&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.allowed.", "type": 1 }],
+            "Answer": [{ "name": "www.allowed.", "type": 1, "TTL": 600, "data": "192.0.2.7" }]
+        }"#);
+        let mut config = Config {
+            allowlist: vec!(String::from("allowed")),
+            allowlist_mode: AllowlistMode::Refused,
+            ..Config::default()
+        };
+        config::set_for_test(config.clone());
+
+        let response = handle_query(query("google.com", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(refusal(&query("google.com", 1)), Some(Refusal::NotAllowed));
+        assert_eq!(mock::calls(), 0);
+
+        let response = handle_query(query("www.allowed", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(refusal(&query("www.allowed", 1)), None);
+
+        config.allowlist_mode = AllowlistMode::NxDomain;
+        config::set_for_test(config);
+        let response = handle_query(query("google.com", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+        assert_eq!(response.authority_records.len(), 1);
+        assert_eq!(refusal(&query("google.com", 1)), None);
+    }
+
+    #[test]
+    fn refusal_test() {
+        let mut config = Config {
+            blocklist: vec!(String::from("ads.example")),
+            ..Config::default()
+        };
+        config::set_for_test(config.clone());
+
+        assert_eq!(refusal(&query("ads.example", 1)), Some(Refusal::Blocked));
+        assert_eq!(refusal(&query("example.org", 1)), None);
+
+        config.block_mode = BlockMode::NoData;
+        config::set_for_test(config);
+        assert_eq!(refusal(&query("ads.example", 1)), None);
+    }
+
+    #[test]
+    fn blocklist_nodata_test() {
+        let config = Config {
+            blocklist: vec!(String::from("ads.example")),
+            block_mode: BlockMode::NoData,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("ads.example", 1), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authority_records.len(), 1);
+        assert_eq!(response.authority_records[0].r#type.to_byte().0, 6);
+        assert_eq!(response.authority_records[0].name, labels("ads.example"));
+    }
+
+    #[test]
+    fn blocklist_nxdomain_test() {
+        let config = Config {
+            blocklist: vec!(String::from("ads.example")),
+            block_mode: BlockMode::NxDomain,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("www.ads.example", 1), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+        assert_eq!(response.authority_records.len(), 1);
+        assert_eq!(response.authority_records[0].ttl, 3600);
+    }
+
+    #[test]
+    fn rewrite_answers_test() {
+        let config = Config {
+            rewrites: vec!(
+                RewriteRule {
+                    name: String::from("*.example.com"),
+                    r#type: String::from("A"),
+                    from: Some(String::from("203.0.113.0/24")),
+                    to: String::from("10.1.2.0/24")
+                },
+                RewriteRule {
+                    name: String::from("host.example.org"),
+                    r#type: String::from("A"),
+                    from: None,
+                    to: String::from("192.168.0.10")
+                }
+            ),
+            ..Config::default()
+        };
+
+        let answer = |name: &str, ip: &str| DnsAnswer::default()
+        .name(labels(name))
+        .ttl(60)
+        .record(DnsRecordType::new_a(ip));
+        let response = DnsResponse::default()
+        .add_answer(answer("www.example.com", "203.0.113.7"))
+        .add_answer(answer("www.example.com", "198.51.100.7"))
+        .add_answer(answer("host.example.org", "198.51.100.8"))
+        .add_answer(answer("other.example.org", "198.51.100.9"));
+
+        let response = rewrite_answers(&config, response);
+
+        assert_eq!(response.answers[0].rdata, vec!(10, 1, 2, 7));
+        assert_eq!(response.answers[1].rdata, vec!(198, 51, 100, 7));
+        assert_eq!(response.answers[2].rdata, vec!(192, 168, 0, 10));
+        assert_eq!(response.answers[3].rdata, vec!(198, 51, 100, 9));
+        assert_eq!(response.answers[0].ttl, 60);
+    }
+
+    #[test]
+    fn multi_question_mode_test() {
+        let two_questions = || query_questions(&[("version.bind", 16, CHAOS_CLASS), ("version.server", 16, CHAOS_CLASS)]);
+        let mut config = Config::default();
+
+        config::set_for_test(config.clone());
+        let response = handle_query(two_questions(), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, labels("version.bind"));
+
+        config.multi_question_mode = MultiQuestionMode::ResolveAll;
+        config::set_for_test(config.clone());
+        let response = handle_query(two_questions(), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(response.answers.len(), 2);
+
+        config.multi_question_mode = MultiQuestionMode::Refuse;
+        config::set_for_test(config);
+        let response = handle_query(two_questions(), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(response.answers.len(), 0);
+
+        //A single question is never refused
+        let response = handle_query(query_questions(&[("version.bind", 16, CHAOS_CLASS)]), client());
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn database_unavailable_test() {
+        database::break_table_for_test("unavailable");
+        config::set_for_test(Config::default());
+
+        let response = handle_query(query("www.unavailable", 1), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::ServerFailure);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn duplicate_questions_test() {
+        mock::respond("name=www.dedupe&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.dedupe.", "type": 1 }],
+            "Answer": [{ "name": "www.dedupe.", "type": 1, "TTL": 600, "data": "192.0.2.9" }]
+        }"#);
+        let mut config = Config {
+            multi_question_mode: MultiQuestionMode::ResolveAll,
+            ..Config::default()
+        };
+        config::set_for_test(config.clone());
+
+        let duplicated = || query_questions(&[("www.dedupe", 1, 1), ("WWW.dedupe", 1, 1)]);
+        let response = handle_query(duplicated(), client());
+
+        assert_eq!(mock::calls(), 1);
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.questions.len(), 2);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 9));
+
+        config.echo_duplicate_questions = false;
+        config.multi_question_mode = MultiQuestionMode::Refuse; //Not refused, there is only one unique question
+        config::set_for_test(config);
+        let response = handle_query(duplicated(), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    fn with_edns(mut query: DnsQuery, udp_size: u16) -> DnsQuery {
+        let edns = Edns {
+            udp_size,
+            ..Edns::default()
+        };
+        query.edns = Some(edns);
+        query
+    }
+
+    #[test]
+    fn edns_udp_size_test() {
+        let config = Config {
+            edns_udp_size: 1400,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(with_edns(query("version.bind", 16), 4096), client());
+        assert_eq!(response.edns.clone().unwrap().udp_size, 1400);
+
+        let bytes = response.build(false);
+        let opt = &bytes[bytes.len()-11..];
+        assert_eq!(&opt[0..3], &[0, 0, 41]); //Root owner name, type (41 = OPT)
+        assert_eq!(u16::from_be_bytes([opt[3], opt[4]]), 1400);
+
+        //Clients without EDNS get no OPT record
+        let response = handle_query(query("version.bind", 16), client());
+        assert!(response.edns.is_none());
+    }
+
+    #[test]
+    fn udp_truncation_test() {
+        let mut config = Config {
+            edns_udp_size: 1232,
+            ..Config::default()
+        };
+        for i in 0..30 {
+            config.local_records.push(local_record("big.example.home", "A", &format!("192.0.2.{}", i)));
+        }
+        config::set_for_test(config);
+
+        let mut message = query_bytes(&[("big.example.home", 1, 1)]);
+        let bytes = handle_message(message.clone(), Transport::Udp, client()).unwrap();
+        let response = dns_request::parse_response(&bytes, false).unwrap();
+        assert!(response.header.tc); //30 answers do not fit in 512 bytes, even compressed
+        assert!(!response.answers.is_empty() && response.answers.len() < 30);
+        assert!(bytes.len() <= 512);
+
+        let response = handle_message(message.clone(), Transport::Https, client()).unwrap();
+        let response = dns_request::parse_response(&response, false).unwrap();
+        assert!(!response.header.tc);
+        assert_eq!(response.answers.len(), 30);
+
+        //An OPT record advertising 4096 bytes, capped at the configured 1232 bytes
+        message[11] = 1; //ar_count
+        message.extend_from_slice(&[0, 0, 41, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        let response = handle_message(message, Transport::Udp, client()).unwrap();
+        let response = dns_request::parse_response(&response, false).unwrap();
+        assert!(!response.header.tc);
+        assert_eq!(response.answers.len(), 30);
+        assert_eq!(response.edns.unwrap().udp_size, 1232);
+    }
+
+    #[test]
+    fn reserved_type_and_class_test() {
+        config::set_for_test(Config::default());
+
+        let response = handle_query(query("example.com", 0), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::FormatError);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.answers.len(), 0);
+
+        let response = handle_query(query_questions(&[("version.bind", 16, 0)]), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::FormatError);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn invalid_edns_test() {
+        config::set_for_test(Config::default());
+
+        let mut bytes = query_bytes(&[("version.bind", 16, 3)]);
+        bytes[11] = 2; //ar_count (2)
+        for _ in 0..2 {
+            bytes.extend(&[0, 0, 41, 0x10, 0x00, 0, 0, 0, 0, 0, 0]); //OPT record
+        }
+
+        let response = handle_query(dns_request::parse_query(&bytes, false).unwrap(), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::FormatError);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn correlation_id_test() {
+        mock::respond("name=www.correlate&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.correlate.", "type": 1 }],
+            "Answer": [
+                { "name": "other.correlate.", "type": 1, "TTL": 60, "data": "203.0.113.1" },
+                { "name": "www.correlate.", "type": 1, "TTL": 60, "data": "192.0.2.1" }
+            ]
+        }"#);
+        let config = Config {
+            log_queries: true,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        handle_message(query_bytes(&[("www.correlate", 1, 1)]), Transport::Udp, client()).unwrap();
+        handle_message(query_bytes(&[("version.bind", 16, CHAOS_CLASS)]), Transport::Udp, client()).unwrap();
+
+        let lines = logging::captured();
+        let id = |text: &str| {
+            let line = lines.iter().find(|line| line.contains(text)).unwrap();
+            String::from(&line[..10])
+        };
+        let first = id("for www.correlate");
+        assert_eq!(id("out-of-bailiwick"), first);
+        assert_eq!(id("Response NoError with 1 answers"), first);
+
+        let second = id("for version.bind");
+        assert_ne!(second, first);
+        assert_eq!(lines.iter().filter(|line| line.starts_with(&second)).count(), 2); //Query and response
+    }
+
+    #[test]
+    fn trace_test() {
+        mock::respond("name=www.traced&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.traced.", "type": 1 }],
+            "Answer": [{ "name": "www.traced.", "type": 1, "TTL": 600, "data": "192.0.2.8" }]
+        }"#);
+        let config = Config {
+            log_trace: true,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        handle_message(query_bytes(&[("www.traced", 1, 1)]), Transport::Udp, client()).unwrap();
+        handle_message(query_bytes(&[("www.traced", 1, 1)]), Transport::Udp, client()).unwrap();
+
+        let lines: Vec<String> = logging::captured().iter()
+        .filter_map(|line| line.split_once("trace: "))
+        .map(|(_, step)| String::from(step))
+        .collect();
+        assert_eq!(lines, vec!(
+            "Question www.traced (type 1, class 1) from 192.0.2.53 over Udp",
+            "Cache miss for www.traced (type 1), asking the upstream",
+            "Response NoError with 1 answers",
+            "Question www.traced (type 1, class 1) from 192.0.2.53 over Udp",
+            "Cache hit for www.traced (type 1), ttl 600",
+            "Response NoError with 1 answers"
+        ));
+    }
+
+    #[test]
+    fn read_only_cache_test() {
+        mock::respond("name=www.readonly&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.readonly.", "type": 1 }],
+            "Answer": [{ "name": "www.readonly.", "type": 1, "TTL": 60, "data": "192.0.2.1" }]
+        }"#);
+        let config = Config {
+            read_only_cache: true,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("www.readonly", 1), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(mock::calls(), 0);
+        assert!(!database::dump_cache().iter().any(|entry| entry.name == "www.readonly"));
+    }
+
+    #[test]
+    fn negative_ttl_test() {
+        let config = Config {
+            blocklist: vec!(String::from("ads.example")),
+            block_mode: BlockMode::NxDomain,
+            negative_ttl: 900,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("ads.example", 1), client());
+
+        let soa = &response.authority_records[0];
+        assert_eq!(soa.ttl, 900);
+        match &soa.r#type {
+            DnsRecordType::SOA(Some(auth_record)) => assert_eq!(auth_record.minimum, 900),
+            other => panic!("Expected a SOA record, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn outstanding_limit_test() {
+        let config = Config {
+            max_outstanding_per_client: 2,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let busy: IpAddr = "198.51.100.70".parse().unwrap();
+        let _slow = (OUTSTANDING.try_acquire(busy, 2), OUTSTANDING.try_acquire(busy, 2)); //Queries still awaiting the upstream
+        let message = query_bytes(&[("version.bind", 16, CHAOS_CLASS)]);
+
+        let response = handle_message(message.clone(), Transport::Udp, busy).unwrap();
+        let response = dns_request::parse_response(&response, false).unwrap();
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(response.questions.len(), 1);
+
+        let response = handle_message(message, Transport::Udp, client()).unwrap();
+        let response = dns_request::parse_response(&response, false).unwrap();
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(OUTSTANDING.active(&client()), 0);
+    }
+
+    #[test]
+    fn client_unavailable_test() {
+        mock::fail_client();
+        let mut config = Config::default();
+        config.local_records.push(local_record("nas.noclient", "A", "192.0.2.20"));
+        config::set_for_test(config);
+
+        let response = handle_query(query("nas.noclient", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 20));
+
+        let response = handle_query(query("www.noclient", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::ServerFailure);
+    }
+
+    #[test]
+    fn local_cname_target_test() {
+        let mut config = Config::default();
+        for (name, r#type, value) in &[("www.localalias", "CNAME", "nas.localalias"), ("nas.localalias", "A", "192.0.2.40")] {
+            config.local_records.push(local_record(name, r#type, value));
+        }
+        config::set_for_test(config);
+
+        let response = handle_query(query("www.localalias", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.header.an_count, 2);
+        assert_eq!(response.answers[0].name, labels("www.localalias"));
+        assert_eq!(response.answers[0].r#type.to_byte().0, 5);
+        assert_eq!(response.answers[1].name, labels("nas.localalias"));
+        assert_eq!(response.answers[1].rdata, vec!(192, 0, 2, 40));
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn duplicate_answers_test() {
+        let mut config = Config::default();
+        for (address, ttl) in &[("192.0.2.30", 300), ("192.0.2.31", 300), ("192.0.2.30", 60)] {
+            config.local_records.push(LocalRecord { ttl: *ttl, ..local_record("nas.dupes", "A", address) });
+        }
+        config::set_for_test(config);
+
+        let response = handle_query(query("nas.dupes", 1), client());
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.header.an_count, 2);
+        let first = response.answers.iter().find(|answer| answer.rdata == vec!(192, 0, 2, 30)).unwrap();
+        assert_eq!(first.ttl, 60);
+    }
+
+    #[test]
+    fn partial_tcp_message_test() {
+        config::set_for_test(Config::default());
+        let mut message = query_bytes(&[("version.bind", 16, CHAOS_CLASS)]);
+        let mut tcp_bytes = ((message.len() + 10) as u16).to_be_bytes().to_vec(); //Larger than the payload
+        tcp_bytes.append(&mut message);
+
+        let response = handle_message(tcp_bytes, Transport::Tcp, client()).unwrap();
+        let response = dns_request::parse_response(&response, true).unwrap();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, DnsResponseCode::FormatError);
+    }
+
+    #[test]
+    fn priming_test() {
+        config::set_for_test(Config::default());
+        let mut bytes = query_bytes(&[]);
+        bytes[5] = 1; //qd_count (1)
+        bytes.extend_from_slice(&[0, 0, 2, 0, 1]); //root, qtype (2), qclass (1)
+        //Over udp without EDNS the glue doesn't fit into 512 bytes
+        let mut tcp_bytes = (bytes.len() as u16).to_be_bytes().to_vec();
+        tcp_bytes.extend(bytes);
+
+        let response = handle_message(tcp_bytes, Transport::Tcp, client()).unwrap();
+        let response = dns_request::parse_response(&response, true).unwrap();
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 13);
+        assert!(response.answers.iter().all(|answer| answer.name.is_empty() && answer.r#type.to_byte().0 == 2));
+        assert_eq!(response.answers[0].rdata, dns_request::labels_to_wire(&labels("A.ROOT-SERVERS.NET")).unwrap());
+
+        //Every root server has its ipv4 and ipv6 address as glue
+        assert_eq!(response.additional_records.len(), 26);
+        assert_eq!(response.additional_records[0].name, labels("A.ROOT-SERVERS.NET"));
+        assert_eq!(response.additional_records[0].rdata, vec!(198, 41, 0, 4));
+        assert_eq!(response.additional_records[1].r#type.to_byte().0, 28);
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn malformed_query_stats_test() {
+        config::set_for_test(Config::default());
+        let header = || query_bytes(&[]);
+        let with_question = |name: &[u8]| {
+            let mut bytes = header();
+            bytes[5] = 1; //qd_count (1)
+            bytes.extend_from_slice(name);
+            bytes.extend_from_slice(&[0, 1, 0, 1]);
+            bytes
+        };
+
+        let mut oversized = vec!(64);
+        oversized.extend_from_slice(&[b'a'; 64]);
+        oversized.push(0);
+        let malformed = vec!(
+            (ParseError::TooShort, header()[0..8].to_vec()),
+            (ParseError::TooShort, with_question(&[3, b'w', b'w'])),
+            (ParseError::BadPointer, with_question(&[0b1100_0000, 12])),
+            (ParseError::OversizedLabel, with_question(&oversized))
+        );
+
+        //The counters are shared with the other tests, which only ever add to them
+        for (reason, bytes) in malformed {
+            let before = stats::malformed(reason);
+            assert_eq!(handle_message(bytes, Transport::Udp, client()), None);
+            assert!(stats::malformed(reason) > before, "{:?} not counted", reason);
+        }
+    }
+
+    #[test]
+    fn nodata_test() {
+        mock::respond("name=www.nodata&type=28", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.nodata.", "type": 28 }]
+        }"#);
+        mock::respond("name=missing.nodata&type=28", r#"{
+            "Status": 3, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "missing.nodata.", "type": 28 }]
+        }"#);
+        config::set_for_test(Config::default());
+
+        let response = handle_query(query("www.nodata", 28), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert!(response.answers.is_empty());
+
+        let response = handle_query(query("missing.nodata", 28), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+    }
+
+    #[test]
+    fn upstream_negative_soa_test() {
+        mock::respond("name=missing.negsoa&type=1", r#"{
+            "Status": 3, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "missing.negsoa.", "type": 1 }],
+            "Authority": [{ "name": "negsoa.", "type": 6, "TTL": 1800, "data": "ns.negsoa. hostmaster.negsoa. 1 7200 3600 1209600 900" }]
+        }"#);
+        config::set_for_test(Config::default());
+
+        let response = handle_query(query("missing.negsoa", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+
+        //The soa is cached no longer than its minimum
+        let soa = &response.authority_records[0];
+        assert_eq!(soa.name, dns_request::domain_to_labels("negsoa"));
+        assert_eq!(soa.ttl, 900);
+        match &soa.r#type {
+            DnsRecordType::SOA(Some(auth_record)) => assert_eq!(auth_record.minimum, 900),
+            other => panic!("Expected a SOA record, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_round_robin_test() {
+        let mut config = Config {
+            round_robin: RoundRobin::Client,
+            ..Config::default()
+        };
+        for i in 1..4 {
+            config.local_records.push(local_record("rr.example.home", "A", &format!("192.0.2.{}", i)));
+        }
+        config::set_for_test(config);
+
+        let order = |client: &str| -> Vec<u8> {
+            let response = handle_query(query("rr.example.home", 1), client.parse().unwrap());
+            response.answers.iter().map(|answer| answer.rdata[3]).collect()
+        };
+
+        let first = order("192.0.2.1");
+        let second = order("198.51.100.7");
+        assert_eq!(first, vec!(2, 3, 1));
+        assert_eq!(second, vec!(3, 1, 2));
+        assert_eq!(order("192.0.2.1"), first);
+        assert_eq!(order("198.51.100.7"), second);
+    }
+
+    #[test]
+    fn opaque_passthrough_test() {
+        //AFSDB record: subtype (1), hostname afs1.example
+        let rdata: Vec<u8> = vec!(0, 1, 4, 97, 102, 115, 49, 7, 101, 120, 97, 109, 112, 108, 101, 0);
+        let config = Config {
+            wire_upstream: Some(wire_upstream::mock::serve(18, rdata.clone())),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("afs.example", 18), client());
+
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].r#type, DnsRecordType::NotImplemented(18, rdata.clone()));
+
+        let bytes = response.build(false);
+        let forwarded = dns_request::parse_response(&bytes, false).unwrap();
+        assert_eq!(forwarded.answers[0].name, labels("afs.example"));
+        assert_eq!(forwarded.answers[0].ttl, 300);
+        assert_eq!(forwarded.answers[0].rdata, rdata);
+
+        //MINFO record: the mailboxes admin.example and errors.example
+        let mut rdata = dns_request::labels_to_wire(&labels("admin.example")).unwrap();
+        rdata.extend(dns_request::labels_to_wire(&labels("errors.example")).unwrap());
+        let config = Config {
+            wire_upstream: Some(wire_upstream::mock::serve(14, rdata.clone())),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("list.example", 14), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].r#type, DnsRecordType::NotImplemented(14, rdata));
+    }
+
+    #[test]
+    fn passthrough_bailiwick_test() {
+        let rdata: Vec<u8> = vec!(0, 1, 4, 97, 102, 115, 49, 7, 101, 120, 97, 109, 112, 108, 101, 0);
+        let config = Config {
+            wire_upstream: Some(wire_upstream::mock::serve_unrelated(18, rdata, "bank.other.example")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        //The record of the unrelated name is dropped
+        let response = handle_query(query("afs.victim.example", 18), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, labels("afs.victim.example"));
+
+        //Records of the target of an alias are kept
+        let target = DnsAnswer::default().name(labels("cdn.provider.example")).record(DnsRecordType::new_a("192.0.2.10"));
+        let alias = DnsAnswer::default().name(labels("WWW.victim.example")).record(DnsRecordType::new_cname("cdn.provider.example"));
+        let unrelated = DnsAnswer::default().name(labels("www.victim.example.evil")).record(DnsRecordType::new_a("203.0.113.67"));
+        let kept = google_dns::in_bailiwick("www.victim.example", vec!(target.clone(), unrelated, alias.clone()));
+        assert_eq!(kept, vec!(target, alias));
+    }
+
+    #[test]
+    fn service_name_test() {
+        mock::respond("name=_dmarc.example.svctest&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "_dmarc.example.svctest.", "type": 16 }],
+            "Answer": [{ "name": "_dmarc.example.svctest.", "type": 16, "TTL": 600, "data": "\"v=DMARC1; p=none\"" }]
+        }"#);
+        config::set_for_test(Config::default());
+
+        let response = handle_query(query("_dmarc.example.svctest", 16), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, labels("_dmarc.example.svctest"));
+        assert_eq!(&response.answers[0].rdata[1..], b"v=DMARC1; p=none");
+        assert_eq!(mock::calls(), 1);
+
+        //Names which are not service names are resolved the same way
+        mock::respond("name=example.svctest&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "example.svctest.", "type": 16 }],
+            "Answer": [{ "name": "example.svctest.", "type": 16, "TTL": 600, "data": "\"v=spf1 -all\"" }]
+        }"#);
+        let response = handle_query(query("example.svctest", 16), client());
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, labels("example.svctest"));
+        assert_eq!(&response.answers[0].rdata[1..], b"v=spf1 -all");
+        assert_eq!(mock::calls(), 2);
+
+        //SRV records of service names are passed through from the wire upstream
+        let rdata: Vec<u8> = vec!(0, 10, 0, 5, 0x13, 0xc4, 3, 115, 105, 112, 7, 101, 120, 97, 109, 112, 108, 101, 0);
+        let config = Config {
+            wire_upstream: Some(wire_upstream::mock::serve(33, rdata.clone())),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("_sip._tcp.example.svctest", 33), client());
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, labels("_sip._tcp.example.svctest"));
+        assert_eq!(response.answers[0].rdata, rdata);
+    }
+
+    #[test]
+    fn server_addresses_test() {
+        let mut config = Config::default();
+        config.server_addresses.insert(String::from("resolver.lan"), vec!(String::from("192.0.2.1"), String::from("2001:db8::53")));
+        config::set_for_test(config);
+
+        let response = handle_query(query("Resolver.lan", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert!(response.header.aa);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 1));
+
+        let response = handle_query(query("resolver.lan", 28), client());
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rdata, "2001:db8::53".parse::<Ipv6Addr>().unwrap().octets().to_vec());
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn local_zone_authoritative_test() {
+        let mut config = Config::default();
+        config.local_records.push(local_record("printer.example.home", "A", "192.0.2.80"));
+        config::set_for_test(config);
+
+        let response = handle_query(query("printer.example.home", 1), client());
+        assert!(response.header.rd);
+        assert!(response.header.aa);
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+
+        //No AAAA record: an authoritative empty answer, the upstream is not asked
+        let response = handle_query(query("printer.example.home", 28), client());
+        assert!(response.header.aa);
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn udp_any_test() {
+        let config = Config {
+            truncate_udp_any: true,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        mock::respond("name=any.example&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "any.example.", "type": 16 }],
+            "Answer": [{ "name": "any.example.", "type": 16, "TTL": 300, "data": "\"any\"" }]
+        }"#);
+        mock::respond("name=any.example&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "any.example.", "type": 1 }],
+            "Answer": [{ "name": "any.example.", "type": 1, "TTL": 300, "data": "192.0.2.1" }]
+        }"#);
+        database::get_record(&labels("any.example"), DnsRecordType::A(None), None).unwrap();
+        database::get_record(&labels("any.example"), DnsRecordType::TXT(None), None).unwrap();
+
+        let bytes = query_bytes(&[("any.example", 255, 1)]);
+        let udp = handle_message(bytes.clone(), Transport::Udp, client()).unwrap();
+        assert_eq!(udp[2] & 0b0000_0010, 0b0000_0010); //tc (1)
+        assert_eq!(&udp[4..8], &[0, 1, 0, 0]); //qd_count (1), an_count (0)
+
+        let mut tcp_bytes = (bytes.len() as u16).to_be_bytes().to_vec();
+        tcp_bytes.extend(bytes);
+        let tcp = handle_message(tcp_bytes, Transport::Tcp, client()).unwrap();
+        assert_eq!(tcp[4] & 0b0000_0010, 0); //tc (0)
+        assert_eq!(&tcp[6..10], &[0, 1, 0, 2]); //qd_count (1), an_count (2)
+
+        //A name without cached records is fetched type by type
+        mock::respond("name=uncached.any.example&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "uncached.any.example.", "type": 16 }],
+            "Answer": [{ "name": "uncached.any.example.", "type": 16, "TTL": 300, "data": "\"any\"" }]
+        }"#);
+        mock::respond("name=uncached.any.example&type=28", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "uncached.any.example.", "type": 28 }],
+            "Answer": [{ "name": "uncached.any.example.", "type": 28, "TTL": 300, "data": "2001:db8::1" }]
+        }"#);
+        let response = handle_query(query("uncached.any.example", 255), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 2);
+        assert!(response.answers.iter().all(|answer| answer.name == labels("uncached.any.example")));
+    }
+
+    #[test]
+    fn answer_limits_test() {
+        let mut config = Config::default();
+        config.answer_limits.insert(String::from("ANY"), AnswerLimit { max_answers: Some(1), max_bytes: None });
+        config.answer_limits.insert(String::from("txt"), AnswerLimit { max_answers: None, max_bytes: Some(6) });
+        config::set_for_test(config);
+
+        mock::respond("name=limited.example&type=16", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "limited.example.", "type": 16 }],
+            "Answer": [{ "name": "limited.example.", "type": 16, "TTL": 300, "data": "\"limited\"" }]
+        }"#);
+        mock::respond("name=limited.example&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "limited.example.", "type": 1 }],
+            "Answer": [{ "name": "limited.example.", "type": 1, "TTL": 300, "data": "192.0.2.1" }]
+        }"#);
+        database::get_record(&labels("limited.example"), DnsRecordType::A(None), None).unwrap();
+        database::get_record(&labels("limited.example"), DnsRecordType::TXT(None), None).unwrap();
+
+        //The ANY response over udp is capped to a single record
+        let bytes = query_bytes(&[("limited.example", 255, 1)]);
+        let udp = handle_message(bytes.clone(), Transport::Udp, client()).unwrap();
+        let udp = dns_request::parse_response(&udp, false).unwrap();
+        assert!(udp.header.tc);
+        assert_eq!(udp.answers.len(), 1);
+
+        let mut tcp_bytes = (bytes.len() as u16).to_be_bytes().to_vec();
+        tcp_bytes.extend(bytes);
+        let tcp = handle_message(tcp_bytes, Transport::Tcp, client()).unwrap();
+        let tcp = dns_request::parse_response(&tcp, true).unwrap();
+        assert!(!tcp.header.tc);
+        assert_eq!(tcp.answers.len(), 2);
+
+        //The TXT record of 8 bytes exceeds the byte limit, while the A record has no limit
+        let udp = handle_message(query_bytes(&[("limited.example", 16, 1)]), Transport::Udp, client()).unwrap();
+        let udp = dns_request::parse_response(&udp, false).unwrap();
+        assert!(udp.header.tc);
+        assert_eq!(udp.answers.len(), 0);
+        let udp = handle_message(query_bytes(&[("limited.example", 1, 1)]), Transport::Udp, client()).unwrap();
+        assert_eq!(dns_request::parse_response(&udp, false).unwrap().answers.len(), 1);
+    }
+
+    #[test]
+    fn tcp_only_types_test() {
+        let config = Config {
+            tcp_only_types: vec!(String::from("ANY"), String::from("TXT")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        mock::respond("name=tcponly.example&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "tcponly.example.", "type": 1 }],
+            "Answer": [{ "name": "tcponly.example.", "type": 1, "TTL": 300, "data": "192.0.2.1" }]
+        }"#);
+        database::get_record(&labels("tcponly.example"), DnsRecordType::A(None), None).unwrap();
+
+        for qtype in &[255, 16] {
+            let bytes = query_bytes(&[("tcponly.example", *qtype, 1)]);
+            let udp = handle_message(bytes, Transport::Udp, client()).unwrap();
+            assert_eq!(udp[2] & 0b0000_0010, 0b0000_0010); //tc (1)
+            assert_eq!(&udp[6..8], &[0, 0]); //an_count (0)
+        }
+
+        let bytes = query_bytes(&[("tcponly.example", 255, 1)]);
+        let mut tcp_bytes = (bytes.len() as u16).to_be_bytes().to_vec();
+        tcp_bytes.extend(bytes);
+        let tcp = handle_message(tcp_bytes, Transport::Tcp, client()).unwrap();
+        assert_eq!(tcp[4] & 0b0000_0010, 0); //tc (0)
+        assert_eq!(&tcp[8..10], &[0, 1]); //an_count (1)
+
+        let udp = handle_message(query_bytes(&[("tcponly.example", 1, 1)]), Transport::Udp, client()).unwrap();
+        assert_eq!(udp[2] & 0b0000_0010, 0); //Other types are answered over udp
+    }
+
+    #[test]
+    fn non_recursive_test() {
+        config::set_for_test(Config::default());
+        mock::respond("name=www.norecurse&type=6", r#"{
+            "Status": 0, "TC": false, "RD": false, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.norecurse.", "type": 6 }],
+            "Authority": [{ "name": "norecurse.", "type": 6, "TTL": 300, "data": "ns.norecurse. hostmaster.norecurse. 1 7200 3600 1209600 300" }]
+        }"#);
+
+        let mut bytes = query_bytes(&[("www.norecurse", 1, 1)]);
+        bytes[2] = 0; //rd (0)
+        let response = handle_query(dns_request::parse_query(&bytes, false).unwrap(), client());
+
+        assert!(!response.header.rd);
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authority_records.len(), 1);
+        assert_eq!(response.authority_records[0].r#type.to_byte().0, 6);
+        assert_eq!(response.validate(), Ok(()));
+    }
+
+    #[test]
+    fn blocked_home_name_test() {
+        let config = Config {
+            blocklist: vec!(String::from("tracker.home"), String::from("ads")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("tracker.home", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        let response = handle_query(query("cdn.tracker.home", 28), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(mock::calls(), 0);
+
+        //The entry ads doesn't match ads.home, which is asked for as ads after stripping the suffix
+        mock::respond("name=ads&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "ads.", "type": 1 }],
+            "Answer": [{ "name": "ads.", "type": 1, "TTL": 300, "data": "192.0.2.101" }]
+        }"#);
+        let response = handle_query(query("ads.home", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 101));
+    }
+
+    #[test]
+    fn local_default_address_test() {
+        let mut config = Config {
+            local_default_address: Some(String::from("192.0.2.99")),
+            ..Config::default()
+        };
+        config.zones.push(AuthoritativeZone {
+            name: String::from("captive"),
+            mname: String::from("ns1.captive"),
+            rname: String::from("hostmaster.captive"),
+            serial: 2021010101,
+            refresh: 1000,
+            retry: 500,
+            expire: 100000,
+            minimum: 120,
+            ttl: 600,
+            notify: Vec::new()
+        });
+        config::set_for_test(config);
+
+        let response = handle_query(query("anything.captive", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert!(response.header.aa);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 99));
+        assert_eq!(response.answers[0].ttl, 600);
+
+        //The name exists, but has no ipv6 address
+        let response = handle_query(query("anything.captive", 28), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authority_records.len(), 1);
+
+        //Names outside the zone are still forwarded
+        mock::respond("name=www.notcaptive&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.notcaptive.", "type": 1 }],
+            "Answer": [{ "name": "www.notcaptive.", "type": 1, "TTL": 300, "data": "192.0.2.100" }]
+        }"#);
+        let response = handle_query(query("www.notcaptive", 1), client());
+        assert!(!response.header.aa);
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 100));
+        assert_eq!(mock::calls(), 1);
+    }
+
+    #[test]
+    fn authoritative_zone_test() {
+        let mut config = Config::default();
+        config.zones.push(AuthoritativeZone {
+            name: String::from("example.zone"),
+            mname: String::from("ns1.example.zone"),
+            rname: String::from("hostmaster.example.zone"),
+            serial: 2021010101,
+            refresh: 1000,
+            retry: 500,
+            expire: 100000,
+            minimum: 120,
+            ttl: 600,
+            notify: Vec::new()
+        });
+        config.local_records.push(local_record("www.example.zone", "A", "192.0.2.10"));
+        config::set_for_test(config);
+
+        let response = handle_query(query("example.zone", 6), client());
+        assert!(response.header.aa);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].ttl, 600);
+        let expected = DnsAuthRecord::new()
+        .mname(labels("ns1.example.zone"))
+        .rname(labels("hostmaster.example.zone"))
+        .serial(2021010101)
+        .refresh(1000)
+        .retry(500)
+        .expire(100000)
+        .minimum(120);
+        assert_eq!(response.answers[0].r#type, DnsRecordType::SOA(Some(expected)));
+
+        let response = handle_query(query("missing.example.zone", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authority_records.len(), 1);
+        assert_eq!(response.authority_records[0].ttl, 120);
+
+        let response = handle_query(query("www.example.zone", 28), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.authority_records.len(), 1);
+        assert_eq!(mock::calls(), 0);
+    }
+
+    #[test]
+    fn ttl_jitter_test() {
+        let mut config = Config {
+            ttl_jitter_percent: 10,
+            min_ttl: 950,
+            ..Config::default()
+        };
+
+        let mut ttls: Vec<u32> = Vec::new();
+        for _ in 0..500 {
+            let answer = DnsAnswer::default()
+            .name(labels("jitter.example"))
+            .ttl(1000)
+            .record(DnsRecordType::new_a("192.0.2.1"));
+            let response = jitter_ttls(&config, DnsResponse::default().add_answer(answer));
+            ttls.push(response.answers[0].ttl);
+        }
+
+        //Between 900 and 1100, raised to the minimum ttl of 950
+        assert!(ttls.iter().all(|ttl| *ttl >= 950 && *ttl <= 1100));
+        assert!(ttls.iter().any(|ttl| *ttl > 1000));
+        assert!(ttls.contains(&950));
+        assert!(ttls.iter().any(|ttl| *ttl > 950 && *ttl < 1000));
+
+        //A minimum ttl beyond the largest ttl is capped to it
+        config.min_ttl = u32::MAX;
+        let answer = DnsAnswer::default().name(labels("jitter.example")).ttl(1000).record(DnsRecordType::new_a("192.0.2.1"));
+        let response = jitter_ttls(&config, DnsResponse::default().add_answer(answer));
+        assert_eq!(response.answers[0].ttl, MAX_TTL);
+    }
+
+    #[test]
+    fn dnssec_ok_test() {
+        let config = Config {
+            wire_upstream: Some(wire_upstream::mock::serve(46, vec!(0, 18, 8, 2, 0, 0, 1, 44))),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let mut with_do = with_edns(query("signed.example", 18), 1232);
+        with_do.edns.as_mut().unwrap().dnssec_ok = true;
+        let response = handle_query(with_do, client());
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].r#type.to_byte().0, 46);
+        assert!(response.edns.unwrap().dnssec_ok);
+
+        let response = handle_query(with_edns(query("signed.example", 18), 1232), client());
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.header.an_count, 0);
+        assert!(!response.edns.unwrap().dnssec_ok);
+
+        //Asked for explicitly
+        let response = handle_query(query("signed.example", 46), client());
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn obsolete_type_test() {
+        let config = Config {
+            wire_upstream: Some(wire_upstream::mock::serve(38, vec!(0))),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let response = handle_query(query("legacy.example", 38), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NotImplemented);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.questions[0].qtype.to_byte().0, 38);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn weighted_answers_test() {
+        let mut config = Config {
+            round_robin: RoundRobin::Global,
+            ..Config::default()
+        };
+        for (address, weight) in &[("192.0.2.1", 70), ("192.0.2.2", 30)] {
+            config.local_records.push(LocalRecord { weight: Some(*weight), ..local_record("weighted.example.home", "A", address) });
+        }
+        config::set_for_test(config);
+
+        let mut first = 0;
+        for _ in 0..2000 {
+            let response = handle_query(query("weighted.example.home", 1), client());
+            assert_eq!(response.answers.len(), 2);
+            if response.answers[0].rdata[3] == 1 {
+                first += 1;
+            }
+        }
+
+        //70% of 2000, the tolerance is more than six standard deviations
+        assert!(first > 1260 && first < 1540, "{} of 2000", first);
+    }
+
+    #[test]
+    fn drop_blocked_test() {
+        let config = Config {
+            blocklist: vec!(String::from("ads.example")),
+            block_mode: BlockMode::Drop,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        assert_eq!(handle_message(query_bytes(&[("tracker.ads.example", 1, 1)]), Transport::Udp, client()), None);
+        assert!(handle_message(query_bytes(&[("version.bind", 16, 3)]), Transport::Udp, client()).is_some());
+    }
+
+    #[test]
+    fn notify_test() {
+        let primary = secondary::mock::serve("notify.test");
+        let config = Config {
+            secondary_zones: vec!(SecondaryZone {
+                name: String::from("notify.test"),
+                primary: primary.address.clone(),
+                allow_notify: Vec::new()
+            }),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let wait_for_serial = |serial: u32| {
+            for _ in 0..100 {
+                if secondary::serial("notify.test") == Some(serial) {
+                    return true;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            false
+        };
+        secondary::spawn_refresh();
+        assert!(wait_for_serial(1));
+
+        let mut notify = query_bytes(&[("notify.test", 6, 1)]);
+        notify[2] = 0b0010_0100; //qr (0), opcode (4), aa (1), tc (0), rd (0)
+
+        //Only the primary may notify, anyone else is refused
+        let response = dns_request::parse_response(&handle_message(notify.clone(), Transport::Udp, client()).unwrap(), false).unwrap();
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+
+        //The refresh interval is an hour, so the new serial is only transferred because of the NOTIFY
+        primary.serial.store(2, Ordering::SeqCst);
+        let primary_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let response = dns_request::parse_response(&handle_message(notify, Transport::Udp, primary_ip).unwrap(), false).unwrap();
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.header.opcode, NOTIFY_OPCODE);
+        assert!(response.header.aa);
+        assert!(wait_for_serial(2));
+        assert_eq!(primary.transfers.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn rebinding_protection_test() {
+        mock::respond("name=rebind.attacker&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "rebind.attacker.", "type": 1 }],
+            "Answer": [{ "name": "rebind.attacker.", "type": 1, "TTL": 300, "data": "192.168.1.1" }]
+        }"#);
+        mock::respond("name=public.attacker&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "public.attacker.", "type": 1 }],
+            "Answer": [{ "name": "public.attacker.", "type": 1, "TTL": 300, "data": "192.0.2.1" }]
+        }"#);
+        let mut config = Config {
+            rebinding_protection: true,
+            ..Config::default()
+        };
+        config.local_records.push(local_record("nas.lan", "A", "192.168.1.20"));
+        config::set_for_test(config);
+
+        let response = handle_query(query("rebind.attacker", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.header.an_count, 0);
+
+        let response = handle_query(query("public.attacker", 1), client());
+        assert_eq!(response.answers[0].rdata, vec!(192, 0, 2, 1));
+
+        let response = handle_query(query("nas.lan", 1), client());
+        assert_eq!(response.answers[0].rdata, vec!(192, 168, 1, 20));
+
+        assert!(is_private_address("10.0.0.1".parse().unwrap()));
+        assert!(is_private_address("169.254.0.1".parse().unwrap()));
+        assert!(is_private_address("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(is_private_address("fd00::1".parse().unwrap()));
+        assert!(is_private_address("fe80::1".parse().unwrap()));
+        assert!(!is_private_address("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn local_fallback_test() {
+        mock::respond("name=intranet&type=1", r#"{
+            "Status": 3, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "intranet.", "type": 1 }],
+            "Authority": [{ "name": ".", "type": 6, "TTL": 1800, "data": "a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400" }]
+        }"#);
+        let mut config = Config::default();
+        config.local_records.push(local_record("intranet", "A", "10.0.0.5"));
+        config::set_for_test(config.clone());
+
+        let response = handle_query(query("intranet.home", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+
+        config.local_fallback = true;
+        config::set_for_test(config);
+        let response = handle_query(query("intranet.home", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rdata, vec!(10, 0, 0, 5));
+        assert_eq!(response.authority_records.len(), 0);
+
+        //Names without local records are still NXDOMAIN
+        let response = handle_query(query("www.intranet.home", 1), client());
+        assert_eq!(response.header.rcode, DnsResponseCode::NxDomain);
+    }
+
+    #[test]
+    fn alias_order_test() {
+        let answer = |name: &str, record: Option<DnsRecordType>| DnsAnswer::default().name(labels(name)).ttl(60).record(record);
+        let response = DnsResponse::default()
+        .add_question(DnsQuestion { qname: labels("www.ordered"), qtype: DnsRecordType::A(None), qclass: 1 })
+        .add_answer(answer("cdn.ordered", DnsRecordType::new_a("192.0.2.1")))
+        .add_answer(answer("edge.ordered", DnsRecordType::new_cname("CDN.ordered")))
+        .add_answer(answer("other.ordered", DnsRecordType::new_a("192.0.2.2")))
+        .add_answer(answer("www.ordered", DnsRecordType::new_cname("edge.ordered")));
+
+        let names: Vec<String> = order_aliases(response).answers.iter().map(|answer| answer.name.join(".")).collect();
+        assert_eq!(names, vec!("other.ordered", "www.ordered", "edge.ordered", "cdn.ordered"));
+
+        //A chased local alias
+        let mut config = Config::default();
+        for (name, r#type, value) in &[("target.ordered.home", "A", "192.0.2.3"), ("alias.ordered.home", "CNAME", "target.ordered.home")] {
+            config.local_records.push(local_record(name, r#type, value));
+        }
+        config::set_for_test(config);
+
+        let response = handle_query(query("alias.ordered.home", 1), client());
+        let types: Vec<u16> = response.answers.iter().map(|answer| answer.r#type.to_byte().0).collect();
+        assert_eq!(types, vec!(5, 1));
+    }
+
+    #[test]
+    fn recursion_available_test() {
+        mock::respond("name=www.recursion&type=1", r#"{
+            "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{ "name": "www.recursion.", "type": 1 }],
+            "Answer": [{ "name": "www.recursion.", "type": 1, "TTL": 300, "data": "192.0.2.1" }]
+        }"#);
+        let mut config = Config::default();
+        config.local_records.push(local_record("printer.recursion.home", "A", "192.0.2.80"));
+        config::set_for_test(config.clone());
+
+        let response = handle_query(query("www.recursion", 1), client());
+        assert!(response.header.ra);
+        assert_eq!(response.answers.len(), 1);
+        assert!(handle_query(query("printer.recursion.home", 1), client()).header.ra);
+
+        //Authoritative only: the local zone is answered, everything else is refused
+        config.recursion_available = false;
+        config::set_for_test(config);
+        let calls = mock::calls();
+
+        let response = handle_query(query("printer.recursion.home", 1), client());
+        assert!(!response.header.ra);
+        assert!(response.header.aa);
+        assert_eq!(response.answers.len(), 1);
+
+        let response = handle_query(query("www.recursion", 1), client());
+        assert!(!response.header.ra);
+        assert_eq!(response.header.rcode, DnsResponseCode::Refused);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(mock::calls(), calls);
+    }
+}
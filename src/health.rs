@@ -0,0 +1,85 @@
+//! # Module for the health endpoint
+//! A liveness probe for container orchestrators, separate from the dns listeners. Every connection is answered
+//! with an http response (200 OK once the startup is complete and while the database is usable,
+//! 503 Service Unavailable otherwise) and then closed
+
+use std::io::{ BufRead, BufReader, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::thread;
+use std::time::Duration;
+
+use crate::database;
+use crate::doh::HttpResponse;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+///Accepts clients on the listener, answering each in its own thread
+pub fn serve(listener: TcpListener) {
+    serve_with_check(listener, || database::is_ready() && database::healthy());
+}
+
+fn serve_with_check(listener: TcpListener, check: fn() -> bool) {
+    for client in listener.incoming() {
+        if let Ok(client) = client {
+            thread::spawn(move || {
+                respond(client, check())
+            });
+        }
+        else {
+            println!("Failed to accept client (Health)");
+        }
+    }
+}
+
+fn respond(mut client: TcpStream, healthy: bool) {
+    //The request is read (and ignored) before answering, closing a connection with unread data would reset it
+    if let Ok(reader) = client.try_clone() {
+        let _ = reader.set_read_timeout(Some(READ_TIMEOUT));
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        while let Ok(num_bytes) = reader.read_line(&mut line) {
+            if num_bytes == 0 || line.trim_end().is_empty() {
+                break;
+            }
+            line.clear();
+        }
+    }
+
+    let response = if healthy {
+        HttpResponse::new(200).body(b"OK".to_vec())
+    }
+    else {
+        HttpResponse::new(503).body(b"Degraded".to_vec())
+    };
+    let _ = client.write_all(&response.build());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn probe(check: fn() -> bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve_with_check(listener, check));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn health_test() {
+        let healthy = probe(|| true);
+        assert!(healthy.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(healthy.ends_with("\r\n\r\nOK"));
+
+        let degraded = probe(|| false);
+        assert!(degraded.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+}
@@ -0,0 +1,33 @@
+extern crate rusqlite;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate base64;
+extern crate openssl;
+#[cfg(unix)]
+extern crate signal_hook;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+pub mod logging;
+
+pub mod dns_request;
+pub mod handle_data;
+pub mod google_dns;
+pub mod database;
+pub mod config;
+pub mod doh;
+pub mod limits;
+pub mod server;
+pub mod self_test;
+pub mod zone;
+pub mod snapshot;
+pub mod root_hints;
+pub mod wire_upstream;
+pub mod stats;
+pub mod secondary;
+pub mod notify;
+pub mod health;
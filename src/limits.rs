@@ -0,0 +1,176 @@
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::{ Mutex, MutexGuard };
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// # Struct counting the number of concurrently running tasks of a kind
+///A slot is taken with [try_acquire](Limit::try_acquire) and given back when the returned guard is dropped
+#[derive(Debug)]
+pub struct Limit {
+    active: AtomicUsize
+}
+
+/// # Guard holding one slot of a [Limit](Limit) until it is dropped
+#[derive(Debug)]
+pub struct LimitGuard<'a> {
+    limit: &'a Limit
+}
+
+impl Limit {
+    ///Returns a new limit with no slots taken
+    pub const fn new() -> Self {
+        Limit {
+            active: AtomicUsize::new(0)
+        }
+    }
+
+    ///Takes a slot if fewer than max slots are taken, otherwise returns None
+    pub fn try_acquire(&self, max: usize) -> Option<LimitGuard<'_>> {
+        let mut active = self.active.load(Ordering::SeqCst);
+        loop {
+            if active >= max {
+                return None;
+            }
+
+            match self.active.compare_exchange(active, active + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(LimitGuard { limit: self }),
+                Err(val) => active = val
+            }
+        }
+    }
+
+    ///Returns the number of slots currently taken
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Limit::new()
+    }
+}
+
+impl<'a> Drop for LimitGuard<'a> {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// # Struct counting the number of concurrently running tasks of each client
+///Like a [Limit](Limit), but with separate slots for every client address
+#[derive(Debug, Default)]
+pub struct ClientLimit {
+    active: Mutex<HashMap<IpAddr, usize>>
+}
+
+/// # Guard holding one slot of a client in a [ClientLimit](ClientLimit) until it is dropped
+#[derive(Debug)]
+pub struct ClientLimitGuard<'a> {
+    limit: &'a ClientLimit,
+    client: IpAddr
+}
+
+impl ClientLimit {
+    ///Returns a new limit with no slots taken
+    pub fn new() -> Self {
+        ClientLimit {
+            active: Mutex::new(HashMap::new())
+        }
+    }
+
+    ///Takes a slot of the client if it holds fewer than max slots, otherwise returns None
+    pub fn try_acquire(&self, client: IpAddr, max: usize) -> Option<ClientLimitGuard<'_>> {
+        let mut active = self.counts();
+        let count = active.get(&client).copied().unwrap_or(0);
+        if count >= max {
+            return None;
+        }
+        active.insert(client, count + 1);
+
+        Some(ClientLimitGuard { limit: self, client })
+    }
+
+    ///Returns the number of slots currently taken by the client
+    pub fn active(&self, client: &IpAddr) -> usize {
+        *self.counts().get(client).unwrap_or(&0)
+    }
+
+    //The counts are updated in single steps, so they are still valid after a thread panicked while holding the lock
+    fn counts(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.active.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<'a> Drop for ClientLimitGuard<'a> {
+    fn drop(&mut self) {
+        let mut active = self.limit.counts();
+        //Clients without running tasks are removed, so the map only holds active clients
+        if let Some(count) = active.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_test() {
+        let limit = Limit::new();
+
+        let first = limit.try_acquire(2);
+        let second = limit.try_acquire(2);
+        assert!(first.is_some() && second.is_some());
+        assert!(limit.try_acquire(2).is_none());
+        assert_eq!(limit.active(), 2);
+
+        drop(first);
+        assert_eq!(limit.active(), 1);
+        assert!(limit.try_acquire(2).is_some());
+    }
+
+    #[test]
+    fn client_limit_test() {
+        let limit = ClientLimit::new();
+        let busy: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = limit.try_acquire(busy, 1);
+        assert!(first.is_some());
+        assert!(limit.try_acquire(busy, 1).is_none());
+        assert!(limit.try_acquire(other, 1).is_some());
+
+        drop(first);
+        assert_eq!(limit.active(&busy), 0);
+        assert!(limit.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn poisoned_client_limit_test() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let limit = Arc::new(ClientLimit::new());
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let panicking = Arc::clone(&limit);
+        let result = thread::spawn(move || {
+            let _guard = panicking.active.lock().unwrap();
+            panic!("A task panicked while holding the lock");
+        }).join();
+        assert!(result.is_err() && limit.active.is_poisoned());
+
+        //Slots are still taken and given back
+        let guard = limit.try_acquire(client, 1);
+        assert!(guard.is_some());
+        assert!(limit.try_acquire(client, 1).is_none());
+        drop(guard);
+        assert_eq!(limit.active(&client), 0);
+        assert!(limit.try_acquire(client, 0).is_none());
+        assert!(limit.counts().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+//! # Module for logging
+//! Every query handled by [handle_message](crate::handle_data::handle_message) gets a correlation id,
+//! which prefixes all lines logged by the thread while the query is processed
+
+use std::cell::Cell;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT_ID: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+///Logs a line, prefixed by the correlation id of the query being processed (if there is one)
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logging::write(&format!($($arg)*))
+    };
+}
+
+///Logs a line like [log](log) if the configuration enables debug lines (log_debug)
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::config::get().log_debug {
+            $crate::logging::write(&format!($($arg)*))
+        }
+    };
+}
+
+///Logs a step of answering a query like [log](log) if the configuration enables tracing (log_trace).
+///Together with the correlation id the lines explain how the answer was produced
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::config::get().log_trace {
+            $crate::logging::write(&format!("trace: {}", format!($($arg)*)))
+        }
+    };
+}
+
+/// # Guard for the correlation id of a query
+///The id is used by the thread until the guard is dropped
+pub struct RequestGuard {
+    id: u64,
+    previous: Option<u64>
+}
+
+impl RequestGuard {
+    ///Returns the correlation id of the query
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        CURRENT_ID.with(|current| current.set(self.previous));
+    }
+}
+
+///Assigns a new correlation id to the query processed by this thread
+pub fn begin_request() -> RequestGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    let previous = CURRENT_ID.with(|current| current.replace(Some(id)));
+
+    RequestGuard {
+        id,
+        previous
+    }
+}
+
+///Returns the correlation id of the query processed by this thread
+pub fn current_id() -> Option<u64> {
+    CURRENT_ID.with(|current| current.get())
+}
+
+pub fn write(line: &str) {
+    let line = match current_id() {
+        Some(id) => format!("[{:08x}] {}", id, line),
+        None => String::from(line)
+    };
+
+    #[cfg(test)]
+    CAPTURED.with(|captured| captured.borrow_mut().push(line.clone()));
+
+    println!("{}", line);
+}
+
+///Returns the lines logged by this thread (only used by tests)
+#[cfg(test)]
+pub fn captured() -> Vec<String> {
+    CAPTURED.with(|captured| captured.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlation_id_test() {
+        assert_eq!(current_id(), None);
+
+        let outer = begin_request();
+        let outer_id = outer.id();
+        log!("outer {}", 1);
+        {
+            let inner = begin_request();
+            assert_ne!(inner.id(), outer_id);
+            log!("inner");
+        }
+        log!("outer {}", 2);
+        drop(outer);
+        log!("none");
+
+        let lines = captured();
+        assert_eq!(lines[0], format!("[{:08x}] outer 1", outer_id));
+        assert_ne!(&lines[1][..10], &lines[0][..10]);
+        assert_eq!(lines[2], format!("[{:08x}] outer 2", outer_id));
+        assert_eq!(lines[3], "none");
+    }
+}
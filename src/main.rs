@@ -1,19 +1,9 @@
-extern crate rusqlite;
-extern crate reqwest;
-extern crate serde;
-extern crate serde_json;
+extern crate dns;
 
-#[macro_use]
-extern crate lazy_static;
+use dns::{ database, config, doh, server, self_test, stats, secondary, notify, health, google_dns, snapshot };
 
-mod dns_request;
-mod handle_data;
-mod google_dns;
-mod database;
-
-use std::net::{ TcpListener, TcpStream, UdpSocket };
+use std::net::{ TcpListener, UdpSocket };
 use std::thread;
-use std::io::{ Read, Write };
 
 fn main() {
     //Startup (Errors can occur here)
@@ -22,91 +12,74 @@ fn main() {
     let server_tcp_v6 = TcpListener::bind("[::]:53").expect("Server failed to bind");
     let server_udp_v6 = UdpSocket::bind("[::]:53").expect("Server failed to bind");
 
-    database::init_db();
-    //No more expects in my code after this point
+    config::init_config();
+    let server_doh = config::get().doh_listen.as_ref().map(|address| TcpListener::bind(address).expect("Server failed to bind"));
+    let server_health = config::get().health_listen.as_ref().map(|address| TcpListener::bind(address).expect("Server failed to bind"));
+
+    //The servers start before the database and the preloaded zones, queries are answered with SERVFAIL until they are ready
+    if let Some(server_health) = server_health {
+        thread::spawn(move || {
+            println!("Health Endpoint Started");
+            health::serve(server_health);
+        });
+    }
+
+    if let Some(server_doh) = server_doh {
+        thread::spawn(move || {
+            println!("DoH Server Started");
+            doh::serve(server_doh);
+        });
+    }
 
     thread::spawn(move || {
         println!("Tcp (Ipv4) Server Started");
-        for client in server_tcp_v4.incoming() {
-            if let Ok(client) = client {
-                thread::spawn(move || {
-                    handle_tcp_client(client)
-                });
-            }
-            else {
-                println!("Failed to accept client (Tcp v4)");
-            }
-        }
+        server::serve_tcp(server_tcp_v4);
     });
 
     thread::spawn(move || {
         println!("Tcp (Ipv6) Server Started");
-        for client in server_tcp_v6.incoming() {
-            if let Ok(client) = client {
-                thread::spawn(move || {
-                    handle_tcp_client(client)
-                });
-            }
-            else {
-                println!("Failed to accept client (Tcp v6)");
-            }
-        }
+        server::serve_tcp(server_tcp_v6);
     });
 
     thread::spawn(move || {
         println!("Udp (Ipv4) Server Started");
-        handle_udp_server(server_udp_v4);
+        server::serve_udp(server_udp_v4);
     });
 
-    println!("Udp (Ipv6) Server Started");
-    handle_udp_server(server_udp_v6);
-}
-
-fn handle_udp_server(server: UdpSocket) {
-    loop {
-        let mut buffer: [u8; 2048] = [0; 2048];
-        let (num_bytes, client) = match server.recv_from(&mut buffer) {
-            Ok(val) => val,
-            Err(_) => {
-                //println!("Failed to accept client (Udp)");
-                continue;
-            }
-        };
-
-        let server_copy = match server.try_clone() {
-            Ok(val) => val,
-            Err(_) => continue
-        };
-        thread::spawn(move || {
-            let bytes = match handle_data::handle_message(buffer[0..num_bytes].to_vec(), false) {
-                Some(val) => val,
-                None => return
-            };
+    let udp_v6 = thread::spawn(move || {
+        println!("Udp (Ipv6) Server Started");
+        server::serve_udp(server_udp_v6);
+    });
 
-            match server_copy.send_to(&bytes, &client) {
-                Ok(_) => (),
-                Err(_) => return
+    database::init_db();
+    snapshot::init_snapshot();
+    if let Err(err) = google_dns::init_client() {
+        println!("Warning: failed to initialize the upstream client ({}), only the local zone and the cache are answered", err);
+    }
+    if config::get().self_test {
+        let failures = self_test::run();
+        if !failures.is_empty() {
+            println!("Warning: self-test failed for: {}", failures.join(", "));
+            if config::get().self_test_fatal {
+                std::process::exit(1);
             }
-        });
+        }
     }
-}
+    database::set_ready();
+    println!("Startup Complete");
+    //No more expects in my code after this point
 
-fn handle_tcp_client(mut client: TcpStream) {
-    let mut buffer: [u8; 2048] = [0; 2048];
-    let num_bytes = match client.read(&mut buffer) {
-        Ok(val) => val,
-        Err(_) => {
-            return;
-        }
-    };
+    secondary::spawn_refresh();
+    notify::notify_secondaries();
 
-    let bytes = match handle_data::handle_message(buffer[0..num_bytes].to_vec(), true) {
-        Some(val) => val,
-        None => return
-    };
+    #[cfg(unix)]
+    if let Err(err) = stats::log_on_signal() {
+        println!("Warning: failed to listen for SIGUSR1: {}", err);
+    }
+    #[cfg(unix)]
+    if let Err(err) = snapshot::reload_on_signal() {
+        println!("Warning: failed to listen for SIGHUP: {}", err);
+    }
 
-    match client.write(&bytes) {
-        Ok(_) => return,
-        Err(_) => return
-    };
-}
\ No newline at end of file
+    let _ = udp_v6.join();
+}
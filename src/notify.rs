@@ -0,0 +1,133 @@
+//! # Module for sending NOTIFY messages
+//! The secondary servers of a configured zone are notified when the zone is loaded,
+//! so they refresh it right away instead of waiting for the refresh interval (RFC 1996)
+
+use std::error::Error;
+use std::net::{ SocketAddr, UdpSocket };
+use std::thread;
+use std::time::Duration;
+
+use crate::dns_request::{ self, DnsQuery, DnsQuestion, DnsRecordType, DnsResponseCode };
+use crate::config::{ self, AuthoritativeZone };
+use crate::wire_upstream;
+
+const NOTIFY_OPCODE: u8 = 4;
+const SOA_TYPE: u16 = 6;
+const TIMEOUT: Duration = Duration::from_secs(2);
+//The number of times an unacknowledged NOTIFY is sent
+const ATTEMPTS: u32 = 5;
+
+///Notifies the secondaries of every configured zone, each in its own thread
+pub fn notify_secondaries() {
+    for zone in &config::get().zones {
+        for secondary in &zone.notify {
+            let zone = zone.clone();
+            let secondary = secondary.clone();
+            thread::spawn(move || {
+                match send(&zone, &secondary, ATTEMPTS) {
+                    Ok(_) => log!("Secondary {} acknowledged NOTIFY for zone {}", secondary, zone.name),
+                    Err(err) => log!("Failed to notify secondary {} of zone {}: {}", secondary, zone.name, err)
+                }
+            });
+        }
+    }
+}
+
+///Sends a NOTIFY for the zone to the secondary (ip:port) until it is acknowledged, at most attempts times.
+///A secondary answering with an error (such as REFUSED) is not asked again
+pub fn send(zone: &AuthoritativeZone, secondary: &str, attempts: u32) -> Result<(), Box<dyn Error>> {
+    let secondary: SocketAddr = secondary.parse()?;
+    let bind_address = if secondary.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_address)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(secondary)?;
+
+    let id = wire_upstream::next_id();
+    let mut query = DnsQuery::default()
+    .id(id)
+    .opcode(NOTIFY_OPCODE)
+    .add_question(DnsQuestion {
+        qname: dns_request::domain_to_labels(&zone.name),
+        qtype: DnsRecordType::NotImplemented(SOA_TYPE, Vec::new()),
+        qclass: 1
+    });
+    query.header.rd = false;
+    query.header.aa = true;
+    let message = query.build(false);
+
+    let mut buffer: [u8; 512] = [0; 512];
+    for _ in 0..attempts {
+        socket.send(&message)?;
+        //The NOTIFY is sent again once the read times out
+        while let Ok(num_bytes) = socket.recv(&mut buffer) {
+            let response = match dns_request::parse_response(&buffer[0..num_bytes], false) {
+                Some(val) => val,
+                None => continue
+            };
+
+            //Responses which do not belong to the NOTIFY are ignored
+            if response.header.id != id || !response.header.qr || response.header.opcode != NOTIFY_OPCODE {
+                continue;
+            }
+            if response.header.rcode != DnsResponseCode::NoError {
+                return Err(format!("NOTIFY answered with {:?}", response.header.rcode).into());
+            }
+            return Ok(());
+        }
+    }
+
+    Err("NOTIFY was not acknowledged".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_request::DnsResponse;
+
+    fn zone() -> AuthoritativeZone {
+        AuthoritativeZone {
+            name: String::from("example.zone"),
+            mname: String::from("ns1.example.zone"),
+            rname: String::from("hostmaster.example.zone"),
+            serial: 1,
+            refresh: 1000,
+            retry: 500,
+            expire: 100000,
+            minimum: 120,
+            ttl: 600,
+            notify: Vec::new()
+        }
+    }
+
+    //Answers a single NOTIFY with the rcode, returning its address
+    fn secondary(rcode: DnsResponseCode) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (num_bytes, primary) = socket.recv_from(&mut buffer).unwrap();
+            let query = dns_request::parse_query(&buffer[0..num_bytes].to_vec(), false).unwrap();
+            assert_eq!(query.header.opcode, NOTIFY_OPCODE);
+            assert!(query.header.aa);
+            assert_eq!(query.questions[0].qname, dns_request::domain_to_labels("example.zone"));
+            assert_eq!(query.questions[0].qtype.to_byte().0, SOA_TYPE);
+
+            let mut response = DnsResponse::default()
+            .id(query.header.id)
+            .opcode(NOTIFY_OPCODE)
+            .aa(true)
+            .rcode(rcode);
+            response.header.rd = false;
+            socket.send_to(&response.build(false), primary).unwrap();
+        });
+
+        address
+    }
+
+    #[test]
+    fn send_test() {
+        assert!(send(&zone(), &secondary(DnsResponseCode::NoError), 1).is_ok());
+        assert!(send(&zone(), &secondary(DnsResponseCode::Refused), 1).is_err());
+    }
+}
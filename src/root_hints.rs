@@ -0,0 +1,161 @@
+//! # Module for the root hints
+//! The NS records of the root and the addresses of the root servers, read from a file in the format of named.root
+//! (root_hints) or built in. Priming queries (NS for the root) are answered from them (see [root_priming](crate::config::Config::root_priming))
+
+use std::fs;
+
+use crate::dns_request::{ self, DnsAnswer, DnsRecordType };
+use crate::config;
+
+const NS_TYPE: u16 = 2;
+
+//The root hints as of 2023 (https://www.internic.net/domain/named.root)
+const BUILTIN_HINTS: &str = "
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000      A     170.247.170.2
+B.ROOT-SERVERS.NET.      3600000      AAAA  2801:1b8:10::b
+.                        3600000      NS    C.ROOT-SERVERS.NET.
+C.ROOT-SERVERS.NET.      3600000      A     192.33.4.12
+C.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:2::c
+.                        3600000      NS    D.ROOT-SERVERS.NET.
+D.ROOT-SERVERS.NET.      3600000      A     199.7.91.13
+D.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:2d::d
+.                        3600000      NS    E.ROOT-SERVERS.NET.
+E.ROOT-SERVERS.NET.      3600000      A     192.203.230.10
+E.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:a8::e
+.                        3600000      NS    F.ROOT-SERVERS.NET.
+F.ROOT-SERVERS.NET.      3600000      A     192.5.5.241
+F.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:2f::f
+.                        3600000      NS    G.ROOT-SERVERS.NET.
+G.ROOT-SERVERS.NET.      3600000      A     192.112.36.4
+G.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:12::d0d
+.                        3600000      NS    H.ROOT-SERVERS.NET.
+H.ROOT-SERVERS.NET.      3600000      A     198.97.190.53
+H.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:1::53
+.                        3600000      NS    I.ROOT-SERVERS.NET.
+I.ROOT-SERVERS.NET.      3600000      A     192.36.148.17
+I.ROOT-SERVERS.NET.      3600000      AAAA  2001:7fe::53
+.                        3600000      NS    J.ROOT-SERVERS.NET.
+J.ROOT-SERVERS.NET.      3600000      A     192.58.128.30
+J.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:c27::2:30
+.                        3600000      NS    K.ROOT-SERVERS.NET.
+K.ROOT-SERVERS.NET.      3600000      A     193.0.14.129
+K.ROOT-SERVERS.NET.      3600000      AAAA  2001:7fd::1
+.                        3600000      NS    L.ROOT-SERVERS.NET.
+L.ROOT-SERVERS.NET.      3600000      A     199.7.83.42
+L.ROOT-SERVERS.NET.      3600000      AAAA  2001:500:9f::42
+.                        3600000      NS    M.ROOT-SERVERS.NET.
+M.ROOT-SERVERS.NET.      3600000      A     202.12.27.33
+M.ROOT-SERVERS.NET.      3600000      AAAA  2001:dc3::35
+";
+
+lazy_static! {
+    static ref HINTS: RootHints = {
+        let contents = match &config::get().root_hints {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(val) => val,
+                Err(err) => {
+                    log!("Failed to read the root hints {}, using the built-in ones: {}", path, err);
+                    String::from(BUILTIN_HINTS)
+                }
+            },
+            None => String::from(BUILTIN_HINTS)
+        };
+
+        RootHints::parse(&contents)
+    };
+}
+
+/// # Struct representing the root hints
+#[derive(PartialEq, Debug, Clone)]
+pub struct RootHints {
+    ///The NS records of the root
+    pub servers: Vec<DnsAnswer>,
+    ///The A and AAAA records of the root servers
+    pub glue: Vec<DnsAnswer>
+}
+
+impl RootHints {
+    ///Parses root hints in the format of named.root. Comments (after ;) are ignored, as are lines which are not
+    ///an NS record of the root or an address record of a root server
+    pub fn parse(contents: &str) -> Self {
+        let mut hints = RootHints {
+            servers: Vec::new(),
+            glue: Vec::new()
+        };
+
+        for line in contents.lines() {
+            let line = line.split(";").next().unwrap_or("");
+            //name, ttl, optional class, type, value
+            let fields: Vec<&str> = line.split_whitespace().filter(|field| !field.eq_ignore_ascii_case("IN")).collect();
+            if fields.len() != 4 {
+                continue;
+            }
+            let ttl: u32 = match fields[1].parse() {
+                Ok(val) => val,
+                Err(_) => continue
+            };
+
+            let name = dns_request::domain_to_labels(fields[0]);
+            let record = match fields[2].to_uppercase().as_str() {
+                "NS" if name.is_empty() => dns_request::labels_to_wire(&dns_request::domain_to_labels(fields[3])).map(|rdata| DnsRecordType::NotImplemented(NS_TYPE, rdata)),
+                "A" => DnsRecordType::new_a(fields[3]),
+                "AAAA" => DnsRecordType::new_aaaa(fields[3]),
+                _ => None
+            };
+            let record = match record {
+                Some(val) => val,
+                None => continue
+            };
+
+            let answer = DnsAnswer::default()
+            .name(name)
+            .ttl(ttl)
+            .record(Some(record));
+            if answer.r#type.to_byte().0 == NS_TYPE {
+                hints.servers.push(answer);
+            }
+            else {
+                hints.glue.push(answer);
+            }
+        }
+
+        hints
+    }
+}
+
+///Returns the root hints, read on first use
+pub fn get() -> &'static RootHints {
+    &HINTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let hints = RootHints::parse("
+            ; formerly NS.INTERNIC.NET
+            .                        3600000      NS    A.ROOT-SERVERS.NET.
+            A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+            A.ROOT-SERVERS.NET.      3600000  IN  AAAA  2001:503:ba3e::2:30 ; with a class
+            example.                 3600000      NS    A.ROOT-SERVERS.NET.
+            B.ROOT-SERVERS.NET.      3600000      A     not an address
+        ");
+
+        assert_eq!(hints.servers.len(), 1);
+        assert_eq!(hints.servers[0].name, Vec::<String>::new());
+        assert_eq!(hints.servers[0].rdata, dns_request::labels_to_wire(&dns_request::domain_to_labels("A.ROOT-SERVERS.NET")).unwrap());
+        assert_eq!(hints.glue.len(), 2);
+        assert_eq!(hints.glue[0].rdata, vec!(198, 41, 0, 4));
+        assert_eq!(hints.glue[1].ttl, 3600000);
+
+        let builtin = RootHints::parse(BUILTIN_HINTS);
+        assert_eq!(builtin.servers.len(), 13);
+        assert_eq!(builtin.glue.len(), 26);
+    }
+}
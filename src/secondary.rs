@@ -0,0 +1,462 @@
+//! # Module for secondary zones
+//! Zones configured in secondary_zones are transferred (AXFR) from their primary over tcp and kept in memory.
+//! A background task per zone checks the serial of the primary every refresh interval of the SOA record,
+//! retries failed checks after the retry interval and stops answering for the zone once it has expired.
+//! A NOTIFY message (RFC 1996) of the primary starts the check right away
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{ Read, Write };
+use std::net::{ IpAddr, SocketAddr, TcpStream };
+use std::sync::{ mpsc, Mutex, RwLock };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::dns_request::{ self, DnsAnswer, DnsAuthRecord, DnsQuery, DnsQuestion, DnsRecordType, DnsResponse, DnsResponseCode };
+use crate::config::{ self, AuthoritativeZone, SecondaryZone };
+use crate::wire_upstream;
+
+const AXFR_TYPE: u16 = 252;
+const TIMEOUT: Duration = Duration::from_secs(10);
+//The retry interval before the first successful transfer (no SOA record is known yet)
+const INITIAL_RETRY: u64 = 60;
+
+lazy_static! {
+    static ref TRANSFERRED: RwLock<HashMap<String, TransferredZone>> = RwLock::new(HashMap::new());
+    //Wakes the refresh task of a zone before its interval has passed
+    static ref WAKERS: Mutex<HashMap<String, mpsc::Sender<()>>> = Mutex::new(HashMap::new());
+}
+
+struct TransferredZone {
+    soa: DnsAuthRecord,
+    soa_ttl: u32,
+    records: Vec<DnsAnswer>,
+    //The time of the last transfer or check of the serial at the primary, the zone expires measured from it
+    refreshed: Instant
+}
+
+///Starts the background task refreshing each configured secondary zone
+pub fn spawn_refresh() {
+    for zone in config::get().secondary_zones.clone() {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut wakers) = WAKERS.lock() {
+            wakers.insert(zone_key(&zone.name), sender);
+        }
+
+        thread::spawn(move || loop {
+            let delay = match refresh(&zone) {
+                Ok(_) => refresh_interval(&zone.name),
+                Err(err) => {
+                    log!("Failed to refresh zone {} from {}: {}", zone.name, zone.primary, err);
+                    expire(&zone.name);
+                    retry_interval(&zone.name)
+                }
+            };
+            let _ = receiver.recv_timeout(Duration::from_secs(delay));
+        });
+    }
+}
+
+///Starts the refresh of a zone right away, returning false if no refresh task is running for it
+pub fn notify(zone: &str) -> bool {
+    match WAKERS.lock() {
+        Ok(wakers) => wakers.get(&zone_key(zone)).is_some_and(|sender| sender.send(()).is_ok()),
+        Err(_) => false
+    }
+}
+
+///Returns whether a client is allowed to send NOTIFY messages for a zone: its primary or an address in allow_notify
+pub fn accepts_notify(zone: &SecondaryZone, client: &IpAddr) -> bool {
+    let primary = zone.primary.parse::<SocketAddr>().map(|address| address.ip());
+    primary.as_ref() == Ok(client) || zone.allow_notify.iter().any(|address| address.parse::<IpAddr>().as_ref() == Ok(client))
+}
+
+///Checks the serial of the zone at its primary and transfers the zone if it is newer than the stored one
+///(or if the zone was not transferred yet). Returns whether the zone was transferred
+pub fn refresh(zone: &SecondaryZone) -> Result<bool, Box<dyn Error>> {
+    if let Some(current) = serial(&zone.name) {
+        let soa = query_soa(&zone.name, &zone.primary)?;
+        if !serial_newer(soa.serial, current) {
+            mark_refreshed(&zone.name);
+            return Ok(false);
+        }
+    }
+
+    let records = transfer(&zone.name, &zone.primary)?;
+    store(&zone.name, records)?;
+    log!("Transferred zone {} (serial {}) from {}", zone.name, serial(&zone.name).unwrap_or(0), zone.primary);
+
+    Ok(true)
+}
+
+///Transfers a zone from the primary at the address (ip:port), returning its records in the order they were sent.
+///The first record is the SOA record of the zone, the closing SOA record is not included
+pub fn transfer(zone: &str, primary: &str) -> Result<Vec<DnsAnswer>, Box<dyn Error>> {
+    let (mut stream, id) = send_query(zone, AXFR_TYPE, primary)?;
+
+    let mut records: Vec<DnsAnswer> = Vec::new();
+    loop {
+        let response = read_response(&mut stream, id)?;
+        if response.header.rcode != DnsResponseCode::NoError {
+            return Err(format!("Transfer refused ({:?})", response.header.rcode).into());
+        }
+
+        for answer in response.answers {
+            let is_soa = answer.r#type.to_byte().0 == 6;
+            if records.is_empty() && !is_soa {
+                return Err("Transfer does not start with a SOA record".into());
+            }
+            if is_soa && !records.is_empty() {
+                return Ok(records);
+            }
+            records.push(answer);
+        }
+    }
+}
+
+///Returns the answers of the transferred zones for a name and record type code (SOA records excluded)
+pub fn lookup(name: &[String], type_code: u16) -> Vec<DnsAnswer> {
+    let name_str = name.join(".").to_lowercase();
+    let transferred = match TRANSFERRED.read() {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+
+    transferred.values()
+    .flat_map(|zone| zone.records.iter())
+    .filter(|record| record.r#type.to_byte().0 == type_code && record.name.join(".").to_lowercase() == name_str)
+    .cloned()
+    .collect()
+}
+
+///Returns whether a transferred zone has records of any type for a name
+pub fn contains(name: &[String]) -> bool {
+    let name_str = name.join(".").to_lowercase();
+    match TRANSFERRED.read() {
+        Ok(val) => val.values().any(|zone| zone.records.iter().any(|record| record.name.join(".").to_lowercase() == name_str)),
+        Err(_) => false
+    }
+}
+
+///Returns the transferred zones, described by their SOA records
+pub fn zones() -> Vec<AuthoritativeZone> {
+    let transferred = match TRANSFERRED.read() {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+
+    transferred.iter()
+    .map(|(name, zone)| AuthoritativeZone {
+        name: name.clone(),
+        mname: dns_request::labels_to_domain(&zone.soa.mname),
+        rname: dns_request::labels_to_domain(&zone.soa.rname),
+        serial: zone.soa.serial,
+        refresh: zone.soa.refresh,
+        retry: zone.soa.retry,
+        expire: zone.soa.expire,
+        minimum: zone.soa.minimum,
+        ttl: zone.soa_ttl,
+        notify: Vec::new()
+    })
+    .collect()
+}
+
+///Returns the serial of a transferred zone
+pub fn serial(zone: &str) -> Option<u32> {
+    TRANSFERRED.read().ok()?.get(&zone_key(zone)).map(|zone| zone.soa.serial)
+}
+
+fn store(zone: &str, mut records: Vec<DnsAnswer>) -> Result<(), Box<dyn Error>> {
+    let (soa, soa_ttl) = match records.first() {
+        Some(DnsAnswer { r#type: DnsRecordType::SOA(Some(soa)), ttl, .. }) => (soa.clone(), *ttl),
+        _ => return Err("Transfer does not start with a SOA record".into())
+    };
+    records.remove(0);
+
+    let transferred = TransferredZone {
+        soa,
+        soa_ttl,
+        records,
+        refreshed: Instant::now()
+    };
+    match TRANSFERRED.write() {
+        Ok(mut val) => val.insert(zone_key(zone), transferred),
+        Err(_) => return Err("Zone store poisoned".into())
+    };
+
+    Ok(())
+}
+
+//Records a successful check of a zone whose serial is unchanged
+fn mark_refreshed(zone: &str) {
+    if let Ok(mut transferred) = TRANSFERRED.write() {
+        if let Some(val) = transferred.get_mut(&zone_key(zone)) {
+            val.refreshed = Instant::now();
+        }
+    }
+}
+
+//Removes a zone which could not be refreshed within its expire interval
+fn expire(zone: &str) {
+    let mut transferred = match TRANSFERRED.write() {
+        Ok(val) => val,
+        Err(_) => return
+    };
+
+    let expired = match transferred.get(&zone_key(zone)) {
+        Some(val) => val.refreshed.elapsed() > Duration::from_secs(val.soa.expire as u64),
+        None => false
+    };
+    if expired {
+        log!("Zone {} expired", zone);
+        transferred.remove(&zone_key(zone));
+    }
+}
+
+fn refresh_interval(zone: &str) -> u64 {
+    match TRANSFERRED.read() {
+        Ok(val) => val.get(&zone_key(zone)).map_or(INITIAL_RETRY, |zone| zone.soa.refresh as u64),
+        Err(_) => INITIAL_RETRY
+    }
+}
+
+fn retry_interval(zone: &str) -> u64 {
+    match TRANSFERRED.read() {
+        Ok(val) => val.get(&zone_key(zone)).map_or(INITIAL_RETRY, |zone| zone.soa.retry as u64),
+        Err(_) => INITIAL_RETRY
+    }
+}
+
+fn zone_key(zone: &str) -> String {
+    zone.trim_end_matches(".").to_lowercase()
+}
+
+//Serial number arithmetic (RFC 1982): whether new is greater than old, allowing for wrap around
+fn serial_newer(new: u32, old: u32) -> bool {
+    (new.wrapping_sub(old) as i32) > 0
+}
+
+fn query_soa(zone: &str, primary: &str) -> Result<DnsAuthRecord, Box<dyn Error>> {
+    let (mut stream, id) = send_query(zone, 6, primary)?;
+    let response = read_response(&mut stream, id)?;
+
+    response.answers.into_iter()
+    .find_map(|answer| match answer.r#type {
+        DnsRecordType::SOA(Some(soa)) => Some(soa),
+        _ => None
+    })
+    .ok_or_else(|| "No SOA record in the response".into())
+}
+
+//Connects to the primary and sends a query for the zone, returning the connection and the id of the query
+fn send_query(zone: &str, type_code: u16, primary: &str) -> Result<(TcpStream, u16), Box<dyn Error>> {
+    let primary: SocketAddr = primary.parse()?;
+    let mut stream = TcpStream::connect_timeout(&primary, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+
+    let id = wire_upstream::next_id();
+    let mut query = DnsQuery::default()
+    .id(id)
+    .add_question(DnsQuestion {
+        qname: dns_request::domain_to_labels(zone),
+        qtype: DnsRecordType::NotImplemented(type_code, Vec::new()),
+        qclass: 1
+    });
+    query.header.rd = false;
+    stream.write_all(&query.build(true))?;
+
+    Ok((stream, id))
+}
+
+//Reads a single length prefixed message of the primary
+fn read_response(stream: &mut TcpStream, id: u16) -> Result<DnsResponse, Box<dyn Error>> {
+    let mut length: [u8; 2] = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut message: Vec<u8> = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+
+    let response = dns_request::parse_response(&message, false).ok_or("Malformed response")?;
+    if response.header.id != id || !response.header.qr {
+        return Err("Response does not belong to the query".into());
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::io::{ Read, Write };
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+    use std::thread;
+
+    use crate::dns_request::{ self, DnsAnswer, DnsAuthRecord, DnsRecordType, DnsResponse };
+
+    ///A primary serving a zone with a SOA record, an A record for www and a NS record.
+    ///The serial can be changed while it is running, the number of transfers is counted
+    pub struct Primary {
+        pub address: String,
+        pub serial: Arc<AtomicU32>,
+        pub transfers: Arc<AtomicUsize>
+    }
+
+    ///Starts a primary for the zone, returning its address along with its serial and transfer count
+    pub fn serve(zone: &str) -> Primary {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let serial = Arc::new(AtomicU32::new(1));
+        let transfers = Arc::new(AtomicUsize::new(0));
+
+        let zone = dns_request::domain_to_labels(zone);
+        let (serial_ref, transfers_ref) = (serial.clone(), transfers.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(val) => val,
+                    Err(_) => return
+                };
+
+                let mut length = [0; 2];
+                if stream.read_exact(&mut length).is_err() {
+                    continue;
+                }
+                let mut query = vec![0; u16::from_be_bytes(length) as usize];
+                if stream.read_exact(&mut query).is_err() {
+                    continue;
+                }
+                let (_, qtype) = dns_request::peek_question(&query, false).unwrap();
+
+                let soa = DnsAnswer::default()
+                .name(zone.clone())
+                .ttl(300)
+                .record(DnsRecordType::new_soa(
+                    DnsAuthRecord::new()
+                    .mname(dns_request::domain_to_labels("ns1.example.com"))
+                    .rname(dns_request::domain_to_labels("hostmaster.example.com"))
+                    .serial(serial_ref.load(Ordering::SeqCst))
+                    .refresh(3600)
+                    .retry(600)
+                    .expire(86400)
+                    .minimum(300)
+                ));
+                let id = u16::from_be_bytes([query[0], query[1]]);
+
+                if qtype == 6 {
+                    let _ = stream.write_all(&DnsResponse::default().id(id).add_answer(soa).build(true));
+                    continue;
+                }
+
+                transfers_ref.fetch_add(1, Ordering::SeqCst);
+                let mut www = zone.clone();
+                www.insert(0, String::from("www"));
+                let first = DnsResponse::default().id(id)
+                .add_answer(soa.clone())
+                .add_answer(DnsAnswer::default().name(www).ttl(600).record(DnsRecordType::new_a("192.0.2.10")));
+                let mut ns1 = zone.clone();
+                ns1.insert(0, String::from("ns1"));
+                let ns_rdata = dns_request::labels_to_wire(&ns1).unwrap();
+                let last = DnsResponse::default().id(id)
+                .add_answer(DnsAnswer::default().name(zone.clone()).ttl(600).record(Some(DnsRecordType::NotImplemented(2, ns_rdata))))
+                .add_answer(soa);
+
+                let _ = stream.write_all(&first.build(true));
+                let _ = stream.write_all(&last.build(true));
+            }
+        });
+
+        Primary {
+            address,
+            serial,
+            transfers
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn serial_newer_test() {
+        assert!(serial_newer(2, 1));
+        assert!(!serial_newer(1, 1));
+        assert!(!serial_newer(1, 2));
+        assert!(serial_newer(1, u32::MAX)); //Wrapped around
+    }
+
+    #[test]
+    fn accepts_notify_test() {
+        let zone = SecondaryZone {
+            name: String::from("example.test"),
+            primary: String::from("192.0.2.1:53"),
+            allow_notify: vec!(String::from("2001:db8::1"))
+        };
+
+        assert!(accepts_notify(&zone, &"192.0.2.1".parse().unwrap()));
+        assert!(accepts_notify(&zone, &"2001:db8::1".parse().unwrap()));
+        assert!(!accepts_notify(&zone, &"192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn transfer_test() {
+        let primary = mock::serve("transfer.test");
+        let zone = SecondaryZone {
+            name: String::from("transfer.test"),
+            primary: primary.address.clone(),
+            allow_notify: Vec::new()
+        };
+
+        assert!(refresh(&zone).unwrap());
+        assert_eq!(serial(&zone.name), Some(1));
+
+        let www = dns_request::domain_to_labels("www.transfer.test");
+        let answers = lookup(&www, 1);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rdata, vec!(192, 0, 2, 10));
+        assert_eq!(answers[0].ttl, 600);
+        assert_eq!(lookup(&dns_request::domain_to_labels("transfer.test"), 2).len(), 1);
+        assert!(contains(&www));
+
+        let authority = zones().into_iter().find(|zone| zone.name == "transfer.test").unwrap();
+        assert_eq!(authority.mname, "ns1.example.com");
+        assert_eq!(authority.refresh, 3600);
+
+        //The zone is only transferred again once the serial of the primary changes
+        assert!(!refresh(&zone).unwrap());
+        assert_eq!(primary.transfers.load(Ordering::SeqCst), 1);
+        primary.serial.store(2, Ordering::SeqCst);
+        assert!(refresh(&zone).unwrap());
+        assert_eq!(primary.transfers.load(Ordering::SeqCst), 2);
+        assert_eq!(serial(&zone.name), Some(2));
+    }
+
+    #[test]
+    fn expire_test() {
+        let primary = mock::serve("expire.test");
+        let zone = SecondaryZone {
+            name: String::from("expire.test"),
+            primary: primary.address.clone(),
+            allow_notify: Vec::new()
+        };
+        let backdate = || {
+            let mut transferred = TRANSFERRED.write().unwrap();
+            let stored = transferred.get_mut("expire.test").unwrap();
+            stored.refreshed = Instant::now().checked_sub(Duration::from_secs(86400 + 60)).unwrap();
+        };
+        assert!(refresh(&zone).unwrap());
+
+        //The transfer is older than the expire interval, but the serial was checked since
+        backdate();
+        assert!(!refresh(&zone).unwrap());
+        expire(&zone.name);
+        assert_eq!(serial(&zone.name), Some(1));
+
+        //Without a successful check within the expire interval the zone is removed
+        backdate();
+        expire(&zone.name);
+        assert_eq!(serial(&zone.name), None);
+        assert_eq!(primary.transfers.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,99 @@
+//! # Module for the startup self-test
+//! Builds a response with a record of every implemented record type (uncompressed and compressed) and parses it back,
+//! so an encoding bug is noticed at startup instead of being served to clients
+
+use crate::dns_request::{ self, DnsResponse, DnsAnswer, DnsRecordType, DnsAuthRecord };
+
+///Runs the self-test, returning a description of every record which failed to round trip. The response is
+///built both uncompressed and compressed, as udp responses are sent
+pub fn run() -> Vec<String> {
+    let mut failures = round_trip(|response| response.build(false));
+    let compressed = round_trip(|response| response.build_compressed(false));
+    failures.extend(compressed.into_iter().map(|failure| format!("{} (compressed)", failure)));
+
+    failures
+}
+
+fn round_trip<F: Fn(&DnsResponse) -> Vec<u8>>(encode: F) -> Vec<String> {
+    let records = sample_records();
+
+    let mut response = DnsResponse::default().id(0x5e1f);
+    for record in &records {
+        response = response.add_answer(record.clone());
+    }
+
+    let parsed = match dns_request::parse_response(&encode(&response), false) {
+        Some(val) => val,
+        None => return vec!(String::from("response could not be parsed"))
+    };
+
+    let mut failures: Vec<String> = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        let matches = match parsed.answers.get(i) {
+            Some(answer) => {
+                answer.name == record.name
+                && answer.r#type == record.r#type
+                && answer.class == record.class
+                && answer.ttl == record.ttl
+                && answer.rdata == record.rdata
+            },
+            None => false
+        };
+
+        if !matches {
+            failures.push(format!("{} record", record.r#type.to_byte().0));
+        }
+    }
+
+    failures
+}
+
+fn sample_records() -> Vec<DnsAnswer> {
+    let name: Vec<String> = vec!(String::from("self-test"), String::from("example"));
+    let auth_record = DnsAuthRecord::new()
+    .mname(vec!(String::from("ns1"), String::from("example")))
+    .rname(vec!(String::from("hostmaster"), String::from("example")))
+    .serial(2020010101)
+    .refresh(7200)
+    .retry(3600)
+    .expire(1209600)
+    .minimum(300);
+
+    let records = vec!(
+        DnsRecordType::new_a("192.0.2.1"),
+        DnsRecordType::new_aaaa("2001:db8::1"),
+        DnsRecordType::new_cname("alias.example"),
+        DnsRecordType::new_txt("v=self-test"),
+        DnsRecordType::new_soa(auth_record),
+        DnsRecordType::new_openpgpkey("mQENBF8A")
+    );
+
+    records.into_iter()
+    .map(|record| DnsAnswer::default().name(name.clone()).ttl(300).record(record))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_test() {
+        assert_eq!(run(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn broken_encoder_test() {
+        //An encoder which flips the last byte of the message (the last byte of the OPENPGPKEY record)
+        let failures = round_trip(|response| {
+            let mut bytes = response.build(false);
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+            bytes
+        });
+        assert_eq!(failures, vec!(String::from("61 record")));
+
+        let failures = round_trip(|_| vec!(0, 1, 2));
+        assert_eq!(failures, vec!(String::from("response could not be parsed")));
+    }
+}
@@ -0,0 +1,285 @@
+use std::net::{ TcpListener, TcpStream, UdpSocket };
+use std::thread;
+use std::io::{ Read, Write };
+use std::time::Duration;
+
+use crate::config;
+use crate::handle_data::{ self, Transport };
+use crate::limits::Limit;
+
+///The open tcp connections of the dns and dns-over-https listeners, bounded by max_tcp_connections
+pub static TCP_CONNECTIONS: Limit = Limit::new();
+//The time a tcp client has to send the rest of a partially received message
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+///Accepts tcp clients, handling each in its own thread. Connections past the
+///configured max_tcp_connections are closed right away
+pub fn serve_tcp(server: TcpListener) {
+    serve_tcp_limited(server, &TCP_CONNECTIONS, config::get().max_tcp_connections);
+}
+
+fn serve_tcp_limited(server: TcpListener, connections: &'static Limit, max_connections: usize) {
+    for client in server.incoming() {
+        if let Ok(client) = client {
+            let guard = match connections.try_acquire(max_connections) {
+                Some(val) => val,
+                None => {
+                    drop(client);
+                    continue;
+                }
+            };
+
+            thread::spawn(move || {
+                handle_tcp_client(client);
+                drop(guard);
+            });
+        }
+        else {
+            println!("Failed to accept client (Tcp)");
+        }
+    }
+}
+
+pub fn serve_udp(server: UdpSocket) {
+    loop {
+        let mut buffer: [u8; 2048] = [0; 2048];
+        let (num_bytes, client) = match server.recv_from(&mut buffer) {
+            Ok(val) => val,
+            Err(_) => {
+                //println!("Failed to accept client (Udp)");
+                continue;
+            }
+        };
+
+        let server_copy = match server.try_clone() {
+            Ok(val) => val,
+            Err(_) => continue
+        };
+        thread::spawn(move || {
+            let bytes = match handle_data::handle_message(buffer[0..num_bytes].to_vec(), Transport::Udp, client.ip()) {
+                Some(val) => val,
+                None => return
+            };
+
+            let _ = server_copy.send_to(&bytes, client);
+        });
+    }
+}
+
+fn handle_tcp_client(mut client: TcpStream) {
+    let address = match client.peer_addr() {
+        Ok(val) => val.ip(),
+        Err(_) => return
+    };
+
+    if client.set_read_timeout(Some(READ_TIMEOUT)).is_err() {
+        return;
+    }
+
+    //Bytes the client sent past the last message read, the start of its next query
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        let message = match read_message(&mut client, &mut pending) {
+            Some(val) => val,
+            None => return
+        };
+
+        let (bytes, idle_timeout) = match handle_data::handle_tcp_message(message, address) {
+            Some(val) => val,
+            None => return
+        };
+
+        if client.write_all(&bytes).is_err() {
+            return;
+        }
+
+        //A client which sent the edns-tcp-keepalive option may send further queries until the connection is idle for too long.
+        //Queries it already sent are answered either way
+        let timeout = match idle_timeout {
+            Some(val) => val,
+            None if pending.is_empty() => return,
+            None => continue
+        };
+        if client.set_read_timeout(Some(timeout)).is_err() {
+            return;
+        }
+    }
+}
+
+///Reads a message from a tcp client until the bytes announced by its length prefix are received, returning None
+///if nothing could be read or the client closed the connection (a read of zero bytes). If the client stops sending
+///early the bytes received so far are returned, which are answered with FORMERR.
+///The message starts with the pending bytes, and the bytes received past its end (pipelined queries, RFC 7766) are left in pending
+fn read_message<T: Read>(client: &mut T, pending: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let mut message: Vec<u8> = std::mem::take(pending);
+    let mut buffer: [u8; 2048] = [0; 2048];
+    while message_length(&message).is_none_or(|length| message.len() < length) {
+        let num_bytes = match client.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(val) => val,
+            Err(_) => break
+        };
+        message.extend_from_slice(&buffer[0..num_bytes]);
+    }
+
+    if message.is_empty() {
+        return None;
+    }
+    if let Some(length) = message_length(&message) {
+        if message.len() > length {
+            *pending = message.split_off(length);
+        }
+    }
+
+    Some(message)
+}
+
+//The length of a message including its length prefix, None until the prefix is received
+fn message_length(message: &[u8]) -> Option<usize> {
+    message.get(0..2).map(|prefix| u16::from_be_bytes([prefix[0], prefix[1]]) as usize + 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn tcp_connection_limit_test() {
+        static CONNECTIONS: Limit = Limit::new();
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || serve_tcp_limited(server, &CONNECTIONS, 1));
+
+        //The first client keeps its connection open without sending a query
+        let _first = TcpStream::connect(address).unwrap();
+        while CONNECTIONS.active() < 1 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut second = TcpStream::connect(address).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(second.read(&mut buffer).unwrap(), 0); //Closed by the server
+        assert_eq!(CONNECTIONS.active(), 1);
+    }
+
+    #[test]
+    fn open_connections_unaffected_test() {
+        static CONNECTIONS: Limit = Limit::new();
+        const MAX_CONNECTIONS: usize = 3;
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || serve_tcp_limited(server, &CONNECTIONS, MAX_CONNECTIONS));
+
+        let mut open: Vec<TcpStream> = Vec::new();
+        for _ in 0..MAX_CONNECTIONS {
+            open.push(TcpStream::connect(address).unwrap());
+        }
+        while CONNECTIONS.active() < MAX_CONNECTIONS {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut rejected = TcpStream::connect(address).unwrap();
+        rejected.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buffer = [0; 512];
+        assert_eq!(rejected.read(&mut buffer).unwrap(), 0);
+
+        //A connection opened before the limit was reached is still answered
+        let query: [u8; 29] = [
+            0, 27, //length (27)
+            0, 9, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, //Header (id 9, rd, 1 question)
+            2, 105, 100, 6, 115, 101, 114, 118, 101, 114, 0, //id.server
+            0, 16, 0, 3 //qtype (16), qclass (3)
+        ];
+        let first = &mut open[0];
+        first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        first.write_all(&query).unwrap();
+        let num_bytes = first.read(&mut buffer).unwrap();
+        assert!(num_bytes > 4);
+        assert_eq!(&buffer[2..4], &[0, 9]); //Same id as the query
+    }
+
+    #[test]
+    fn keepalive_test() {
+        static CONNECTIONS: Limit = Limit::new();
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || serve_tcp_limited(server, &CONNECTIONS, 4));
+
+        let query = |id: u8, keepalive: bool| {
+            let mut query: Vec<u8> = vec!(
+                0, 0, //length (set below)
+                0, id, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, //Header (rd, 1 question, 1 additional record)
+                2, 105, 100, 6, 115, 101, 114, 118, 101, 114, 0, //id.server
+                0, 16, 0, 3, //qtype (16), qclass (3)
+                0, 0, 41, 4, 208, 0, 0, 0, 0 //OPT record (udp payload size 1232)
+            );
+            let options: Vec<u8> = if keepalive { vec!(0, 11, 0, 0) } else { Vec::new() }; //edns-tcp-keepalive without a timeout
+            query.extend(&(options.len() as u16).to_be_bytes());
+            query.extend(&options);
+            let length = (query.len() - 2) as u16;
+            query[0..2].copy_from_slice(&length.to_be_bytes());
+            query
+        };
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(&query(1, true)).unwrap();
+        let response = crate::dns_request::parse_response(&read_message(&mut client, &mut Vec::new()).unwrap(), true).unwrap();
+        assert_eq!(response.header.id, 1);
+        assert_eq!(response.edns.unwrap().options, vec!((11, vec!(0, 100)))); //10 seconds
+
+        //The connection stays open for another query, which does not ask to keep it open
+        client.write_all(&query(2, false)).unwrap();
+        let response = crate::dns_request::parse_response(&read_message(&mut client, &mut Vec::new()).unwrap(), true).unwrap();
+        assert_eq!(response.header.id, 2);
+        assert_eq!(response.edns.unwrap().options, Vec::new());
+
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0); //Closed by the server
+
+        //Two queries written at once are both answered without waiting for the idle timeout
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut pipelined = query(3, true);
+        pipelined.extend(query(4, true));
+        client.write_all(&pipelined).unwrap();
+        let mut pending: Vec<u8> = Vec::new();
+        let response = crate::dns_request::parse_response(&read_message(&mut client, &mut pending).unwrap(), true).unwrap();
+        assert_eq!(response.header.id, 3);
+        let response = crate::dns_request::parse_response(&read_message(&mut client, &mut pending).unwrap(), true).unwrap();
+        assert_eq!(response.header.id, 4);
+    }
+
+    #[test]
+    fn closed_connection_test() {
+        let mut closed: &[u8] = &[];
+        assert_eq!(read_message(&mut closed, &mut Vec::new()), None);
+
+        let mut open: &[u8] = &[0, 2, 7, 7];
+        assert_eq!(read_message(&mut open, &mut Vec::new()), Some(vec!(0, 2, 7, 7)));
+
+        //The client stopped sending before the announced length was received
+        let mut partial: &[u8] = &[0, 9, 7, 7];
+        assert_eq!(read_message(&mut partial, &mut Vec::new()), Some(vec!(0, 9, 7, 7)));
+    }
+
+    #[test]
+    fn split_message_test() {
+        //A message arriving in several segments is read as a whole
+        let mut segments = Read::chain(&[0u8, 4, 1][..], &[2u8, 3, 4][..]);
+        assert_eq!(read_message(&mut segments, &mut Vec::new()), Some(vec!(0, 4, 1, 2, 3, 4)));
+
+        //Two messages arriving in one segment are read one after the other
+        let mut pipelined: &[u8] = &[0, 1, 7, 0, 2, 8, 8];
+        let mut pending: Vec<u8> = Vec::new();
+        assert_eq!(read_message(&mut pipelined, &mut pending), Some(vec!(0, 1, 7)));
+        assert_eq!(pending, vec!(0, 2, 8, 8));
+        assert_eq!(read_message(&mut pipelined, &mut pending), Some(vec!(0, 2, 8, 8)));
+        assert!(pending.is_empty());
+    }
+}
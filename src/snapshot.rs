@@ -0,0 +1,210 @@
+//! # Module for preloaded zones
+//! Records of the files configured in snapshot_zones (json lists in the format of local_records) are read
+//! at startup into a map which is never written afterwards, so large static zones are answered without taking
+//! the lock of the cache. [get_records](crate::database::get_records) consults the snapshot before the cache.
+//! A reload (SIGHUP on unix) reads the files into a new snapshot which replaces the old one
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{ Arc, RwLock };
+
+use crate::dns_request::{ DnsAnswer, DnsRecordType };
+use crate::config::{ self, LocalRecord };
+use crate::zone;
+
+lazy_static! {
+    //Only the Arc is replaced on a reload, lookups hold the lock just long enough to clone it
+    static ref SNAPSHOT: RwLock<Arc<Snapshot>> = RwLock::new(Arc::new(Snapshot::load(&config::get().snapshot_zones, config::get().local_ttl)));
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_SNAPSHOT: std::cell::RefCell<Option<Arc<Snapshot>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// # Struct holding the preloaded records by name and type
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    records: HashMap<(String, u16), Vec<DnsAnswer>>
+}
+
+impl Snapshot {
+    ///Builds the snapshot of a list of records, invalid records are logged and left out
+    pub fn build(records: &Vec<LocalRecord>) -> Self {
+        let mut map: HashMap<(String, u16), Vec<DnsAnswer>> = HashMap::new();
+        for local in records {
+            let record = match zone::to_record(local) {
+                Some(val) => val,
+                None => {
+                    log!("Invalid preloaded record for {}: {}", local.name, local.r#type);
+                    continue;
+                }
+            };
+            let name = local.name.trim_end_matches(".").to_lowercase();
+            let labels: Vec<String> = name.split(".").map(String::from).collect();
+            let key = (name, record.to_byte().0);
+
+            map.entry(key).or_default().push(
+                DnsAnswer::default()
+                .name(labels)
+                .ttl(local.ttl)
+                .record(Some(record))
+            );
+        }
+
+        Snapshot {
+            records: map
+        }
+    }
+
+    //Reads every file, a file which can't be read or parsed is logged and skipped.
+    //The ttl (if set) replaces the ttls of the records
+    fn load(paths: &Vec<String>, ttl: Option<u32>) -> Self {
+        let mut records: Vec<LocalRecord> = Vec::new();
+        for path in paths {
+            let contents = match fs::read_to_string(path) {
+                Ok(val) => val,
+                Err(err) => {
+                    log!("Failed to read preloaded zone {}: {}", path, err);
+                    continue;
+                }
+            };
+            match serde_json::from_str::<Vec<LocalRecord>>(&contents) {
+                Ok(mut val) => records.append(&mut val),
+                Err(err) => log!("Failed to parse preloaded zone {}: {}", path, err)
+            }
+        }
+
+        if let Some(ttl) = ttl {
+            for record in records.iter_mut() {
+                record.ttl = ttl;
+            }
+        }
+
+        let snapshot = Snapshot::build(&records);
+        if !paths.is_empty() {
+            log!("Preloaded {} records", snapshot.len());
+        }
+        snapshot
+    }
+
+    ///Returns the preloaded records of a name and type, or None if there are none
+    pub fn lookup(&self, name: &[String], record_type: &DnsRecordType) -> Option<&Vec<DnsAnswer>> {
+        self.records.get(&(name.join(".").to_lowercase(), record_type.to_byte().0))
+    }
+
+    ///Returns the number of preloaded records
+    pub fn len(&self) -> usize {
+        self.records.values().map(|answers| answers.len()).sum()
+    }
+
+    ///Returns whether no record is preloaded
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+///Reads the preloaded zones, which otherwise happens on the first query
+pub fn init_snapshot() {
+    lazy_static::initialize(&SNAPSHOT);
+}
+
+///Returns the preloaded records
+pub fn get() -> Arc<Snapshot> {
+    #[cfg(test)]
+    {
+        if let Some(snapshot) = TEST_SNAPSHOT.with(|snapshot| snapshot.borrow().clone()) {
+            return snapshot;
+        }
+    }
+
+    match SNAPSHOT.read() {
+        Ok(val) => val.clone(),
+        Err(poisoned) => poisoned.into_inner().clone()
+    }
+}
+
+///Reads the preloaded zones again, replacing the records served
+pub fn reload() {
+    let config = config::get();
+    let snapshot = Arc::new(Snapshot::load(&config.snapshot_zones, config.local_ttl));
+    match SNAPSHOT.write() {
+        Ok(mut val) => *val = snapshot,
+        Err(poisoned) => *poisoned.into_inner() = snapshot
+    }
+}
+
+///Reloads the preloaded zones every time the process receives SIGHUP
+#[cfg(unix)]
+pub fn reload_on_signal() -> std::io::Result<()> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            log!("Reloading the preloaded zones");
+            reload();
+        }
+    });
+
+    Ok(())
+}
+
+///Overrides the preloaded records for the current thread (only used by tests)
+#[cfg(test)]
+pub fn set_for_test(snapshot: Snapshot) {
+    TEST_SNAPSHOT.with(|test_snapshot| *test_snapshot.borrow_mut() = Some(Arc::new(snapshot)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ Config, local_record };
+    use crate::database;
+
+    #[test]
+    fn reload_test() {
+        let path = std::env::temp_dir().join(format!("dns-snapshot-{}.json", std::process::id()));
+        let write = |address: &str| {
+            let zone = format!(r#"[{{ "name": "www.reloaded", "type": "A", "value": "{}", "ttl": 3600 }}]"#, address);
+            fs::write(&path, zone).unwrap();
+        };
+        let config = Config {
+            snapshot_zones: vec!(path.to_string_lossy().into_owned()),
+            local_ttl: Some(60),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+        let name = vec!(String::from("www"), String::from("reloaded"));
+
+        write("192.0.2.1");
+        reload();
+        let answer = database::get_records(&name, DnsRecordType::A(None), None).unwrap().remove(0);
+        assert_eq!(answer.rdata, vec!(192, 0, 2, 1));
+        assert_eq!(answer.ttl, 60);
+
+        write("192.0.2.2");
+        reload();
+        let answer = database::get_records(&name, DnsRecordType::A(None), None).unwrap().remove(0);
+        assert_eq!(answer.rdata, vec!(192, 0, 2, 2));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn build_test() {
+        let snapshot = Snapshot::build(&vec!(
+            local_record("www.example.home.", "A", "192.0.2.1"),
+            local_record("WWW.example.home", "A", "192.0.2.2"),
+            local_record("www.example.home", "AAAA", "2001:db8::1"),
+            local_record("bad.example.home", "A", "not an address")
+        ));
+        let name = vec!(String::from("www"), String::from("example"), String::from("home"));
+
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.lookup(&name, &DnsRecordType::A(None)).unwrap().len(), 2);
+        assert_eq!(snapshot.lookup(&name, &DnsRecordType::AAAA(None)).unwrap()[0].ttl, 300);
+        assert_eq!(snapshot.lookup(&name, &DnsRecordType::TXT(None)), None);
+        assert_eq!(snapshot.lookup(&[String::from("bad"), String::from("example"), String::from("home")], &DnsRecordType::A(None)), None);
+    }
+}
@@ -0,0 +1,288 @@
+//! # Module for the statistics of the server
+//! Counters of the handled queries, which are logged on request (SIGUSR1 on unix)
+
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use std::time::Duration;
+
+use crate::database;
+use crate::dns_request::ParseError;
+
+static QUERIES: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static UPSTREAM_ERRORS: AtomicU64 = AtomicU64::new(0);
+static MALFORMED_TOO_SHORT: AtomicU64 = AtomicU64::new(0);
+static MALFORMED_BAD_POINTER: AtomicU64 = AtomicU64::new(0);
+static MALFORMED_OVERSIZED_LABEL: AtomicU64 = AtomicU64::new(0);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static UPSTREAM_LATENCY: Histogram = Histogram::new();
+static ANSWER_COUNTS: Histogram = Histogram::with_bounds(&ANSWER_COUNT_BOUNDS);
+
+//Upper bounds (in milliseconds) of the latency buckets, slower requests fall into a last bucket without bound
+const LATENCY_BOUNDS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+//Upper bounds of the buckets of the number of answers of responses, larger responses fall into a last bucket
+const ANSWER_COUNT_BOUNDS: [u64; 6] = [0, 1, 2, 4, 8, 16];
+//The most bounds a histogram can have
+const MAX_BOUNDS: usize = 15;
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// # Histogram of values in buckets of increasing size
+pub struct Histogram {
+    bounds: &'static [u64],
+    counts: [AtomicU64; MAX_BOUNDS + 1]
+}
+
+impl Histogram {
+    ///Returns an empty histogram of durations (in the buckets of the upstream latency)
+    pub const fn new() -> Self {
+        Histogram::with_bounds(&LATENCY_BOUNDS)
+    }
+
+    ///Returns an empty histogram with buckets up to the given bounds (at most 15, in increasing order)
+    ///and a last bucket without bound
+    pub const fn with_bounds(bounds: &'static [u64]) -> Self {
+        assert!(bounds.len() <= MAX_BOUNDS, "A histogram has at most 15 bounds");
+        Histogram {
+            bounds,
+            counts: [ZERO; MAX_BOUNDS + 1]
+        }
+    }
+
+    ///Adds a duration (in milliseconds) to the histogram
+    pub fn record(&self, duration: Duration) {
+        self.record_value(duration.as_millis() as u64);
+    }
+
+    ///Adds a value to the histogram
+    pub fn record_value(&self, value: u64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::SeqCst);
+    }
+
+    ///Returns the number of values in every bucket, the last one being the bucket without bound
+    pub fn buckets(&self) -> Vec<u64> {
+        self.counts[..self.bounds.len() + 1].iter().map(|count| count.load(Ordering::SeqCst)).collect()
+    }
+
+    ///Returns the upper bound of the bucket holding the percentile, None if the histogram is empty
+    ///or the percentile is beyond the largest bound
+    pub fn percentile(&self, percent: u64) -> Option<u64> {
+        let counts = self.buckets();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        //The rank of the percentile, rounded up (the 50th percentile of 3 values is the 2nd)
+        let rank = std::cmp::max((total * percent).div_ceil(100), 1);
+        let mut seen: u64 = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self.bounds.get(bucket).copied();
+            }
+        }
+
+        None
+    }
+
+    //Formats the percentile for the summary line
+    fn format_percentile(&self, percent: u64) -> String {
+        match self.percentile(percent) {
+            Some(bound) => format!("<={}ms", bound),
+            None if self.counts[self.bounds.len()].load(Ordering::SeqCst) > 0 => format!(">{}ms", self.bounds[self.bounds.len()-1]),
+            None => String::from("none")
+        }
+    }
+}
+
+/// # Guard for a query being handled
+///The query counts as active until the guard is dropped
+pub struct ActiveGuard;
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+///Counts a query and marks it as active until the returned guard is dropped
+pub fn begin_query() -> ActiveGuard {
+    QUERIES.fetch_add(1, Ordering::SeqCst);
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    ActiveGuard
+}
+
+///Counts a record answered from the cache
+pub fn record_hit() {
+    CACHE_HITS.fetch_add(1, Ordering::SeqCst);
+}
+
+///Counts a record which was not cached
+pub fn record_miss() {
+    CACHE_MISSES.fetch_add(1, Ordering::SeqCst);
+}
+
+///Counts a failed request to an upstream server
+pub fn record_upstream_error() {
+    UPSTREAM_ERRORS.fetch_add(1, Ordering::SeqCst);
+}
+
+///Counts a query which could not be parsed, by the reason it failed
+pub fn record_malformed(reason: ParseError) {
+    malformed_counter(reason).fetch_add(1, Ordering::SeqCst);
+}
+
+///Returns the number of queries which could not be parsed for a reason
+pub fn malformed(reason: ParseError) -> u64 {
+    malformed_counter(reason).load(Ordering::SeqCst)
+}
+
+fn malformed_counter(reason: ParseError) -> &'static AtomicU64 {
+    match reason {
+        ParseError::TooShort => &MALFORMED_TOO_SHORT,
+        ParseError::BadPointer => &MALFORMED_BAD_POINTER,
+        ParseError::OversizedLabel => &MALFORMED_OVERSIZED_LABEL
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+///Records the time a request to the upstream took
+pub fn record_upstream_latency(duration: Duration) {
+    UPSTREAM_LATENCY.record(duration);
+}
+
+///Records the number of answers of a response
+pub fn record_answer_count(count: usize) {
+    ANSWER_COUNTS.record_value(count as u64);
+}
+
+//Formats the buckets of a histogram for the summary line, each as its bound and count (0:12,1:30,...,inf:0)
+fn format_buckets(histogram: &Histogram) -> String {
+    let bounds = histogram.bounds.iter().map(|bound| bound.to_string()).chain(std::iter::once(String::from("inf")));
+    bounds.zip(histogram.buckets())
+    .map(|(bound, count)| format!("{}:{}", bound, count))
+    .collect::<Vec<String>>()
+    .join(",")
+}
+
+///Returns a single line summarizing the statistics
+pub fn summary() -> String {
+    let hits = CACHE_HITS.load(Ordering::SeqCst);
+    let misses = CACHE_MISSES.load(Ordering::SeqCst);
+    let hit_ratio = if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 };
+
+    format!(
+        "Stats: queries={} cache_size={} hit_ratio={:.3} upstream_errors={} upstream_p50={} upstream_p90={} upstream_p99={} active={} \
+        malformed_too_short={} malformed_bad_pointer={} malformed_oversized_label={} answer_counts={}",
+        QUERIES.load(Ordering::SeqCst),
+        database::cache_size(),
+        hit_ratio,
+        UPSTREAM_ERRORS.load(Ordering::SeqCst),
+        UPSTREAM_LATENCY.format_percentile(50),
+        UPSTREAM_LATENCY.format_percentile(90),
+        UPSTREAM_LATENCY.format_percentile(99),
+        ACTIVE.load(Ordering::SeqCst),
+        malformed(ParseError::TooShort),
+        malformed(ParseError::BadPointer),
+        malformed(ParseError::OversizedLabel),
+        format_buckets(&ANSWER_COUNTS)
+    )
+}
+
+///Logs the statistics every time the process receives SIGUSR1
+#[cfg(unix)]
+pub fn log_on_signal() -> std::io::Result<()> {
+    on_signal(|| log!("{}", summary()))
+}
+
+#[cfg(unix)]
+fn on_signal<F: Fn() + Send + 'static>(callback: F) -> std::io::Result<()> {
+    use signal_hook::consts::SIGUSR1;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGUSR1])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            callback();
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_test() {
+        use std::sync::mpsc;
+
+        let (sender, receiver) = mpsc::channel();
+        on_signal(move || { let _ = sender.send(summary()); }).unwrap();
+        drop(begin_query());
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGUSR1).unwrap();
+        let line = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(line.starts_with("Stats: queries="));
+        assert!(line.contains("hit_ratio="));
+        assert!(line.contains("upstream_p99="));
+        assert!(line.contains("answer_counts=0:"));
+    }
+
+    #[test]
+    fn histogram_test() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentile(50), None);
+        assert_eq!(histogram.format_percentile(50), "none");
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_millis(8));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(150));
+        }
+        assert_eq!(histogram.percentile(50), Some(10));
+        assert_eq!(histogram.percentile(90), Some(10));
+        assert_eq!(histogram.percentile(99), Some(200));
+
+        //A slower upstream shifts the percentiles
+        for _ in 0..100 {
+            histogram.record(Duration::from_millis(900));
+        }
+        assert_eq!(histogram.percentile(50), Some(200));
+        assert_eq!(histogram.percentile(90), Some(1000));
+
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.percentile(100), None);
+        assert_eq!(histogram.format_percentile(100), ">5000ms");
+    }
+
+    #[test]
+    fn answer_count_histogram_test() {
+        let histogram = Histogram::with_bounds(&ANSWER_COUNT_BOUNDS);
+        for count in &[0, 0, 1, 1, 1, 3, 4, 12, 40] {
+            histogram.record_value(*count);
+        }
+
+        assert_eq!(histogram.buckets(), vec!(2, 3, 0, 2, 0, 1, 1));
+        assert_eq!(format_buckets(&histogram), "0:2,1:3,2:0,4:2,8:0,16:1,inf:1");
+        assert_eq!(histogram.percentile(50), Some(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 15 bounds")]
+    fn too_many_bounds_test() {
+        static BOUNDS: [u64; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        Histogram::with_bounds(&BOUNDS);
+    }
+}
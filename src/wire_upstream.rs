@@ -0,0 +1,312 @@
+//! # Module for the wire upstream
+//! Record types the json upstream cannot answer are forwarded to a plain dns server over udp
+//! (over tcp if the udp response is truncated).
+//! The records of its response are passed through, including those of types this server does not implement
+
+use std::error::Error;
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream, UdpSocket };
+use std::sync::atomic::{ AtomicU16, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use crate::dns_request::{ self, DnsQuery, DnsQuestion, DnsResponse, Edns };
+use crate::config;
+use crate::handle_data;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    static ref NEXT_ID: AtomicU16 = AtomicU16::new(initial_id());
+}
+
+fn initial_id() -> u16 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(val) => val.subsec_nanos() as u16,
+        Err(_) => 0
+    }
+}
+
+///Returns the id for the next query sent to an upstream or primary server
+pub fn next_id() -> u16 {
+    NEXT_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+///Sends a question to the dns server at the address (ip:port) and returns its response.
+///If dnssec_ok is set the query asks for DNSSEC records (the DO bit of an OPT record).
+///With wire_upstream_randomize_case the name is sent with random case, the records of the response
+///are returned with the name as it was asked
+pub fn request_query(server: &str, question: &DnsQuestion, dnssec_ok: bool) -> Result<DnsResponse, Box<dyn Error>> {
+    let randomize_case = config::get().wire_upstream_randomize_case;
+    let mut sent = question.clone();
+    if randomize_case {
+        sent.qname = randomized_case(&question.qname);
+    }
+
+    let server: SocketAddr = server.parse()?;
+    let bind_address = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_address)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(server)?;
+
+    let id = next_id();
+    let mut query = DnsQuery::default()
+    .id(id)
+    .add_question(sent.clone());
+    if dnssec_ok {
+        let edns = Edns {
+            dnssec_ok: true,
+            ..Edns::default()
+        };
+        query = query.edns(edns);
+    }
+    socket.send(&query.build(false))?;
+
+    let mut buffer: [u8; 4096] = [0; 4096];
+    loop {
+        let num_bytes = socket.recv(&mut buffer)?;
+        let mut response = match dns_request::parse_response(&buffer[0..num_bytes], false) {
+            Some(val) => val,
+            None => continue
+        };
+
+        //Responses which do not belong to the query are ignored
+        if response.header.id != id || !response.header.qr {
+            continue;
+        }
+        //A truncated response is not passed on, the query is sent again over tcp
+        if response.header.tc {
+            response = request_tcp(server, &query, id)?;
+        }
+        if randomize_case {
+            //A spoofed response would have to guess the case as well as the id
+            if response.questions.len() != 1 || response.questions[0].qname != sent.qname {
+                log!("Ignoring a wire upstream response for {} not echoing the case of the name", question.qname.join("."));
+                continue;
+            }
+            return Ok(restore_case(response, &sent.qname, &question.qname));
+        }
+
+        return Ok(response);
+    }
+}
+
+//Sends the query over tcp and reads the single length prefixed response
+fn request_tcp(server: SocketAddr, query: &DnsQuery, id: u16) -> Result<DnsResponse, Box<dyn Error>> {
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(&query.build(true))?;
+
+    let mut length: [u8; 2] = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut message: Vec<u8> = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+
+    let response = dns_request::parse_response(&message, false).ok_or("Malformed response")?;
+    if response.header.id != id || !response.header.qr {
+        return Err("Response does not belong to the query".into());
+    }
+
+    Ok(response)
+}
+
+//Flips the case of every letter of the name with a probability of one half
+fn randomized_case(name: &[String]) -> Vec<String> {
+    name.iter().map(|label| {
+        let bits = handle_data::random();
+        label.chars().enumerate().map(|(i, letter)| {
+            if bits & (1 << (i % 64)) != 0 { letter.to_ascii_uppercase() } else { letter.to_ascii_lowercase() }
+        })
+        .collect()
+    })
+    .collect()
+}
+
+//Replaces the randomized name with the asked one in the question and the records of the response
+fn restore_case(mut response: DnsResponse, sent: &[String], asked: &[String]) -> DnsResponse {
+    for question in response.questions.iter_mut() {
+        if question.qname == sent {
+            question.qname = asked.to_vec();
+        }
+    }
+    let records = response.answers.iter_mut()
+    .chain(response.authority_records.iter_mut())
+    .chain(response.additional_records.iter_mut());
+    for record in records {
+        if record.name == sent {
+            record.name = asked.to_vec();
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::io::{ Read, Write };
+    use std::net::{ TcpListener, UdpSocket };
+    use std::thread;
+
+    use crate::dns_request;
+
+    ///Starts a dns server answering every query with a single record (name compressed to the question),
+    ///returning its address
+    pub fn serve(r#type: u16, rdata: Vec<u8>) -> String {
+        serve_with(r#type, rdata, false, false, None)
+    }
+
+    ///Starts a dns server like [serve](serve), which echoes the name of the question in lowercase
+    pub fn serve_lowercase(r#type: u16, rdata: Vec<u8>) -> String {
+        serve_with(r#type, rdata, true, false, None)
+    }
+
+    ///Starts a dns server like [serve](serve), which answers udp queries with an empty truncated response
+    ///and tcp queries (on the same port) with the record
+    pub fn serve_truncated(r#type: u16, rdata: Vec<u8>) -> String {
+        serve_with(r#type, rdata, false, true, None)
+    }
+
+    ///Starts a dns server like [serve](serve), which also answers with a record of the same type for an unrelated
+    ///name, as an upstream trying to poison the cache would
+    pub fn serve_unrelated(r#type: u16, rdata: Vec<u8>, unrelated: &str) -> String {
+        let owner = dns_request::labels_to_wire(&dns_request::domain_to_labels(unrelated)).unwrap();
+        serve_with(r#type, rdata, false, false, Some(owner))
+    }
+
+    fn serve_with(r#type: u16, rdata: Vec<u8>, lowercase: bool, truncated: bool, unrelated: Option<Vec<u8>>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        if truncated {
+            let listener = TcpListener::bind(&address).unwrap();
+            let rdata = rdata.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(val) => val,
+                        Err(_) => return
+                    };
+                    let mut length: [u8; 2] = [0; 2];
+                    if stream.read_exact(&mut length).is_err() {
+                        continue;
+                    }
+                    let mut query = vec![0; u16::from_be_bytes(length) as usize];
+                    if stream.read_exact(&mut query).is_err() {
+                        continue;
+                    }
+                    if let Some(response) = answer(&query, r#type, &rdata, false, false, None) {
+                        let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                        message.extend(response);
+                        let _ = stream.write_all(&message);
+                    }
+                }
+            });
+        }
+
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            loop {
+                let (num_bytes, client) = match socket.recv_from(&mut buffer) {
+                    Ok(val) => val,
+                    Err(_) => return
+                };
+
+                if let Some(response) = answer(&buffer[0..num_bytes], r#type, &rdata, lowercase, truncated, unrelated.as_deref()) {
+                    let _ = socket.send_to(&response, client);
+                }
+            }
+        });
+
+        address
+    }
+
+    //The response to a query, with the record (and the one of the unrelated name) unless it is truncated
+    fn answer(query: &[u8], r#type: u16, rdata: &[u8], lowercase: bool, truncated: bool, unrelated: Option<&[u8]>) -> Option<Vec<u8>> {
+        let mut response: Vec<u8> = query.get(0..2)?.to_vec(); //id
+        if truncated {
+            response.extend_from_slice(&[0b1000_0011, 0b1000_0000]); //qr (1), tc (1), rd (1), ra (1)
+            response.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); //qd_count (1)
+        }
+        else {
+            response.extend_from_slice(&[0b1000_0001, 0b1000_0000]); //qr (1), rd (1), ra (1)
+            let an_count = if unrelated.is_some() { 2 } else { 1 };
+            response.extend_from_slice(&[0, 1, 0, an_count, 0, 0, 0, 0]); //qd_count (1), an_count
+        }
+        //The question ends 4 bytes (type and class) after the root label of its name
+        let name_end = 12 + query.get(12..)?.iter().position(|byte| *byte == 0)? + 1;
+        let question = query.get(12..name_end+4)?;
+        if lowercase {
+            response.extend(question.iter().map(|byte| byte.to_ascii_lowercase()));
+        }
+        else {
+            response.extend_from_slice(question);
+        }
+        if truncated {
+            return Some(response);
+        }
+        response.extend_from_slice(&[0xc0, 12]); //name (pointer to the question)
+        response.extend_from_slice(&r#type.to_be_bytes());
+        response.extend_from_slice(&[0, 1, 0, 0, 1, 44]); //class (1), ttl (300)
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(rdata);
+        if let Some(owner) = unrelated {
+            response.extend_from_slice(owner);
+            response.extend_from_slice(&r#type.to_be_bytes());
+            response.extend_from_slice(&[0, 1, 0, 0, 1, 44]); //class (1), ttl (300)
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(rdata);
+        }
+
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::dns_request::DnsRecordType;
+
+    #[test]
+    fn randomized_case_test() {
+        let config = Config {
+            wire_upstream_randomize_case: true,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let name = dns_request::domain_to_labels("abcdefghijklmnopqrstuvwxyz.example");
+        let randomized = randomized_case(&name);
+        assert_eq!(randomized.join(".").to_lowercase(), name.join("."));
+        assert_ne!(randomized, name); //All lowercase with a chance of 2^-33
+
+        let question = DnsQuestion {
+            qname: name.clone(),
+            qtype: DnsRecordType::NotImplemented(99, Vec::new()),
+            qclass: 1
+        };
+        let server = mock::serve(99, vec!(1, 2, 3));
+        let response = request_query(&server, &question, false).unwrap();
+        assert_eq!(response.answers[0].name, name);
+        assert_eq!(response.answers[0].rdata, vec!(1, 2, 3));
+
+        //The response of the lowercase server doesn't match the case sent and is ignored until the timeout
+        let server = mock::serve_lowercase(99, vec!(1, 2, 3));
+        assert!(request_query(&server, &question, false).is_err());
+    }
+
+    #[test]
+    fn truncated_response_test() {
+        config::set_for_test(Config::default());
+        let question = DnsQuestion {
+            qname: dns_request::domain_to_labels("www.truncated.example"),
+            qtype: DnsRecordType::NotImplemented(99, Vec::new()),
+            qclass: 1
+        };
+
+        let server = mock::serve_truncated(99, vec!(4, 5, 6));
+        let response = request_query(&server, &question, false).unwrap();
+        assert!(!response.header.tc);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rdata, vec!(4, 5, 6));
+    }
+}
@@ -0,0 +1,356 @@
+//! # Module for the local zone
+//! Records declared in the configuration (local_records) are answered by this server directly.
+//! Names within a configured zone (zones) are answered authoritatively, including the SOA record of the zone.
+//! Zones transferred from a primary (see [secondary](crate::secondary)) are answered the same way.
+//! Records can also be read from zone files (zone_files), see [zone_file_records](zone_file_records).
+//! TLSA records can be generated from a certificate file, see [tlsa_from_certificate](tlsa_from_certificate)
+
+use std::fs;
+use std::collections::HashMap;
+use openssl::hash::{ hash, MessageDigest };
+use openssl::x509::X509;
+
+use crate::dns_request::{ self, DnsAnswer, DnsAuthRecord, DnsRecordType };
+use crate::config::{ self, LocalRecord, AuthoritativeZone };
+use crate::secondary;
+
+const CNAME_TYPE: u16 = 5;
+//The longest chain of local aliases followed for an answer
+const MAX_CNAME_CHAIN: usize = 8;
+
+///The local records with their parsed record, by their lowercase name without the trailing dot
+pub type LocalIndex = HashMap<String, Vec<(LocalRecord, DnsRecordType)>>;
+
+///Parses the local records once, so certificates are only read when the configuration is loaded.
+///Invalid records are logged and left out
+pub fn index(records: &Vec<LocalRecord>) -> LocalIndex {
+    let mut index: LocalIndex = HashMap::new();
+    for local in records {
+        let record = match to_record(local) {
+            Some(val) => val,
+            None => {
+                log!("Invalid local record for {}: {}", local.name, local.r#type);
+                continue;
+            }
+        };
+        index.entry(local.name.trim_end_matches(".").to_lowercase())
+        .or_default()
+        .push((local.clone(), record));
+    }
+
+    index
+}
+
+///Returns the answers of the local zone for a name and record type, or None if the local zone has no such record.
+///An alias of the name is answered for every type, followed by the answers of its target if that is a local name too
+pub fn lookup(name: &[String], record_type: &DnsRecordType) -> Option<Vec<DnsAnswer>> {
+    lookup_chain(name, record_type, &mut Vec::new())
+}
+
+//The names already answered are not followed again, so aliases pointing at each other end the chain
+fn lookup_chain(name: &[String], record_type: &DnsRecordType, visited: &mut Vec<String>) -> Option<Vec<DnsAnswer>> {
+    let config = config::get();
+    let name_str = name.join(".").to_lowercase();
+    let (type_code, _) = record_type.to_byte();
+    visited.push(name_str.clone());
+
+    let mut answers: Vec<DnsAnswer> = Vec::new();
+    for (local, record) in config.local_index.get(&name_str).into_iter().flatten() {
+        let record_code = record.to_byte().0;
+        if record_code != type_code && record_code != CNAME_TYPE {
+            continue;
+        }
+
+        answers.push(
+            DnsAnswer::default()
+            .name(name.to_vec())
+            .ttl(local.ttl)
+            .record(Some(record.clone()))
+        );
+        if record_code != type_code && visited.len() < MAX_CNAME_CHAIN {
+            let target: Vec<String> = local.value.split(".")
+            .filter(|label| !label.is_empty())
+            .map(String::from)
+            .collect();
+            if visited.contains(&target.join(".").to_lowercase()) {
+                log!("Local alias loop at {}", local.name);
+                continue;
+            }
+            answers.append(&mut lookup_chain(&target, record_type, visited).unwrap_or_default());
+        }
+    }
+    answers.append(&mut secondary::lookup(name, type_code));
+
+    if answers.is_empty() {
+        return None;
+    }
+
+    Some(answers)
+}
+
+///Returns whether the local zone has records of any type for a name
+pub fn contains(name: &[String]) -> bool {
+    let name_str = name.join(".").to_lowercase();
+    config::get().local_index.contains_key(&name_str)
+    || secondary::contains(name)
+}
+
+///Returns the configured zone a name is within (the most specific one if zones are nested)
+pub fn authority(name: &[String]) -> Option<AuthoritativeZone> {
+    config::get().zones.iter().cloned()
+    .chain(secondary::zones())
+    .filter(|zone| dns_request::in_zone(name, &zone.name))
+    .max_by_key(|zone| zone.name.trim_end_matches(".").len())
+}
+
+///Returns the names of the configured and transferred zones, which this server answers authoritatively
+pub fn suffixes() -> Vec<String> {
+    config::get().zones.iter().cloned()
+    .chain(secondary::zones())
+    .map(|zone| zone.name)
+    .collect()
+}
+
+///Returns whether a name is the apex (the name) of a zone
+pub fn is_apex(name: &[String], zone: &AuthoritativeZone) -> bool {
+    name.join(".").to_lowercase() == zone.name.trim_end_matches(".").to_lowercase()
+}
+
+///Returns the SOA record of a configured zone
+pub fn soa(zone: &AuthoritativeZone) -> DnsAnswer {
+    let auth_record = DnsAuthRecord::new()
+    .mname(dns_request::domain_to_labels(&zone.mname))
+    .rname(dns_request::domain_to_labels(&zone.rname))
+    .serial(zone.serial)
+    .refresh(zone.refresh)
+    .retry(zone.retry)
+    .expire(zone.expire)
+    .minimum(zone.minimum);
+
+    DnsAnswer::default()
+    .name(dns_request::domain_to_labels(&zone.name))
+    .ttl(zone.ttl)
+    .record(DnsRecordType::new_soa(auth_record))
+}
+
+///Returns the configured weight of a local A or AAAA record, identified by its name, type and rdata
+pub fn weight(name: &[String], type_code: u16, rdata: &[u8]) -> Option<u32> {
+    let name_str = name.join(".").to_lowercase();
+    config::get().local_index.get(&name_str)?.iter()
+    .filter(|(local, _)| local.weight.is_some())
+    .find(|(_, record)| {
+        let (code, data) = record.to_byte();
+        (code == 1 || code == 28) && code == type_code && data.as_deref() == Some(rdata) //1 = A, 28 = AAAA
+    })
+    .and_then(|(local, _)| local.weight)
+}
+
+pub(crate) fn to_record(local: &LocalRecord) -> Option<DnsRecordType> {
+    match local.r#type.to_uppercase().as_str() {
+        "A" => DnsRecordType::new_a(&local.value),
+        "AAAA" => DnsRecordType::new_aaaa(&local.value),
+        "CNAME" => DnsRecordType::new_cname(&local.value),
+        "TXT" => DnsRecordType::new_txt(&local.value),
+        "MX" => DnsRecordType::new_mx(&local.value),
+        "PTR" => DnsRecordType::new_ptr(&local.value),
+        "TLSA" => match &local.certificate {
+            Some(path) => tlsa_from_certificate(path, local.tlsa_usage, local.tlsa_selector),
+            None => DnsRecordType::new_tlsa(&local.value)
+        },
+        _ => None
+    }
+}
+
+///Reads the records of zone files (lists in the format of local_records, yaml if the file ends in .yaml or .yml and json otherwise).
+///A file which can't be read or parsed is logged and skipped, as is every entry which isn't a valid record of its type
+pub fn zone_file_records(paths: &Vec<String>) -> Vec<LocalRecord> {
+    let mut records: Vec<LocalRecord> = Vec::new();
+    for path in paths {
+        let contents = match fs::read_to_string(path) {
+            Ok(val) => val,
+            Err(err) => {
+                log!("Failed to read zone file {}: {}", path, err);
+                continue;
+            }
+        };
+        let parsed = if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str::<Vec<LocalRecord>>(&contents).map_err(|err| err.to_string())
+        }
+        else {
+            serde_json::from_str::<Vec<LocalRecord>>(&contents).map_err(|err| err.to_string())
+        };
+        let entries = match parsed {
+            Ok(val) => val,
+            Err(err) => {
+                log!("Failed to parse zone file {}: {}", path, err);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            if to_record(&entry).is_none() {
+                log!("Invalid record in zone file {} for {}: {} {}", path, entry.name, entry.r#type, entry.value);
+                continue;
+            }
+            records.push(entry);
+        }
+    }
+
+    records
+}
+
+///Creates a TLSA record (matching type 1: SHA-256) from a PEM or DER encoded certificate file.
+///The digest is taken of the full certificate for selector 0 and of the SubjectPublicKeyInfo for selector 1
+pub fn tlsa_from_certificate(path: &str, usage: u8, selector: u8) -> Option<DnsRecordType> {
+    let contents = match fs::read(path) {
+        Ok(val) => val,
+        Err(err) => {
+            log!("Failed to read certificate {}: {}", path, err);
+            return None;
+        }
+    };
+
+    let certificate = match X509::from_pem(&contents) {
+        Ok(val) => val,
+        Err(_) => match X509::from_der(&contents) {
+            Ok(val) => val,
+            Err(_) => return None
+        }
+    };
+
+    let selected = match selector {
+        0 => certificate.to_der().ok()?,
+        1 => certificate.public_key().ok()?.public_key_to_der().ok()?,
+        _ => return None
+    };
+    let digest = hash(MessageDigest::sha256(), &selected).ok()?;
+
+    let mut rdata: Vec<u8> = vec!(usage, selector, 1); //1 = SHA-256
+    rdata.extend_from_slice(&digest);
+
+    Some(DnsRecordType::TLSA(Some(rdata)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ Config, local_record };
+
+    const CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/certificate.pem");
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn tlsa_from_certificate_test() {
+        let spki = tlsa_from_certificate(CERTIFICATE, 3, 1).unwrap().to_byte().1.unwrap();
+        assert_eq!(&spki[0..3], &[3, 1, 1]);
+        assert_eq!(hex(&spki[3..]), "f52cf5d6098fa4eb3ae954b073f7cab8e2cd9c41e53b1f9baa732e7ca663798a");
+
+        let full = tlsa_from_certificate(CERTIFICATE, 3, 0).unwrap().to_byte().1.unwrap();
+        assert_eq!(&full[0..3], &[3, 0, 1]);
+        assert_eq!(hex(&full[3..]), "1d241e2754156d41d736947f0f52c65dc3a739de68479b09bc943b229645c555");
+
+        assert_eq!(tlsa_from_certificate(CERTIFICATE, 3, 2), None);
+        assert_eq!(tlsa_from_certificate("./missing.pem", 3, 1), None);
+    }
+
+    #[test]
+    fn multiple_ptr_test() {
+        let ptr = |hostname: &str| local_record("10.2.0.192.in-addr.arpa", "PTR", hostname);
+        let config = Config {
+            local_records: vec!(ptr("nas.example.home"), ptr("media.example.home.")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let name = dns_request::domain_to_labels("10.2.0.192.in-addr.arpa");
+        let answers = lookup(&name, &DnsRecordType::PTR(None)).unwrap();
+
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].r#type, DnsRecordType::PTR(Some(dns_request::labels_to_wire(&dns_request::domain_to_labels("nas.example.home")).unwrap())));
+        assert_eq!(answers[1].r#type, DnsRecordType::PTR(Some(dns_request::labels_to_wire(&dns_request::domain_to_labels("media.example.home")).unwrap())));
+        assert!(lookup(&name, &DnsRecordType::A(None)).is_none());
+    }
+
+    #[test]
+    fn local_tlsa_test() {
+        let certificate = std::env::temp_dir().join(format!("dns-zone-certificate-{}.pem", std::process::id()));
+        fs::copy(CERTIFICATE, &certificate).unwrap();
+        let config = Config {
+            local_records: vec!(LocalRecord {
+                certificate: Some(certificate.to_string_lossy().into_owned()),
+                ..local_record("_443._tcp.www.example.home", "TLSA", "")
+            }),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+        //The certificate is only read when the configuration is loaded
+        fs::remove_file(&certificate).unwrap();
+
+        let name: Vec<String> = "_443._tcp.www.example.home".split(".").map(String::from).collect();
+        let answers = lookup(&name, &DnsRecordType::TLSA(None)).unwrap();
+
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].ttl, 300);
+        assert_eq!(answers[0].rdata.len(), 3 + 32);
+        assert_eq!(hex(&answers[0].rdata[3..7]), "f52cf5d6");
+        assert!(lookup(&name, &DnsRecordType::A(None)).is_none());
+    }
+
+    #[test]
+    fn zone_file_test() {
+        let path = std::env::temp_dir().join(format!("dns-zone-file-{}.json", std::process::id()));
+        fs::write(&path, r#"[
+            { "name": "www.zonefile.home", "type": "A", "value": "192.0.2.1", "ttl": 600 },
+            { "name": "www.zonefile.home", "type": "AAAA", "value": "2001:db8::1" },
+            { "name": "zonefile.home", "type": "MX", "value": "10 mail.zonefile.home.", "ttl": 3600 },
+            { "name": "zonefile.home", "type": "TXT", "value": "v=spf1 mx -all" },
+            { "name": "bad.zonefile.home", "type": "MX", "value": "mail.zonefile.home" }
+        ]"#).unwrap();
+        let records = zone_file_records(&vec!(path.to_string_lossy().into_owned(), String::from("./missing.json")));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 4);
+
+        let config = Config {
+            local_records: records,
+            ..Config::default()
+        };
+        config::set_for_test(config);
+        let www = dns_request::domain_to_labels("www.zonefile.home");
+        let zone = dns_request::domain_to_labels("zonefile.home");
+
+        let a = lookup(&www, &DnsRecordType::A(None)).unwrap();
+        assert_eq!(a[0].rdata, vec!(192, 0, 2, 1));
+        assert_eq!(a[0].ttl, 600);
+
+        let aaaa = lookup(&www, &DnsRecordType::AAAA(None)).unwrap();
+        assert_eq!(aaaa[0].rdata, "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets().to_vec());
+        assert_eq!(aaaa[0].ttl, 300);
+
+        let mut mx: Vec<u8> = vec!(0, 10);
+        mx.extend(dns_request::labels_to_wire(&dns_request::domain_to_labels("mail.zonefile.home")).unwrap());
+        assert_eq!(lookup(&zone, &DnsRecordType::MX(None)).unwrap()[0].rdata, mx);
+
+        let txt = lookup(&zone, &DnsRecordType::TXT(None)).unwrap();
+        assert_eq!(txt[0].rdata[0] as usize, "v=spf1 mx -all".len());
+        assert_eq!(&txt[0].rdata[1..], b"v=spf1 mx -all");
+        assert!(lookup(&dns_request::domain_to_labels("bad.zonefile.home"), &DnsRecordType::MX(None)).is_none());
+    }
+
+    #[test]
+    fn cname_loop_test() {
+        let cname = |name: &str, target: &str| local_record(name, "CNAME", target);
+        let config = Config {
+            local_records: vec!(cname("a.loop.home", "b.loop.home."), cname("b.loop.home", "A.loop.home")),
+            ..Config::default()
+        };
+        config::set_for_test(config);
+
+        let answers = lookup(&dns_request::domain_to_labels("a.loop.home"), &DnsRecordType::A(None)).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].name, dns_request::domain_to_labels("a.loop.home"));
+        assert_eq!(answers[1].name, dns_request::domain_to_labels("b.loop.home"));
+    }
+}
@@ -0,0 +1,32 @@
+//! The startup gate, which unit tests can't exercise as they share the (always ready) state of one process
+
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpListener, TcpStream };
+use std::thread;
+use std::time::Duration;
+
+use dns::{ database, health };
+
+fn probe(address: SocketAddr) -> String {
+    let mut client = TcpStream::connect(address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+
+    response
+}
+
+#[test]
+fn ready_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || health::serve(listener));
+
+    assert!(!database::is_ready());
+    assert!(probe(address).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+    database::set_ready();
+    assert!(database::is_ready());
+    assert!(probe(address).starts_with("HTTP/1.1 200 OK\r\n"));
+}